@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
 use language::{interpreter::Interpreter, perform_on_interpreter};
+use language::compiler::core::{header_arity, header_rc, header_tag};
 use serde::Serialize;
 use serde_wasm_bindgen::preserve::serialize;
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
@@ -34,6 +35,18 @@ impl From<InterpreterData> for Data {
     }
 }
 
+// The packed header is shown as its tag, size and refs. Unboxed tuples have -1
+// in its place
+fn header_data(value: InterpreterData) -> Data {
+    match value {
+        InterpreterData::Value(x) if x >= 0 => Data {
+            is_ptr: false,
+            val: format!("{} {} {}", header_tag(x), header_arity(x), header_rc(x))
+        },
+        value => value.into()
+    }
+}
+
 pub fn take_interpreter_memory_snapshot_helper(interp: &Interpreter) -> MemorySnapshot {
     let mut variables = BTreeMap::new();
 
@@ -43,7 +56,9 @@ pub fn take_interpreter_memory_snapshot_helper(interp: &Interpreter) -> MemorySn
 
     MemorySnapshot { 
         variables, 
-        heap: interp.get_memory_raw().into_iter().map(|x| x.into_iter().map(|x| x.into()).collect()).collect(),
+        heap: interp.get_memory_raw().into_iter().map(|x| {
+            x.into_iter().enumerate().map(|(i, x)| if i == 0 { header_data(x) } else { x.into() }).collect()
+        }).collect(),
         call_stack: interp.get_function_names_stack()
     }
 }
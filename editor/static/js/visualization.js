@@ -175,16 +175,14 @@ async function update_visualization() {
         fields = fields.map((field, i) => {
             let label = "";
 
-            if(i == 0) label = "Tag";
-            else if (i == 1) label = "Size";
-            else if (i == 2) label = "Refs";
+            if(i == 0) label = "Header";
 
             field.label = label;
         
             return { data: field, index: i }
         })
 
-        if(!show_header) fields.splice(0, 3);
+        if(!show_header) fields.splice(0, 1);
 
         let node_id = i.toString();
         let node = {
@@ -203,7 +201,7 @@ async function update_visualization() {
 
         node.edges = [];
         for(let j = 0; j < fields.length; j++) {
-            let port_id = `${node_id}[${j + (show_header ? 0 : 3)}]`;
+            let port_id = `${node_id}[${j + (show_header ? 0 : 1)}]`;
 
             node.ports.push({
                 id: port_id,
@@ -456,7 +454,7 @@ async function update_visualization() {
             d3.select(this)
                 .selectAll(".field")
                 .stable_data(p.data.fields, d => d.index)
-                .call(join_field, (_, i) => [field_dx(i), field_dy(i)], d => "field" + (d.index < 3 ? " header-field" : ""), _ => {}, 
+                .call(join_field, (_, i) => [field_dx(i), field_dy(i)], d => "field" + (d.index < 1 ? " header-field" : ""), _ => {}, 
                     exit => {
                         exit
                             .transition(node_exit_transition)
@@ -8,7 +8,7 @@ for src_dir in reverse_fip reverse_nofip; do
     base=$(basename "$c_file" .c)
     out_bin="${out_dir}/${base}"
 
-    gcc -Wno-int-conversion "$c_file" -o "$out_bin"
+    gcc -Wall -Wextra -Werror -std=c11 -I ../runtime "$c_file" -o "$out_bin"
 
     echo "Compiled $c_file -> $out_bin"
   done
@@ -18,7 +18,7 @@ for dir in FIP NOFIP; do
 
     # Compile the .c file with gcc
 
-    gcc -Wno-int-conversion "$c_file" -o "$bin_file"
+    gcc -Wall -Wextra -Werror -std=c11 -I ../runtime "$c_file" -o "$bin_file"
     echo "Compiled binary: $c_file -> $bin_file"
   done
 done
@@ -0,0 +1,189 @@
+// Runtime of the C programs generated by goopea. Programs check the version
+// they were generated for, so a stale copy of this header is caught at
+// compile time. Define GOOPEA_FREE_LIST to recycle freed cells through
+// per-size free lists and GOOPEA_ALLOC_STATS to count allocations
+#ifndef GOOPEA_RUNTIME_H
+#define GOOPEA_RUNTIME_H
+
+#define GOOPEA_RUNTIME_VERSION 2
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef int64_t Value;
+
+// A cell starts with one header word holding the tag in the low byte, the
+// arity in the next and the reference count in the rest. Cells built at
+// compile time have a count of zero that inc and dec leave alone
+#define HEADER_WORDS 1
+#define RC_ONE ((Value) 1 << 16)
+#define STATIC_HEADER(tag, arity) ((Value) (tag) | (Value) (arity) << 8)
+#define HEADER(tag, arity) (STATIC_HEADER(tag, arity) | RC_ONE)
+#define FIELD(p, i) (((Value*) (p))[i])
+#define TAG(p) (FIELD(p, 0) & 0xff)
+#define ARITY(p) ((FIELD(p, 0) >> 8) & 0xff)
+#define RC(p) (FIELD(p, 0) >> 16)
+#define IS_INT(v) ((v) & 1)
+// Constructors without fields are immediates holding their tag, the others
+// are cells
+#define CTOR(v) (IS_INT(v) ? (v) >> 1 : TAG(v))
+
+// Ints are stored shifted with the low bit set, which tells them from cells
+static inline Value goopea_int(int64_t i) {
+	return (Value) ((uint64_t) i << 1) | 1;
+}
+
+static inline int64_t goopea_from_int(Value v) {
+	return v >> 1;
+}
+
+// Freed cells are kept in a list per size, chained through their first
+// word, and handed out again before malloc is asked for a new one
+#define SIZE_CLASSES 16
+
+#ifdef GOOPEA_FREE_LIST
+static Value* goopea_free_lists[SIZE_CLASSES];
+#endif
+#ifdef GOOPEA_ALLOC_STATS
+static long long goopea_mallocs, goopea_reused, goopea_frees, goopea_live, goopea_peak;
+#endif
+
+static inline Value goopea_alloc_cell(int words) {
+#ifdef GOOPEA_ALLOC_STATS
+	if (++goopea_live > goopea_peak) goopea_peak = goopea_live;
+#endif
+#ifdef GOOPEA_FREE_LIST
+	if (words < SIZE_CLASSES && goopea_free_lists[words]) {
+		Value* cell = goopea_free_lists[words];
+		goopea_free_lists[words] = (Value*) cell[0];
+#ifdef GOOPEA_ALLOC_STATS
+		goopea_reused++;
+#endif
+		return (Value) cell;
+	}
+#endif
+#ifdef GOOPEA_ALLOC_STATS
+	goopea_mallocs++;
+#endif
+	return (Value) malloc(words * sizeof(Value));
+}
+
+static inline void goopea_free_cell(Value* cell, int words) {
+#ifdef GOOPEA_ALLOC_STATS
+	goopea_live--;
+	goopea_frees++;
+#endif
+#ifdef GOOPEA_FREE_LIST
+	if (words < SIZE_CLASSES) {
+		cell[0] = (Value) goopea_free_lists[words];
+		goopea_free_lists[words] = cell;
+		return;
+	}
+#else
+	(void) words;
+#endif
+	free(cell);
+}
+
+static inline void goopea_alloc_stats(void) {
+#ifdef GOOPEA_ALLOC_STATS
+	fprintf(stderr, "mallocs: %lld, reused: %lld, frees: %lld, peak: %lld\n",
+		goopea_mallocs, goopea_reused, goopea_frees, goopea_peak);
+#endif
+}
+
+static inline Value goopea_inc(Value ref) {
+	if (!IS_INT(ref) && RC(ref)) {
+		FIELD(ref, 0) += RC_ONE;
+	}
+	return ref;
+}
+
+// Drops a reference, and if it was the last one pushes the cell on the cells
+// still to visit. The cells only it holds on to through last fields are
+// already chained through those fields, so they go on as they are and the
+// last of them is chained to the rest. Every cell in the chain is freed, so
+// its last field is no longer needed, and the chain takes the place of the
+// C stack whatever the width of addresses
+static inline Value* goopea_dec_onto(Value ref, Value* todo) {
+	if (IS_INT(ref)) {
+		return todo;
+	}
+	if (RC(ref) != 1) {
+		if (RC(ref)) FIELD(ref, 0) -= RC_ONE;
+		return todo;
+	}
+	Value* cell = (Value*) ref;
+	Value next = cell[ARITY(cell)];
+	while (!IS_INT(next) && RC(next) == 1) {
+		cell = (Value*) next;
+		next = cell[ARITY(cell)];
+	}
+	if (!IS_INT(next) && RC(next)) {
+		FIELD(next, 0) -= RC_ONE;
+	}
+	cell[ARITY(cell)] = (Value) todo;
+	return (Value*) ref;
+}
+
+// Each cell is freed before its fields, which are visited from first to last.
+// The interpreter, the VM and the other runtimes free in the same order
+static inline void goopea_free_cells(Value* cell) {
+	Value* todo = goopea_dec_onto((Value) cell, NULL);
+	while (todo) {
+		Value* ptr = todo;
+		todo = (Value*) ptr[ARITY(ptr)];
+		for (int i = ARITY(ptr) - 1; i >= 1; i--) {
+			todo = goopea_dec_onto(ptr[i], todo);
+		}
+		goopea_free_cell(ptr, ARITY(ptr) + HEADER_WORDS);
+	}
+}
+
+static inline Value goopea_dec(Value ref) {
+	if (!IS_INT(ref)) {
+		if (RC(ref) == 1) {
+			goopea_free_cells((Value*) ref);
+		}
+		else if (RC(ref)) {
+			FIELD(ref, 0) -= RC_ONE;
+		}
+	}
+	return ref;
+}
+
+static inline void goopea_decu(Value* fields, int size) {
+	for (int i = 0; i < size; i++) {
+		goopea_dec(fields[i]);
+	}
+}
+
+// The cell to reuse if the reference was the last one, otherwise 0
+static inline Value goopea_drop_reuse(Value ref) {
+	if (RC(ref) == 1) {
+		for (int i = 1; i <= ARITY(ref); i++) {
+			goopea_dec(FIELD(ref, i));
+		}
+		return ref;
+	}
+	if (RC(ref)) FIELD(ref, 0) -= RC_ONE;
+	return 0;
+}
+
+// Like goopea_drop_reuse, but the fields in the keep mask stay in the cell
+static inline Value goopea_drop_reuse_keep(Value ref, Value keep) {
+	if (RC(ref) == 1) {
+		for (int i = 0; i < ARITY(ref); i++) {
+			if (!((keep >> i) & 1)) goopea_dec(FIELD(ref, i + 1));
+		}
+		return ref;
+	}
+	for (int i = 0; i < ARITY(ref); i++) {
+		if ((keep >> i) & 1) goopea_inc(FIELD(ref, i + 1));
+	}
+	if (RC(ref)) FIELD(ref, 0) -= RC_ONE;
+	return 0;
+}
+
+#endif
@@ -0,0 +1,258 @@
+; Runtime of the LLVM IR programs generated by goopea, pasted at the top of
+; every program. It lays out cells like goopea_runtime.h does, but always
+; allocates with malloc and keeps no allocation stats. Values are i64
+; everywhere, cells are turned into pointers where they are read
+
+declare i8* @malloc(i64)
+declare void @free(i8*)
+declare i32 @printf(i8*, ...)
+
+@goopea_int_format = private unnamed_addr constant [5 x i8] c"%lld\00"
+
+define internal i64 @goopea_alloc_cell(i64 %words) {
+entry:
+  %size = shl i64 %words, 3
+  %cell = call i8* @malloc(i64 %size)
+  %value = ptrtoint i8* %cell to i64
+  ret i64 %value
+}
+
+define internal void @goopea_free_cell(i64 %cell, i64 %words) {
+entry:
+  %ptr = inttoptr i64 %cell to i8*
+  call void @free(i8* %ptr)
+  ret void
+}
+
+define internal i64 @goopea_rc(i64 %ref) {
+entry:
+  %ptr = inttoptr i64 %ref to i64*
+  %header = load i64, i64* %ptr
+  %rc = ashr i64 %header, 16
+  ret i64 %rc
+}
+
+; Constructors without fields are immediates holding their tag, the others
+; are cells
+define internal i64 @goopea_ctor(i64 %v) {
+entry:
+  %bit = and i64 %v, 1
+  %is_int = icmp ne i64 %bit, 0
+  br i1 %is_int, label %immediate, label %cell
+immediate:
+  %tag = ashr i64 %v, 1
+  ret i64 %tag
+cell:
+  %ptr = inttoptr i64 %v to i64*
+  %header = load i64, i64* %ptr
+  %cell_tag = and i64 %header, 255
+  ret i64 %cell_tag
+}
+
+define internal i64 @goopea_inc(i64 %ref) {
+entry:
+  %bit = and i64 %ref, 1
+  %is_int = icmp ne i64 %bit, 0
+  br i1 %is_int, label %done, label %cell
+cell:
+  %ptr = inttoptr i64 %ref to i64*
+  %header = load i64, i64* %ptr
+  %rc = ashr i64 %header, 16
+  %static = icmp eq i64 %rc, 0
+  br i1 %static, label %done, label %count
+count:
+  %next = add i64 %header, 65536
+  store i64 %next, i64* %ptr
+  br label %done
+done:
+  ret i64 %ref
+}
+
+; Drops a reference that is not the last one, unless the cell is static
+define internal void @goopea_release(i64 %ref) {
+entry:
+  %ptr = inttoptr i64 %ref to i64*
+  %header = load i64, i64* %ptr
+  %rc = ashr i64 %header, 16
+  %static = icmp eq i64 %rc, 0
+  br i1 %static, label %done, label %count
+count:
+  %next = sub i64 %header, 65536
+  store i64 %next, i64* %ptr
+  br label %done
+done:
+  ret void
+}
+
+; Drops a reference, and if it was the last one pushes the cell on the cells
+; still to visit. The cells only it holds on to through last fields are
+; already chained through those fields, so they go on as they are and the
+; last of them is chained to the rest. Every cell in the chain is freed, so
+; its last field is no longer needed, and the chain takes the place of the
+; stack whatever the width of addresses
+define internal i64 @goopea_dec_onto(i64 %ref, i64 %todo) {
+entry:
+  %bit = and i64 %ref, 1
+  %is_int = icmp ne i64 %bit, 0
+  br i1 %is_int, label %done, label %cell
+cell:
+  %rc = call i64 @goopea_rc(i64 %ref)
+  %last = icmp eq i64 %rc, 1
+  br i1 %last, label %walk, label %shared
+shared:
+  call void @goopea_release(i64 %ref)
+  br label %done
+walk:
+  %spine = phi i64 [ %ref, %cell ], [ %next, %next.cell ]
+  %ptr = inttoptr i64 %spine to i64*
+  %header = load i64, i64* %ptr
+  %arity.shifted = ashr i64 %header, 8
+  %arity = and i64 %arity.shifted, 255
+  %last.ptr = getelementptr i64, i64* %ptr, i64 %arity
+  %next = load i64, i64* %last.ptr
+  %next.bit = and i64 %next, 1
+  %next.is_int = icmp ne i64 %next.bit, 0
+  br i1 %next.is_int, label %chain, label %next.cell
+next.cell:
+  %next.rc = call i64 @goopea_rc(i64 %next)
+  %next.last = icmp eq i64 %next.rc, 1
+  br i1 %next.last, label %walk, label %next.shared
+next.shared:
+  call void @goopea_release(i64 %next)
+  br label %chain
+chain:
+  store i64 %todo, i64* %last.ptr
+  ret i64 %ref
+done:
+  ret i64 %todo
+}
+
+; Each cell is freed before its fields, which are visited from first to last.
+; The interpreter, the VM and the other runtimes free in the same order
+define internal void @goopea_free_cells(i64 %first) {
+entry:
+  %todo = alloca i64
+  %i = alloca i64
+  %first.todo = call i64 @goopea_dec_onto(i64 %first, i64 0)
+  store i64 %first.todo, i64* %todo
+  br label %next
+next:
+  %cell = load i64, i64* %todo
+  %empty = icmp eq i64 %cell, 0
+  br i1 %empty, label %exit, label %visit
+visit:
+  %ptr = inttoptr i64 %cell to i64*
+  %header = load i64, i64* %ptr
+  %arity.shifted = ashr i64 %header, 8
+  %arity = and i64 %arity.shifted, 255
+  %last.ptr = getelementptr i64, i64* %ptr, i64 %arity
+  %rest = load i64, i64* %last.ptr
+  store i64 %rest, i64* %todo
+  %before.last = sub i64 %arity, 1
+  store i64 %before.last, i64* %i
+  br label %field
+field:
+  %index = load i64, i64* %i
+  %more = icmp sge i64 %index, 1
+  br i1 %more, label %check, label %free
+check:
+  %field.ptr = getelementptr i64, i64* %ptr, i64 %index
+  %value = load i64, i64* %field.ptr
+  %index.next = sub i64 %index, 1
+  store i64 %index.next, i64* %i
+  %pending = load i64, i64* %todo
+  %pushed = call i64 @goopea_dec_onto(i64 %value, i64 %pending)
+  store i64 %pushed, i64* %todo
+  br label %field
+free:
+  %words = add i64 %arity, 1
+  call void @goopea_free_cell(i64 %cell, i64 %words)
+  br label %next
+exit:
+  ret void
+}
+
+define internal i64 @goopea_dec(i64 %ref) {
+entry:
+  %bit = and i64 %ref, 1
+  %is_int = icmp ne i64 %bit, 0
+  br i1 %is_int, label %done, label %cell
+cell:
+  %rc = call i64 @goopea_rc(i64 %ref)
+  %last = icmp eq i64 %rc, 1
+  br i1 %last, label %free, label %shared
+free:
+  call void @goopea_free_cells(i64 %ref)
+  br label %done
+shared:
+  call void @goopea_release(i64 %ref)
+  br label %done
+done:
+  ret i64 %ref
+}
+
+; The cell to reuse if the reference was the last one, otherwise 0. The
+; fields in the keep mask stay in the cell
+define internal i64 @goopea_drop_reuse_keep(i64 %ref, i64 %keep) {
+entry:
+  %i = alloca i64
+  %ptr = inttoptr i64 %ref to i64*
+  %header = load i64, i64* %ptr
+  %rc = ashr i64 %header, 16
+  %arity.shifted = ashr i64 %header, 8
+  %arity = and i64 %arity.shifted, 255
+  store i64 0, i64* %i
+  %last = icmp eq i64 %rc, 1
+  br i1 %last, label %drop, label %share
+drop:
+  %index = load i64, i64* %i
+  %more = icmp slt i64 %index, %arity
+  br i1 %more, label %drop.field, label %reuse
+drop.field:
+  %index.next = add i64 %index, 1
+  store i64 %index.next, i64* %i
+  %kept.shifted = ashr i64 %keep, %index
+  %kept.bit = and i64 %kept.shifted, 1
+  %kept = icmp ne i64 %kept.bit, 0
+  br i1 %kept, label %drop, label %drop.dec
+drop.dec:
+  %field.ptr = getelementptr i64, i64* %ptr, i64 %index.next
+  %field = load i64, i64* %field.ptr
+  %dropped = call i64 @goopea_dec(i64 %field)
+  br label %drop
+reuse:
+  ret i64 %ref
+share:
+  %share.index = load i64, i64* %i
+  %share.more = icmp slt i64 %share.index, %arity
+  br i1 %share.more, label %share.field, label %release
+share.field:
+  %share.next = add i64 %share.index, 1
+  store i64 %share.next, i64* %i
+  %share.shifted = ashr i64 %keep, %share.index
+  %share.bit = and i64 %share.shifted, 1
+  %share.kept = icmp ne i64 %share.bit, 0
+  br i1 %share.kept, label %share.inc, label %share
+share.inc:
+  %share.ptr = getelementptr i64, i64* %ptr, i64 %share.next
+  %share.value = load i64, i64* %share.ptr
+  %shared = call i64 @goopea_inc(i64 %share.value)
+  br label %share
+release:
+  call void @goopea_release(i64 %ref)
+  ret i64 0
+}
+
+define internal i64 @goopea_drop_reuse(i64 %ref) {
+entry:
+  %cell = call i64 @goopea_drop_reuse_keep(i64 %ref, i64 0)
+  ret i64 %cell
+}
+
+define internal void @goopea_print_int(i64 %v) {
+entry:
+  %i = ashr i64 %v, 1
+  %format = getelementptr inbounds [5 x i8], [5 x i8]* @goopea_int_format, i64 0, i64 0
+  %written = call i32 (i8*, ...) @printf(i8* %format, i64 %i)
+  ret void
+}
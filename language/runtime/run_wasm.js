@@ -0,0 +1,20 @@
+// Runs a module from the wasm backend with node: node run_wasm.js program.wasm
+// The result of main is written to stdout and traces to stderr. Externs are
+// not provided, a program calling them needs a host that defines them
+const fs = require("fs");
+
+const bytes = fs.readFileSync(process.argv[2]);
+let memory;
+const imports = {
+  env: {
+    print_int: (value) => process.stdout.write(value.toString()),
+    print_text: (offset, length) =>
+      process.stdout.write(Buffer.from(memory.buffer, offset, length)),
+    trace: (value) => process.stderr.write(`trace: ${value}\n`),
+  },
+};
+
+WebAssembly.instantiate(bytes, imports).then(({ instance }) => {
+  memory = instance.exports.memory;
+  instance.exports.main();
+});
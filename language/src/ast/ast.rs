@@ -21,13 +21,16 @@ pub struct Program<D, E> {
     pub adts: BTreeMap<AID, Vec<FID>>,
     pub constructors: BTreeMap<FID, Constructor>,
     pub function_datas: BTreeMap<FID, FunctionData>,
-    pub function_bodies: BTreeMap<FID, ExpressionNode<D, E>>
+    pub function_bodies: BTreeMap<FID, ExpressionNode<D, E>>,
+    // Functions implemented outside the program, in C or by the interpreter
+    pub externs: BTreeMap<FID, FunctionSignature>
 }
 
 pub struct ProgramData {
     pub adts: BTreeMap<AID, Vec<FID>>,
     pub constructors: BTreeMap<FID, Constructor>,
     pub function_datas: BTreeMap<FID, FunctionData>,
+    pub externs: BTreeMap<FID, FunctionSignature>,
 }
 
 #[derive(Debug, Clone)]
@@ -47,7 +50,16 @@ pub struct FunctionData {
 pub struct FunctionSignature {
     pub argument_type: UTuple<Type>,
     pub result_type: UTuple<Type>,
-    pub is_fip: bool
+    pub is_fip: bool,
+    pub inline: Inline
+}
+
+// What the definition asks of the inliner, Auto leaves it to the size heuristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inline {
+    Auto,
+    Always,
+    Never
 }
 
 #[derive(Debug)]
@@ -149,13 +161,14 @@ impl<D, E> Program<D, E> {
             adts: self.adts, 
             constructors: self.constructors,
             function_datas: self.function_datas,
-            function_bodies: self.function_bodies.into_iter().map(|(fid, body)| (fid, body.map())).collect()
+            function_bodies: self.function_bodies.into_iter().map(|(fid, body)| (fid, body.map())).collect(),
+            externs: self.externs
         }
     }
 
     pub fn split_data_and_bodies(self) -> (ProgramData, BTreeMap<FID, ExpressionNode<D, E>>) {
         (
-            ProgramData { adts: self.adts, constructors: self.constructors, function_datas: self.function_datas },
+            ProgramData { adts: self.adts, constructors: self.constructors, function_datas: self.function_datas, externs: self.externs },
             self.function_bodies
         )
     }
@@ -170,7 +183,8 @@ impl<D, E> Program<D, E> {
             adts: program_data.adts,
             constructors: program_data.constructors, 
             function_datas: program_data.function_datas, 
-            function_bodies
+            function_bodies,
+            externs: program_data.externs
         })
     }
 }
@@ -280,6 +294,11 @@ impl<D: DisplayData, E> Display for Program<D, E>
             writeln!(f)?;
         }
 
+        for (fid, signature) in &self.externs {
+            writeln!(f, "extern {}:{} {fid};", signature.argument_type, signature.result_type)?;
+            writeln!(f)?;
+        }
+
         for (fid, func, body) in self.function_iter() {
             writeln!(f, "{}\n{fid}{} =", func.signature, func.vars)?;
             write_expression_node(f, body, 1)?;
@@ -490,6 +509,11 @@ impl Display for Type {
 
 impl Display for FunctionSignature {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.inline {
+            Inline::Auto => (),
+            Inline::Always => write!(f, "inline ")?,
+            Inline::Never => write!(f, "noinline ")?
+        }
         if self.is_fip { write!(f, "fip ")?; }
 
         write!(f, "{}:{}", self.argument_type, self.result_type)
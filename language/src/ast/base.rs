@@ -2,7 +2,7 @@ use std::{collections::{BTreeMap, HashSet}, fmt::Display, iter::once, ops::{Boun
 
 use crate::{error::{Error, ErrorReason, Result}, grammar, lexer::Lexer};
 
-use super::ast::{Constructor, ExpressionNode, FullExpression, FunctionData, Operator, Pattern, Program, Type, UTuple, AID, FID, VID};
+use super::ast::{Constructor, ExpressionNode, FullExpression, FunctionData, FunctionSignature, Operator, Pattern, Program, Type, UTuple, AID, FID, VID};
 
 pub type BaseSliceNode<'i> = ExpressionNode<SourceReference<'i>, SyntaxExpression<SourceReference<'i>>>;
 pub type BaseSliceProgram<'i> = Program<SourceReference<'i>, SyntaxExpression<SourceReference<'i>>>;
@@ -13,7 +13,8 @@ pub type BaseRangeProgram = Program<Range<usize>, SyntaxExpression<Range<usize>>
 #[derive(Debug)]
 pub enum Definition {
     ADT(AID, Vec<(FID, UTuple<Type>)>),
-    Function(FID, (FunctionData, BaseRangeNode))
+    Function(FID, (FunctionData, BaseRangeNode)),
+    Extern(FID, FunctionSignature)
 }
 
 #[derive(Clone, Debug)]
@@ -45,6 +46,7 @@ impl<'i> BaseSliceProgram<'i> {
         let mut all_constructors = BTreeMap::new();
         let mut function_datas = BTreeMap::new();
         let mut function_bodies = BTreeMap::new();
+        let mut externs = BTreeMap::new();
         for def in program.into_iter().chain(builtin_defs.into_iter()) {
             match def {
                 Definition::ADT(aid, constructors) => {
@@ -64,6 +66,11 @@ impl<'i> BaseSliceProgram<'i> {
                         return Err(ErrorReason::MultipleFunctionDefinitions(fid).into())
                     }
                     function_bodies.insert(fid, body.make_slice(code, &linebreaks));
+                },
+                Definition::Extern(fid, signature) => {
+                    if externs.insert(fid.clone(), signature).is_some() {
+                        return Err(ErrorReason::MultipleFunctionDefinitions(fid).into())
+                    }
                 }
             }
         }
@@ -72,11 +79,20 @@ impl<'i> BaseSliceProgram<'i> {
             return Err(ErrorReason::MultipleFunctionDefinitions((*fid).clone()).into())
         }
 
+        if let Some(fid) = externs.keys().find(|fid| function_datas.contains_key(*fid) || all_constructors.contains_key(*fid)) {
+            return Err(ErrorReason::MultipleFunctionDefinitions(fid.clone()).into())
+        }
+
+        // Only Ints cross into C, where they are untagged and tagged again
+        if let Some((fid, _)) = externs.iter().find(|(_, sig)| sig.argument_type.0.iter().any(|tp| *tp != Type::Int) || sig.result_type.0 != [Type::Int]) {
+            return Err(ErrorReason::ExternWithNonIntType(fid.clone()).into())
+        }
+
         if !function_datas.contains_key("main") {
             return Err(ErrorReason::MissingMainFunction.into())
         }
 
-        let program = BaseSliceProgram { adts, constructors: all_constructors, function_datas, function_bodies };
+        let program = BaseSliceProgram { adts, constructors: all_constructors, function_datas, function_bodies, externs };
         program.validate_all_types()?;
 
         Ok(program)
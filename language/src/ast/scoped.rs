@@ -5,6 +5,7 @@ use std::{
     rc::Rc,
 };
 
+use crate::compiler::intrinsics::INTRINSICS;
 use crate::error::{Error, ErrorReason, Result};
 
 use super::{
@@ -62,6 +63,18 @@ impl<'i> ScopedProgram<'i> {
             .function_datas
             .iter()
             .filter_map(|(fid, sig)| (sig.vars.0.len() == 0).then_some(fid.clone()))
+            .chain(
+                program
+                    .externs
+                    .iter()
+                    .filter_map(|(fid, sig)| sig.argument_type.0.is_empty().then_some(fid.clone())),
+            )
+            .chain(
+                INTRINSICS
+                    .iter()
+                    .filter(|intrinsic| intrinsic.ownership.is_empty())
+                    .map(|intrinsic| intrinsic.id.to_string()),
+            )
             .collect();
 
         let program = program.transform_functions(|_, body, func, _| {
@@ -1,8 +1,8 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::error::{ErrorReason, Result, Error};
+use crate::{compiler::intrinsics::INTRINSICS, error::{ErrorReason, Result, Error}};
 
-use super::{ast::{ChainedData, ExpressionNode, FunctionSignature, Operator, Pattern, Program, Type, UTuple, FID}, base::SourceReference, scoped::{ScopedData, ScopedNode, ScopedProgram, SimplifiedExpression}};
+use super::{ast::{ChainedData, ExpressionNode, FunctionSignature, Inline, Operator, Pattern, Program, Type, UTuple, FID}, base::SourceReference, scoped::{ScopedData, ScopedNode, ScopedProgram, SimplifiedExpression}};
 
 pub type TypedData<'i> = ChainedData<ExpressionType, ScopedData<'i>>;
 
@@ -58,7 +58,8 @@ impl<'i> TypedProgram<'i> {
             all_function_signatures.insert(op.to_string(), FunctionSignature { 
                 argument_type: UTuple(vec![Type::Int, Type::Int]),
                 result_type: UTuple(vec![Type::Int]),
-                is_fip: true
+                is_fip: true,
+                inline: Inline::Auto
             });
         }
 
@@ -66,7 +67,8 @@ impl<'i> TypedProgram<'i> {
             all_function_signatures.insert(op.to_string(), FunctionSignature { 
                 argument_type: UTuple(vec![Type::Int, Type::Int]),
                 result_type: UTuple(vec![Type::ADT("Bool".to_string())]),
-                is_fip: true
+                is_fip: true,
+                inline: Inline::Auto
             });
         }
 
@@ -76,15 +78,25 @@ impl<'i> TypedProgram<'i> {
                 FunctionSignature {
                     argument_type: cons.args.clone(),
                     result_type: UTuple(vec! [Type::ADT(cons.adt.clone())]),
-                    is_fip: true
+                    is_fip: true,
+                    inline: Inline::Auto
                 }
             );
         }
 
+        // Functions of the program take the place of intrinsics with their name
+        for intrinsic in INTRINSICS {
+            all_function_signatures.insert(intrinsic.id.to_string(), intrinsic.signature());
+        }
+
         for (fid, func) in &program.function_datas {
             all_function_signatures.insert(fid.clone(), func.signature.clone());
         }
 
+        for (fid, signature) in &program.externs {
+            all_function_signatures.insert(fid.clone(), signature.clone());
+        }
+
         let program = program.transform_functions(|fid, body, func, _| {
             let func_vars = &func.vars.0;
             let func_types = &func.signature.argument_type.0;
@@ -1,6 +1,9 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::compiler::stir::{Body, Constant, Exp, Stir, Var};
+use crate::compiler::crux::Type;
+use crate::compiler::intrinsics::INTRINSICS;
+use crate::compiler::stir::{Body, Constant, Exp, Function, Stir, Var};
+use crate::compiler::trmc::hole_index;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Status {
@@ -10,6 +13,9 @@ pub enum Status {
 
 pub fn get_ownership(prog: &Stir) -> HashMap<Constant, Vec<Status>> {
     let mut map = HashMap::new();
+    for intrinsic in INTRINSICS {
+        map.insert(intrinsic.id.to_string(), intrinsic.ownership.to_vec());
+    }
     for func in prog {
         map.insert(func.id.clone(), vec![Status::Borrowed; func.args.len()]);
     }
@@ -26,6 +32,20 @@ pub fn get_ownership(prog: &Stir) -> HashMap<Constant, Vec<Status>> {
                     new_map.insert(func.id.clone(), ownership.clone());
                 }
             }
+            // Passing an owned value to a borrowed parameter would need a dec after
+            // the call, so such parameters are made owned to keep tail calls intact,
+            // including self calls that tail recursion modulo cons turns into loops
+            if func.id == "main" {
+                continue;
+            }
+            let borrowed = borrowed_vars(func, &new_map);
+            for (fid, args) in tail_calls(&func.id, &func.body) {
+                for (i, arg) in args.iter().enumerate() {
+                    if arg.1 == Type::Heaped && !borrowed.contains(arg) {
+                        new_map.get_mut(fid).unwrap()[i] = Status::Owned;
+                    }
+                }
+            }
         }
         if map != new_map {
             map = new_map;
@@ -38,14 +58,14 @@ pub fn get_ownership(prog: &Stir) -> HashMap<Constant, Vec<Status>> {
 fn collect(body: &Body, map: &HashMap<Constant, Vec<Status>>) -> HashSet<Var> {
     match body {
         Body::Let(var, e, next) => match e {
-            Exp::Int(_) => collect(next, map),
+            Exp::Int(_) | Exp::Static(_) => collect(next, map),
             Exp::Ctor(_, _) => collect(next, map),
-            Exp::Reset(var) => {
+            Exp::Reset(var, _) => {
                 let mut set = collect(next, map);
                 set.insert(var.clone());
                 set
             }
-            Exp::Reuse(_, _, _) => collect(next, map),
+            Exp::Reuse(_, _, _, _, _) => collect(next, map),
             Exp::App(fid, args) => {
                 let mut set = collect(next, map);
                 if let Some(statuses) = map.get(fid) {
@@ -69,6 +89,7 @@ fn collect(body: &Body, map: &HashMap<Constant, Vec<Status>>) -> HashSet<Var> {
                 set
             }
             Exp::UTuple(_) => collect(next, map),
+            Exp::StackCtor(_, _) => panic!("Does not exist at this stage "),
         },
         Body::Ret(_) => HashSet::new(),
         Body::Match(_, branches) => {
@@ -78,6 +99,68 @@ fn collect(body: &Body, map: &HashMap<Constant, Vec<Status>>) -> HashSet<Var> {
             }
             combined
         }
+        Body::Join(_, _, join, body) => {
+            let mut set = collect(join, map);
+            set.extend(collect(body, map));
+            set
+        }
+        Body::Jump(_, _) => HashSet::new(),
         _ => panic!("Does not exist at this stage "),
     }
 }
+
+// Variables of a function that are not owned: its borrowed parameters and
+// whatever is projected out of them
+fn borrowed_vars(func: &Function, map: &HashMap<Constant, Vec<Status>>) -> HashSet<Var> {
+    fn helper(body: &Body, set: &mut HashSet<Var>) {
+        match body {
+            Body::Let(var, Exp::Proj(_, v), next) => {
+                if set.contains(v) {
+                    set.insert(var.clone());
+                }
+                helper(next, set);
+            }
+            Body::Let(_, _, next) => helper(next, set),
+            Body::Match(_, branches) => {
+                for (_, branch) in branches {
+                    helper(branch, set);
+                }
+            }
+            Body::Join(_, _, join, body) => {
+                helper(join, set);
+                helper(body, set);
+            }
+            _ => (),
+        }
+    }
+    let mut set = func
+        .args
+        .iter()
+        .zip(map.get(&func.id).unwrap())
+        .filter(|(_, status)| **status == Status::Borrowed)
+        .map(|(arg, _)| arg.clone())
+        .collect();
+    helper(&func.body, &mut set);
+    set
+}
+
+fn tail_calls<'a>(id: &str, body: &'a Body) -> Vec<(&'a Constant, &'a Vec<Var>)> {
+    match body {
+        Body::Let(var, Exp::App(fid, args), next) if **next == Body::Ret(var.clone()) => {
+            vec![(fid, args)]
+        }
+        Body::Let(var, Exp::App(fid, args), next)
+            if fid == id && hole_index(var, next).is_some() =>
+        {
+            vec![(fid, args)]
+        }
+        Body::Let(_, _, next) => tail_calls(id, next),
+        // Calls before a jump are not in tail position
+        Body::Join(_, _, join, _) => tail_calls(id, join),
+        Body::Match(_, branches) => branches
+            .iter()
+            .flat_map(|(_, branch)| tail_calls(id, branch))
+            .collect(),
+        _ => vec![],
+    }
+}
@@ -1,15 +1,60 @@
+use super::borrow::{Status, get_ownership};
 use super::core::Prog;
-use super::crux::{from_exp_type, from_type, from_typed_expr};
+use super::crux::{Layouts, externs, from_exp_type, from_type, from_typed_expr, layouts};
+use super::library::Export;
 use super::stir::remove_dead_bindings;
 use super::stir::{self, Stir};
 use super::stir::{Body, Function, from_simple};
+use crate::ast::ast::FID;
 use crate::ast::typed::TypedProgram;
 
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    // 0 leaves stir as translated, see simplify for what the levels enable
+    pub opt_level: u8,
+    // Non-recursive functions up to this size are inlined, 0 leaves only the
+    // ones marked inline
+    pub inline_size: usize,
+    // Restricts reuse analysis to functions marked fip, mainly for benchmarking
+    pub reuse_fip_only: bool,
+    // Leaves the tag and fields a reuse writes back unchanged in place
+    pub specialize_reuse: bool,
+    // Cancels inc/dec pairs and specializes decs of matched cells
+    pub fuse_rc: bool,
+    // Turns self calls in constructor context into loops filling a hole
+    pub trmc: bool,
+    // Builds constructors applied to literals only once, at compile time
+    pub static_data: bool,
+    // Puts constructors that never leave their frame on the stack
+    pub stack_alloc: bool,
+    // Only functions reachable from these are compiled
+    pub entry_points: Vec<String>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            opt_level: 2,
+            inline_size: 20,
+            reuse_fip_only: false,
+            specialize_reuse: true,
+            fuse_rc: true,
+            trmc: true,
+            static_data: true,
+            stack_alloc: true,
+            entry_points: vec!["main".to_string()],
+        }
+    }
+}
+
 pub struct CompiledProgram {
     pub stir: Stir,
+    // Constructors never built from the entry points, for warnings
+    pub unused_constructors: Vec<FID>,
     pub reuse: Stir,
     pub rc: Stir,
     pub core: Prog,
+    pub layouts: Layouts,
 }
 
 fn from_typed(typed: &TypedProgram) -> Stir {
@@ -18,14 +63,15 @@ fn from_typed(typed: &TypedProgram) -> Stir {
     for (id, func, body) in typed.function_iter() {
         stir.push(Function {
             fip: func.signature.is_fip,
+            inline: func.signature.inline,
             id: id.clone(),
-            typ: from_exp_type(&body.data.data),
+            typ: from_exp_type(&body.data.data, typed),
             args: func
                 .vars
                 .0
                 .iter()
                 .zip(func.signature.argument_type.0.iter())
-                .map(|(var, typ)| (var.clone(), from_type(typ)))
+                .map(|(var, typ)| (var.clone(), from_type(typ, typed)))
                 .collect(),
             body: remove_dead_bindings(from_simple(&from_typed_expr(body, typed), &|var| {
                 Body::Ret(var)
@@ -37,26 +83,82 @@ fn from_typed(typed: &TypedProgram) -> Stir {
 }
 
 pub fn compile_typed(typed: &TypedProgram) -> CompiledProgram {
-    let stir = from_typed(typed);
-    let reuse = crate::compiler::reuse::add_reuse(&stir);
-    let rc = crate::compiler::rc::add_rc(&reuse, true);
-    let core = crate::compiler::score::translate(&rc);
+    compile_typed_with(typed, &CompileOptions::default())
+}
+
+pub fn compile_typed_with(typed: &TypedProgram, options: &CompileOptions) -> CompiledProgram {
+    let stir = crate::compiler::inline::inline(&from_typed(typed), options.inline_size);
+    let stir = crate::compiler::simplify::simplify(&stir, options.opt_level);
+    let stir = crate::compiler::reach::remove_unreachable(&stir, &options.entry_points);
+    let stir = if options.static_data {
+        crate::compiler::statics::preallocate(&stir)
+    } else {
+        stir
+    };
+    let mut reuse = crate::compiler::reuse::add_reuse(&stir, options.reuse_fip_only);
+    if options.specialize_reuse {
+        reuse = crate::compiler::reuse::specialize_reuse(&reuse);
+    }
+    let mut rc = crate::compiler::rc::add_rc(&reuse, true);
+    if options.fuse_rc {
+        rc = crate::compiler::fusion::fuse_rc(&rc);
+    }
+    if options.trmc {
+        rc = crate::compiler::trmc::add_trmc(&rc);
+    }
+    if options.stack_alloc {
+        rc = crate::compiler::escape::stack_allocate(&rc);
+    }
+    let core = crate::compiler::score::translate(&rc, &externs(typed));
     CompiledProgram {
         stir,
+        unused_constructors: crate::compiler::reach::unused_constructors(
+            typed,
+            &options.entry_points,
+        ),
         reuse,
         rc,
         core,
+        layouts: layouts(typed),
     }
 }
 
 pub fn compile_with_scoped_rc(typed: &TypedProgram) -> CompiledProgram {
     let stir = from_typed(typed);
     let rc = crate::compiler::scoped_rc::add_rc(&stir);
-    let core = crate::compiler::score::translate(&rc);
+    let core = crate::compiler::score::translate(&rc, &externs(typed));
     CompiledProgram {
         stir: stir.clone(),
+        unused_constructors: vec![],
         reuse: stir,
         rc,
         core,
+        layouts: layouts(typed),
     }
 }
+
+// The first entry point that is not a function of the program
+pub fn unknown_entry_point<'a>(
+    typed: &TypedProgram,
+    entry_points: &'a [String],
+) -> Option<&'a String> {
+    entry_points
+        .iter()
+        .find(|id| !typed.function_datas.contains_key(*id))
+}
+
+// The entry points of a library, with the ownership that reference counting
+// settled on for their parameters
+pub fn exports(program: &CompiledProgram, entry_points: &[String]) -> Vec<Export> {
+    let ownership = get_ownership(&program.reuse);
+    entry_points
+        .iter()
+        .map(|id| Export {
+            id: id.clone(),
+            borrowed: ownership[id]
+                .iter()
+                .map(|status| *status == Status::Borrowed)
+                .collect(),
+        })
+        .collect()
+}
@@ -1,11 +1,40 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fmt::{Display, Formatter},
 };
 
 //core = C-Oriented-Representation for Execution
-use crate::compiler::crux::Operator;
-pub type Prog = (Vec<Def>, HashSet<u8>);
+use crate::ast::ast::{self, AID};
+use crate::compiler::crux::{Layouts, Operator, Repr};
+use crate::compiler::intrinsics::{INTRINSICS, Intrinsic};
+pub type Prog = (Vec<Def>, HashSet<u8>, Vec<StaticCell>, Vec<Extern>);
+
+// A cell starts with one header word holding the tag in the low byte, the
+// arity in the next and the reference count in the rest
+pub const HEADER_WORDS: i64 = 1;
+pub const RC_ONE: i64 = 1 << 16;
+
+pub fn header(tag: u8, arity: u8) -> i64 {
+    static_header(tag, arity) | RC_ONE
+}
+
+// Cells built at compile time have a count of zero that inc and dec leave
+// alone, so they are never freed
+pub fn static_header(tag: u8, arity: u8) -> i64 {
+    tag as i64 | (arity as i64) << 8
+}
+
+pub fn header_tag(header: i64) -> i64 {
+    header & 0xff
+}
+
+pub fn header_arity(header: i64) -> i64 {
+    (header >> 8) & 0xff
+}
+
+pub fn header_rc(header: i64) -> i64 {
+    header >> 16
+}
 
 #[derive(Debug, Clone)]
 pub struct Def {
@@ -15,11 +44,24 @@ pub struct Def {
     pub body: Vec<Statement>,
 }
 
+// A cell built at compile time, its fields are literals or earlier cells
+#[derive(Debug, Clone)]
+pub struct StaticCell {
+    pub tag: u8,
+    pub fields: Vec<Operand>,
+}
+
+// A C function the program calls with untagged Ints, returning one
+#[derive(Debug, Clone)]
+pub struct Extern {
+    pub id: String,
+    pub arity: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum Type {
     Standard,
     Value(u8),
-    VoidPtrPtr,
     None,
 }
 
@@ -28,7 +70,6 @@ impl Display for Type {
         match self {
             Type::Standard => write!(f, "Value "),
             Type::Value(u) => write!(f, "Value{} ", u),
-            Type::VoidPtrPtr => write!(f, "void** "),
             Type::None => write!(f, ""),
         }
     }
@@ -40,37 +81,105 @@ pub enum Operand {
     NonShifted(i64),
     Int(i64),
     Negate(String),
+    Static(usize),
 }
 
 #[derive(Debug, Clone)]
 pub enum Statement {
     IfElse(Vec<(Operand, Vec<Statement>)>),
+    // Whether the branch is for a constructor with fields, and its statements
+    Switch(Operand, Vec<(bool, Vec<Statement>)>),
     Return(Operand),
     Print(Operand),
     AssignMalloc(Type, String, u8),
+    AssignStackAlloc(String, u8),
     Assign(Type, String, Operand),
     AssignToField(String, i64, Operand),
+    AssignHeader(String, u8, u8),
     AssignFromField(String, i64, Operand),
     AssignBinaryOperation(String, Operator, Operand, Operand),
-    AssignTagCheck(String, bool, Operand, i64),
     AssignFunctionCall(String, String, Vec<Operand>, Type),
-    AssignDropReuse(String, String),
+    AssignExternCall(String, String, Vec<Operand>),
+    AssignIntrinsic(String, String, Vec<Operand>),
+    TailCall(String, Vec<Operand>),
+    AssignDropReuse(String, String, Vec<u8>),
     AssignUTuple(u8, String, Vec<String>),
     AssignUTupleField(String, i64, Operand),
     Inc(String),
     Dec(String),
     DecUTuple(String, u8),
+    Drop(String, Vec<u8>, Vec<u8>),
+    FillHole(String, i64, Operand),
+    Declare(Type, String),
+    Label(String),
+    Jump(String),
 }
 
-pub fn output(prog: &Prog) -> Vec<String> {
-    let mut lines = vec![
-        "#include <stdio.h>".to_string(),
-        "#include <stdlib.h>".to_string(),
+// The runtime that generated programs include, and the version of it they
+// are generated for
+pub const RUNTIME_HEADER: &str = include_str!("../../runtime/goopea_runtime.h");
+pub const RUNTIME_VERSION: u32 = 2;
+
+// Switches of the C runtime. They are emitted as defines, so a program can
+// also be built with them given to the C compiler instead. Unless inlined,
+// the runtime is included from goopea_runtime.h
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Runtime {
+    pub free_list: bool,
+    pub alloc_stats: bool,
+    pub inline: bool,
+}
+
+pub fn output(prog: &Prog, layouts: &Layouts) -> Vec<String> {
+    output_with(prog, layouts, Runtime::default())
+}
+
+pub fn output_with(prog: &Prog, layouts: &Layouts, runtime: Runtime) -> Vec<String> {
+    let mut lines = runtime_lines(runtime);
+    lines.extend(utuple_typedefs(prog));
+    lines.extend(extern_prototypes(prog));
+    lines.extend(intrinsic_definitions(prog));
+    lines.extend(static_cells(prog));
+    for def in prog.0.iter().filter(|def| def.id != "main") {
+        lines.push(format!("{};", function_head(def)));
+    }
+    lines.push(String::new());
+    lines.extend(printers(prog, layouts));
+    for def in &prog.0 {
+        lines.extend(function_definition(def, &function_head(def)));
+    }
+    lines
+}
+
+// Defines for the switches, then the runtime and a check of its version
+pub fn runtime_lines(runtime: Runtime) -> Vec<String> {
+    let mut lines = vec![];
+    if runtime.free_list {
+        lines.push("#define GOOPEA_FREE_LIST".to_string());
+    }
+    if runtime.alloc_stats {
+        lines.push("#define GOOPEA_ALLOC_STATS".to_string());
+    }
+    if runtime.inline {
+        lines.extend(RUNTIME_HEADER.lines().map(str::to_string));
+    } else {
+        lines.push("#include \"goopea_runtime.h\"".to_string());
+    }
+    lines.extend(vec![
         String::new(),
-        "typedef __int64_t Value;".to_string(),
+        format!("#if GOOPEA_RUNTIME_VERSION != {}", RUNTIME_VERSION),
+        format!(
+            "#error \"this program needs version {} of goopea_runtime.h\"",
+            RUNTIME_VERSION
+        ),
+        "#endif".to_string(),
         String::new(),
-    ];
+    ]);
+    lines
+}
 
+pub fn utuple_typedefs(prog: &Prog) -> Vec<String> {
+    let mut lines = vec![];
     for num in &prog.1 {
         lines.push("typedef struct {".to_string());
         for i in 0..*num {
@@ -79,114 +188,456 @@ pub fn output(prog: &Prog) -> Vec<String> {
         lines.push(format!("}} Value{};", num));
         lines.push(String::new());
     }
+    lines
+}
 
-    for def in &prog.0 {
-        lines.push(output_function_decls(def));
+pub fn extern_prototypes(prog: &Prog) -> Vec<String> {
+    let mut lines = vec![];
+    for ext in &prog.3 {
+        let args = if ext.arity == 0 {
+            "void".to_string()
+        } else {
+            vec!["int64_t"; ext.arity].join(", ")
+        };
+        lines.push(format!("int64_t {}({});", ext.id, args));
     }
-    lines.extend(vec![
-        String::new(),
-        "Value inc(Value ref) {".to_string(),
-        "\tif (!(1 & ref)) {".to_string(),
-        "\t\tvoid** ptr = ref;".to_string(),
-        "\t\tptr[2]++;".to_string(),
-        "\t}".to_string(),
-        "\treturn ref;".to_string(),
-        "}".to_string(),
-        String::new(),
-        "Value dec(Value ref) {".to_string(),
-        "\tif (!(1 & ref)) {".to_string(),
-        "\t\tvoid** ptr = ref;".to_string(),
-        "\t\tif (ptr[2] == 1) {".to_string(),
-        "\t\t\tfor (int i = 3; i < ptr[1] + 3; i++) {".to_string(),
-        "\t\t\t\tdec(ptr[i]);".to_string(),
-        "\t\t\t}".to_string(),
-        "\t\t\tfree(ref);".to_string(),
-        "\t\t}".to_string(),
-        "\t\telse {".to_string(),
-        "\t\t\tptr[2]--;".to_string(),
-        "\t\t}".to_string(),
-        "\t}".to_string(),
-        "\treturn ref;".to_string(),
-        "}".to_string(),
-        String::new(),
-        "Value decu(Value* ptr, int size) {".to_string(),
-        "\tfor (int i = 0; i < size; i++) {".to_string(),
-        "\t\t dec(ptr[i]);".to_string(),
-        "\t}".to_string(),
-        "}".to_string(),
-        String::new(),
-    ]);
-    lines.extend(vec![
-        "void** drop_reuse(Value ref) {".to_string(),
-        "\tif (((void**) ref)[2] == 1) {".to_string(),
-        "\t\tfor (int i = 3; i < ((void**) ref)[1] + 3; i++) {".to_string(),
-        "\t\t\tdec(((void**) ref)[i]);".to_string(),
-        "\t\t}".to_string(),
-        "\t\treturn ref;".to_string(),
-        "\t}".to_string(),
-        "\telse {".to_string(),
-        "\t\t((void**) ref)[2]--;".to_string(),
-        "\t\treturn NULL;".to_string(),
-        "\t}".to_string(),
-        "}".to_string(),
-        String::new(),
-    ]);
+    if !prog.3.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
 
-    for def in &prog.0 {
-        let args_str = def
-            .args
+// The intrinsics the program calls, in the order of INTRINSICS
+pub fn used_intrinsics(prog: &Prog) -> Vec<&'static Intrinsic> {
+    fn calls(stmts: &[Statement], out: &mut Vec<String>) {
+        for stmt in stmts {
+            match stmt {
+                Statement::AssignIntrinsic(_, id, _) if !out.contains(id) => out.push(id.clone()),
+                Statement::IfElse(branches) => {
+                    branches.iter().for_each(|(_, stmts)| calls(stmts, out))
+                }
+                Statement::Switch(_, cases) => {
+                    cases.iter().for_each(|(_, stmts)| calls(stmts, out))
+                }
+                _ => (),
+            }
+        }
+    }
+    let mut used = vec![];
+    prog.0.iter().for_each(|def| calls(&def.body, &mut used));
+    INTRINSICS
+        .iter()
+        .filter(|intrinsic| used.iter().any(|id| id == intrinsic.id))
+        .collect()
+}
+
+// The C functions of the intrinsics the program calls
+pub fn intrinsic_definitions(prog: &Prog) -> Vec<String> {
+    let mut lines = vec![];
+    for intrinsic in used_intrinsics(prog) {
+        lines.extend(intrinsic.c_definition());
+        lines.push(String::new());
+    }
+    lines
+}
+
+pub fn static_cells(prog: &Prog) -> Vec<String> {
+    let mut lines = vec![];
+    for (i, cell) in prog.2.iter().enumerate() {
+        lines.push(format!(
+            "static Value static{}[{}] = {{STATIC_HEADER({}, {}), {}}};",
+            i,
+            cell.fields.len() as i64 + HEADER_WORDS,
+            cell.tag,
+            cell.fields.len(),
+            cell.fields
+                .iter()
+                .map(operand_to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if !prog.2.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+pub fn function_definition(def: &Def, head: &str) -> Vec<String> {
+    let mut lines = vec![format!("{} {{", head)];
+    for arg in &def.args {
+        if !def.body.iter().any(|stmt| uses(stmt, arg)) {
+            lines.push(format!("  (void) {};", arg));
+        }
+    }
+    let mut scope = def.args.iter().cloned().collect();
+    if calls_self(&def.body, &def.id) {
+        lines.push("tailcall:;".to_string());
+    }
+    for stmt in &def.body {
+        lines.push(statement_to_string(stmt, 1, def, &mut scope));
+    }
+    lines.push("}".to_string());
+    lines.push(String::new());
+    lines
+}
+
+// The program prints the result of main and exits with 0
+pub fn function_head(def: &Def) -> String {
+    if def.id == "main" {
+        return "int main(void)".to_string();
+    }
+    let args_str = if def.args.is_empty() {
+        "void".to_string()
+    } else {
+        def.args
             .iter()
             .map(|arg| format!("Value {}", arg))
             .collect::<Vec<_>>()
-            .join(", ");
-        lines.push(format!("{}{}({}) {{", def.typ, def.id.clone(), args_str));
-        let stmts_as_str = def
-            .body
-            .iter()
-            .map(|stmt| statement_to_string(stmt, 1))
-            .collect::<Vec<_>>();
-        lines.extend(stmts_as_str);
+            .join(", ")
+    };
+    format!("{}{}({})", def.typ, def.id, args_str)
+}
+
+// Printers for the result of main and every ADT reachable from it, writing
+// values with their constructor names like the interpreter formats them
+fn printers(prog: &Prog, layouts: &Layouts) -> Vec<String> {
+    let Some(main) = prog.0.iter().find(|def| def.id == "main") else {
+        return vec![];
+    };
+    let adts = printed_adts(layouts);
+
+    let mut lines = vec![];
+    for adt in &adts {
+        lines.push(format!("void print_{}(Value v);", adt));
+    }
+    lines.push(String::new());
+    for adt in adts {
+        let (repr, ctors) = &layouts.adts[adt];
+        let names = &layouts.names[adt];
+        lines.push(format!("void print_{}(Value v) {{", adt));
+        match repr {
+            // A last field of the same type is printed by going around again
+            // instead of recursing, so that long lists fit on the stack
+            Repr::Boxed => {
+                let own = ast::Type::ADT(adt.clone());
+                let walks = ctors.iter().any(|fields| fields.last() == Some(&own));
+                let tab = if walks { "\t\t" } else { "\t" };
+                if walks {
+                    lines.push("\tlong long open = 0;".to_string());
+                    lines.push("\tfor (;;) {".to_string());
+                }
+                lines.push(format!("{}switch (CTOR(v)) {{", tab));
+                for (tag, (name, fields)) in names.iter().zip(ctors).enumerate() {
+                    lines.push(format!("{}case {}:", tab, tag));
+                    let mut fields = fields
+                        .iter()
+                        .enumerate()
+                        .map(|(i, typ)| (format!("FIELD(v, {})", i as i64 + HEADER_WORDS), typ))
+                        .collect::<Vec<_>>();
+                    if fields.last().is_some_and(|(_, typ)| **typ == own) {
+                        let (last, _) = fields.pop().unwrap();
+                        lines.extend(print_ctor_head(name, fields, &format!("{}\t", tab)));
+                        lines.push(format!("{}\tv = {};", tab, last));
+                        lines.push(format!("{}\topen++;", tab));
+                        lines.push(format!("{}\tcontinue;", tab));
+                    } else {
+                        lines.extend(print_ctor(name, fields, &format!("{}\t", tab)));
+                        lines.push(format!("{}\tbreak;", tab));
+                    }
+                }
+                lines.push(format!("{}}}", tab));
+                if walks {
+                    lines.push("\t\tbreak;".to_string());
+                    lines.push("\t}".to_string());
+                    lines.push("\twhile (open--) {".to_string());
+                    lines.push("\t\tprintf(\")\");".to_string());
+                    lines.push("\t}".to_string());
+                }
+            }
+            Repr::Newtype => {
+                lines.extend(print_ctor(
+                    &names[0],
+                    vec![("v".to_string(), &ctors[0][0])],
+                    "\t",
+                ));
+            }
+            // Odd values hold the field, even ones are a nullary constructor.
+            // The field is 2 * x + 1, which is how a shifted int looks anyway
+            Repr::Immediate => {
+                lines.push("\tValue n = v >> 1;".to_string());
+                lines.push("\tif (n & 1) {".to_string());
+                let (name, fields) = names
+                    .iter()
+                    .zip(ctors)
+                    .find(|(_, fields)| !fields.is_empty())
+                    .unwrap();
+                lines.extend(print_ctor(
+                    name,
+                    vec![("n".to_string(), &fields[0])],
+                    "\t\t",
+                ));
+                lines.push("\t\treturn;".to_string());
+                lines.push("\t}".to_string());
+                lines.push("\tswitch (n / 2) {".to_string());
+                let nullary = names
+                    .iter()
+                    .zip(ctors)
+                    .filter(|(_, fields)| fields.is_empty());
+                for (i, (name, _)) in nullary.enumerate() {
+                    lines.push(format!("\tcase {}:", i));
+                    lines.extend(print_ctor(name, vec![], "\t\t"));
+                    lines.push("\t\tbreak;".to_string());
+                }
+                lines.push("\t}".to_string());
+            }
+        }
         lines.push("}".to_string());
         lines.push(String::new());
     }
+
+    lines.push(format!("void print_result({}v) {{", main.typ));
+    match &layouts.result[..] {
+        [typ] => lines.push(format!("\t{}", print_value("v", typ))),
+        types => {
+            let fields = types
+                .iter()
+                .enumerate()
+                .map(|(i, typ)| print_value(&format!("v.elem{}", i), typ))
+                .collect::<Vec<_>>();
+            if fields.is_empty() {
+                lines.push("\t(void) v;".to_string());
+            }
+            lines.push("\tprintf(\"(\");".to_string());
+            lines.push(format!("\t{}", fields.join("\n\tprintf(\", \");\n\t")));
+            lines.push("\tprintf(\")\");".to_string());
+        }
+    }
+    lines.push("\tprintf(\"\\n\");".to_string());
+    lines.push("}".to_string());
+    lines.push(String::new());
     lines
 }
 
-fn output_function_decls(def: &Def) -> String {
-    let args_str = def
-        .args
-        .iter()
-        .map(|arg| format!("Value {}", arg))
-        .collect::<Vec<_>>()
-        .join(", ");
-    format!("{}{}({});", def.typ, def.id.clone(), args_str)
+// The ADTs the result of main holds, directly or through other ADTs
+pub fn printed_adts(layouts: &Layouts) -> Vec<&AID> {
+    let mut adts: Vec<&AID> = vec![];
+    let mut todo = layouts.result.iter().collect::<Vec<_>>();
+    while let Some(typ) = todo.pop() {
+        if let ast::Type::ADT(adt) = typ
+            && !adts.contains(&adt)
+        {
+            adts.push(adt);
+            todo.extend(layouts.adts[adt].1.iter().flatten().rev());
+        }
+    }
+    adts
+}
+
+fn print_value(value: &str, typ: &ast::Type) -> String {
+    match typ {
+        ast::Type::Int => format!("printf(\"%lld\", (long long) ({} >> 1));", value),
+        ast::Type::ADT(adt) => format!("print_{}({});", adt, value),
+    }
+}
+
+fn print_ctor(name: &str, fields: Vec<(String, &ast::Type)>, tab: &str) -> Vec<String> {
+    if fields.is_empty() {
+        return vec![format!("{}printf(\"{}\");", tab, name)];
+    }
+    let mut lines = vec![format!("{}printf(\"{}(\");", tab, name)];
+    for (i, (value, typ)) in fields.iter().enumerate() {
+        if i > 0 {
+            lines.push(format!("{}printf(\", \");", tab));
+        }
+        lines.push(format!("{}{}", tab, print_value(value, typ)));
+    }
+    lines.push(format!("{}printf(\")\");", tab));
+    lines
+}
+
+// Prints the constructor up to its last field, which is left to the caller
+fn print_ctor_head(name: &str, fields: Vec<(String, &ast::Type)>, tab: &str) -> Vec<String> {
+    let mut lines = vec![format!("{}printf(\"{}(\");", tab, name)];
+    for (value, typ) in fields {
+        lines.push(format!("{}{}", tab, print_value(&value, typ)));
+        lines.push(format!("{}printf(\", \");", tab));
+    }
+    lines
 }
 
-fn statement_to_string(stmt: &Statement, depth: usize) -> String {
+fn operand_uses(op: &Operand, var: &str) -> bool {
+    match op {
+        Operand::Ident(id) | Operand::Negate(id) => id == var,
+        Operand::NonShifted(_) | Operand::Int(_) | Operand::Static(_) => false,
+    }
+}
+
+// Whether the statement reads the variable
+fn uses(stmt: &Statement, var: &str) -> bool {
+    match stmt {
+        Statement::IfElse(branches) => branches.iter().any(|(cond, stmts)| {
+            operand_uses(cond, var) || stmts.iter().any(|stmt| uses(stmt, var))
+        }),
+        Statement::Switch(op, cases) => {
+            operand_uses(op, var)
+                || cases
+                    .iter()
+                    .any(|(_, stmts)| stmts.iter().any(|stmt| uses(stmt, var)))
+        }
+        Statement::Return(op)
+        | Statement::Print(op)
+        | Statement::Assign(_, _, op)
+        | Statement::AssignFromField(_, _, op)
+        | Statement::AssignUTupleField(_, _, op) => operand_uses(op, var),
+        Statement::AssignToField(id, _, op) | Statement::FillHole(id, _, op) => {
+            id == var || operand_uses(op, var)
+        }
+        Statement::AssignBinaryOperation(_, _, left, right) => {
+            operand_uses(left, var) || operand_uses(right, var)
+        }
+        Statement::AssignFunctionCall(_, _, ops, _)
+        | Statement::AssignExternCall(_, _, ops)
+        | Statement::AssignIntrinsic(_, _, ops)
+        | Statement::TailCall(_, ops) => ops.iter().any(|op| operand_uses(op, var)),
+        Statement::AssignUTuple(_, _, args) => args.iter().any(|arg| arg == var),
+        Statement::AssignHeader(id, _, _)
+        | Statement::AssignDropReuse(_, id, _)
+        | Statement::Inc(id)
+        | Statement::Dec(id)
+        | Statement::DecUTuple(id, _)
+        | Statement::Drop(id, _, _) => id == var,
+        Statement::AssignMalloc(_, _, _)
+        | Statement::AssignStackAlloc(_, _)
+        | Statement::Declare(_, _)
+        | Statement::Label(_)
+        | Statement::Jump(_) => false,
+    }
+}
+
+// The type of every variable a function binds, from the statement that
+// declares it. Assignments without a type go to a variable declared elsewhere
+pub fn collect_vars(
+    stmts: &[Statement],
+    vars: &mut BTreeMap<String, Type>,
+    cells: &mut BTreeMap<String, u8>,
+) {
+    for stmt in stmts {
+        let (var, typ) = match stmt {
+            Statement::IfElse(branches) => {
+                branches
+                    .iter()
+                    .for_each(|(_, stmts)| collect_vars(stmts, vars, cells));
+                continue;
+            }
+            Statement::Switch(_, cases) => {
+                cases
+                    .iter()
+                    .for_each(|(_, stmts)| collect_vars(stmts, vars, cells));
+                continue;
+            }
+            Statement::AssignStackAlloc(var, size) => {
+                let cell = cells.entry(var.clone()).or_insert(*size);
+                *cell = (*cell).max(*size);
+                (var, Type::Standard)
+            }
+            Statement::Assign(typ, var, _)
+            | Statement::AssignMalloc(typ, var, _)
+            | Statement::AssignFunctionCall(var, _, _, typ)
+            | Statement::Declare(typ, var) => (var, typ.clone()),
+            Statement::AssignUTuple(size, var, _) => (var, Type::Value(*size)),
+            Statement::AssignFromField(var, _, _)
+            | Statement::AssignBinaryOperation(var, _, _, _)
+            | Statement::AssignExternCall(var, _, _)
+            | Statement::AssignIntrinsic(var, _, _)
+            | Statement::AssignDropReuse(var, _, _)
+            | Statement::AssignUTupleField(var, _, _) => (var, Type::Standard),
+            _ => continue,
+        };
+        if !matches!(typ, Type::None) {
+            vars.entry(var.clone()).or_insert(typ);
+        }
+    }
+}
+
+pub fn calls_self(stmts: &[Statement], id: &str) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Statement::TailCall(fun, _) => fun == id,
+        Statement::IfElse(branches) => branches.iter().any(|(_, stmts)| calls_self(stmts, id)),
+        Statement::Switch(_, cases) => cases.iter().any(|(_, stmts)| calls_self(stmts, id)),
+        _ => false,
+    })
+}
+
+// A name bound again in the same function is assigned to instead of declared
+// again, like the interpreter keeps one variable per name and frame
+fn declare(typ: &Type, id: &str, scope: &mut HashSet<String>) -> String {
+    match typ {
+        Type::None => id.to_string(),
+        _ if scope.insert(id.to_string()) => format!("{}{}", typ, id),
+        _ => id.to_string(),
+    }
+}
+
+fn statement_to_string(
+    stmt: &Statement,
+    depth: usize,
+    def: &Def,
+    scope: &mut HashSet<String>,
+) -> String {
     let tab = "  ".repeat(depth);
     match stmt {
-        Statement::Assign(t, id, op) => format!("{}{}{} = {};", tab, t, id, operand_to_string(op)),
-        Statement::AssignToField(id, index, op) => {
-            format!("{}{}[{}] = {};", tab, id, index, operand_to_string(op))
+        Statement::Assign(t, id, op) => {
+            format!(
+                "{}{} = {};",
+                tab,
+                declare(t, id, scope),
+                operand_to_string(op)
+            )
         }
-        Statement::AssignFromField(id, index, op) => {
+        Statement::AssignToField(id, index, op) | Statement::FillHole(id, index, op) => {
             format!(
-                "{}Value {} = ((void**) {})[{}];",
+                "{}FIELD({}, {}) = {};",
                 tab,
                 id,
+                index,
+                operand_to_string(op)
+            )
+        }
+        Statement::AssignHeader(id, tag, arity) => {
+            format!("{}FIELD({}, 0) = HEADER({}, {});", tab, id, tag, arity)
+        }
+        Statement::Declare(_, id) if scope.contains(id) => String::new(),
+        Statement::Declare(t, id) => format!("{}{};", tab, declare(t, id, scope)),
+        Statement::Label(label) => format!("{}{}:;", tab, label),
+        Statement::Jump(label) => format!("{}goto {};", tab, label),
+        Statement::AssignFromField(id, index, op) => {
+            format!(
+                "{}{} = FIELD({}, {});",
+                tab,
+                declare(&Type::Standard, id, scope),
                 operand_to_string(op),
                 index
             )
         }
         Statement::AssignMalloc(t, var, size) => {
             format!(
-                "{}{}{} = malloc({} * sizeof(Value));",
+                "{}{} = goopea_alloc_cell({});",
                 tab,
-                t,
-                var,
-                size + 3
+                declare(t, var, scope),
+                *size as i64 + HEADER_WORDS
             )
         }
+        // The cell is a local array, it is never freed nor counted
+        Statement::AssignStackAlloc(var, size) => format!(
+            "{}Value {}_cell[{}];\n{}{} = (Value) {}_cell;",
+            tab,
+            var,
+            *size as i64 + HEADER_WORDS,
+            tab,
+            declare(&Type::Standard, var, scope),
+            var
+        ),
         Statement::IfElse(branches) => {
             let mut lines = vec![];
 
@@ -198,51 +649,91 @@ fn statement_to_string(stmt: &Statement, depth: usize) -> String {
                 } else {
                     lines.push(format!("{}else if ({}) {{", tab, operand_to_string(cond)));
                 }
+                let mut scope = scope.clone();
+                for stmt in stmts {
+                    lines.push(statement_to_string(stmt, depth + 1, def, &mut scope));
+                }
+                lines.push(format!("{}}}", tab));
+            }
+            lines.join("\n")
+        }
+        Statement::Switch(_, cases) if cases.len() == 1 => cases[0]
+            .1
+            .iter()
+            .map(|stmt| statement_to_string(stmt, depth, def, scope))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        // Constructors without fields are immediates, the others cells, so the
+        // tag is read from whichever the matched type has
+        Statement::Switch(op, cases) => {
+            let var = operand_to_string(op);
+            let key = if cases.iter().all(|(boxed, _)| *boxed) {
+                format!("TAG({})", var)
+            } else if cases.iter().all(|(boxed, _)| !*boxed) {
+                format!("{} >> 1", var)
+            } else {
+                format!("CTOR({})", var)
+            };
+            let inner = "  ".repeat(depth + 1);
+            let mut lines = vec![format!("{}switch ({}) {{", tab, key)];
+            for (i, (_, stmts)) in cases.iter().enumerate() {
+                if i == cases.len() - 1 {
+                    lines.push(format!("{}default: {{", tab));
+                } else {
+                    lines.push(format!("{}case {}: {{", tab, i));
+                }
+                let mut scope = scope.clone();
                 for stmt in stmts {
-                    lines.push(statement_to_string(stmt, depth + 1));
+                    lines.push(statement_to_string(stmt, depth + 1, def, &mut scope));
+                }
+                if !matches!(
+                    stmts.last(),
+                    Some(Statement::Return(_) | Statement::TailCall(_, _) | Statement::Jump(_))
+                ) {
+                    lines.push(format!("{}break;", inner));
                 }
                 lines.push(format!("{}}}", tab));
             }
+            lines.push(format!("{}}}", tab));
             lines.join("\n")
         }
         Statement::Return(op) => format!("{}return {};", tab, operand_to_string(op)),
         Statement::Print(op) => format!(
-            "{}printf(\"%lld\\n\", {} >> 1);",
+            "{}print_result({});\n{}goopea_alloc_stats();",
             tab,
-            operand_to_string(op)
+            operand_to_string(op),
+            tab
         ),
         Statement::AssignBinaryOperation(id, op, op1, op2) => {
             let left = operand_to_string(op1);
             let right = operand_to_string(op2);
+            let id = declare(&Type::Standard, id, scope);
             match op {
-                Operator::Add => format!("{}Value {} = {} + {} - 1;", tab, id, left, right),
-                Operator::Sub => format!("{}Value {} = {} - {} | 1;", tab, id, left, right),
-                Operator::Mul => format!(
-                    "{}Value {} = (({} - 1) * ({} >> 1) | 1);",
-                    tab, id, left, right
-                ),
+                Operator::Add => format!("{}{} = {} + {} - 1;", tab, id, left, right),
+                Operator::Sub => format!("{}{} = ({} - {}) | 1;", tab, id, left, right),
+                Operator::Mul => {
+                    format!("{}{} = (({} - 1) * ({} >> 1)) | 1;", tab, id, left, right)
+                }
+                // Untagged first, so that negative numbers truncate like they do
+                // in the interpreter
                 Operator::Div => format!(
-                    "{}Value {} = ({} / ({} - 1)) << 1 | 1;",
+                    "{}{} = ((({} >> 1) / ({} >> 1)) << 1) | 1;",
                     tab, id, left, right
                 ),
                 Operator::Mod => {
                     format!(
-                        "{}Value {} = (({} >> 1) % ({} >> 1)) << 1 | 1;",
+                        "{}{} = ((({} >> 1) % ({} >> 1)) << 1) | 1;",
                         tab, id, left, right
                     )
                 }
-                op => format!(
-                    "{}Value {} = ({} {} {}) << 1 | 1;",
-                    tab, id, left, op, right
-                ),
+                op => format!("{}{} = (({} {} {}) << 1) | 1;", tab, id, left, op, right),
             }
         }
         Statement::AssignFunctionCall(var, fun, operands, typ) => {
             format!(
-                "{}{}{} = {}({});",
+                "{}{} = {}({});",
                 tab,
-                typ,
-                var,
+                declare(typ, var, scope),
                 fun,
                 operands
                     .iter()
@@ -251,36 +742,127 @@ fn statement_to_string(stmt: &Statement, depth: usize) -> String {
                     .join(", ")
             )
         }
-        Statement::AssignTagCheck(id, b, op, tag) => {
-            let result = operand_to_string(op);
-            if *b {
-                format!(
-                    "{}Value {} = !(1 & {}) && {} == ((void**) {})[0];",
-                    tab, id, result, tag, result
-                )
-            } else {
-                format!("{}Value {} = {} == {};", tab, id, tag, result)
+        // Ints are untagged on the way into C and tagged again on the way out
+        Statement::AssignExternCall(var, fun, operands) => {
+            format!(
+                "{}{} = goopea_int({}({}));",
+                tab,
+                declare(&Type::Standard, var, scope),
+                fun,
+                operands
+                    .iter()
+                    .map(|op| format!("goopea_from_int({})", operand_to_string(op)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        Statement::AssignIntrinsic(var, id, operands) => {
+            format!(
+                "{}{} = goopea_{}({});",
+                tab,
+                declare(&Type::Standard, var, scope),
+                id,
+                operands
+                    .iter()
+                    .map(operand_to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        // Arguments go through temporaries since they may refer to the parameters
+        Statement::TailCall(fun, operands) if *fun == def.id => {
+            let inner = "  ".repeat(depth + 1);
+            let mut lines = vec![format!("{}{{", tab)];
+            for (i, op) in operands.iter().enumerate() {
+                lines.push(format!(
+                    "{}Value tail{} = {};",
+                    inner,
+                    i,
+                    operand_to_string(op)
+                ));
             }
+            for (i, arg) in def.args.iter().enumerate() {
+                lines.push(format!("{}{} = tail{};", inner, arg, i));
+            }
+            lines.push(format!("{}}}", tab));
+            lines.push(format!("{}goto tailcall;", tab));
+            lines.join("\n")
         }
-        Statement::AssignDropReuse(var, reset_var) => {
-            format!("{}void** {} = drop_reuse({});", tab, var, reset_var)
+        Statement::TailCall(fun, operands) => format!(
+            "{}return {}({});",
+            tab,
+            fun,
+            operands
+                .iter()
+                .map(operand_to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Statement::AssignDropReuse(var, reset_var, kept) if kept.is_empty() => {
+            let var = declare(&Type::Standard, var, scope);
+            format!("{}{} = goopea_drop_reuse({});", tab, var, reset_var)
         }
+        Statement::AssignDropReuse(var, reset_var, kept) => format!(
+            "{}{} = goopea_drop_reuse_keep({}, {});",
+            tab,
+            declare(&Type::Standard, var, scope),
+            reset_var,
+            kept.iter().fold(0u64, |mask, i| mask | (1 << i))
+        ),
+        // Only a declaration can take an initializer list
         Statement::AssignUTuple(size, var, args) => {
-            format!("{}Value{} {} = {{{}}};", tab, size, var, args.join(", "))
+            match declare(&Type::Value(*size), var, scope) {
+                var if var.contains(' ') => format!("{}{} = {{{}}};", tab, var, args.join(", ")),
+                var => format!("{}{} = (Value{}) {{{}}};", tab, var, size, args.join(", ")),
+            }
         }
         Statement::AssignUTupleField(var, i, op) => {
             format!(
-                "{}Value {} = {}.elem{};",
+                "{}{} = {}.elem{};",
                 tab,
-                var,
+                declare(&Type::Standard, var, scope),
                 operand_to_string(op),
                 i
             )
         }
 
-        Statement::Inc(var) => format!("{}inc({});", tab, var),
-        Statement::Dec(var) => format!("{}dec({});", tab, var),
-        Statement::DecUTuple(var, size) => format!("{}decu(&{}, {});", tab, var, size),
+        Statement::Inc(var) => format!("{}goopea_inc({});", tab, var),
+        Statement::Dec(var) => format!("{}goopea_dec({});", tab, var),
+        Statement::DecUTuple(var, size) => {
+            format!("{}goopea_decu((Value*) &{}, {});", tab, var, size)
+        }
+        Statement::Drop(var, decs, incs) => {
+            let inner = "  ".repeat(depth + 1);
+            let mut lines = vec![format!("{}if (RC({}) == 1) {{", tab, var)];
+            for i in decs {
+                lines.push(format!(
+                    "{}goopea_dec(FIELD({}, {}));",
+                    inner,
+                    var,
+                    *i as i64 + HEADER_WORDS
+                ));
+            }
+            lines.push(format!(
+                "{}goopea_free_cell((Value*) {}, ARITY({}) + HEADER_WORDS);",
+                inner, var, var
+            ));
+            lines.push(format!("{}}}", tab));
+            lines.push(format!("{}else {{", tab));
+            for i in incs {
+                lines.push(format!(
+                    "{}goopea_inc(FIELD({}, {}));",
+                    inner,
+                    var,
+                    *i as i64 + HEADER_WORDS
+                ));
+            }
+            lines.push(format!(
+                "{}if (RC({})) FIELD({}, 0) -= RC_ONE;",
+                inner, var, var
+            ));
+            lines.push(format!("{}}}", tab));
+            lines.join("\n")
+        }
     }
 }
 
@@ -290,5 +872,6 @@ fn operand_to_string(op: &Operand) -> String {
         Operand::Int(i) => (i << 1 | 1).to_string(),
         Operand::NonShifted(i) => i.to_string(),
         Operand::Negate(var) => format!("!{}", var),
+        Operand::Static(i) => format!("(Value) static{}", i),
     }
 }
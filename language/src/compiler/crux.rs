@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result};
 
+use crate::ast::ast::{AID, FID};
 use crate::ast::typed::ExpressionType;
 use crate::ast::{
     ast, scoped,
     typed::{TypedNode, TypedProgram},
 };
+use crate::compiler::core::Extern;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Type {
@@ -74,72 +77,96 @@ pub fn from_typed_expr(expr: &TypedNode, context: &TypedProgram) -> Crux {
                 Operator::Add,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "-" => Crux::Operation(
                 Operator::Sub,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "*" => Crux::Operation(
                 Operator::Mul,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "/" => Crux::Operation(
                 Operator::Div,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             ">" => Crux::Operation(
                 Operator::Greater,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "<" => Crux::Operation(
                 Operator::Less,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             ">=" => Crux::Operation(
                 Operator::GreaterOrEqual,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "<=" => Crux::Operation(
                 Operator::LessOrEq,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "==" => Crux::Operation(
                 Operator::Equal,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "!=" => Crux::Operation(
                 Operator::NotEqual,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             "%" => Crux::Operation(
                 Operator::Mod,
                 from_typed_expr(&args.0[0], context).into(),
                 from_typed_expr(&args.0[1], context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             ),
             _ => match context.constructors.get(id) {
+                Some(cons) if adt_repr(&cons.adt, context) == Repr::Newtype => {
+                    from_typed_expr(&args.0[0], context)
+                }
+                Some(cons) if adt_repr(&cons.adt, context) == Repr::Immediate => {
+                    if args.0.is_empty() {
+                        Crux::Int(2 * nullary_index(id, context), Type::Int)
+                    } else {
+                        Crux::Operation(
+                            Operator::Add,
+                            Crux::Operation(
+                                Operator::Mul,
+                                from_typed_expr(&args.0[0], context).into(),
+                                Crux::Int(2, Type::Int).into(),
+                                Type::Int,
+                            )
+                            .into(),
+                            Crux::Int(1, Type::Int).into(),
+                            Type::Int,
+                        )
+                    }
+                }
                 Some(cons) => {
                     if args.0.is_empty() {
-                        Crux::Int(cons.sibling_index as i64, from_exp_type(&expr.data.data))
+                        Crux::Int(
+                            cons.sibling_index as i64,
+                            from_exp_type(&expr.data.data, context),
+                        )
                     } else {
                         Crux::Constructor(
                             cons.sibling_index as i64,
@@ -147,7 +174,7 @@ pub fn from_typed_expr(expr: &TypedNode, context: &TypedProgram) -> Crux {
                                 .iter()
                                 .map(|arg| from_typed_expr(arg, context))
                                 .collect(),
-                            from_exp_type(&expr.data.data),
+                            from_exp_type(&expr.data.data, context),
                         )
                     }
                 }
@@ -157,16 +184,54 @@ pub fn from_typed_expr(expr: &TypedNode, context: &TypedProgram) -> Crux {
                         .iter()
                         .map(|arg| from_typed_expr(arg, context))
                         .collect(),
-                    from_exp_type(&expr.data.data),
+                    from_exp_type(&expr.data.data, context),
                 ),
             },
         },
-        scoped::SimplifiedExpression::Integer(i) => Crux::Int(*i, from_exp_type(&expr.data.data)),
+        scoped::SimplifiedExpression::Integer(i) => {
+            Crux::Int(*i, from_exp_type(&expr.data.data, context))
+        }
         scoped::SimplifiedExpression::Variable(id) => {
-            Crux::Ident(id.clone(), from_exp_type(&expr.data.data))
+            Crux::Ident(id.clone(), from_exp_type(&expr.data.data, context))
+        }
+        scoped::SimplifiedExpression::Match(var_node, cases)
+            if scrutinee_repr(&var_node.data.data, context) == Some(Repr::Newtype) =>
+        {
+            // The only constructor always matches, and so does a variable
+            let (pattern, exp) = &cases[0];
+            let binder = match pattern {
+                ast::Pattern::Constructor(_, vars) => &vars.0[0],
+                ast::Pattern::Variable(var) => var,
+                ast::Pattern::Integer(_) => unreachable!("Rejected by the type checker"),
+            };
+            Crux::Let(
+                binder.clone(),
+                Crux::Ident(
+                    var_node.expr.clone(),
+                    from_exp_type(&var_node.data.data, context),
+                )
+                .into(),
+                from_typed_expr(exp, context).into(),
+                from_exp_type(&expr.data.data, context),
+            )
+        }
+        scoped::SimplifiedExpression::Match(var_node, cases)
+            if scrutinee_repr(&var_node.data.data, context) == Some(Repr::Immediate) =>
+        {
+            immediate_match(
+                &var_node.expr,
+                scrutinee_adt(&var_node.data.data).unwrap(),
+                cases,
+                context,
+                from_exp_type(&expr.data.data, context),
+            )
         }
         scoped::SimplifiedExpression::Match(var_node, cases) => Crux::Match(
-            Crux::Ident(var_node.expr.clone(), from_exp_type(&var_node.data.data)).into(),
+            Crux::Ident(
+                var_node.expr.clone(),
+                from_exp_type(&var_node.data.data, context),
+            )
+            .into(),
             cases
                 .iter()
                 .map(|(pattern, exp)| {
@@ -199,6 +264,7 @@ pub fn from_typed_expr(expr: &TypedNode, context: &TypedProgram) -> Crux {
                                                                 .unwrap()
                                                                 .args
                                                                 .0[i],
+                                                            context,
                                                         )
                                                     })
                                                 })
@@ -212,44 +278,249 @@ pub fn from_typed_expr(expr: &TypedNode, context: &TypedProgram) -> Crux {
                     )
                 })
                 .collect(),
-            from_exp_type(&expr.data.data),
+            from_exp_type(&expr.data.data, context),
         ),
         scoped::SimplifiedExpression::UTuple(args) => Crux::UTuple(
             args.0
                 .iter()
                 .map(|arg| from_typed_expr(arg, context))
                 .collect(),
-            from_exp_type(&expr.data.data),
+            from_exp_type(&expr.data.data, context),
         ),
         scoped::SimplifiedExpression::LetEqualIn(bindings, exp, next) if bindings.0.len() == 1 => {
             Crux::Let(
                 bindings.0[0].clone(),
                 from_typed_expr(exp, context).into(),
                 from_typed_expr(next, context).into(),
-                from_exp_type(&expr.data.data),
+                from_exp_type(&expr.data.data, context),
             )
         }
         scoped::SimplifiedExpression::LetEqualIn(bindings, exp, next) => Crux::LetApp(
             bindings.0.clone(),
             from_typed_expr(exp, context).into(),
             from_typed_expr(next, context).into(),
-            from_exp_type(&expr.data.data),
+            from_exp_type(&expr.data.data, context),
         ),
     }
 }
 
-pub fn from_exp_type(typ: &ExpressionType) -> Type {
+pub fn from_exp_type(typ: &ExpressionType, context: &TypedProgram) -> Type {
     match typ {
-        ExpressionType::UTuple(vec) => Type::Unboxed(vec.0.iter().map(from_type).collect()),
-        ExpressionType::Type(typ) => from_type(typ),
+        ExpressionType::UTuple(vec) => {
+            Type::Unboxed(vec.0.iter().map(|typ| from_type(typ, context)).collect())
+        }
+        ExpressionType::Type(typ) => from_type(typ, context),
     }
 }
 
-pub fn from_type(typ: &ast::Type) -> Type {
+pub fn from_type(typ: &ast::Type, context: &TypedProgram) -> Type {
     match typ {
         ast::Type::Int => Type::Int,
-        ast::Type::ADT(_) => Type::Heaped,
+        ast::Type::ADT(adt) => match adt_repr(adt, context) {
+            Repr::Boxed => Type::Heaped,
+            Repr::Newtype => from_type(&newtype_field(adt, context).unwrap(), context),
+            Repr::Immediate => Type::Int,
+        },
+    }
+}
+
+// How the values of an ADT are represented at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repr {
+    // Constructors with fields are heap cells, nullary ones just their tag
+    Boxed,
+    // A single constructor with a single field is that field
+    Newtype,
+    // Nullary constructors and one whose single field is an enum are all
+    // ints, the nullary ones twice their index among the nullary ones and the
+    // other 2 * x + 1. Enum values are small tags, so nothing is lost
+    Immediate,
+}
+
+fn fields<'a>(adt: &str, context: &'a TypedProgram) -> Vec<&'a Vec<ast::Type>> {
+    context.adts[adt]
+        .iter()
+        .map(|fid| &context.constructors[fid].args.0)
+        .collect()
+}
+
+fn newtype_field(adt: &str, context: &TypedProgram) -> Option<ast::Type> {
+    match &fields(adt, context)[..] {
+        [field] if field.len() == 1 => Some(field[0].clone()),
+        _ => None,
+    }
+}
+
+pub fn adt_repr(adt: &str, context: &TypedProgram) -> Repr {
+    // A newtype wrapping itself, possibly through other newtypes, stays boxed
+    let mut seen = vec![adt.to_string()];
+    let mut inner = newtype_field(adt, context);
+    while let Some(ast::Type::ADT(next)) = &inner {
+        if seen.contains(next) {
+            return Repr::Boxed;
+        }
+        seen.push(next.clone());
+        inner = newtype_field(next, context);
+    }
+    if newtype_field(adt, context).is_some() {
+        return Repr::Newtype;
+    }
+    let with_fields = fields(adt, context)
+        .into_iter()
+        .filter(|fields| !fields.is_empty())
+        .collect::<Vec<_>>();
+    match &with_fields[..] {
+        [fields] if fields.len() == 1 && is_enum(&fields[0], context) => Repr::Immediate,
+        _ => Repr::Boxed,
+    }
+}
+
+// An ADT whose constructors are all nullary, so its values are just tags
+fn is_enum(typ: &ast::Type, context: &TypedProgram) -> bool {
+    match typ {
+        ast::Type::ADT(adt) => fields(adt, context).iter().all(|fields| fields.is_empty()),
+        ast::Type::Int => false,
+    }
+}
+
+// Representation and field types of every ADT, and the type main returns, so
+// that results can be printed as if everything was boxed
+#[derive(Debug, Clone, Default)]
+pub struct Layouts {
+    pub adts: HashMap<AID, (Repr, Vec<Vec<ast::Type>>)>,
+    // Constructor names of every ADT, in tag order
+    pub names: HashMap<AID, Vec<FID>>,
+    pub result: Vec<ast::Type>,
+}
+
+pub fn layouts(context: &TypedProgram) -> Layouts {
+    Layouts {
+        adts: context
+            .adts
+            .keys()
+            .map(|adt| {
+                (
+                    adt.clone(),
+                    (
+                        adt_repr(adt, context),
+                        fields(adt, context).into_iter().cloned().collect(),
+                    ),
+                )
+            })
+            .collect(),
+        names: context
+            .adts
+            .iter()
+            .map(|(adt, ctors)| (adt.clone(), ctors.clone()))
+            .collect(),
+        result: context
+            .function_datas
+            .get("main")
+            .map(|main| main.signature.result_type.0.clone())
+            .unwrap_or_default(),
+    }
+}
+
+pub fn externs(context: &TypedProgram) -> Vec<Extern> {
+    context
+        .externs
+        .iter()
+        .map(|(id, signature)| Extern {
+            id: id.clone(),
+            arity: signature.argument_type.0.len(),
+        })
+        .collect()
+}
+
+// Index of a nullary constructor among the nullary constructors of its ADT
+fn nullary_index(fid: &str, context: &TypedProgram) -> i64 {
+    let cons = &context.constructors[fid];
+    context.adts[&cons.adt][..cons.sibling_index]
+        .iter()
+        .filter(|fid| context.constructors[*fid].args.0.is_empty())
+        .count() as i64
+}
+
+fn scrutinee_adt(typ: &ExpressionType) -> Option<&str> {
+    match typ {
+        ExpressionType::Type(ast::Type::ADT(adt)) => Some(adt),
+        _ => None,
+    }
+}
+
+fn scrutinee_repr(typ: &ExpressionType, context: &TypedProgram) -> Option<Repr> {
+    scrutinee_adt(typ).map(|adt| adt_repr(adt, context))
+}
+
+// Odd values hold the field, even ones are a nullary constructor. Every
+// constructor takes the first case naming it, or else the first variable
+// pattern, which binds the value itself
+fn immediate_match(
+    var: &str,
+    adt: &str,
+    cases: &[(ast::Pattern, TypedNode)],
+    context: &TypedProgram,
+    typ: Type,
+) -> Crux {
+    let int = |i| Box::new(Crux::Int(i, Type::Int));
+    let scrutinee = || Box::new(Crux::Ident(var.to_string(), Type::Int));
+    let mut with_field = None;
+    let mut nullary = vec![];
+    for fid in &context.adts[adt] {
+        let (pattern, exp) = cases
+            .iter()
+            .find(|(pattern, _)| match pattern {
+                ast::Pattern::Constructor(other, _) => other == fid,
+                ast::Pattern::Variable(_) => true,
+                ast::Pattern::Integer(_) => false,
+            })
+            .expect("Matches are exhaustive after type checking");
+        let body = from_typed_expr(exp, context);
+        let field = context.constructors[fid].args.0.first();
+        let body = match (pattern, field) {
+            (ast::Pattern::Variable(binder), _) => {
+                Crux::Let(binder.clone(), scrutinee(), body.into(), typ.clone())
+            }
+            (ast::Pattern::Constructor(_, vars), Some(field)) => Crux::Let(
+                vars.0[0].clone(),
+                Crux::Operation(
+                    Operator::Div,
+                    Crux::Operation(Operator::Sub, scrutinee(), int(1), Type::Int).into(),
+                    int(2),
+                    from_type(field, context),
+                )
+                .into(),
+                body.into(),
+                typ.clone(),
+            ),
+            _ => body,
+        };
+        match field {
+            Some(_) => with_field = Some(body),
+            None => nullary.push(((nullary_index(fid, context), vec![]), body)),
+        }
     }
+    let field_body = with_field.unwrap();
+    let nullary_body = if nullary.len() == 1 {
+        nullary.pop().unwrap().1
+    } else {
+        Crux::Match(
+            Crux::Operation(Operator::Div, scrutinee(), int(2), Type::Int).into(),
+            nullary,
+            typ.clone(),
+        )
+    };
+    Crux::Match(
+        Crux::Operation(
+            Operator::Equal,
+            Crux::Operation(Operator::Mod, scrutinee(), int(2), Type::Int).into(),
+            int(0),
+            Type::Heaped,
+        )
+        .into(),
+        vec![((0, vec![]), field_body), ((1, vec![]), nullary_body)],
+        typ,
+    )
 }
 
 pub fn get_type(expr: &Crux) -> Type {
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use crate::compiler::crux::Type;
+use crate::compiler::stir::{Body, Constant, Exp, Function, Stir, Var, exp_vars};
+
+// Whether every use of the variable in the body only looks at it: matches on
+// it, projects from it or passes it to a parameter that only looks at it
+// too. The constructor binding itself may also be dropped, its cell never
+// leaves the frame then. Calls in tail position replace the frame
+fn only_inspected(
+    var: &Var,
+    body: &Body,
+    local: &HashMap<Constant, Vec<bool>>,
+    binding: bool,
+) -> bool {
+    let recurse = |next: &Body| only_inspected(var, next, local, binding);
+    match body {
+        Body::Ret(v) => v != var,
+        Body::Jump(_, args) => !args.contains(var),
+        Body::Let(_, Exp::Proj(_, _), next) => recurse(next),
+        Body::Let(res, Exp::App(fid, args), next) => {
+            let passed = args
+                .iter()
+                .enumerate()
+                .all(|(i, arg)| arg != var || local.get(fid).is_some_and(|params| params[i]));
+            let tail = **next == Body::Ret(res.clone());
+            passed && (!binding || !tail || !args.contains(var)) && recurse(next)
+        }
+        Body::Let(_, exp, next) => !exp_vars(exp).contains(&var) && recurse(next),
+        Body::Match(_, branches) => branches.iter().all(|(_, branch)| recurse(branch)),
+        Body::Inc(v, next) => v != var && recurse(next),
+        Body::Dec(v, next) | Body::Drop(v, _, _, next) => (binding || v != var) && recurse(next),
+        Body::Fill(cell, _, v, next) => cell != var && v != var && recurse(next),
+        Body::Join(_, _, join, body) => recurse(join) && recurse(body),
+    }
+}
+
+// Parameters of each function that only look at what is passed to them, so
+// that a caller can hand them a cell on its own stack
+fn local_params(prog: &Stir) -> HashMap<Constant, Vec<bool>> {
+    let mut local = prog
+        .iter()
+        .map(|func| {
+            let params = func.args.iter().map(|arg| arg.1 == Type::Heaped);
+            (func.id.clone(), params.collect::<Vec<_>>())
+        })
+        .collect::<HashMap<_, _>>();
+    let mut change = true;
+    while change {
+        change = false;
+        for func in prog {
+            for (i, arg) in func.args.iter().enumerate() {
+                if local[&func.id][i] && !only_inspected(arg, &func.body, &local, false) {
+                    local.get_mut(&func.id).unwrap()[i] = false;
+                    change = true;
+                }
+            }
+        }
+    }
+    local
+}
+
+// The cell of a stack constructor is never shared, dropping it only drops
+// what it was built from
+fn drop_fields(args: &[Var], fields: impl Iterator<Item = u8>, next: Body) -> Body {
+    fields
+        .map(|i| &args[i as usize])
+        .filter(|arg| arg.1 == Type::Heaped)
+        .fold(next, |next, arg| Body::Dec(arg.clone(), next.into()))
+}
+
+fn stack_body(
+    body: &Body,
+    cells: &HashMap<Var, Vec<Var>>,
+    local: &HashMap<Constant, Vec<bool>>,
+) -> Body {
+    let rewrite = |next: &Body| stack_body(next, cells, local);
+    match body {
+        Body::Let(var, Exp::Ctor(tag, args), next)
+            if !args.is_empty() && only_inspected(var, next, local, true) =>
+        {
+            let mut cells = cells.clone();
+            cells.insert(var.clone(), args.clone());
+            Body::Let(
+                var.clone(),
+                Exp::StackCtor(*tag, args.clone()),
+                stack_body(next, &cells, local).into(),
+            )
+        }
+        Body::Let(var, exp, next) => Body::Let(var.clone(), exp.clone(), rewrite(next).into()),
+        Body::Dec(var, next) if cells.contains_key(var) => {
+            let args = &cells[var];
+            drop_fields(args, 0..args.len() as u8, rewrite(next))
+        }
+        Body::Drop(var, decs, _, next) if cells.contains_key(var) => {
+            drop_fields(&cells[var], decs.iter().copied(), rewrite(next))
+        }
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(arity, branch)| (*arity, rewrite(branch)))
+                .collect(),
+        ),
+        Body::Inc(var, next) => Body::Inc(var.clone(), rewrite(next).into()),
+        Body::Dec(var, next) => Body::Dec(var.clone(), rewrite(next).into()),
+        Body::Drop(var, decs, incs, next) => Body::Drop(
+            var.clone(),
+            decs.clone(),
+            incs.clone(),
+            rewrite(next).into(),
+        ),
+        Body::Fill(cell, field, var, next) => {
+            Body::Fill(cell.clone(), *field, var.clone(), rewrite(next).into())
+        }
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            rewrite(join).into(),
+            rewrite(body).into(),
+        ),
+        Body::Ret(_) | Body::Jump(_, _) => body.clone(),
+    }
+}
+
+// Constructors whose cell does not outlive the frame that builds it are put
+// on the stack instead of the heap, and their rc operations are dropped. Runs
+// last, on the counted program
+pub fn stack_allocate(prog: &Stir) -> Stir {
+    let local = local_params(prog);
+    prog.iter()
+        .map(|func| Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: stack_body(&func.body, &HashMap::new(), &local),
+        })
+        .collect()
+}
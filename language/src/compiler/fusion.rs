@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use crate::compiler::crux::Type;
+use crate::compiler::stir::{Body, Exp, Function, Stir, Var, exp_vars};
+
+#[derive(Clone, Default)]
+struct Context {
+    // Arity of the constructor each matched variable is known to hold
+    matched: HashMap<Var, u8>,
+    // Field and cell each projection was read from
+    projs: HashMap<Var, (u8, Var)>,
+}
+
+impl Context {
+    // A new binding shadows everything known about the old one
+    fn bind(&mut self, var: &Var) {
+        self.matched.remove(var);
+        self.projs.remove(var);
+        self.projs.retain(|_, (_, cell)| cell != var);
+    }
+
+    // Fields of a matched cell that may hold pointers
+    fn heaped_fields(&self, cell: &Var) -> Vec<u8> {
+        (0..self.matched[cell])
+            .filter(|i| {
+                !self
+                    .projs
+                    .iter()
+                    .any(|(var, (j, c))| c == cell && j == i && var.1 == Type::Int)
+            })
+            .collect()
+    }
+
+    fn is_matched_cell(&self, var: &Var) -> bool {
+        self.matched.get(var).is_some_and(|arity| *arity > 0)
+    }
+}
+
+// Moves an inc down to a dec it cancels against, either of the same variable
+// or of the cell it was projected from. Gives up at anything that could
+// consume the variable or its cell before that
+fn sink_inc(var: &Var, body: &Body, ctx: &Context) -> Option<Body> {
+    let proj = ctx.projs.get(var);
+    let blocks = |v: &Var| v == var || proj.is_some_and(|(_, cell)| cell == v);
+    match body {
+        Body::Dec(v, next) if v == var => Some((**next).clone()),
+        Body::Dec(v, next) if proj.is_some_and(|(_, cell)| cell == v) => {
+            let (i, cell) = proj.unwrap();
+            if !ctx.is_matched_cell(cell) {
+                return None;
+            }
+            Some(Body::Drop(
+                v.clone(),
+                ctx.heaped_fields(cell)
+                    .into_iter()
+                    .filter(|j| j != i)
+                    .collect(),
+                vec![*i],
+                next.clone(),
+            ))
+        }
+        Body::Drop(v, decs, incs, next) if proj.is_some_and(|(_, cell)| cell == v) => {
+            let (i, _) = proj.unwrap();
+            let mut incs = incs.clone();
+            incs.push(*i);
+            incs.sort();
+            Some(Body::Drop(
+                v.clone(),
+                decs.iter().filter(|j| *j != i).cloned().collect(),
+                incs,
+                next.clone(),
+            ))
+        }
+        Body::Let(x, Exp::Reset(v, kept), next)
+            if proj.is_some_and(|(i, cell)| cell == v && !kept.contains(i)) =>
+        {
+            let (i, _) = proj.unwrap();
+            let mut kept = kept.clone();
+            kept.push(*i);
+            kept.sort();
+            Some(Body::Let(
+                x.clone(),
+                Exp::Reset(v.clone(), kept),
+                next.clone(),
+            ))
+        }
+        // Reading from the cell is fine, it is still alive
+        Body::Let(x, exp @ (Exp::Proj(_, _) | Exp::Op(_, _, _) | Exp::Int(_)), next)
+            if !blocks(x) && !matches!(exp, Exp::Proj(_, v) if v == var) =>
+        {
+            sink_inc(var, next, ctx).map(|next| Body::Let(x.clone(), exp.clone(), next.into()))
+        }
+        Body::Let(x, exp, next) if !blocks(x) && !exp_vars(exp).into_iter().any(blocks) => {
+            sink_inc(var, next, ctx).map(|next| Body::Let(x.clone(), exp.clone(), next.into()))
+        }
+        Body::Inc(v, next) => {
+            sink_inc(var, next, ctx).map(|next| Body::Inc(v.clone(), next.into()))
+        }
+        Body::Dec(v, next) if !blocks(v) => {
+            sink_inc(var, next, ctx).map(|next| Body::Dec(v.clone(), next.into()))
+        }
+        Body::Drop(v, decs, incs, next) if !blocks(v) => sink_inc(var, next, ctx)
+            .map(|next| Body::Drop(v.clone(), decs.clone(), incs.clone(), next.into())),
+        // Worth pushing into the branches if any of them can absorb it
+        Body::Match(v, branches) if !blocks(v) => {
+            let sunk = branches
+                .iter()
+                .map(|(_, branch)| sink_inc(var, branch, ctx))
+                .collect::<Vec<_>>();
+            sunk.iter().any(Option::is_some).then(|| {
+                Body::Match(
+                    v.clone(),
+                    branches
+                        .iter()
+                        .zip(sunk)
+                        .map(|((arity, branch), sunk)| {
+                            (
+                                *arity,
+                                sunk.unwrap_or_else(|| {
+                                    Body::Inc(var.clone(), branch.clone().into())
+                                }),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+        }
+        _ => None,
+    }
+}
+
+fn fuse_body(body: &Body, ctx: &Context) -> Body {
+    match body {
+        Body::Ret(var) => Body::Ret(var.clone()),
+        Body::Let(var, exp, next) => {
+            let mut ctx = ctx.clone();
+            ctx.bind(var);
+            if let Exp::Proj(i, cell) = exp
+                && cell != var
+            {
+                ctx.projs.insert(var.clone(), (*i, cell.clone()));
+            }
+            Body::Let(var.clone(), exp.clone(), fuse_body(next, &ctx).into())
+        }
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(arity, branch)| {
+                    let mut ctx = ctx.clone();
+                    ctx.matched.insert(var.clone(), *arity);
+                    (*arity, fuse_body(branch, &ctx))
+                })
+                .collect(),
+        ),
+        Body::Inc(var, next) => match sink_inc(var, next, ctx) {
+            Some(next) => fuse_body(&next, ctx),
+            None => Body::Inc(var.clone(), fuse_body(next, ctx).into()),
+        },
+        // A nullary constructor is just an int, nothing to dec
+        Body::Dec(var, next) if ctx.matched.get(var) == Some(&0) => fuse_body(next, ctx),
+        Body::Dec(var, next) if ctx.is_matched_cell(var) => Body::Drop(
+            var.clone(),
+            ctx.heaped_fields(var),
+            vec![],
+            fuse_body(next, ctx).into(),
+        ),
+        Body::Dec(var, next) => Body::Dec(var.clone(), fuse_body(next, ctx).into()),
+        Body::Drop(var, decs, incs, next) => {
+            let heaped = ctx.heaped_fields(var);
+            Body::Drop(
+                var.clone(),
+                decs.iter()
+                    .filter(|i| heaped.contains(i))
+                    .cloned()
+                    .collect(),
+                incs.clone(),
+                fuse_body(next, ctx).into(),
+            )
+        }
+        Body::Fill(cell, field, var, next) => Body::Fill(
+            cell.clone(),
+            *field,
+            var.clone(),
+            fuse_body(next, ctx).into(),
+        ),
+        // Jumped to from several places, so nothing is known about the parameters
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            fuse_body(join, &Context::default()).into(),
+            fuse_body(body, ctx).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(label.clone(), args.clone()),
+    }
+}
+
+// Perceus style cleanup after rc insertion: incs are cancelled against later
+// decs where possible, and decs of cells whose constructor is known are
+// specialized into per field decs and a free
+pub fn fuse_rc(prog: &Stir) -> Stir {
+    prog.iter()
+        .map(|func| Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: fuse_body(&func.body, &Context::default()),
+        })
+        .collect()
+}
@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::ast::Inline;
+use crate::compiler::stir::{
+    Body, Constant, Exp, Function, Stir, Var, calls, free_vars, next_label, next_var,
+    replace_var_body,
+};
+
+// Bindings, matches and exits, roughly what a call to it would save
+fn size(body: &Body) -> usize {
+    match body {
+        Body::Ret(_) | Body::Jump(_, _) => 1,
+        Body::Let(_, _, next) => 1 + size(next),
+        Body::Match(_, branches) => 1 + branches.iter().map(|(_, b)| size(b)).sum::<usize>(),
+        Body::Join(_, _, join, body) => size(join) + size(body),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+fn count_rets(body: &Body) -> usize {
+    match body {
+        Body::Ret(_) => 1,
+        Body::Jump(_, _) => 0,
+        Body::Let(_, _, next) => count_rets(next),
+        Body::Match(_, branches) => branches.iter().map(|(_, b)| count_rets(b)).sum(),
+        Body::Join(_, _, join, body) => count_rets(join) + count_rets(body),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+fn has_joins(body: &Body) -> bool {
+    match body {
+        Body::Let(_, _, next) => has_joins(next),
+        Body::Match(_, branches) => branches.iter().any(|(_, b)| has_joins(b)),
+        Body::Join(_, _, _, _) => true,
+        _ => false,
+    }
+}
+
+// Functions ordered so that the ones called come before their callers, except
+// within a cycle
+fn post_order<'a>(
+    id: &'a Constant,
+    graph: &'a HashMap<Constant, Vec<Constant>>,
+    visited: &mut HashSet<&'a Constant>,
+    order: &mut Vec<&'a Constant>,
+) {
+    if !graph.contains_key(id) || !visited.insert(id) {
+        return;
+    }
+    for callee in graph.get(id).into_iter().flatten() {
+        post_order(callee, graph, visited, order);
+    }
+    order.push(id);
+}
+
+fn reaches(from: &Constant, to: &Constant, graph: &HashMap<Constant, Vec<Constant>>) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(id) = stack.pop() {
+        for callee in graph.get(id).into_iter().flatten() {
+            if callee == to {
+                return true;
+            }
+            if visited.insert(callee) {
+                stack.push(callee);
+            }
+        }
+    }
+    false
+}
+
+fn rename_var(var: &Var, vars: &HashMap<Var, Var>) -> Var {
+    vars.get(var).cloned().unwrap_or_else(|| var.clone())
+}
+
+fn rename_exp(exp: &Exp, vars: &HashMap<Var, Var>) -> Exp {
+    let rename_all = |args: &Vec<Var>| args.iter().map(|arg| rename_var(arg, vars)).collect();
+    match exp {
+        Exp::App(id, args) => Exp::App(id.clone(), rename_all(args)),
+        Exp::Ctor(tag, args) => Exp::Ctor(*tag, rename_all(args)),
+        Exp::UTuple(args) => Exp::UTuple(rename_all(args)),
+        Exp::Proj(i, var) => Exp::Proj(*i, rename_var(var, vars)),
+        Exp::Op(op, left, right) => Exp::Op(*op, rename_var(left, vars), rename_var(right, vars)),
+        Exp::Int(i) => Exp::Int(*i),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+// Renaming can make two shared join parameters the same variable, it is only
+// passed once. The last one is the result and stays
+fn dedup_shared(vars: impl Iterator<Item = Var>) -> Vec<Var> {
+    let mut vars = vars.collect::<Vec<_>>();
+    let result = vars.pop().unwrap();
+    let mut shared: Vec<Var> = vec![];
+    for var in vars {
+        if !shared.contains(&var) {
+            shared.push(var);
+        }
+    }
+    shared.into_iter().chain([result]).collect()
+}
+
+// Copy of a callee body with fresh names for every binder and join point, so
+// it cannot capture or shadow anything at the call site
+fn rename(body: &Body, vars: &HashMap<Var, Var>, labels: &HashMap<Constant, Constant>) -> Body {
+    match body {
+        Body::Ret(var) => Body::Ret(rename_var(var, vars)),
+        Body::Let(var, exp, next) => {
+            let fresh = (next_var(), var.1.clone());
+            let exp = rename_exp(exp, vars);
+            let mut vars = vars.clone();
+            vars.insert(var.clone(), fresh.clone());
+            Body::Let(fresh, exp, rename(next, &vars, labels).into())
+        }
+        Body::Match(var, branches) => Body::Match(
+            rename_var(var, vars),
+            branches
+                .iter()
+                .map(|(arity, branch)| (*arity, rename(branch, vars, labels)))
+                .collect(),
+        ),
+        // Shared parameters keep standing for the same variables as outside
+        Body::Join(label, params, join, body) => {
+            let fresh_label = next_label();
+            let (result, shared) = params.split_last().unwrap();
+            let fresh = (next_var(), result.1.clone());
+            let mut join_vars = vars.clone();
+            join_vars.insert(result.clone(), fresh.clone());
+            let mut labels = labels.clone();
+            labels.insert(label.clone(), fresh_label.clone());
+            Body::Join(
+                fresh_label,
+                dedup_shared(
+                    shared
+                        .iter()
+                        .map(|param| rename_var(param, vars))
+                        .chain([fresh]),
+                ),
+                rename(join, &join_vars, &labels).into(),
+                rename(body, vars, &labels).into(),
+            )
+        }
+        Body::Jump(label, args) => Body::Jump(
+            labels[label].clone(),
+            dedup_shared(args.iter().map(|arg| rename_var(arg, vars))),
+        ),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+// Puts the rest of the caller where the inlined body returns. The variables
+// in shared are threaded through the join points of the inlined body so that
+// they are still around to be passed on at its returns
+fn splice(body: Body, shared: &[Var], ret: &dyn Fn(Var) -> Body) -> Body {
+    let with_shared = |vars: Vec<Var>| {
+        let (last, rest) = vars.split_last().unwrap();
+        dedup_shared(rest.iter().chain(shared).chain([last]).cloned())
+    };
+    match body {
+        Body::Ret(var) => ret(var),
+        Body::Let(var, exp, next) => Body::Let(var, exp, splice(*next, shared, ret).into()),
+        Body::Match(var, branches) => Body::Match(
+            var,
+            branches
+                .into_iter()
+                .map(|(arity, branch)| (arity, splice(branch, shared, ret)))
+                .collect(),
+        ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label,
+            with_shared(params),
+            splice(*join, shared, ret).into(),
+            splice(*body, shared, ret).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(label, with_shared(args)),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+fn inline_call(callee: &Function, args: &[Var], result: &Var, next: Body) -> Body {
+    let vars = callee
+        .args
+        .iter()
+        .cloned()
+        .zip(args.iter().cloned())
+        .collect();
+    let body = rename(&callee.body, &vars, &HashMap::new());
+    if next == Body::Ret(result.clone()) {
+        return body;
+    }
+    if count_rets(&body) == 1 && !has_joins(&body) {
+        return splice(body, &[], &|var| {
+            replace_var_body(var, result, next.clone())
+        });
+    }
+    let mut shared = free_vars(&next)
+        .into_iter()
+        .filter(|var| var != result)
+        .collect::<Vec<_>>();
+    shared.sort_by(|a, b| a.0.cmp(&b.0));
+    let label = next_label();
+    let jump = |var: Var| Body::Jump(label.clone(), shared.iter().cloned().chain([var]).collect());
+    let body = splice(body, &shared, &jump);
+    Body::Join(
+        label.clone(),
+        shared.iter().cloned().chain([result.clone()]).collect(),
+        next.into(),
+        body.into(),
+    )
+}
+
+fn inline_body(body: &Body, inlinable: &HashMap<Constant, Function>) -> Body {
+    match body {
+        Body::Let(var, Exp::App(id, args), next) if inlinable.contains_key(id) => {
+            inline_call(&inlinable[id], args, var, inline_body(next, inlinable))
+        }
+        Body::Let(var, exp, next) => Body::Let(
+            var.clone(),
+            exp.clone(),
+            inline_body(next, inlinable).into(),
+        ),
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(arity, branch)| (*arity, inline_body(branch, inlinable)))
+                .collect(),
+        ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            inline_body(join, inlinable).into(),
+            inline_body(body, inlinable).into(),
+        ),
+        body => body.clone(),
+    }
+}
+
+// Inlines calls to functions that are not recursive and at most max_size big
+// after their own calls were inlined, or are marked inline. Functions marked
+// noinline and main are left alone
+pub fn inline(prog: &Stir, max_size: usize) -> Stir {
+    let graph = prog
+        .iter()
+        .map(|func| {
+            let mut out = vec![];
+            calls(&func.body, &mut out);
+            (func.id.clone(), out)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+    for func in prog {
+        post_order(&func.id, &graph, &mut visited, &mut order);
+    }
+
+    let funcs = prog
+        .iter()
+        .map(|func| (&func.id, func))
+        .collect::<HashMap<_, _>>();
+    let mut done = HashMap::new();
+    let mut inlinable = HashMap::new();
+    for id in order {
+        let func = funcs[id];
+        let func = Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: inline_body(&func.body, &inlinable),
+        };
+        let wanted = match func.inline {
+            Inline::Always => true,
+            Inline::Never => false,
+            Inline::Auto => size(&func.body) <= max_size,
+        };
+        if wanted && func.id != "main" && !reaches(id, id, &graph) {
+            inlinable.insert(id.clone(), func.clone());
+        }
+        done.insert(id, func);
+    }
+    prog.iter().map(|func| done[&func.id].clone()).collect()
+}
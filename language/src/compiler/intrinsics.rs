@@ -0,0 +1,197 @@
+use crate::ast::ast::{FunctionSignature, Inline, Type, UTuple};
+use crate::compiler::borrow::Status;
+
+// What an intrinsic does in the interpreter and the VM, given the state of
+// the generator and the untagged arguments
+pub type Eval = fn(&mut u64, &[i64]) -> i64;
+
+// A function every program can call without defining it. The backends emit
+// the definition of the ones a program uses, the interpreter and the VM run
+// eval. A function of the program with the same name takes its place
+pub struct Intrinsic {
+    pub id: &'static str,
+    pub ownership: &'static [Status],
+    pub eval: Eval,
+    // Definition of the C function goopea_<id>, on tagged values. {seed},
+    // {multiplier} and {increment} stand for the constants of the generator
+    pub c: &'static [&'static str],
+    // The same function in LLVM IR
+    pub llvm: &'static [&'static str],
+}
+
+// Every backend draws from the same generator, seeded the same way, so that
+// a program gives the same numbers in C and in the interpreter
+pub const RANDOM_SEED: u64 = 0x853c49e6748fea9b;
+pub const RANDOM_MULTIPLIER: u64 = 6364136223846793005;
+pub const RANDOM_INCREMENT: u64 = 1442695040888963407;
+
+pub const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic {
+        id: "abs",
+        ownership: &[Status::Borrowed],
+        eval: |_, args| args[0].wrapping_abs(),
+        c: &[
+            "static inline Value goopea_abs(Value x) {",
+            "\tint64_t i = goopea_from_int(x);",
+            "\treturn goopea_int(i < 0 ? -i : i);",
+            "}",
+        ],
+        llvm: &[
+            "define internal i64 @goopea_abs(i64 %x) {",
+            "entry:",
+            "  %i = ashr i64 %x, 1",
+            "  %negative = icmp slt i64 %i, 0",
+            "  %negated = sub i64 0, %i",
+            "  %abs = select i1 %negative, i64 %negated, i64 %i",
+            "  %shifted = shl i64 %abs, 1",
+            "  %tagged = or i64 %shifted, 1",
+            "  ret i64 %tagged",
+            "}",
+        ],
+    },
+    Intrinsic {
+        id: "min",
+        ownership: &[Status::Borrowed, Status::Borrowed],
+        eval: |_, args| args[0].min(args[1]),
+        c: &[
+            "static inline Value goopea_min(Value a, Value b) {",
+            "\treturn a < b ? a : b;",
+            "}",
+        ],
+        llvm: &[
+            "define internal i64 @goopea_min(i64 %a, i64 %b) {",
+            "entry:",
+            "  %less = icmp slt i64 %a, %b",
+            "  %min = select i1 %less, i64 %a, i64 %b",
+            "  ret i64 %min",
+            "}",
+        ],
+    },
+    Intrinsic {
+        id: "max",
+        ownership: &[Status::Borrowed, Status::Borrowed],
+        eval: |_, args| args[0].max(args[1]),
+        c: &[
+            "static inline Value goopea_max(Value a, Value b) {",
+            "\treturn a > b ? a : b;",
+            "}",
+        ],
+        llvm: &[
+            "define internal i64 @goopea_max(i64 %a, i64 %b) {",
+            "entry:",
+            "  %greater = icmp sgt i64 %a, %b",
+            "  %max = select i1 %greater, i64 %a, i64 %b",
+            "  ret i64 %max",
+            "}",
+        ],
+    },
+    // A number from 0 up to but not including the bound, 0 if there is none
+    Intrinsic {
+        id: "random",
+        ownership: &[Status::Borrowed],
+        eval: random,
+        c: &[
+            "static uint64_t goopea_random_state = {seed}ULL;",
+            "",
+            "static inline Value goopea_random(Value bound) {",
+            "\tint64_t n = goopea_from_int(bound);",
+            "\tgoopea_random_state = goopea_random_state * {multiplier}ULL + {increment}ULL;",
+            "\treturn goopea_int(n > 0 ? (int64_t) ((goopea_random_state >> 33) % (uint64_t) n) : 0);",
+            "}",
+        ],
+        llvm: &[
+            "@goopea_random_state = internal global i64 {seed}",
+            "",
+            "define internal i64 @goopea_random(i64 %bound) {",
+            "entry:",
+            "  %n = ashr i64 %bound, 1",
+            "  %state = load i64, i64* @goopea_random_state",
+            "  %multiplied = mul i64 %state, {multiplier}",
+            "  %next = add i64 %multiplied, {increment}",
+            "  store i64 %next, i64* @goopea_random_state",
+            "  %positive = icmp sgt i64 %n, 0",
+            "  br i1 %positive, label %draw, label %none",
+            "draw:",
+            "  %high = lshr i64 %next, 33",
+            "  %value = urem i64 %high, %n",
+            "  %shifted = shl i64 %value, 1",
+            "  %tagged = or i64 %shifted, 1",
+            "  ret i64 %tagged",
+            "none:",
+            "  ret i64 1",
+            "}",
+        ],
+    },
+    // Prints the value to stderr and returns it
+    Intrinsic {
+        id: "trace",
+        ownership: &[Status::Borrowed],
+        eval: |_, args| {
+            eprintln!("trace: {}", args[0]);
+            args[0]
+        },
+        c: &[
+            "static inline Value goopea_trace(Value x) {",
+            "\tfprintf(stderr, \"trace: %lld\\n\", (long long) goopea_from_int(x));",
+            "\treturn x;",
+            "}",
+        ],
+        llvm: &[
+            "@stderr = external global i8*",
+            "@goopea_trace_format = private unnamed_addr constant [13 x i8] c\"trace: %lld\\0A\\00\"",
+            "",
+            "declare i32 @fprintf(i8*, i8*, ...)",
+            "",
+            "define internal i64 @goopea_trace(i64 %x) {",
+            "entry:",
+            "  %i = ashr i64 %x, 1",
+            "  %stream = load i8*, i8** @stderr",
+            "  %format = getelementptr inbounds [13 x i8], [13 x i8]* @goopea_trace_format, i64 0, i64 0",
+            "  %written = call i32 (i8*, i8*, ...) @fprintf(i8* %stream, i8* %format, i64 %i)",
+            "  ret i64 %x",
+            "}",
+        ],
+    },
+];
+
+pub fn get(id: &str) -> Option<&'static Intrinsic> {
+    INTRINSICS.iter().find(|intrinsic| intrinsic.id == id)
+}
+
+impl Intrinsic {
+    // Intrinsics take and return Ints
+    pub fn signature(&self) -> FunctionSignature {
+        FunctionSignature {
+            argument_type: UTuple(vec![Type::Int; self.ownership.len()]),
+            result_type: UTuple(vec![Type::Int]),
+            is_fip: true,
+            inline: Inline::Never,
+        }
+    }
+
+    pub fn c_definition(&self) -> Vec<String> {
+        self.c.iter().map(|line| fill(line)).collect()
+    }
+
+    pub fn llvm_definition(&self) -> Vec<String> {
+        self.llvm.iter().map(|line| fill(line)).collect()
+    }
+}
+
+fn fill(line: &str) -> String {
+    line.replace("{seed}", &RANDOM_SEED.to_string())
+        .replace("{multiplier}", &RANDOM_MULTIPLIER.to_string())
+        .replace("{increment}", &RANDOM_INCREMENT.to_string())
+}
+
+// Steps the generator and draws a number below the bound
+fn random(state: &mut u64, args: &[i64]) -> i64 {
+    *state = state
+        .wrapping_mul(RANDOM_MULTIPLIER)
+        .wrapping_add(RANDOM_INCREMENT);
+    if args[0] > 0 {
+        ((*state >> 33) % args[0] as u64) as i64
+    } else {
+        0
+    }
+}
@@ -0,0 +1,210 @@
+use crate::compiler::core::{
+    Def, HEADER_WORDS, Prog, Runtime, extern_prototypes, function_definition, function_head,
+    intrinsic_definitions, runtime_lines, static_cells, utuple_typedefs,
+};
+use crate::compiler::crux::{Layouts, Repr};
+
+// A function the library exports, with whether each of its parameters is
+// borrowed from the caller
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub id: String,
+    pub borrowed: Vec<bool>,
+}
+
+fn guard(header: &str) -> String {
+    let name = header.rsplit('/').next().unwrap();
+    let name = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect::<String>();
+    format!("GOOPEA_LIB_{}", name)
+}
+
+fn prototype(def: &Def, borrowed: &[bool]) -> String {
+    let args = def
+        .args
+        .iter()
+        .zip(borrowed)
+        .map(|(arg, borrowed)| match borrowed {
+            true => format!("Value /* borrowed */ {}", arg),
+            false => format!("Value {}", arg),
+        })
+        .collect::<Vec<_>>();
+    let args = if args.is_empty() {
+        "void".to_string()
+    } else {
+        args.join(", ")
+    };
+    format!("{}{}({});", def.typ, def.id, args)
+}
+
+// Builds the value of each constructor, tells which constructor a value was
+// built with and reads its fields, in whatever representation the ADT has
+fn adt_functions(adt: &str, layouts: &Layouts) -> Vec<String> {
+    let (repr, ctors) = &layouts.adts[adt];
+    let names = &layouts.names[adt];
+    let mut lines = vec![format!(
+        "enum {} {{ {} }};",
+        adt,
+        names
+            .iter()
+            .map(|name| format!("{}_{}", adt, name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )];
+    lines.push(String::new());
+
+    let mut nullary = 0;
+    for (tag, (name, fields)) in names.iter().zip(ctors).enumerate() {
+        let params = (0..fields.len())
+            .map(|i| format!("Value field{}", i))
+            .collect::<Vec<_>>();
+        let params = if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        };
+        lines.push(format!("static inline Value new_{}({}) {{", name, params));
+        match repr {
+            Repr::Boxed if fields.is_empty() => lines.push(format!("\treturn {};", tag << 1 | 1)),
+            Repr::Boxed => {
+                lines.push(format!(
+                    "\tValue v = goopea_alloc_cell({});",
+                    fields.len() as i64 + HEADER_WORDS
+                ));
+                lines.push(format!(
+                    "\tFIELD(v, 0) = HEADER({}, {});",
+                    tag,
+                    fields.len()
+                ));
+                for i in 0..fields.len() {
+                    lines.push(format!(
+                        "\tFIELD(v, {}) = field{};",
+                        i as i64 + HEADER_WORDS,
+                        i
+                    ));
+                }
+                lines.push("\treturn v;".to_string());
+            }
+            Repr::Newtype => lines.push("\treturn field0;".to_string()),
+            Repr::Immediate if fields.is_empty() => {
+                lines.push(format!("\treturn {};", (nullary * 2) << 1 | 1));
+                nullary += 1;
+            }
+            Repr::Immediate => lines.push("\treturn (field0 << 1) | 1;".to_string()),
+        }
+        lines.push("}".to_string());
+        lines.push(String::new());
+    }
+
+    lines.push(format!(
+        "static inline enum {} {}_tag(Value v) {{",
+        adt, adt
+    ));
+    match repr {
+        Repr::Boxed => lines.push(format!("\treturn (enum {}) CTOR(v);", adt)),
+        Repr::Newtype => {
+            lines.push("\t(void) v;".to_string());
+            lines.push(format!("\treturn {}_{};", adt, names[0]));
+        }
+        // Odd values hold the field, even ones are a nullary constructor
+        Repr::Immediate => {
+            let tags = names.iter().zip(ctors);
+            let (with_field, _) = tags.clone().find(|(_, fields)| !fields.is_empty()).unwrap();
+            let nullary = tags
+                .filter(|(_, fields)| fields.is_empty())
+                .map(|(name, _)| format!("{}_{}", adt, name))
+                .collect::<Vec<_>>();
+            lines.push(format!(
+                "\tstatic const enum {} nullary[] = {{ {} }};",
+                adt,
+                nullary.join(", ")
+            ));
+            lines.push("\tValue n = v >> 1;".to_string());
+            lines.push(format!(
+                "\treturn n & 1 ? {}_{} : nullary[n / 2];",
+                adt, with_field
+            ));
+        }
+    }
+    lines.push("}".to_string());
+    lines.push(String::new());
+
+    for (name, fields) in names.iter().zip(ctors) {
+        for i in 0..fields.len() {
+            lines.push(format!(
+                "static inline Value {}_field{}(Value v) {{",
+                name, i
+            ));
+            match repr {
+                Repr::Boxed => {
+                    lines.push(format!("\treturn FIELD(v, {});", i as i64 + HEADER_WORDS))
+                }
+                Repr::Newtype => lines.push("\treturn v;".to_string()),
+                Repr::Immediate => lines.push("\treturn v >> 1;".to_string()),
+            }
+            lines.push("}".to_string());
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+// The source of a library and the header declaring what it exports. Other
+// functions are static, so their names stay inside the library
+pub fn output_lib(
+    prog: &Prog,
+    layouts: &Layouts,
+    runtime: Runtime,
+    header: &str,
+    exports: &[Export],
+) -> (Vec<String>, Vec<String>) {
+    let exported = |def: &Def| exports.iter().find(|export| export.id == def.id);
+
+    let mut source = vec![format!("#include \"{}\"", header), String::new()];
+    source.extend(extern_prototypes(prog));
+    source.extend(intrinsic_definitions(prog));
+    source.extend(static_cells(prog));
+    for def in prog.0.iter().filter(|def| exported(def).is_none()) {
+        source.push(format!("static {};", function_head(def)));
+    }
+    source.push(String::new());
+    for def in &prog.0 {
+        let head = match exported(def) {
+            Some(_) => function_head(def),
+            None => format!("static {}", function_head(def)),
+        };
+        source.extend(function_definition(def, &head));
+    }
+
+    let guard = guard(header);
+    let mut lines = vec![
+        "// Values passed to exported functions and constructors are owned".to_string(),
+        "// references, except for parameters marked borrowed, which the caller".to_string(),
+        "// keeps. Tags and fields are read without taking a reference. Ints are".to_string(),
+        "// passed as made by goopea_int, references are shared with goopea_inc".to_string(),
+        "// and given up with goopea_dec".to_string(),
+        format!("#ifndef {}", guard),
+        format!("#define {}", guard),
+        String::new(),
+    ];
+    lines.extend(runtime_lines(runtime));
+    lines.extend(utuple_typedefs(prog));
+    for def in &prog.0 {
+        if let Some(export) = exported(def) {
+            lines.push(prototype(def, &export.borrowed));
+        }
+    }
+    lines.push(String::new());
+    let mut adts = layouts.names.keys().collect::<Vec<_>>();
+    adts.sort();
+    for adt in adts {
+        lines.extend(adt_functions(adt, layouts));
+    }
+    lines.push("#endif".to_string());
+    (source, lines)
+}
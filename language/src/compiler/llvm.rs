@@ -0,0 +1,811 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Lowers core to textual LLVM IR, as a second native backend next to the C
+// text of core::output. Every variable lives in a stack slot, like clang
+// emits without optimizations, which leaves SSA form to llc and opt
+use crate::ast::ast;
+use crate::compiler::core::{
+    Def, HEADER_WORDS, Operand, Prog, Statement, Type, calls_self, collect_vars, header,
+    printed_adts, static_header, used_intrinsics,
+};
+use crate::compiler::crux::{Layouts, Operator, Repr};
+
+pub const RUNTIME_IR: &str = include_str!("../../runtime/goopea_runtime.ll");
+
+pub fn output(prog: &Prog, layouts: &Layouts) -> Vec<String> {
+    let mut lines = RUNTIME_IR.lines().map(str::to_string).collect::<Vec<_>>();
+    lines.push(String::new());
+    for ext in &prog.3 {
+        lines.push(format!(
+            "declare i64 @{}({})",
+            ext.id,
+            vec!["i64"; ext.arity].join(", ")
+        ));
+    }
+    if !prog.3.is_empty() {
+        lines.push(String::new());
+    }
+    for intrinsic in used_intrinsics(prog) {
+        lines.extend(intrinsic.llvm_definition());
+        lines.push(String::new());
+    }
+    for (i, cell) in prog.2.iter().enumerate() {
+        let fields = std::iter::once(format!(
+            "i64 {}",
+            static_header(cell.tag, cell.fields.len() as u8)
+        ))
+        .chain(
+            cell.fields
+                .iter()
+                .map(|field| format!("i64 {}", constant(prog, field))),
+        );
+        lines.push(format!(
+            "@static{} = internal global [{} x i64] [{}]",
+            i,
+            cell.fields.len() as i64 + HEADER_WORDS,
+            fields.collect::<Vec<_>>().join(", ")
+        ));
+    }
+    if !prog.2.is_empty() {
+        lines.push(String::new());
+    }
+
+    let mut strings = vec![];
+    let returns = prog
+        .0
+        .iter()
+        .map(|def| (def.id.as_str(), llvm_type(&def.typ)))
+        .collect::<HashMap<_, _>>();
+    lines.extend(printers(prog, layouts, &mut strings));
+    for def in &prog.0 {
+        lines.extend(function_definition(prog, def, &returns));
+    }
+    for (i, string) in strings.iter().enumerate() {
+        lines.push(format!(
+            "@str{} = private unnamed_addr constant [{} x i8] c\"{}\\00\"",
+            i,
+            string.len() + 1,
+            escape(string)
+        ));
+    }
+    lines
+}
+
+fn llvm_type(typ: &Type) -> String {
+    match typ {
+        Type::Value(size) => format!("[{} x i64]", size),
+        Type::Standard | Type::None => "i64".to_string(),
+    }
+}
+
+fn escape(string: &str) -> String {
+    string
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'"' && b != b'\\' => (b as char).to_string(),
+            b => format!("\\{:02X}", b),
+        })
+        .collect()
+}
+
+fn static_ref(prog: &Prog, i: usize) -> String {
+    let size = prog.2[i].fields.len() as i64 + HEADER_WORDS;
+    format!("ptrtoint ([{} x i64]* @static{} to i64)", size, i)
+}
+
+fn constant(prog: &Prog, op: &Operand) -> String {
+    match op {
+        Operand::Int(i) => (i << 1 | 1).to_string(),
+        Operand::NonShifted(i) => i.to_string(),
+        Operand::Static(i) => static_ref(prog, *i),
+        Operand::Ident(_) | Operand::Negate(_) => panic!("static cell holds a variable"),
+    }
+}
+
+// The instructions of one function, split into blocks as they are emitted
+struct Builder<'a> {
+    prog: &'a Prog,
+    lines: Vec<String>,
+    temps: usize,
+    labels: usize,
+    terminated: bool,
+    vars: BTreeMap<String, Type>,
+    // Sizes of the stack cells, by the variable pointing at them
+    cells: BTreeMap<String, u8>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(prog: &'a Prog) -> Self {
+        Builder {
+            prog,
+            lines: vec![],
+            temps: 0,
+            labels: 0,
+            terminated: false,
+            vars: BTreeMap::new(),
+            cells: BTreeMap::new(),
+        }
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("{}{}", kind, self.labels)
+    }
+
+    // Anything after a terminator is unreachable, but still needs a block
+    fn emit(&mut self, instruction: String) {
+        if self.terminated {
+            let label = self.label("dead");
+            self.lines.push(format!("{}:", label));
+            self.terminated = false;
+        }
+        self.lines.push(format!("  {}", instruction));
+    }
+
+    fn assign(&mut self, instruction: String) -> String {
+        self.temps += 1;
+        let temp = format!("%t{}", self.temps);
+        self.emit(format!("{} = {}", temp, instruction));
+        temp
+    }
+
+    fn terminate(&mut self, instruction: String) {
+        self.emit(instruction);
+        self.terminated = true;
+    }
+
+    fn block(&mut self, label: &str) {
+        if !self.terminated {
+            self.lines.push(format!("  br label %{}", label));
+        }
+        self.lines.push(format!("{}:", label));
+        self.terminated = false;
+    }
+
+    fn var_type(&self, var: &str) -> String {
+        llvm_type(self.vars.get(var).unwrap_or(&Type::Standard))
+    }
+
+    fn load(&mut self, var: &str) -> String {
+        let typ = self.var_type(var);
+        self.assign(format!("load {}, {}* %v.{}", typ, typ, var))
+    }
+
+    fn store(&mut self, var: &str, value: &str) {
+        let typ = self.var_type(var);
+        self.emit(format!("store {} {}, {}* %v.{}", typ, value, typ, var));
+    }
+
+    fn operand(&mut self, op: &Operand) -> String {
+        match op {
+            Operand::Ident(var) => self.load(var),
+            Operand::Negate(var) => {
+                let value = self.load(var);
+                let zero = self.assign(format!("icmp eq i64 {}, 0", value));
+                self.assign(format!("zext i1 {} to i64", zero))
+            }
+            op => constant(self.prog, op),
+        }
+    }
+
+    fn condition(&mut self, op: &Operand) -> String {
+        match op {
+            Operand::Negate(var) => {
+                let value = self.load(var);
+                self.assign(format!("icmp eq i64 {}, 0", value))
+            }
+            op => {
+                let value = self.operand(op);
+                self.assign(format!("icmp ne i64 {}, 0", value))
+            }
+        }
+    }
+
+    fn field_ptr(&mut self, cell: &str, index: i64) -> String {
+        let ptr = self.assign(format!("inttoptr i64 {} to i64*", cell));
+        self.assign(format!("getelementptr i64, i64* {}, i64 {}", ptr, index))
+    }
+
+    fn load_field(&mut self, cell: &str, index: i64) -> String {
+        let ptr = self.field_ptr(cell, index);
+        self.assign(format!("load i64, i64* {}", ptr))
+    }
+
+    fn store_field(&mut self, cell: &str, index: i64, value: &str) {
+        let ptr = self.field_ptr(cell, index);
+        self.emit(format!("store i64 {}, i64* {}", value, ptr));
+    }
+
+    fn call(&mut self, function: &str, args: &[String]) -> String {
+        let args = args
+            .iter()
+            .map(|arg| format!("i64 {}", arg))
+            .collect::<Vec<_>>();
+        self.assign(format!("call i64 @{}({})", function, args.join(", ")))
+    }
+
+    fn call_void(&mut self, function: &str, args: &[String]) {
+        let args = args
+            .iter()
+            .map(|arg| format!("i64 {}", arg))
+            .collect::<Vec<_>>();
+        self.emit(format!("call void @{}({})", function, args.join(", ")));
+    }
+
+    fn tag(&mut self, value: &str) -> String {
+        self.assign(format!("or i64 {}, 1", value))
+    }
+
+    fn untag(&mut self, value: &str) -> String {
+        self.assign(format!("ashr i64 {}, 1", value))
+    }
+
+    fn shift_tag(&mut self, value: &str) -> String {
+        let shifted = self.assign(format!("shl i64 {}, 1", value));
+        self.tag(&shifted)
+    }
+
+    fn print_text(&mut self, text: &str, strings: &mut Vec<String>) {
+        let i = match strings.iter().position(|string| string == text) {
+            Some(i) => i,
+            None => {
+                strings.push(text.to_string());
+                strings.len() - 1
+            }
+        };
+        let size = text.len() + 1;
+        self.assign(format!(
+            "call i32 (i8*, ...) @printf(i8* getelementptr inbounds ([{} x i8], [{} x i8]* @str{}, i64 0, i64 0))",
+            size, size, i
+        ));
+    }
+
+    fn print_value(&mut self, value: &str, typ: &ast::Type) {
+        match typ {
+            ast::Type::Int => self.call_void("goopea_print_int", &[value.to_string()]),
+            ast::Type::ADT(adt) => self.call_void(&format!("print_{}", adt), &[value.to_string()]),
+        }
+    }
+
+    fn print_ctor(
+        &mut self,
+        name: &str,
+        fields: Vec<(String, &ast::Type)>,
+        strings: &mut Vec<String>,
+    ) {
+        if fields.is_empty() {
+            self.print_text(name, strings);
+            return;
+        }
+        self.print_text(&format!("{}(", name), strings);
+        for (i, (value, typ)) in fields.iter().enumerate() {
+            if i > 0 {
+                self.print_text(", ", strings);
+            }
+            self.print_value(value, typ);
+        }
+        self.print_text(")", strings);
+    }
+
+    // Prints the constructor up to its last field, which is left to the caller
+    fn print_ctor_head(
+        &mut self,
+        name: &str,
+        fields: Vec<(String, &ast::Type)>,
+        strings: &mut Vec<String>,
+    ) {
+        self.print_text(&format!("{}(", name), strings);
+        for (value, typ) in fields {
+            self.print_value(&value, typ);
+            self.print_text(", ", strings);
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if !self.terminated {
+            self.lines.push("  unreachable".to_string());
+        }
+        self.lines.push("}".to_string());
+        self.lines.push(String::new());
+        self.lines
+    }
+}
+
+fn function_definition(prog: &Prog, def: &Def, returns: &HashMap<&str, String>) -> Vec<String> {
+    let mut builder = Builder::new(prog);
+    for arg in &def.args {
+        builder.vars.insert(arg.clone(), Type::Standard);
+    }
+    collect_vars(&def.body, &mut builder.vars, &mut builder.cells);
+
+    let head = if def.id == "main" {
+        "define i32 @main()".to_string()
+    } else {
+        let args = def
+            .args
+            .iter()
+            .map(|arg| format!("i64 %a.{}", arg))
+            .collect::<Vec<_>>();
+        format!(
+            "define {} @{}({})",
+            llvm_type(&def.typ),
+            def.id,
+            args.join(", ")
+        )
+    };
+    builder.lines.push(format!("{} {{", head));
+    builder.lines.push("entry:".to_string());
+    for (var, typ) in builder.vars.clone() {
+        builder.emit(format!("%v.{} = alloca {}", var, llvm_type(&typ)));
+    }
+    for (var, size) in builder.cells.clone() {
+        builder.emit(format!(
+            "%c.{} = alloca [{} x i64]",
+            var,
+            size as i64 + HEADER_WORDS
+        ));
+    }
+    for arg in &def.args {
+        builder.store(arg, &format!("%a.{}", arg));
+    }
+    if calls_self(&def.body, &def.id) {
+        builder.block("tailcall");
+    }
+    for stmt in &def.body {
+        statement(&mut builder, stmt, def, returns);
+    }
+    builder.finish()
+}
+
+fn statement(b: &mut Builder, stmt: &Statement, def: &Def, returns: &HashMap<&str, String>) {
+    match stmt {
+        Statement::Assign(_, var, op) => {
+            let value = b.operand(op);
+            b.store(var, &value);
+        }
+        Statement::AssignToField(var, index, op) | Statement::FillHole(var, index, op) => {
+            let value = b.operand(op);
+            let cell = b.load(var);
+            b.store_field(&cell, *index, &value);
+        }
+        Statement::AssignHeader(var, tag, arity) => {
+            let cell = b.load(var);
+            b.store_field(&cell, 0, &header(*tag, *arity).to_string());
+        }
+        Statement::Declare(_, _) => (),
+        Statement::Label(label) => b.block(&format!("j.{}", label)),
+        Statement::Jump(label) => b.terminate(format!("br label %j.{}", label)),
+        Statement::AssignFromField(var, index, op) => {
+            let cell = b.operand(op);
+            let value = b.load_field(&cell, *index);
+            b.store(var, &value);
+        }
+        Statement::AssignMalloc(_, var, size) => {
+            let cell = b.call(
+                "goopea_alloc_cell",
+                &[(*size as i64 + HEADER_WORDS).to_string()],
+            );
+            b.store(var, &cell);
+        }
+        // The cell is a slot of the frame, it is never freed nor counted
+        Statement::AssignStackAlloc(var, _) => {
+            let size = b.cells[var] as i64 + HEADER_WORDS;
+            let cell = b.assign(format!("ptrtoint [{} x i64]* %c.{} to i64", size, var));
+            b.store(var, &cell);
+        }
+        // Like the else chain of the C backend, the last of several branches
+        // is taken without looking at its condition
+        Statement::IfElse(branches) => {
+            let end = b.label("end");
+            for (i, (cond, stmts)) in branches.iter().enumerate() {
+                if i > 0 && i == branches.len() - 1 {
+                    stmts
+                        .iter()
+                        .for_each(|stmt| statement(b, stmt, def, returns));
+                    break;
+                }
+                let then = b.label("then");
+                let next = if i == branches.len() - 1 {
+                    end.clone()
+                } else {
+                    b.label("else")
+                };
+                let cond = b.condition(cond);
+                b.terminate(format!("br i1 {}, label %{}, label %{}", cond, then, next));
+                b.block(&then);
+                stmts
+                    .iter()
+                    .for_each(|stmt| statement(b, stmt, def, returns));
+                if !b.terminated {
+                    b.terminate(format!("br label %{}", end));
+                }
+                if next != end {
+                    b.block(&next);
+                }
+            }
+            b.block(&end);
+        }
+        Statement::Switch(_, cases) if cases.len() == 1 => cases[0]
+            .1
+            .iter()
+            .for_each(|stmt| statement(b, stmt, def, returns)),
+        Statement::Switch(op, cases) => {
+            let value = b.operand(op);
+            let key = if cases.iter().all(|(boxed, _)| *boxed) {
+                let header = b.load_field(&value, 0);
+                b.assign(format!("and i64 {}, 255", header))
+            } else if cases.iter().all(|(boxed, _)| !*boxed) {
+                b.untag(&value)
+            } else {
+                b.call("goopea_ctor", &[value])
+            };
+            let end = b.label("end");
+            let labels = cases.iter().map(|_| b.label("case")).collect::<Vec<_>>();
+            let (default, rest) = labels.split_last().unwrap();
+            let targets = rest
+                .iter()
+                .enumerate()
+                .map(|(i, label)| format!("i64 {}, label %{}", i, label))
+                .collect::<Vec<_>>();
+            b.terminate(format!(
+                "switch i64 {}, label %{} [{}]",
+                key,
+                default,
+                targets.join(" ")
+            ));
+            for ((_, stmts), label) in cases.iter().zip(&labels) {
+                b.block(label);
+                stmts
+                    .iter()
+                    .for_each(|stmt| statement(b, stmt, def, returns));
+                if !b.terminated {
+                    b.terminate(format!("br label %{}", end));
+                }
+            }
+            b.block(&end);
+        }
+        Statement::Return(_) if def.id == "main" => b.terminate("ret i32 0".to_string()),
+        Statement::Return(op) => {
+            let value = b.operand(op);
+            b.terminate(format!("ret {} {}", llvm_type(&def.typ), value));
+        }
+        Statement::Print(op) => {
+            let value = b.operand(op);
+            b.emit(format!(
+                "call void @print_result({} {})",
+                llvm_type(&def.typ),
+                value
+            ));
+        }
+        Statement::AssignBinaryOperation(var, op, left, right) => {
+            let left = b.operand(left);
+            let right = b.operand(right);
+            let result = match op {
+                Operator::Add => {
+                    let sum = b.assign(format!("add i64 {}, {}", left, right));
+                    b.assign(format!("sub i64 {}, 1", sum))
+                }
+                Operator::Sub => {
+                    let difference = b.assign(format!("sub i64 {}, {}", left, right));
+                    b.tag(&difference)
+                }
+                Operator::Mul => {
+                    let left = b.assign(format!("sub i64 {}, 1", left));
+                    let right = b.untag(&right);
+                    let product = b.assign(format!("mul i64 {}, {}", left, right));
+                    b.tag(&product)
+                }
+                // Signed division truncates like the interpreter does
+                Operator::Div | Operator::Mod => {
+                    let left = b.untag(&left);
+                    let right = b.untag(&right);
+                    let instruction = if matches!(op, Operator::Div) {
+                        "sdiv"
+                    } else {
+                        "srem"
+                    };
+                    let result = b.assign(format!("{} i64 {}, {}", instruction, left, right));
+                    b.shift_tag(&result)
+                }
+                op => {
+                    let predicate = match op {
+                        Operator::Equal => "eq",
+                        Operator::NotEqual => "ne",
+                        Operator::Less => "slt",
+                        Operator::LessOrEq => "sle",
+                        Operator::Greater => "sgt",
+                        _ => "sge",
+                    };
+                    let cmp = b.assign(format!("icmp {} i64 {}, {}", predicate, left, right));
+                    let bool = b.assign(format!("zext i1 {} to i64", cmp));
+                    b.shift_tag(&bool)
+                }
+            };
+            b.store(var, &result);
+        }
+        Statement::AssignFunctionCall(var, fun, ops, typ) => {
+            let args = ops.iter().map(|op| b.operand(op)).collect::<Vec<_>>();
+            let typ = returns.get(fun.as_str()).cloned().unwrap_or(llvm_type(typ));
+            let args = args
+                .iter()
+                .map(|arg| format!("i64 {}", arg))
+                .collect::<Vec<_>>();
+            let result = b.assign(format!("call {} @{}({})", typ, fun, args.join(", ")));
+            b.store(var, &result);
+        }
+        // Ints are untagged on the way into C and tagged again on the way out
+        Statement::AssignExternCall(var, fun, ops) => {
+            let args = ops
+                .iter()
+                .map(|op| {
+                    let value = b.operand(op);
+                    b.untag(&value)
+                })
+                .collect::<Vec<_>>();
+            let result = b.call(fun, &args);
+            let result = b.shift_tag(&result);
+            b.store(var, &result);
+        }
+        Statement::AssignIntrinsic(var, id, ops) => {
+            let args = ops.iter().map(|op| b.operand(op)).collect::<Vec<_>>();
+            let result = b.call(&format!("goopea_{}", id), &args);
+            b.store(var, &result);
+        }
+        // Arguments are all read before any parameter is written
+        Statement::TailCall(fun, ops) if *fun == def.id => {
+            let args = ops.iter().map(|op| b.operand(op)).collect::<Vec<_>>();
+            for (param, arg) in def.args.iter().zip(args) {
+                b.store(param, &arg);
+            }
+            b.terminate("br label %tailcall".to_string());
+        }
+        Statement::TailCall(fun, ops) => {
+            let args = ops
+                .iter()
+                .map(|op| format!("i64 {}", b.operand(op)))
+                .collect::<Vec<_>>();
+            let typ = llvm_type(&def.typ);
+            let result = b.assign(format!("tail call {} @{}({})", typ, fun, args.join(", ")));
+            b.terminate(format!("ret {} {}", typ, result));
+        }
+        Statement::AssignDropReuse(var, reset_var, kept) => {
+            let cell = b.load(reset_var);
+            let result = if kept.is_empty() {
+                b.call("goopea_drop_reuse", &[cell])
+            } else {
+                let mask = kept.iter().fold(0u64, |mask, i| mask | (1 << i));
+                b.call("goopea_drop_reuse_keep", &[cell, mask.to_string()])
+            };
+            b.store(var, &result);
+        }
+        Statement::AssignUTuple(size, var, args) => {
+            let mut tuple = "undef".to_string();
+            for (i, arg) in args.iter().enumerate() {
+                let value = b.load(arg);
+                tuple = b.assign(format!(
+                    "insertvalue [{} x i64] {}, i64 {}, {}",
+                    size, tuple, value, i
+                ));
+            }
+            b.store(var, &tuple);
+        }
+        Statement::AssignUTupleField(var, i, op) => {
+            let Operand::Ident(tuple_var) = op else {
+                panic!("projection of {:?}", op)
+            };
+            let typ = b.var_type(tuple_var);
+            let tuple = b.load(tuple_var);
+            let value = b.assign(format!("extractvalue {} {}, {}", typ, tuple, i));
+            b.store(var, &value);
+        }
+        Statement::Inc(var) => {
+            let value = b.load(var);
+            b.call("goopea_inc", &[value]);
+        }
+        Statement::Dec(var) => {
+            let value = b.load(var);
+            b.call("goopea_dec", &[value]);
+        }
+        Statement::DecUTuple(var, size) => {
+            let tuple = b.load(var);
+            for i in 0..*size {
+                let value = b.assign(format!("extractvalue [{} x i64] {}, {}", size, tuple, i));
+                b.call("goopea_dec", &[value]);
+            }
+        }
+        Statement::Drop(var, decs, incs) => {
+            let cell = b.load(var);
+            let rc = b.call("goopea_rc", std::slice::from_ref(&cell));
+            let last = b.assign(format!("icmp eq i64 {}, 1", rc));
+            let (unique, shared, end) = (b.label("unique"), b.label("shared"), b.label("end"));
+            b.terminate(format!(
+                "br i1 {}, label %{}, label %{}",
+                last, unique, shared
+            ));
+            b.block(&unique);
+            for i in decs {
+                let field = b.load_field(&cell, *i as i64 + HEADER_WORDS);
+                b.call("goopea_dec", &[field]);
+            }
+            let header = b.load_field(&cell, 0);
+            let arity = b.assign(format!("ashr i64 {}, 8", header));
+            let arity = b.assign(format!("and i64 {}, 255", arity));
+            let words = b.assign(format!("add i64 {}, {}", arity, HEADER_WORDS));
+            b.call_void("goopea_free_cell", &[cell.clone(), words]);
+            b.terminate(format!("br label %{}", end));
+            b.block(&shared);
+            for i in incs {
+                let field = b.load_field(&cell, *i as i64 + HEADER_WORDS);
+                b.call("goopea_inc", &[field]);
+            }
+            b.call_void("goopea_release", &[cell]);
+            b.block(&end);
+        }
+    }
+}
+
+// Printers for the result of main and every ADT reachable from it, writing
+// values like the printers of the C backend
+fn printers(prog: &Prog, layouts: &Layouts, strings: &mut Vec<String>) -> Vec<String> {
+    let Some(main) = prog.0.iter().find(|def| def.id == "main") else {
+        return vec![];
+    };
+    let mut lines = vec![];
+    for adt in printed_adts(layouts) {
+        let (repr, ctors) = &layouts.adts[adt];
+        let names = &layouts.names[adt];
+        let mut b = Builder::new(prog);
+        b.lines
+            .push(format!("define internal void @print_{}(i64 %v) {{", adt));
+        b.lines.push("entry:".to_string());
+        match repr {
+            // Like in C, a last field of the same type is printed by going
+            // around again instead of recursing
+            Repr::Boxed => {
+                let own = ast::Type::ADT(adt.clone());
+                let walks = ctors.iter().any(|fields| fields.last() == Some(&own));
+                let next = b.label("next");
+                let (current, open) = if walks {
+                    (
+                        b.assign("alloca i64".to_string()),
+                        b.assign("alloca i64".to_string()),
+                    )
+                } else {
+                    (String::new(), String::new())
+                };
+                let v = if walks {
+                    b.emit(format!("store i64 %v, i64* {}", current));
+                    b.emit(format!("store i64 0, i64* {}", open));
+                    b.block(&next);
+                    b.assign(format!("load i64, i64* {}", current))
+                } else {
+                    "%v".to_string()
+                };
+                let key = b.call("goopea_ctor", std::slice::from_ref(&v));
+                let end = b.label("end");
+                let labels = ctors.iter().map(|_| b.label("case")).collect::<Vec<_>>();
+                let targets = labels
+                    .iter()
+                    .enumerate()
+                    .map(|(tag, label)| format!("i64 {}, label %{}", tag, label))
+                    .collect::<Vec<_>>();
+                b.terminate(format!(
+                    "switch i64 {}, label %{} [{}]",
+                    key,
+                    end,
+                    targets.join(" ")
+                ));
+                for ((name, fields), label) in names.iter().zip(ctors).zip(&labels) {
+                    b.block(label);
+                    let mut fields = fields
+                        .iter()
+                        .enumerate()
+                        .map(|(i, typ)| (b.load_field(&v, i as i64 + HEADER_WORDS), typ))
+                        .collect::<Vec<_>>();
+                    if fields.last().is_some_and(|(_, typ)| **typ == own) {
+                        let (last, _) = fields.pop().unwrap();
+                        b.print_ctor_head(name, fields, strings);
+                        b.emit(format!("store i64 {}, i64* {}", last, current));
+                        let count = b.assign(format!("load i64, i64* {}", open));
+                        let count = b.assign(format!("add i64 {}, 1", count));
+                        b.emit(format!("store i64 {}, i64* {}", count, open));
+                        b.terminate(format!("br label %{}", next));
+                    } else {
+                        b.print_ctor(name, fields, strings);
+                        b.terminate(format!("br label %{}", end));
+                    }
+                }
+                b.block(&end);
+                if walks {
+                    let (close, paren, done) =
+                        (b.label("close"), b.label("paren"), b.label("done"));
+                    b.block(&close);
+                    let count = b.assign(format!("load i64, i64* {}", open));
+                    let more = b.assign(format!("icmp sgt i64 {}, 0", count));
+                    b.terminate(format!("br i1 {}, label %{}, label %{}", more, paren, done));
+                    b.block(&paren);
+                    b.print_text(")", strings);
+                    let count = b.assign(format!("sub i64 {}, 1", count));
+                    b.emit(format!("store i64 {}, i64* {}", count, open));
+                    b.terminate(format!("br label %{}", close));
+                    b.block(&done);
+                }
+            }
+            Repr::Newtype => {
+                b.print_ctor(&names[0], vec![("%v".to_string(), &ctors[0][0])], strings);
+            }
+            // Odd values hold the field, even ones are a nullary constructor.
+            // The field is 2 * x + 1, which is how a shifted int looks anyway
+            Repr::Immediate => {
+                let n = b.untag("%v");
+                let bit = b.assign(format!("and i64 {}, 1", n));
+                let odd = b.assign(format!("icmp ne i64 {}, 0", bit));
+                let (field, nullary, end) = (b.label("field"), b.label("nullary"), b.label("end"));
+                b.terminate(format!(
+                    "br i1 {}, label %{}, label %{}",
+                    odd, field, nullary
+                ));
+                b.block(&field);
+                let (name, fields) = names
+                    .iter()
+                    .zip(ctors)
+                    .find(|(_, fields)| !fields.is_empty())
+                    .unwrap();
+                b.print_ctor(name, vec![(n.clone(), &fields[0])], strings);
+                b.terminate(format!("br label %{}", end));
+                b.block(&nullary);
+                let key = b.untag(&n);
+                let nullary = names
+                    .iter()
+                    .zip(ctors)
+                    .filter(|(_, fields)| fields.is_empty())
+                    .map(|(name, _)| (b.label("case"), name))
+                    .collect::<Vec<_>>();
+                let targets = nullary
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (label, _))| format!("i64 {}, label %{}", i, label))
+                    .collect::<Vec<_>>();
+                b.terminate(format!(
+                    "switch i64 {}, label %{} [{}]",
+                    key,
+                    end,
+                    targets.join(" ")
+                ));
+                for (label, name) in &nullary {
+                    b.block(label);
+                    b.print_ctor(name, vec![], strings);
+                    b.terminate(format!("br label %{}", end));
+                }
+                b.block(&end);
+            }
+        }
+        b.terminate("ret void".to_string());
+        lines.extend(b.finish());
+    }
+
+    let mut b = Builder::new(prog);
+    b.lines.push(format!(
+        "define internal void @print_result({} %v) {{",
+        llvm_type(&main.typ)
+    ));
+    b.lines.push("entry:".to_string());
+    match &layouts.result[..] {
+        [typ] => b.print_value("%v", typ),
+        types => {
+            b.print_text("(", strings);
+            for (i, typ) in types.iter().enumerate() {
+                if i > 0 {
+                    b.print_text(", ", strings);
+                }
+                let value = b.assign(format!("extractvalue {} %v, {}", llvm_type(&main.typ), i));
+                b.print_value(&value, typ);
+            }
+            b.print_text(")", strings);
+        }
+    }
+    b.print_text("\n", strings);
+    b.terminate("ret void".to_string());
+    lines.extend(b.finish());
+    lines
+}
@@ -2,8 +2,19 @@ pub mod borrow;
 pub mod compile;
 pub mod core;
 pub mod crux;
+pub mod escape;
+pub mod fusion;
+pub mod inline;
+pub mod intrinsics;
+pub mod library;
+pub mod llvm;
 pub mod rc;
+pub mod reach;
 pub mod reuse;
 pub mod scoped_rc;
 pub mod score;
+pub mod simplify;
+pub mod statics;
 pub mod stir;
+pub mod trmc;
+pub mod wasm;
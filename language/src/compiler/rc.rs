@@ -25,8 +25,13 @@ fn insert_rc_fun(func: &Function, beta_map: &HashMap<Constant, Vec<Status>>) ->
     for (i, status) in beta_map.get(&func.id).unwrap().iter().enumerate() {
         betal.insert(func.args[i].clone(), *status);
     }
+    // Projections kept in place by a specialized reuse are never owned by the function
+    for var in kept_vars(&func.body) {
+        betal.insert(var, Status::Borrowed);
+    }
     Function {
         fip: func.fip,
+        inline: func.inline,
         id: func.id.clone(),
         typ: func.typ.clone(),
         args: func.args.clone(),
@@ -66,7 +71,9 @@ fn insert_rc_body(
         }
         Body::Let(var, exp, next) => match exp {
             Exp::Proj(_, proj_var)
-                if default_betal(proj_var, betal) == Status::Owned && var.1 == Type::Heaped =>
+                if default_betal(proj_var, betal) == Status::Owned
+                    && default_betal(var, betal) == Status::Owned
+                    && var.1 == Type::Heaped =>
             {
                 Body::Let(
                     var.clone(),
@@ -79,24 +86,31 @@ fn insert_rc_body(
                 )
             }
             Exp::Proj(_, proj_var)
-                if default_betal(proj_var, betal) == Status::Borrowed || var.1 != Type::Heaped =>
+                if default_betal(proj_var, betal) == Status::Borrowed
+                    || default_betal(var, betal) == Status::Borrowed
+                    || var.1 != Type::Heaped =>
             {
                 let mut new_betal = betal.clone();
                 new_betal.insert(var.clone(), Status::Borrowed);
                 Body::Let(
                     var.clone(),
                     exp.clone(),
-                    insert_rc_body(next, &new_betal, beta_map).into(),
+                    owned_minus(proj_var, &insert_rc_body(next, &new_betal, beta_map), betal)
+                        .into(),
                 )
             }
-            Exp::Reset(_) => Body::Let(
+            Exp::Reset(_, _) => Body::Let(
                 var.clone(),
                 exp.clone(),
                 insert_rc_body(next, betal, beta_map).into(),
             ),
+            // Externs only take Ints, which are never counted
             Exp::App(fid, args) => cappy(
                 args.clone(),
-                beta_map.get(fid).unwrap().clone(),
+                beta_map
+                    .get(fid)
+                    .cloned()
+                    .unwrap_or_else(|| vec![Status::Borrowed; args.len()]),
                 &Body::Let(
                     var.clone(),
                     exp.clone(),
@@ -124,9 +138,17 @@ fn insert_rc_body(
                 ),
                 betal,
             ),
-            Exp::Reuse(_, _, args) => cappy(
+            Exp::Reuse(_, _, args, kept, _) => cappy(
                 args.clone(),
-                vec![Status::Owned; args.len()],
+                (0..args.len() as u8)
+                    .map(|i| {
+                        if kept.contains(&i) {
+                            Status::Borrowed
+                        } else {
+                            Status::Owned
+                        }
+                    })
+                    .collect(),
                 &Body::Let(
                     var.clone(),
                     exp.clone(),
@@ -134,7 +156,7 @@ fn insert_rc_body(
                 ),
                 betal,
             ),
-            Exp::Int(_) => Body::Let(
+            Exp::Int(_) | Exp::Static(_) => Body::Let(
                 var.clone(),
                 exp.clone(),
                 insert_rc_body(next, betal, beta_map).into(),
@@ -146,10 +168,71 @@ fn insert_rc_body(
             ),
             _ => panic!("Shouldn't be possible!"),
         },
+        // Shared parameters are owned if the variables passed to them are, the
+        // result is always owned. A jump is then treated like a call
+        Body::Join(label, params, join, body) => {
+            let (result, shared) = params.split_last().unwrap();
+            let mut statuses = shared
+                .iter()
+                .map(|param| default_betal(param, betal))
+                .collect::<Vec<_>>();
+            statuses.push(Status::Owned);
+            let mut join_betal = betal.clone();
+            join_betal.insert(result.clone(), Status::Owned);
+            let mut beta_map = beta_map.clone();
+            beta_map.insert(label.clone(), statuses);
+            Body::Join(
+                label.clone(),
+                params.clone(),
+                owned_minus_all(
+                    params.clone(),
+                    &insert_rc_body(join, &join_betal, &beta_map),
+                    &join_betal,
+                )
+                .into(),
+                insert_rc_body(body, betal, &beta_map).into(),
+            )
+        }
+        Body::Jump(label, args) => args.iter().zip(&beta_map[label]).enumerate().fold(
+            body.clone(),
+            |body, (i, (arg, status))| {
+                if *status == Status::Owned {
+                    owned_plus(
+                        arg.clone(),
+                        args[..i].iter().cloned().collect(),
+                        &body,
+                        betal,
+                    )
+                } else {
+                    body
+                }
+            },
+        ),
         _ => todo!(),
     }
 }
 
+fn kept_vars(body: &Body) -> HashSet<Var> {
+    match body {
+        Body::Let(_, Exp::Reuse(_, _, args, kept, _), next) => {
+            let mut set = kept_vars(next);
+            set.extend(kept.iter().map(|i| args[*i as usize].clone()));
+            set
+        }
+        Body::Let(_, _, next) => kept_vars(next),
+        Body::Match(_, branches) => branches
+            .iter()
+            .flat_map(|(_, branch)| kept_vars(branch))
+            .collect(),
+        Body::Join(_, _, join, body) => {
+            let mut set = kept_vars(join);
+            set.extend(kept_vars(body));
+            set
+        }
+        _ => HashSet::new(),
+    }
+}
+
 fn cappy(
     mut vars: Vec<Var>,
     mut stats: Vec<Status>,
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+
+use crate::ast::ast::FID;
+use crate::ast::scoped::SimplifiedExpression;
+use crate::ast::typed::{TypedNode, TypedProgram};
+use crate::compiler::stir::{Stir, calls};
+
+fn typed_calls(expr: &TypedNode, out: &mut Vec<FID>) {
+    match &expr.expr {
+        SimplifiedExpression::FunctionCall(id, args) => {
+            out.push(id.clone());
+            args.0.iter().for_each(|arg| typed_calls(arg, out));
+        }
+        SimplifiedExpression::UTuple(args) => args.0.iter().for_each(|arg| typed_calls(arg, out)),
+        SimplifiedExpression::Match(_, cases) => {
+            cases.iter().for_each(|(_, case)| typed_calls(case, out))
+        }
+        SimplifiedExpression::LetEqualIn(_, exp, next) => {
+            typed_calls(exp, out);
+            typed_calls(next, out);
+        }
+        SimplifiedExpression::Integer(_) | SimplifiedExpression::Variable(_) => (),
+    }
+}
+
+// Everything in the call graph that can be reached from the entry points
+fn reachable<'a>(
+    graph: &'a HashMap<FID, Vec<FID>>,
+    entry_points: &'a [String],
+) -> HashSet<&'a FID> {
+    let mut seen = HashSet::new();
+    let mut stack = entry_points.iter().collect::<Vec<_>>();
+    while let Some(id) = stack.pop() {
+        if seen.insert(id) {
+            stack.extend(graph.get(id).into_iter().flatten());
+        }
+    }
+    seen
+}
+
+// Drops the functions that cannot be called from the entry points
+pub fn remove_unreachable(prog: &Stir, entry_points: &[String]) -> Stir {
+    let graph = prog
+        .iter()
+        .map(|func| {
+            let mut out = vec![];
+            calls(&func.body, &mut out);
+            (func.id.clone(), out)
+        })
+        .collect::<HashMap<_, _>>();
+    let live = reachable(&graph, entry_points);
+    prog.iter()
+        .filter(|func| live.contains(&func.id))
+        .cloned()
+        .collect()
+}
+
+// Constructors that reachable code never builds, sorted by name. Bool is left
+// out, its constructors come from comparisons
+pub fn unused_constructors(typed: &TypedProgram, entry_points: &[String]) -> Vec<FID> {
+    let graph = typed
+        .function_iter()
+        .map(|(id, _, body)| {
+            let mut out = vec![];
+            typed_calls(body, &mut out);
+            (id.clone(), out)
+        })
+        .collect::<HashMap<_, _>>();
+    let live = reachable(&graph, entry_points);
+    typed
+        .constructors
+        .iter()
+        .filter(|(id, cons)| cons.adt != "Bool" && !live.contains(id))
+        .map(|(id, _)| id.clone())
+        .sorted()
+        .collect()
+}
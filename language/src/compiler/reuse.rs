@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use crate::compiler::crux::Type;
-use crate::compiler::stir::{Body, Exp, Function, Stir, Var, next_var};
+use crate::compiler::stir::{Body, Exp, Function, Stir, Var, exp_vars, next_var};
 
 fn reuse_all_matches(body: &Body) -> Body {
     match body {
@@ -17,6 +19,13 @@ fn reuse_all_matches(body: &Body) -> Body {
             }
             Body::Match(var.clone(), new_branches)
         }
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            reuse_all_matches(join).into(),
+            reuse_all_matches(body).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(label.clone(), args.clone()),
         _ => panic!("Does not exist at this stage"),
     }
 }
@@ -33,29 +42,67 @@ fn evaluate_reuse_in_case(var: Var, len: u8, body: &Body) -> Body {
                 .collect(),
         ),
         Body::Ret(ret_var) => Body::Ret(ret_var.clone()),
+        Body::Join(label, params, join, next) if next.member(&var) => {
+            if params.contains(&var) || join.member(&var) {
+                body.clone()
+            } else {
+                Body::Join(
+                    label.clone(),
+                    params.clone(),
+                    join.clone(),
+                    evaluate_reuse_in_case(var, len, next).into(),
+                )
+            }
+        }
         Body::Let(let_var, exp, next) if exp.member(&var) || next.member(&var) => Body::Let(
             let_var.clone(),
             exp.clone(),
             evaluate_reuse_in_case(var, len, next).into(),
         ),
-        _ => {
+        // Only reset here if every path below can use the cell, otherwise a path
+        // without a fitting constructor would be left holding an unused token
+        _ if reuses_on_all_paths(len, body) => {
             let fresh = next_var();
-            let try_replace = insert_reuse(fresh.clone(), len, body);
-            if try_replace != *body {
-                Body::Let((fresh, Type::Heaped), Exp::Reset(var), try_replace.into())
-            } else {
-                try_replace
-            }
+            Body::Let(
+                (fresh.clone(), Type::Heaped),
+                Exp::Reset(var, vec![]),
+                insert_reuse(fresh, len, body).into(),
+            )
         }
+        Body::Let(let_var, exp, next) => Body::Let(
+            let_var.clone(),
+            exp.clone(),
+            evaluate_reuse_in_case(var, len, next).into(),
+        ),
+        _ => body.clone(),
+    }
+}
+
+// Every cell has the same header, so cells with as many fields are the same
+// size. Reuse does not care which type the cell belonged to
+fn fits(len: u8, vars: &[Var]) -> bool {
+    len > 0 && vars.len() == len as usize
+}
+
+fn reuses_on_all_paths(len: u8, body: &Body) -> bool {
+    match body {
+        Body::Let(_, Exp::Ctor(_, vars), _) if fits(len, vars) => true,
+        Body::Let(_, _, next) => reuses_on_all_paths(len, next),
+        Body::Match(_, branches) => branches
+            .iter()
+            .all(|(_, branch)| reuses_on_all_paths(len, branch)),
+        // Every path through the join point ends up in its body
+        Body::Join(_, _, join, _) => reuses_on_all_paths(len, join),
+        _ => false,
     }
 }
 
 fn insert_reuse(var: String, len: u8, body: &Body) -> Body {
     match body {
         Body::Let(let_var, exp, next) => match exp {
-            Exp::Ctor(tag, vars) if vars.len() as u8 == len => Body::Let(
+            Exp::Ctor(tag, vars) if fits(len, vars) => Body::Let(
                 let_var.clone(),
-                Exp::Reuse((var, Type::Heaped), *tag, vars.clone()),
+                Exp::Reuse((var, Type::Heaped), *tag, vars.clone(), vec![], false),
                 next.clone(),
             ),
             _ => Body::Let(
@@ -72,18 +119,63 @@ fn insert_reuse(var: String, len: u8, body: &Body) -> Body {
                 .map(|(cons_len, branch)| (*cons_len, insert_reuse(var.clone(), len, branch)))
                 .collect(),
         ),
+        // The token is passed on to the join body along with the other shared
+        // variables
+        Body::Join(label, params, join, body) => {
+            let token = (var.clone(), Type::Heaped);
+            let mut params = params.clone();
+            params.insert(params.len() - 1, token.clone());
+            Body::Join(
+                label.clone(),
+                params,
+                insert_reuse(var, len, join).into(),
+                pass_token(label, &token, body).into(),
+            )
+        }
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+fn pass_token(label: &String, token: &Var, body: &Body) -> Body {
+    match body {
+        Body::Jump(l, args) if l == label => {
+            let mut args = args.clone();
+            args.insert(args.len() - 1, token.clone());
+            Body::Jump(l.clone(), args)
+        }
+        Body::Jump(l, args) => Body::Jump(l.clone(), args.clone()),
+        Body::Ret(var) => Body::Ret(var.clone()),
+        Body::Let(var, exp, next) => Body::Let(
+            var.clone(),
+            exp.clone(),
+            pass_token(label, token, next).into(),
+        ),
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(cons_len, branch)| (*cons_len, pass_token(label, token, branch)))
+                .collect(),
+        ),
+        Body::Join(l, params, join, body) => Body::Join(
+            l.clone(),
+            params.clone(),
+            pass_token(label, token, join).into(),
+            pass_token(label, token, body).into(),
+        ),
         _ => panic!("Does not exist at this stage"),
     }
 }
 
-pub fn add_reuse(prog: &Stir) -> Stir {
+pub fn add_reuse(prog: &Stir, fip_only: bool) -> Stir {
     prog.iter()
         .map(|func| Function {
             fip: func.fip,
+            inline: func.inline,
             id: func.id.clone(),
             typ: func.typ.clone(),
             args: func.args.clone(),
-            body: if func.fip {
+            body: if func.fip || !fip_only {
                 reuse_all_matches(&func.body)
             } else {
                 func.body.clone()
@@ -91,3 +183,151 @@ pub fn add_reuse(prog: &Stir) -> Stir {
         })
         .collect()
 }
+
+#[derive(Default)]
+struct ReuseInfo {
+    projs: HashMap<Var, (u8, Var)>,
+    resets: HashMap<String, Var>,
+    reset_tags: HashMap<String, u8>,
+    uses: HashMap<Var, usize>,
+    reuses: Vec<(String, Vec<Var>)>,
+}
+
+// Tags tracks which constructor each matched variable is known to hold
+fn collect_reuse_info(body: &Body, tags: &HashMap<Var, u8>, info: &mut ReuseInfo) {
+    match body {
+        Body::Ret(var) => *info.uses.entry(var.clone()).or_default() += 1,
+        Body::Let(var, exp, next) => {
+            match exp {
+                Exp::Proj(i, cell) if cell != var => {
+                    info.projs.insert(var.clone(), (*i, cell.clone()));
+                }
+                Exp::Reset(cell, _) => {
+                    info.resets.insert(var.0.clone(), cell.clone());
+                    if let Some(tag) = tags.get(cell) {
+                        info.reset_tags.insert(var.0.clone(), *tag);
+                    }
+                }
+                Exp::Reuse(token, _, args, _, _) => {
+                    info.reuses.push((token.0.clone(), args.clone()))
+                }
+                _ => (),
+            }
+            for used in exp_vars(exp) {
+                *info.uses.entry(used.clone()).or_default() += 1;
+            }
+            collect_reuse_info(next, tags, info);
+        }
+        Body::Match(var, branches) => {
+            *info.uses.entry(var.clone()).or_default() += 1;
+            for (tag, (_, branch)) in branches.iter().enumerate() {
+                let mut tags = tags.clone();
+                tags.insert(var.clone(), tag as u8);
+                collect_reuse_info(branch, &tags, info);
+            }
+        }
+        Body::Join(_, _, join, body) => {
+            collect_reuse_info(join, tags, info);
+            collect_reuse_info(body, tags, info);
+        }
+        Body::Jump(_, args) => {
+            for arg in args {
+                *info.uses.entry(arg.clone()).or_default() += 1;
+            }
+        }
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+// A field can be kept when every reuse of the token writes back the projection
+// of that same field, and the projection is not used for anything else
+fn kept_fields(info: &ReuseInfo) -> HashMap<String, Vec<u8>> {
+    let mut candidates: HashMap<String, Vec<(u8, Var)>> = HashMap::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (token, args) in &info.reuses {
+        let Some(cell) = info.resets.get(token) else {
+            continue;
+        };
+        let fields = args
+            .iter()
+            .enumerate()
+            .filter(|(i, arg)| info.projs.get(*arg) == Some(&(*i as u8, cell.clone())))
+            .map(|(i, arg)| (i as u8, arg.clone()))
+            .collect::<Vec<_>>();
+        match candidates.get_mut(token) {
+            Some(prev) => prev.retain(|field| fields.contains(field)),
+            None => {
+                candidates.insert(token.clone(), fields);
+            }
+        }
+        *counts.entry(token.clone()).or_default() += 1;
+    }
+    candidates
+        .into_iter()
+        .map(|(token, fields)| {
+            let fields = fields
+                .into_iter()
+                .filter(|(_, var)| info.uses[var] == counts[&token])
+                .map(|(i, _)| i)
+                .collect();
+            (token, fields)
+        })
+        .collect()
+}
+
+fn apply_kept(body: &Body, kept: &HashMap<String, Vec<u8>>, info: &ReuseInfo) -> Body {
+    match body {
+        Body::Ret(var) => Body::Ret(var.clone()),
+        Body::Let(var, exp, next) => {
+            let exp = match exp {
+                Exp::Reset(cell, _) => {
+                    Exp::Reset(cell.clone(), kept.get(&var.0).cloned().unwrap_or_default())
+                }
+                Exp::Reuse(token, tag, args, _, _) => Exp::Reuse(
+                    token.clone(),
+                    *tag,
+                    args.clone(),
+                    kept.get(&token.0).cloned().unwrap_or_default(),
+                    info.reset_tags.get(&token.0) == Some(tag),
+                ),
+                _ => exp.clone(),
+            };
+            Body::Let(var.clone(), exp, apply_kept(next, kept, info).into())
+        }
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(cons_len, branch)| (*cons_len, apply_kept(branch, kept, info)))
+                .collect(),
+        ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            apply_kept(join, kept, info).into(),
+            apply_kept(body, kept, info).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(label.clone(), args.clone()),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+// Fields that a reuse writes back unchanged are left in place, which saves the
+// write as well as the inc of the projection and the dec of the field on reset.
+// The tag is likewise only written when it differs from the matched one
+pub fn specialize_reuse(prog: &Stir) -> Stir {
+    prog.iter()
+        .map(|func| {
+            let mut info = ReuseInfo::default();
+            collect_reuse_info(&func.body, &HashMap::new(), &mut info);
+            Function {
+                fip: func.fip,
+                inline: func.inline,
+                id: func.id.clone(),
+                typ: func.typ.clone(),
+                args: func.args.clone(),
+                body: apply_kept(&func.body, &kept_fields(&info), &info),
+            }
+        })
+        .collect()
+}
@@ -12,6 +12,7 @@ pub fn add_rc(stir: &Stir) -> Stir {
 fn insert_rc_fun(func: &Function) -> Function {
     Function {
         fip: func.fip,
+        inline: func.inline,
         id: func.id.clone(),
         typ: func.typ.clone(),
         args: func.args.clone(),
@@ -66,8 +67,10 @@ fn insert_rc_body(body: &Body, mut set: HashSet<Var>) -> Body {
                     ),
                 )
             }
-            Exp::Reset(_) => panic!("Should not be possible"),
-            Exp::Reuse(_, _, _) => panic!("Should not be possible"),
+            Exp::Reset(_, _) => panic!("Should not be possible"),
+            Exp::Reuse(_, _, _, _, _) => panic!("Should not be possible"),
+            Exp::StackCtor(_, _) => panic!("Should not be possible"),
+            Exp::Static(_) => panic!("Should not be possible"),
         },
         Body::Match(var, branches) => Body::Match(
             var.clone(),
@@ -76,8 +79,42 @@ fn insert_rc_body(body: &Body, mut set: HashSet<Var>) -> Body {
                 .map(|(i, branch)| (*i, insert_rc_body(branch, set.clone())))
                 .collect(),
         ),
+        // The join body owns whatever is passed to it, everything else owned
+        // here is dropped before the jump
+        Body::Join(label, params, join, body) => {
+            let (result, shared) = params.split_last().unwrap();
+            let mut join_set = set
+                .iter()
+                .filter(|var| shared.contains(var))
+                .cloned()
+                .collect::<HashSet<_>>();
+            join_set.insert(result.clone());
+            Body::Join(
+                label.clone(),
+                params.clone(),
+                insert_rc_body(join, join_set).into(),
+                insert_rc_body(body, set).into(),
+            )
+        }
+        Body::Jump(_, args) => {
+            let (result, shared) = args.split_last().unwrap();
+            let ret = if !set.contains(result) || shared.contains(result) {
+                add_inc(vec![result.clone()], body)
+            } else {
+                body.clone()
+            };
+            set.iter().fold(ret, |body, var| {
+                if var.1 != Type::Int && !args.contains(var) {
+                    Body::Dec(var.clone(), Box::new(body))
+                } else {
+                    body
+                }
+            })
+        }
         Body::Dec(_, _) => panic!("Should not be possible"),
         Body::Inc(_, _) => panic!("Should not be possible"),
+        Body::Drop(_, _, _, _) => panic!("Should not be possible"),
+        Body::Fill(_, _, _, _) => panic!("Should not be possible"),
     }
 }
 
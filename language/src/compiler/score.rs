@@ -1,27 +1,31 @@
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::vec;
 
 //score = Stir-to-CORE
-use crate::compiler::core::{Def, Operand, Prog, Statement, Type};
+use crate::compiler::core::{
+    Def, Extern, HEADER_WORDS, Operand, Prog, Statement, StaticCell, Type,
+};
 use crate::compiler::crux::Type as SType;
-use crate::compiler::stir::{Body, Exp, Stir};
+use crate::compiler::intrinsics::INTRINSICS;
+use crate::compiler::stir::{Body, Const, Constant, Exp, Stir, Var};
 
-fn next_var() -> String {
-    thread_local!(
-        static COUNTER: RefCell<usize> = Default::default();
-    );
-    let current = COUNTER.with_borrow_mut(|c| {
-        *c += 1;
-        *c
-    });
-    format!("match_var{}", current)
-}
-
-pub fn translate(prog: &Stir) -> Prog {
+// Calls to the externs become C calls and calls to intrinsics the program
+// does not define itself are left to the backend, the rest of the program is
+// functions of its own
+pub fn translate(prog: &Stir, externs: &[Extern]) -> Prog {
+    let intrinsics = INTRINSICS
+        .iter()
+        .map(|intrinsic| intrinsic.id.to_string())
+        .filter(|id| {
+            !prog.iter().any(|def| def.id == *id) && !externs.iter().any(|ext| ext.id == *id)
+        })
+        .collect::<HashSet<_>>();
     let mut utuples = HashSet::new();
+    let mut statics = HashMap::new();
+    let mut cells = vec![];
     for def in prog {
         utuples.extend(collect_utuples(&def.body));
+        collect_statics(&def.body, &mut statics, &mut cells);
     }
     (
         prog.iter()
@@ -33,13 +37,68 @@ pub fn translate(prog: &Stir) -> Prog {
                     .iter()
                     .map(|(var, _)| var.clone())
                     .collect::<Vec<String>>(),
-                body: translate_body(&def.body, vec![], &def.id),
+                body: translate_body(
+                    &def.body,
+                    vec![],
+                    &def.id,
+                    &collect_joins(&def.body),
+                    &statics,
+                    externs,
+                    &intrinsics,
+                ),
             })
             .collect(),
         utuples.clone(),
+        cells,
+        externs.to_vec(),
     )
 }
 
+// Numbers the cells of a static term, the ones it points to first, so that
+// identical terms share their cells
+fn static_operand(
+    term: &Const,
+    numbers: &mut HashMap<Const, usize>,
+    cells: &mut Vec<StaticCell>,
+) -> Operand {
+    match term {
+        Const::Int(i) => Operand::Int(*i),
+        Const::Cell(_, _) if numbers.contains_key(term) => Operand::Static(numbers[term]),
+        Const::Cell(tag, fields) => {
+            let fields = fields
+                .iter()
+                .map(|field| static_operand(field, numbers, cells))
+                .collect();
+            cells.push(StaticCell { tag: *tag, fields });
+            numbers.insert(term.clone(), cells.len() - 1);
+            Operand::Static(cells.len() - 1)
+        }
+    }
+}
+
+fn collect_statics(body: &Body, numbers: &mut HashMap<Const, usize>, cells: &mut Vec<StaticCell>) {
+    match body {
+        Body::Ret(_) | Body::Jump(_, _) => (),
+        Body::Let(_, exp, next) => {
+            if let Exp::Static(term) = exp {
+                static_operand(term, numbers, cells);
+            }
+            collect_statics(next, numbers, cells);
+        }
+        Body::Inc(_, next)
+        | Body::Dec(_, next)
+        | Body::Drop(_, _, _, next)
+        | Body::Fill(_, _, _, next) => collect_statics(next, numbers, cells),
+        Body::Match(_, branches) => branches
+            .iter()
+            .for_each(|(_, branch)| collect_statics(branch, numbers, cells)),
+        Body::Join(_, _, join, body) => {
+            collect_statics(join, numbers, cells);
+            collect_statics(body, numbers, cells);
+        }
+    }
+}
+
 fn collect_utuples(body: &Body) -> HashSet<u8> {
     match body {
         Body::Ret(_) => HashSet::new(),
@@ -59,6 +118,35 @@ fn collect_utuples(body: &Body) -> HashSet<u8> {
         }
         Body::Inc(_, next) => collect_utuples(next),
         Body::Dec(_, next) => collect_utuples(next),
+        Body::Drop(_, _, _, next) => collect_utuples(next),
+        Body::Fill(_, _, _, next) => collect_utuples(next),
+        Body::Join(_, _, join, body) => {
+            let mut set = collect_utuples(join);
+            set.extend(collect_utuples(body));
+            set
+        }
+        Body::Jump(_, _) => HashSet::new(),
+    }
+}
+
+fn collect_joins(body: &Body) -> HashMap<Constant, Vec<Var>> {
+    match body {
+        Body::Ret(_) | Body::Jump(_, _) => HashMap::new(),
+        Body::Let(_, _, next)
+        | Body::Inc(_, next)
+        | Body::Dec(_, next)
+        | Body::Drop(_, _, _, next)
+        | Body::Fill(_, _, _, next) => collect_joins(next),
+        Body::Match(_, branches) => branches
+            .iter()
+            .flat_map(|(_, branch)| collect_joins(branch))
+            .collect(),
+        Body::Join(label, params, join, body) => {
+            let mut map = collect_joins(join);
+            map.extend(collect_joins(body));
+            map.insert(label.clone(), params.clone());
+            map
+        }
     }
 }
 
@@ -70,7 +158,15 @@ fn from_type(typ: &SType) -> Type {
     }
 }
 
-fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<Statement> {
+fn translate_body(
+    body: &Body,
+    mut stmts: Vec<Statement>,
+    fid: &String,
+    joins: &HashMap<Constant, Vec<Var>>,
+    statics: &HashMap<Const, usize>,
+    externs: &[Extern],
+    intrinsics: &HashSet<Constant>,
+) -> Vec<Statement> {
     match body {
         Body::Ret(var) => {
             if fid == "main" {
@@ -82,6 +178,19 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
 
             stmts
         }
+        // A call whose result is returned right away can reuse the current frame
+        Body::Let(var, Exp::App(id, args), next)
+            if fid != "main"
+                && **next == Body::Ret(var.clone())
+                && !externs.iter().any(|ext| ext.id == *id)
+                && !intrinsics.contains(id) =>
+        {
+            stmts.push(Statement::TailCall(
+                id.clone(),
+                args.iter().map(|a| Operand::Ident(a.0.clone())).collect(),
+            ));
+            stmts
+        }
         Body::Let(var, exp, next) => {
             match exp {
                 Exp::Int(i) => {
@@ -91,6 +200,20 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                         Operand::Int(*i),
                     ));
                 }
+                Exp::App(id, args) if externs.iter().any(|ext| ext.id == *id) => {
+                    stmts.push(Statement::AssignExternCall(
+                        var.0.clone(),
+                        id.clone(),
+                        args.iter().map(|a| Operand::Ident(a.0.clone())).collect(),
+                    ));
+                }
+                Exp::App(id, args) if intrinsics.contains(id) => {
+                    stmts.push(Statement::AssignIntrinsic(
+                        var.0.clone(),
+                        id.clone(),
+                        args.iter().map(|a| Operand::Ident(a.0.clone())).collect(),
+                    ));
+                }
                 Exp::App(id, args) => {
                     stmts.push(Statement::AssignFunctionCall(
                         var.0.clone(),
@@ -99,7 +222,7 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                         from_type(&var.1),
                     ));
                 }
-                Exp::Ctor(tag, args) => {
+                Exp::Ctor(tag, args) | Exp::StackCtor(tag, args) => {
                     if args.is_empty() {
                         stmts.push(Statement::Assign(
                             Type::Standard,
@@ -107,35 +230,35 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                             Operand::Int(*tag as i64),
                         ))
                     } else {
-                        stmts.push(Statement::AssignMalloc(
-                            Type::VoidPtrPtr,
+                        if let Exp::StackCtor(_, _) = exp {
+                            stmts
+                                .push(Statement::AssignStackAlloc(var.0.clone(), args.len() as u8));
+                        } else {
+                            stmts.push(Statement::AssignMalloc(
+                                Type::Standard,
+                                var.0.clone(),
+                                args.len() as u8,
+                            ));
+                        }
+                        stmts.push(Statement::AssignHeader(
                             var.0.clone(),
+                            *tag,
                             args.len() as u8,
                         ));
-                        stmts.push(Statement::AssignToField(
-                            var.0.clone(),
-                            0,
-                            Operand::Int(*tag as i64),
-                        ));
-                        stmts.push(Statement::AssignToField(
-                            var.0.clone(),
-                            1,
-                            Operand::NonShifted(args.len() as i64),
-                        ));
-                        stmts.push(Statement::AssignToField(
-                            var.0.clone(),
-                            2,
-                            Operand::NonShifted(1),
-                        ));
                         for (i, arg) in args.iter().enumerate() {
                             stmts.push(Statement::AssignToField(
                                 var.0.clone(),
-                                (i + 3) as i64,
+                                i as i64 + HEADER_WORDS,
                                 Operand::Ident(arg.0.clone()),
                             ));
                         }
                     }
                 }
+                Exp::Static(term) => stmts.push(Statement::Assign(
+                    Type::Standard,
+                    var.0.clone(),
+                    Operand::Static(statics[term]),
+                )),
                 Exp::UTuple(vars) => stmts.push(Statement::AssignUTuple(
                     vars.len() as u8,
                     var.0.clone(),
@@ -161,38 +284,57 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                     } else {
                         stmts.push(Statement::AssignFromField(
                             var.0.clone(),
-                            *field as i64 + 3,
+                            *field as i64 + HEADER_WORDS,
                             Operand::Ident(projectee.0.clone()),
                         ));
                     }
                 }
-                Exp::Reset(reset_var) => stmts.push(Statement::AssignDropReuse(
+                Exp::Reset(reset_var, kept) => stmts.push(Statement::AssignDropReuse(
                     var.0.clone(),
                     reset_var.0.clone(),
+                    kept.clone(),
                 )),
-                Exp::Reuse(reuse_var, tag, args) => {
-                    let if_stmts = vec![
-                        Statement::AssignMalloc(Type::None, reuse_var.0.clone(), args.len() as u8),
-                        Statement::AssignToField(
+                Exp::Reuse(reuse_var, tag, args, kept, same_tag) => {
+                    let mut if_stmts = vec![Statement::AssignMalloc(
+                        Type::None,
+                        reuse_var.0.clone(),
+                        args.len() as u8,
+                    )];
+                    // Kept parts are already in place unless the cell is fresh
+                    if *same_tag {
+                        if_stmts.push(Statement::AssignHeader(
                             reuse_var.0.clone(),
-                            1,
-                            Operand::NonShifted(args.len() as i64),
-                        ),
-                        Statement::AssignToField(reuse_var.0.clone(), 2, Operand::NonShifted(1)),
-                    ];
+                            *tag,
+                            args.len() as u8,
+                        ));
+                    }
+                    for i in kept {
+                        if_stmts.push(Statement::AssignToField(
+                            reuse_var.0.clone(),
+                            *i as i64 + HEADER_WORDS,
+                            Operand::Ident(args[*i as usize].0.clone()),
+                        ));
+                    }
                     stmts.push(Statement::IfElse(vec![(
                         Operand::Negate(reuse_var.0.clone()),
                         if_stmts,
                     )]));
-                    stmts.push(Statement::AssignToField(
-                        reuse_var.0.clone(),
-                        0,
-                        Operand::Int(*tag as i64),
-                    ));
+                    // A reused cell is unique and has the same arity, so the
+                    // whole header can be written over
+                    if !*same_tag {
+                        stmts.push(Statement::AssignHeader(
+                            reuse_var.0.clone(),
+                            *tag,
+                            args.len() as u8,
+                        ));
+                    }
                     for (i, arg) in args.iter().enumerate() {
+                        if kept.contains(&(i as u8)) {
+                            continue;
+                        }
                         stmts.push(Statement::AssignToField(
                             reuse_var.0.clone(),
-                            (i + 3) as i64,
+                            i as i64 + HEADER_WORDS,
                             Operand::Ident(arg.0.clone()),
                         ));
                     }
@@ -203,35 +345,24 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                     ));
                 }
             }
-            translate_body(next, stmts, fid)
+            translate_body(next, stmts, fid, joins, statics, externs, intrinsics)
         }
+        // Branches are in tag order and the last one also takes anything else
         Body::Match(var, branches) => {
-            let mut new_branches = vec![];
-            let mut operands = vec![];
-            for (i, branch) in branches.iter().enumerate() {
-                if i > 0 && i == branches.len() - 1 {
-                    operands.push(Operand::NonShifted(1));
-                    break;
-                }
-                let match_var = next_var();
-                operands.push(Operand::Ident(match_var.clone()));
-                stmts.push(Statement::AssignTagCheck(
-                    match_var,
-                    branch.0 != 0,
-                    Operand::Ident(var.0.clone()),
-                    (i as i64) << 1 | 1,
-                ));
-            }
-            for (i, (_, branch)) in branches.iter().enumerate() {
-                let translated = translate_body(branch, vec![], fid);
-                new_branches.push((operands[i].clone(), translated));
-            }
-            stmts.push(Statement::IfElse(new_branches));
+            let cases = branches
+                .iter()
+                .map(|(arity, branch)| {
+                    let translated =
+                        translate_body(branch, vec![], fid, joins, statics, externs, intrinsics);
+                    (*arity != 0, translated)
+                })
+                .collect();
+            stmts.push(Statement::Switch(Operand::Ident(var.0.clone()), cases));
             stmts
         }
         Body::Inc(var, next) => {
             stmts.push(Statement::Inc(var.0.clone()));
-            translate_body(next, stmts, fid)
+            translate_body(next, stmts, fid, joins, statics, externs, intrinsics)
         }
         Body::Dec(var, next) => {
             if let SType::Unboxed(vec) = &var.1 {
@@ -240,7 +371,41 @@ fn translate_body(body: &Body, mut stmts: Vec<Statement>, fid: &String) -> Vec<S
                 stmts.push(Statement::Dec(var.0.clone()));
             }
 
-            translate_body(next, stmts, fid)
+            translate_body(next, stmts, fid, joins, statics, externs, intrinsics)
+        }
+        Body::Drop(var, decs, incs, next) => {
+            stmts.push(Statement::Drop(var.0.clone(), decs.clone(), incs.clone()));
+            translate_body(next, stmts, fid, joins, statics, externs, intrinsics)
+        }
+        Body::Fill(cell, field, var, next) => {
+            stmts.push(Statement::FillHole(
+                cell.0.clone(),
+                *field as i64 + HEADER_WORDS,
+                Operand::Ident(var.0.clone()),
+            ));
+            translate_body(next, stmts, fid, joins, statics, externs, intrinsics)
+        }
+        // Shared parameters have the names of the variables passed to them, so
+        // only the result needs a declaration and an assignment
+        Body::Join(label, params, join, body) => {
+            let (result, _) = params.split_last().unwrap();
+            stmts.push(Statement::Declare(from_type(&result.1), result.0.clone()));
+            stmts = translate_body(body, stmts, fid, joins, statics, externs, intrinsics);
+            stmts.push(Statement::Label(label.clone()));
+            translate_body(join, stmts, fid, joins, statics, externs, intrinsics)
+        }
+        Body::Jump(label, args) => {
+            for (param, arg) in joins[label].iter().zip(args) {
+                if param.0 != arg.0 {
+                    stmts.push(Statement::Assign(
+                        Type::None,
+                        param.0.clone(),
+                        Operand::Ident(arg.0.clone()),
+                    ));
+                }
+            }
+            stmts.push(Statement::Jump(label.clone()));
+            stmts
         }
     }
 }
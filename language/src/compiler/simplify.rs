@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use crate::compiler::crux::Operator;
+use crate::compiler::stir::{
+    Body, Constant, Exp, Function, Stir, Var, exp_vars, free_vars, remove_dead_bindings,
+    replace_var_body,
+};
+
+#[derive(Debug, Clone)]
+enum Known {
+    Int(i64),
+    Ctor(u8, Vec<Var>),
+    // Tag known from an enclosing match, the fields are not
+    Tag(u8),
+}
+
+fn fold(op: Operator, left: i64, right: i64) -> Option<i64> {
+    let value = match op {
+        Operator::Equal => (left == right) as i64,
+        Operator::NotEqual => (left != right) as i64,
+        Operator::Less => (left < right) as i64,
+        Operator::LessOrEq => (left <= right) as i64,
+        Operator::Greater => (left > right) as i64,
+        Operator::GreaterOrEqual => (left >= right) as i64,
+        Operator::Add => left.wrapping_add(right),
+        Operator::Sub => left.wrapping_sub(right),
+        Operator::Mul => left.wrapping_mul(right),
+        Operator::Div if right != 0 => left.wrapping_div(right),
+        Operator::Mod if right != 0 => left.wrapping_rem(right),
+        Operator::Div | Operator::Mod => return None,
+    };
+    // Ints are 63 bits at runtime
+    Some((value << 1) >> 1)
+}
+
+// Branch a match on a value known to be this takes, if it can tell
+fn select(known: &Known, branches: &[(u8, Body)]) -> Option<usize> {
+    let (tag, arity) = match known {
+        Known::Int(i) => (usize::try_from(*i).ok()?, Some(0)),
+        Known::Ctor(tag, args) => (*tag as usize, Some(args.len() as u8)),
+        Known::Tag(tag) => (*tag as usize, None),
+    };
+    let (branch_arity, _) = branches.get(tag)?;
+    arity
+        .is_none_or(|arity| arity == *branch_arity)
+        .then_some(tag)
+}
+
+// Projections out of the cell are replaced by the variables it was built from
+fn forward_projs(cell: &Var, args: &[Var], body: Body) -> Body {
+    match body {
+        Body::Let(var, Exp::Proj(i, v), next) if v == *cell => replace_var_body(
+            args[i as usize].clone(),
+            &var,
+            forward_projs(cell, args, *next),
+        ),
+        Body::Let(var, exp, next) => Body::Let(var, exp, forward_projs(cell, args, *next).into()),
+        Body::Match(var, branches) => Body::Match(
+            var,
+            branches
+                .into_iter()
+                .map(|(arity, branch)| (arity, forward_projs(cell, args, branch)))
+                .collect(),
+        ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label,
+            params,
+            forward_projs(cell, args, *join).into(),
+            forward_projs(cell, args, *body).into(),
+        ),
+        body => body,
+    }
+}
+
+fn count_jumps(label: &Constant, body: &Body) -> usize {
+    match body {
+        Body::Jump(l, _) => (l == label) as usize,
+        Body::Let(_, _, next) => count_jumps(label, next),
+        Body::Match(_, branches) => branches
+            .iter()
+            .map(|(_, branch)| count_jumps(label, branch))
+            .sum(),
+        Body::Join(_, _, join, body) => count_jumps(label, join) + count_jumps(label, body),
+        _ => 0,
+    }
+}
+
+// Replaces the jumps to the join point with its body
+fn inline_join(label: &Constant, params: &[Var], join: &Body, body: Body) -> Body {
+    match body {
+        Body::Jump(l, args) if l == *label => params
+            .iter()
+            .zip(args)
+            .filter(|(param, arg)| *param != arg)
+            .fold(join.clone(), |join, (param, arg)| {
+                replace_var_body(arg, param, join)
+            }),
+        Body::Let(var, exp, next) => {
+            Body::Let(var, exp, inline_join(label, params, join, *next).into())
+        }
+        Body::Match(var, branches) => Body::Match(
+            var,
+            branches
+                .into_iter()
+                .map(|(arity, branch)| (arity, inline_join(label, params, join, branch)))
+                .collect(),
+        ),
+        Body::Join(l, ps, inner, body) => Body::Join(
+            l,
+            ps,
+            inline_join(label, params, join, *inner).into(),
+            inline_join(label, params, join, *body).into(),
+        ),
+        body => body,
+    }
+}
+
+// Moves a cheap binding down to where it is used, into a single branch if
+// only one needs it. Cells are left where they are, moving them into fewer
+// branches could cost a reuse on the others
+fn float_in(var: Var, exp: Exp, body: Body) -> Body {
+    match body {
+        Body::Let(next_var, next_exp, next)
+            if next_var != var
+                && !exp_vars(&next_exp).contains(&&var)
+                && !exp_vars(&exp).contains(&&next_var) =>
+        {
+            Body::Let(next_var, next_exp, float_in(var, exp, *next).into())
+        }
+        Body::Match(scrutinee, branches) if scrutinee != var => {
+            let users = branches
+                .iter()
+                .filter(|(_, branch)| free_vars(branch).contains(&var))
+                .count();
+            if users == 1 {
+                Body::Match(
+                    scrutinee,
+                    branches
+                        .into_iter()
+                        .map(|(arity, branch)| {
+                            if free_vars(&branch).contains(&var) {
+                                (arity, float_in(var.clone(), exp.clone(), branch))
+                            } else {
+                                (arity, branch)
+                            }
+                        })
+                        .collect(),
+                )
+            } else {
+                Body::Let(var, exp, Body::Match(scrutinee, branches).into())
+            }
+        }
+        // The join body does not use it, or it would have been passed along
+        Body::Join(label, params, join, body) if !params.contains(&var) => {
+            Body::Join(label, params, join, float_in(var, exp, *body).into())
+        }
+        body => Body::Let(var, exp, body.into()),
+    }
+}
+
+fn simplify_body(body: &Body, known: &HashMap<Var, Known>, level: u8) -> Body {
+    match body {
+        Body::Ret(var) => Body::Ret(var.clone()),
+        Body::Let(var, exp, next) => {
+            let exp = match exp {
+                Exp::Op(op, left, right) => match (known.get(left), known.get(right)) {
+                    (Some(Known::Int(left)), Some(Known::Int(right))) => {
+                        fold(*op, *left, *right).map_or(exp.clone(), Exp::Int)
+                    }
+                    _ => exp.clone(),
+                },
+                _ => exp.clone(),
+            };
+            let mut known = known.clone();
+            known.remove(var);
+            known.retain(|_, k| !matches!(k, Known::Ctor(_, args) if args.contains(var)));
+            match &exp {
+                Exp::Int(i) => {
+                    known.insert(var.clone(), Known::Int(*i));
+                }
+                Exp::Ctor(tag, args) => {
+                    known.insert(var.clone(), Known::Ctor(*tag, args.clone()));
+                }
+                _ => (),
+            }
+            let next = simplify_body(next, &known, level);
+            match exp {
+                Exp::Int(_) | Exp::Op(_, _, _) | Exp::Ctor(_, _)
+                    if level >= 2 && !matches!(&exp, Exp::Ctor(_, args) if !args.is_empty()) =>
+                {
+                    float_in(var.clone(), exp, next)
+                }
+                exp => Body::Let(var.clone(), exp, next.into()),
+            }
+        }
+        Body::Match(var, branches) => {
+            let selected = known.get(var).and_then(|k| Some((k, select(k, branches)?)));
+            match selected {
+                Some((Known::Ctor(_, args), tag)) => simplify_body(
+                    &forward_projs(var, args, branches[tag].1.clone()),
+                    known,
+                    level,
+                ),
+                Some((_, tag)) => simplify_body(&branches[tag].1, known, level),
+                None => Body::Match(
+                    var.clone(),
+                    branches
+                        .iter()
+                        .enumerate()
+                        .map(|(tag, (arity, branch))| {
+                            let mut known = known.clone();
+                            known.insert(var.clone(), Known::Tag(tag as u8));
+                            (*arity, simplify_body(branch, &known, level))
+                        })
+                        .collect(),
+                ),
+            }
+        }
+        // A join point left with a single jump after branches were cut away is
+        // put back in place, where what is known about the arguments applies
+        Body::Join(label, params, join, next) => {
+            let next = simplify_body(next, known, level);
+            if count_jumps(label, &next) <= 1 {
+                simplify_body(&inline_join(label, params, join, next), known, level)
+            } else {
+                let join = simplify_body(join, known, level);
+                Body::Join(label.clone(), params.clone(), join.into(), next.into())
+            }
+        }
+        Body::Jump(label, args) => Body::Jump(label.clone(), args.clone()),
+        _ => panic!("Does not exist at this stage"),
+    }
+}
+
+// Constant folding and case-of-known-constructor from level 1, and from level
+// 2 also floating of cheap bindings into the branches that use them
+pub fn simplify(prog: &Stir, level: u8) -> Stir {
+    if level == 0 {
+        return prog.clone();
+    }
+    prog.iter()
+        .map(|func| Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: remove_dead_bindings(simplify_body(&func.body, &HashMap::new(), level)),
+        })
+        .collect()
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use crate::compiler::stir::{Body, Const, Exp, Function, Stir, Var, remove_dead_bindings};
+
+fn static_body(body: &Body, consts: &HashMap<Var, Const>) -> Body {
+    match body {
+        Body::Let(var, exp, next) => {
+            let term = match exp {
+                Exp::Int(i) => Some(Const::Int(*i)),
+                Exp::Ctor(tag, args) if args.is_empty() => Some(Const::Int(*tag as i64)),
+                Exp::Ctor(tag, args) => args
+                    .iter()
+                    .map(|arg| consts.get(arg).cloned())
+                    .collect::<Option<Vec<_>>>()
+                    .map(|fields| Const::Cell(*tag, fields)),
+                _ => None,
+            };
+            let mut consts = consts.clone();
+            let exp = match term {
+                Some(term @ Const::Cell(_, _)) => {
+                    consts.insert(var.clone(), term.clone());
+                    Exp::Static(term)
+                }
+                Some(term) => {
+                    consts.insert(var.clone(), term);
+                    exp.clone()
+                }
+                None => exp.clone(),
+            };
+            Body::Let(var.clone(), exp, static_body(next, &consts).into())
+        }
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(arity, branch)| (*arity, static_body(branch, consts)))
+                .collect(),
+        ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label.clone(),
+            params.clone(),
+            static_body(join, consts).into(),
+            static_body(body, consts).into(),
+        ),
+        body => body.clone(),
+    }
+}
+
+// Constructors applied to literals only are built at compile time. Their
+// cells are shared by every run of the function and have a sticky count, so
+// they are never freed and a reuse of one gets a fresh cell
+pub fn preallocate(prog: &Stir) -> Stir {
+    prog.iter()
+        .map(|func| Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: remove_dead_bindings(static_body(&func.body, &HashMap::new())),
+        })
+        .collect()
+}
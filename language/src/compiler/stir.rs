@@ -1,6 +1,8 @@
 use core::panic;
 //stir = Sequentially-Transformed-Intermediate-Representation
+use crate::ast::ast::Inline;
 use crate::compiler::crux::{Binder, Crux, Operator, Type, get_type};
+use itertools::Itertools;
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter, Result};
@@ -14,6 +16,7 @@ type Tag = u8;
 #[derive(Debug, Clone)]
 pub struct Function {
     pub fip: bool,
+    pub inline: Inline,
     pub id: Constant,
     pub typ: Type,
     pub args: Vec<Var>,
@@ -51,6 +54,18 @@ pub enum Body {
     Match(Var, Vec<(u8, Body)>),
     Inc(Var, Box<Body>),
     Dec(Var, Box<Body>),
+    // Dec of a cell with known fields, if it is unique the first fields are
+    // dec'd and the cell freed, otherwise the second fields are inc'd
+    Drop(Var, Vec<u8>, Vec<u8>, Box<Body>),
+    // Writes the value into a field of a cell built with a hole in it
+    Fill(Var, u8, Var, Box<Body>),
+    // Join point for the rest of a function after a match that is not in tail
+    // position, the first body runs when jumped to and the second right away.
+    // The last parameter receives the result of the match, the ones before it
+    // are the variables the join body shares with its surroundings, passed
+    // along under the same names
+    Join(Constant, Vec<Var>, Box<Body>, Box<Body>),
+    Jump(Constant, Vec<Var>),
 }
 
 impl Body {
@@ -60,6 +75,10 @@ impl Body {
             Body::Let(_, exp, body) => exp.member(var) || body.member(var), //Not sure if this should be like this or as below
             //Body::Let(v, exp, body) => v == var || exp.member(var) || body.member(var),
             Body::Match(_, branches) => branches.iter().any(|(_, b)| b.member(var)),
+            Body::Join(_, params, join, body) => {
+                params.contains(var) || join.member(var) || body.member(var)
+            }
+            Body::Jump(_, args) => args.contains(var),
             _ => todo!(),
         }
     }
@@ -85,6 +104,36 @@ impl Body {
             }
             Body::Inc(var, body) => format!("{}inc {};\n{}", tab, var.0, body.pretty_body(indent)),
             Body::Dec(var, body) => format!("{}dec {};\n{}", tab, var.0, body.pretty_body(indent)),
+            Body::Drop(var, decs, incs, body) => format!(
+                "{}drop {} dec {:?} inc {:?};\n{}",
+                tab,
+                var.0,
+                decs,
+                incs,
+                body.pretty_body(indent)
+            ),
+            Body::Fill(cell, field, var, body) => format!(
+                "{}fill {}[{}] = {};\n{}",
+                tab,
+                cell.0,
+                field,
+                var.0,
+                body.pretty_body(indent)
+            ),
+            Body::Join(label, params, join, body) => format!(
+                "{}join {}({}) =\n{}{}",
+                tab,
+                label,
+                params.iter().map(|(var, _)| var.clone()).join(", "),
+                join.pretty_body(indent + 4),
+                body.pretty_body(indent)
+            ),
+            Body::Jump(label, args) => format!(
+                "{}jump {}({})\n",
+                tab,
+                label,
+                args.iter().map(|(var, _)| var.clone()).join(", ")
+            ),
         }
     }
 }
@@ -103,20 +152,47 @@ pub enum Exp {
     UTuple(Vec<Var>),
     Int(i64),
     Op(Operator, Var, Var),
-    Reset(Var),
-    Reuse(Var, Tag, Vec<Var>),
+    // Reset leaves the listed fields alone if the cell is unique and incs them
+    // otherwise, reuse does not write the listed fields nor the tag if flagged
+    Reset(Var, Vec<u8>),
+    Reuse(Var, Tag, Vec<Var>, Vec<u8>, bool),
+    // A constructor whose cell stays in the frame that builds it
+    StackCtor(Tag, Vec<Var>),
+    // A constructor term known at compile time, built once and never freed
+    Static(Const),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Const {
+    Int(i64),
+    Cell(Tag, Vec<Const>),
+}
+
+impl Display for Const {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Const::Int(i) => write!(f, "{}", i),
+            Const::Cell(tag, fields) => write!(
+                f,
+                "Ctor({}, {})",
+                tag,
+                fields.iter().map(|field| field.to_string()).join(", ")
+            ),
+        }
+    }
 }
 
 impl Exp {
     pub fn member(&self, var: &Var) -> bool {
         match self {
             Exp::App(_, vars) => vars.iter().any(|v| v == var),
-            Exp::Ctor(_, vars) => vars.iter().any(|v| v == var),
+            Exp::Ctor(_, vars) | Exp::StackCtor(_, vars) => vars.iter().any(|v| v == var),
             Exp::Proj(_, v) => v == var,
             Exp::Op(_, v1, v2) => v1 == var || v2 == var,
             Exp::Int(_) => false,
-            Exp::Reset(_) => false,
-            Exp::Reuse(_, _, _) => false,
+            Exp::Reset(_, _) => false,
+            Exp::Reuse(_, _, _, _, _) => false,
+            Exp::Static(_) => false,
             Exp::UTuple(vars) => vars.iter().any(|v| v == var),
         }
     }
@@ -138,9 +214,14 @@ impl Display for Exp {
                         .join(", ")
                 }
             ),
-            Exp::Ctor(tag, args) => write!(
+            Exp::Ctor(tag, args) | Exp::StackCtor(tag, args) => write!(
                 f,
-                "Ctor({}, {})",
+                "{}({}, {})",
+                if matches!(self, Exp::Ctor(_, _)) {
+                    "Ctor"
+                } else {
+                    "StackCtor"
+                },
                 tag,
                 if args.is_empty() {
                     "[]".to_string()
@@ -154,10 +235,12 @@ impl Display for Exp {
             Exp::Proj(tag, var) => write!(f, "Proj({}, {})", tag, var.0),
             Exp::Int(i) => write!(f, "{}", i),
             Exp::Op(op, var1, var2) => write!(f, "{} {} {}", var1.0, op, var2.0),
-            Exp::Reset(var) => write!(f, "reset {}", var.0),
-            Exp::Reuse(var, tag, args) => write!(
+            Exp::Static(term) => write!(f, "static {}", term),
+            Exp::Reset(var, kept) if kept.is_empty() => write!(f, "reset {}", var.0),
+            Exp::Reset(var, kept) => write!(f, "reset {} keep {:?}", var.0, kept),
+            Exp::Reuse(var, tag, args, kept, same_tag) => write!(
                 f,
-                "reuse {} in Ctor({}, {})",
+                "reuse {} in Ctor({}, {}){}",
                 var.0,
                 tag,
                 if args.is_empty() {
@@ -167,6 +250,18 @@ impl Display for Exp {
                         .map(|x| x.0.to_string())
                         .collect::<Vec<String>>()
                         .join(", ")
+                },
+                {
+                    let kept = same_tag
+                        .then(|| "tag".to_string())
+                        .into_iter()
+                        .chain(kept.iter().map(|i| i.to_string()))
+                        .collect::<Vec<String>>();
+                    if kept.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" keep [{}]", kept.join(", "))
+                    }
                 }
             ),
             Exp::UTuple(vars) => write!(
@@ -196,6 +291,17 @@ pub fn next_var() -> String {
     format!("v{}", current)
 }
 
+pub fn next_label() -> String {
+    thread_local!(
+        static COUNTER: RefCell<usize> = Default::default();
+    );
+    let current = COUNTER.with_borrow_mut(|c| {
+        *c += 1;
+        *c
+    });
+    format!("join{}", current)
+}
+
 pub fn reset_var_counter() {
     thread_local!(
         static COUNTER: RefCell<usize> = Default::default();
@@ -247,28 +353,60 @@ pub fn from_simple(expr: &Crux, k: &dyn Fn(Var) -> Body) -> Body {
             let binding = (fresh, typ.clone());
             Body::Let(binding.clone(), Exp::UTuple(vars), k(binding).into())
         }),
-        Crux::Match(expr, branches, _) => {
+        Crux::Match(expr, branches, typ) => {
             let mut branches = branches.clone();
             branches.sort_by_key(|((tag, _), _)| *tag);
             from_simple(expr, &move |var| {
+                // Unless the rest of the function is just a return or a jump it is
+                // put in a join point, so that it is not copied into every branch
+                let result = (next_var(), typ.clone());
+                let rest = k(result.clone());
+                let mut captured = free_vars(&rest)
+                    .into_iter()
+                    .filter(|v| *v != result)
+                    .collect::<Vec<_>>();
+                captured.sort_by(|a, b| a.0.cmp(&b.0));
+                let label = next_label();
+                let jump = |v: Var| {
+                    Body::Jump(label.clone(), captured.iter().cloned().chain([v]).collect())
+                };
+                let tail = matches!(rest, Body::Ret(_) | Body::Jump(_, _));
                 let mut new_bodies: Vec<(u8, Body)> = vec![];
                 for ((_, binders), expr) in &branches {
-                    let mut body = from_simple(expr, k);
+                    let mut body = if tail {
+                        from_simple(expr, k)
+                    } else {
+                        from_simple(expr, &jump)
+                    };
                     for i in (0..binders.len()).rev() {
                         match &binders[i] {
                             Binder::Variable(binder, t) => {
-                                body = Body::Let(
-                                    (binder.clone(), t.clone()),
-                                    Exp::Proj(i as u8, var.clone()),
-                                    body.into(),
-                                );
+                                let mut binder = (binder.clone(), t.clone());
+                                // A binder shadowing a variable passed to the join
+                                // point would be passed in its place
+                                if !tail && captured.contains(&binder) {
+                                    let fresh = (next_var(), t.clone());
+                                    body = replace_var_body(fresh.clone(), &binder, body);
+                                    binder = fresh;
+                                }
+                                body =
+                                    Body::Let(binder, Exp::Proj(i as u8, var.clone()), body.into());
                             }
                             Binder::Wildcard => (),
                         }
                     }
                     new_bodies.push((binders.len() as u8, body));
                 }
-                Body::Match(var, new_bodies)
+                if tail {
+                    Body::Match(var, new_bodies)
+                } else {
+                    Body::Join(
+                        label,
+                        captured.into_iter().chain([result]).collect(),
+                        rest.into(),
+                        Body::Match(var, new_bodies).into(),
+                    )
+                }
             })
         }
         Crux::Let(var, exp, next, _) => from_simple(exp, &move |var1| {
@@ -316,7 +454,7 @@ fn translate_list(exprs: Vec<Crux>, k: &dyn Fn(Vec<Var>) -> Body) -> Body {
     }
 }
 
-fn replace_var_body(replacing: Var, replacee: &Var, body: Body) -> Body {
+pub fn replace_var_body(replacing: Var, replacee: &Var, body: Body) -> Body {
     match body {
         Body::Ret(var) => Body::Ret(replace_var(var, replacing.clone(), replacee)),
         Body::Let(var, exp, next) => Body::Let(
@@ -336,8 +474,25 @@ fn replace_var_body(replacing: Var, replacee: &Var, body: Body) -> Body {
                 })
                 .collect(),
         ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label,
+            params
+                .into_iter()
+                .map(|param| replace_var(param, replacing.clone(), replacee))
+                .collect(),
+            replace_var_body(replacing.clone(), replacee, *join).into(),
+            replace_var_body(replacing, replacee, *body).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(
+            label,
+            args.into_iter()
+                .map(|arg| replace_var(arg, replacing.clone(), replacee))
+                .collect(),
+        ),
         Body::Inc(_, _) => panic!("Should not exist at this stage"),
         Body::Dec(_, _) => panic!("Should not exist at this stage"),
+        Body::Drop(_, _, _, _) => panic!("Should not exist at this stage"),
+        Body::Fill(_, _, _, _) => panic!("Should not exist at this stage"),
     }
 }
 
@@ -367,8 +522,10 @@ fn replace_var_exp(replacing: Var, replacee: &Var, exp: Exp) -> Exp {
                 .map(|arg| replace_var(arg.clone(), replacing.clone(), replacee))
                 .collect(),
         ),
-        Exp::Reset(_) => panic!("Should not exist at this stage"),
-        Exp::Reuse(_, _, _) => panic!("Should not exist at this stage"),
+        Exp::Reset(_, _) => panic!("Should not exist at this stage"),
+        Exp::Reuse(_, _, _, _, _) => panic!("Should not exist at this stage"),
+        Exp::StackCtor(_, _) => panic!("Should not exist at this stage"),
+        Exp::Static(_) => panic!("Should not exist at this stage"),
     }
 }
 
@@ -379,11 +536,13 @@ fn replace_var(var: Var, replacing: Var, replacee: &Var) -> Var {
 pub fn remove_dead_bindings(body: Body) -> Body {
     match body {
         Body::Ret(var) => Body::Ret(var),
+        // Bottom up, so that bindings only used by dead ones go too
         Body::Let(var, exp, next) => {
+            let next = remove_dead_bindings(*next);
             if free_vars(&next).contains(&var) {
-                Body::Let(var, exp, remove_dead_bindings(*next).into())
+                Body::Let(var, exp, next.into())
             } else {
-                remove_dead_bindings(*next)
+                next
             }
         }
         Body::Match(var, branches) => Body::Match(
@@ -393,8 +552,17 @@ pub fn remove_dead_bindings(body: Body) -> Body {
                 .map(|(cons_len, branch)| (*cons_len, remove_dead_bindings(branch.clone())))
                 .collect(),
         ),
+        Body::Join(label, params, join, body) => Body::Join(
+            label,
+            params,
+            remove_dead_bindings(*join).into(),
+            remove_dead_bindings(*body).into(),
+        ),
+        Body::Jump(label, args) => Body::Jump(label, args),
         Body::Inc(_, _) => panic!("Should not exist at this stage"),
         Body::Dec(_, _) => panic!("Should not exist at this stage"),
+        Body::Drop(_, _, _, _) => panic!("Should not exist at this stage"),
+        Body::Fill(_, _, _, _) => panic!("Should not exist at this stage"),
     }
 }
 
@@ -409,7 +577,7 @@ fn free_vars_exp(exp: &Exp, bound: &HashSet<Var>) -> HashSet<Var> {
             }
             set
         }
-        Exp::Ctor(_, args) => {
+        Exp::Ctor(_, args) | Exp::StackCtor(_, args) => {
             let mut set = HashSet::new();
             for arg in args {
                 if !bound.contains(arg) {
@@ -435,14 +603,14 @@ fn free_vars_exp(exp: &Exp, bound: &HashSet<Var>) -> HashSet<Var> {
             }
             set
         }
-        Exp::Reset(var) => {
+        Exp::Reset(var, _) => {
             let mut set = HashSet::new();
             if !bound.contains(var) {
                 set.insert(var.clone());
             }
             set
         }
-        Exp::Reuse(var, _, args) => {
+        Exp::Reuse(var, _, args, _, _) => {
             let mut set = HashSet::new();
             if !bound.contains(var) {
                 set.insert(var.clone());
@@ -454,7 +622,7 @@ fn free_vars_exp(exp: &Exp, bound: &HashSet<Var>) -> HashSet<Var> {
             }
             set
         }
-        Exp::Int(_) => HashSet::new(),
+        Exp::Int(_) | Exp::Static(_) => HashSet::new(),
         Exp::UTuple(args) => {
             let mut set = HashSet::new();
             for arg in args {
@@ -500,13 +668,65 @@ fn free_vars_helper(body: &Body, mut bound: HashSet<Var>) -> HashSet<Var> {
             }
             set
         }
-        Body::Dec(var, next) => {
+        Body::Dec(var, next) | Body::Drop(var, _, _, next) => {
             let mut set = free_vars_helper(next, bound.clone());
             if !bound.contains(var) {
                 set.insert(var.clone());
             }
             set
         }
+        Body::Join(_, params, join, body) => {
+            let mut join_bound = bound.clone();
+            join_bound.extend(params.iter().cloned());
+            let mut set = free_vars_helper(join, join_bound);
+            set.extend(free_vars_helper(body, bound));
+            set
+        }
+        Body::Jump(_, args) => args
+            .iter()
+            .filter(|arg| !bound.contains(arg))
+            .cloned()
+            .collect(),
+        Body::Fill(cell, _, var, next) => {
+            let mut set = free_vars_helper(next, bound.clone());
+            for v in [cell, var] {
+                if !bound.contains(v) {
+                    set.insert(v.clone());
+                }
+            }
+            set
+        }
+    }
+}
+
+// Every occurrence of a variable in the expression, including repeats
+pub fn exp_vars(exp: &Exp) -> Vec<&Var> {
+    match exp {
+        Exp::App(_, args) | Exp::Ctor(_, args) | Exp::StackCtor(_, args) | Exp::UTuple(args) => {
+            args.iter().collect()
+        }
+        Exp::Reuse(var, _, args, _, _) => std::iter::once(var).chain(args).collect(),
+        Exp::Proj(_, var) | Exp::Reset(var, _) => vec![var],
+        Exp::Op(_, left, right) => vec![left, right],
+        Exp::Int(_) | Exp::Static(_) => vec![],
+    }
+}
+
+// The functions the body calls, in order and with repeats
+pub fn calls(body: &Body, out: &mut Vec<Constant>) {
+    match body {
+        Body::Let(_, exp, next) => {
+            if let Exp::App(id, _) = exp {
+                out.push(id.clone());
+            }
+            calls(next, out);
+        }
+        Body::Match(_, branches) => branches.iter().for_each(|(_, b)| calls(b, out)),
+        Body::Join(_, _, join, body) => {
+            calls(join, out);
+            calls(body, out);
+        }
+        _ => (),
     }
 }
 
@@ -0,0 +1,190 @@
+use itertools::Itertools;
+
+use crate::compiler::crux::Type;
+use crate::compiler::stir::{Body, Exp, Function, Stir, Var, exp_vars, next_var};
+
+// Field of the constructor built and returned by the body that the variable
+// ends up in, looking past work that could just as well happen before the
+// variable is bound
+pub fn hole_index(var: &Var, body: &Body) -> Option<u8> {
+    match body {
+        Body::Let(cell, exp, next) if **next == Body::Ret(cell.clone()) => {
+            let (args, kept) = match exp {
+                Exp::Ctor(_, args) => (args, &vec![]),
+                Exp::Reuse(_, _, args, kept, _) => (args, kept),
+                _ => return None,
+            };
+            let mut holes = args.iter().positions(|arg| arg == var);
+            match (holes.next(), holes.next()) {
+                (Some(i), None) if !kept.contains(&(i as u8)) => Some(i as u8),
+                _ => None,
+            }
+        }
+        Body::Let(x, exp @ (Exp::Int(_) | Exp::Op(_, _, _) | Exp::Proj(_, _)), next)
+            if x != var && !exp_vars(exp).contains(&var) =>
+        {
+            hole_index(var, next)
+        }
+        Body::Inc(v, next) if v != var => hole_index(var, next),
+        _ => None,
+    }
+}
+
+// The field every self call in constructor context fills, if there are any
+// such calls and they agree on it
+fn function_hole(func: &Function) -> Option<u8> {
+    fn holes(id: &str, body: &Body) -> Vec<u8> {
+        match body {
+            Body::Let(var, Exp::App(fid, _), next) if fid == id => match hole_index(var, next) {
+                Some(hole) => vec![hole],
+                None => holes(id, next),
+            },
+            Body::Let(_, _, next)
+            | Body::Inc(_, next)
+            | Body::Dec(_, next)
+            | Body::Drop(_, _, _, next)
+            | Body::Fill(_, _, _, next) => holes(id, next),
+            Body::Match(_, branches) => branches
+                .iter()
+                .flat_map(|(_, branch)| holes(id, branch))
+                .collect(),
+            Body::Join(_, _, join, body) => {
+                let mut found = holes(id, join);
+                found.extend(holes(id, body));
+                found
+            }
+            Body::Ret(_) | Body::Jump(_, _) => vec![],
+        }
+    }
+    if func.id == "main" || func.typ != Type::Heaped {
+        return None;
+    }
+    let holes = holes(&func.id, &func.body);
+    let first = *holes.first()?;
+    holes.iter().all(|hole| *hole == first).then_some(first)
+}
+
+fn loop_id(id: &str) -> String {
+    format!("{}_trmc", id)
+}
+
+// Result of the whole loop and the cell whose hole is still open
+fn loop_params() -> (Var, Var) {
+    (
+        ("trmc_res".to_string(), Type::Heaped),
+        ("trmc_cell".to_string(), Type::Heaped),
+    )
+}
+
+// Builds the cell with a placeholder in the hole and hands it to the loop
+// instead of waiting on the call for the field
+fn rewrite_site(var: &Var, args: &[Var], next: &Body, id: &str, in_loop: bool) -> Body {
+    match next {
+        Body::Let(cell, exp @ (Exp::Ctor(_, _) | Exp::Reuse(_, _, _, _, _)), _) => {
+            let (res, open) = loop_params();
+            let mut loop_args = args.to_vec();
+            if in_loop {
+                loop_args.push(res);
+            } else {
+                loop_args.push(cell.clone());
+            }
+            loop_args.push(cell.clone());
+            let ret = (next_var(), Type::Heaped);
+            let call = Body::Let(
+                ret.clone(),
+                Exp::App(loop_id(id), loop_args),
+                Body::Ret(ret).into(),
+            );
+            let hole = hole_index(var, next).unwrap();
+            let call = if in_loop {
+                Body::Fill(open, hole, cell.clone(), call.into())
+            } else {
+                call
+            };
+            Body::Let(
+                (var.0.clone(), Type::Int),
+                Exp::Int(0),
+                Body::Let(cell.clone(), exp.clone(), call.into()).into(),
+            )
+        }
+        Body::Let(x, exp, next) => Body::Let(
+            x.clone(),
+            exp.clone(),
+            rewrite_site(var, args, next, id, in_loop).into(),
+        ),
+        Body::Inc(v, next) => {
+            Body::Inc(v.clone(), rewrite_site(var, args, next, id, in_loop).into())
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn rewrite_body(body: &Body, id: &str, hole: u8, in_loop: bool) -> Body {
+    let rewrite = |next: &Body| rewrite_body(next, id, hole, in_loop).into();
+    match body {
+        Body::Ret(var) if in_loop => {
+            let (res, open) = loop_params();
+            Body::Fill(open, hole, var.clone(), Body::Ret(res).into())
+        }
+        Body::Ret(var) => Body::Ret(var.clone()),
+        Body::Let(var, Exp::App(fid, args), next)
+            if fid == id && hole_index(var, next).is_some() =>
+        {
+            rewrite_site(var, args, next, id, in_loop)
+        }
+        Body::Let(var, exp, next) => Body::Let(var.clone(), exp.clone(), rewrite(next)),
+        Body::Match(var, branches) => Body::Match(
+            var.clone(),
+            branches
+                .iter()
+                .map(|(arity, branch)| (*arity, rewrite_body(branch, id, hole, in_loop)))
+                .collect(),
+        ),
+        Body::Inc(var, next) => Body::Inc(var.clone(), rewrite(next)),
+        Body::Dec(var, next) => Body::Dec(var.clone(), rewrite(next)),
+        Body::Drop(var, decs, incs, next) => {
+            Body::Drop(var.clone(), decs.clone(), incs.clone(), rewrite(next))
+        }
+        Body::Fill(cell, field, var, next) => {
+            Body::Fill(cell.clone(), *field, var.clone(), rewrite(next))
+        }
+        Body::Join(label, params, join, body) => {
+            Body::Join(label.clone(), params.clone(), rewrite(join), rewrite(body))
+        }
+        Body::Jump(label, args) => Body::Jump(label.clone(), args.clone()),
+    }
+}
+
+// Tail recursion modulo cons: a self call whose result only goes into the
+// constructor being returned is turned into a loop in destination passing
+// style. The first call builds the outermost cell and enters a second
+// function that keeps the final result and the cell whose field is still
+// missing, each iteration fills that field with the next cell. Runs after rc
+// insertion, the cells are built with the same ctor or reuse as before
+pub fn add_trmc(prog: &Stir) -> Stir {
+    let mut out = vec![];
+    for func in prog {
+        let Some(hole) = function_hole(func) else {
+            out.push(func.clone());
+            continue;
+        };
+        let (res, open) = loop_params();
+        out.push(Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: func.id.clone(),
+            typ: func.typ.clone(),
+            args: func.args.clone(),
+            body: rewrite_body(&func.body, &func.id, hole, false),
+        });
+        out.push(Function {
+            fip: func.fip,
+            inline: func.inline,
+            id: loop_id(&func.id),
+            typ: func.typ.clone(),
+            args: func.args.iter().cloned().chain([res, open]).collect(),
+            body: rewrite_body(&func.body, &func.id, hole, true),
+        });
+    }
+    out
+}
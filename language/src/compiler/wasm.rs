@@ -0,0 +1,1589 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Lowers core straight to a binary WebAssembly module, so programs run in the
+// browser without the interpreter. Cells live in linear memory laid out like
+// in the C runtime, with values as i64 and addresses below 4 GiB. Results are
+// printed through the imports print_int and print_text, and main is exported
+use crate::ast::ast;
+use crate::compiler::core::{
+    Def, HEADER_WORDS, Operand, Prog, Statement, Type, calls_self, collect_vars, header,
+    printed_adts, static_header, used_intrinsics,
+};
+use crate::compiler::crux::{Layouts, Operator, Repr};
+use crate::compiler::intrinsics::{RANDOM_INCREMENT, RANDOM_MULTIPLIER, RANDOM_SEED};
+
+// Address 0 is never a cell, since drop_reuse returns it for "no cell". The
+// free lists follow, one head per cell size in words, then the static cells
+// and the text the printers write. The frames of stack cells grow down from
+// the heap, which grows up
+const FREE_LISTS: u32 = 8;
+const MAX_WORDS: u32 = 256 + HEADER_WORDS as u32;
+const DATA_START: u32 = FREE_LISTS + 8 * MAX_WORDS;
+const STACK_BYTES: u32 = 4 * PAGE;
+const PAGE: u32 = 1 << 16;
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const EMPTY: u8 = 0x40;
+
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const BR_TABLE: u8 = 0x0e;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const SELECT: u8 = 0x1b;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const I64_LOAD: u8 = 0x29;
+const I64_STORE: u8 = 0x37;
+const MEMORY_SIZE: u8 = 0x3f;
+const MEMORY_GROW: u8 = 0x40;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQ: u8 = 0x46;
+const I32_GT_U: u8 = 0x4b;
+const I64_EQZ: u8 = 0x50;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const I64_LT_S: u8 = 0x53;
+const I64_GT_S: u8 = 0x55;
+const I64_LE_S: u8 = 0x57;
+const I64_GE_S: u8 = 0x59;
+const I32_ADD: u8 = 0x6a;
+const I32_SUB: u8 = 0x6b;
+const I32_SHL: u8 = 0x74;
+const I32_SHR_U: u8 = 0x76;
+const I64_ADD: u8 = 0x7c;
+const I64_SUB: u8 = 0x7d;
+const I64_MUL: u8 = 0x7e;
+const I64_DIV_S: u8 = 0x7f;
+const I64_REM_S: u8 = 0x81;
+const I64_REM_U: u8 = 0x82;
+const I64_AND: u8 = 0x83;
+const I64_OR: u8 = 0x84;
+const I64_SHL: u8 = 0x86;
+const I64_SHR_S: u8 = 0x87;
+const I64_SHR_U: u8 = 0x88;
+const I32_WRAP_I64: u8 = 0xa7;
+const I64_EXTEND_I32_U: u8 = 0xad;
+
+fn unsigned(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn signed(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    unsigned(bytes, name.len() as u64);
+    bytes.extend(name.as_bytes());
+}
+
+fn section(module: &mut Vec<u8>, id: u8, count: usize, content: Vec<u8>) {
+    let mut body = vec![];
+    unsigned(&mut body, count as u64);
+    body.extend(content);
+    module.push(id);
+    unsigned(module, body.len() as u64);
+    module.extend(body);
+}
+
+// The code of one function. Branches name the block they leave, and the
+// depth wasm wants is counted from the blocks still open
+struct Code {
+    bytes: Vec<u8>,
+    labels: Vec<String>,
+    next: usize,
+}
+
+impl Code {
+    fn new() -> Self {
+        Code {
+            bytes: vec![],
+            labels: vec![],
+            next: 0,
+        }
+    }
+
+    fn op(&mut self, op: u8) {
+        self.bytes.push(op);
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.next += 1;
+        format!("{}{}", kind, self.next)
+    }
+
+    fn index(&mut self, op: u8, index: u32) {
+        self.bytes.push(op);
+        unsigned(&mut self.bytes, index as u64);
+    }
+
+    fn local_get(&mut self, local: u32) {
+        self.index(LOCAL_GET, local);
+    }
+
+    fn local_set(&mut self, local: u32) {
+        self.index(LOCAL_SET, local);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.index(CALL, function);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.bytes.push(I32_CONST);
+        signed(&mut self.bytes, value as i64);
+    }
+
+    fn i64_const(&mut self, value: i64) {
+        self.bytes.push(I64_CONST);
+        signed(&mut self.bytes, value);
+    }
+
+    // The address is an i64 value on the stack
+    fn load(&mut self, offset: i64) {
+        self.bytes.extend([I32_WRAP_I64, I64_LOAD, 3]);
+        unsigned(&mut self.bytes, offset as u64);
+    }
+
+    // The address is an i32 under the i64 to store
+    fn store(&mut self, offset: i64) {
+        self.bytes.extend([I64_STORE, 3]);
+        unsigned(&mut self.bytes, offset as u64);
+    }
+
+    fn address(&mut self, local: u32) {
+        self.local_get(local);
+        self.op(I32_WRAP_I64);
+    }
+
+    fn untag(&mut self) {
+        self.i64_const(1);
+        self.op(I64_SHR_S);
+    }
+
+    fn tag(&mut self) {
+        self.i64_const(1);
+        self.op(I64_SHL);
+        self.i64_const(1);
+        self.op(I64_OR);
+    }
+
+    fn open(&mut self, op: u8, typ: u8, label: &str) {
+        self.bytes.extend([op, typ]);
+        self.labels.push(label.to_string());
+    }
+
+    fn block(&mut self, label: &str) {
+        self.open(BLOCK, EMPTY, label);
+    }
+
+    fn looping(&mut self, label: &str) {
+        self.open(LOOP, EMPTY, label);
+    }
+
+    fn if_then(&mut self, label: &str) {
+        self.open(IF, EMPTY, label);
+    }
+
+    fn else_then(&mut self) {
+        self.op(ELSE);
+    }
+
+    fn end(&mut self) {
+        self.op(END);
+        self.labels.pop();
+    }
+
+    fn depth(&self, label: &str) -> u32 {
+        let i = self.labels.iter().rposition(|l| l == label).unwrap();
+        (self.labels.len() - 1 - i) as u32
+    }
+
+    fn br(&mut self, label: &str) {
+        let depth = self.depth(label);
+        self.index(BR, depth);
+    }
+
+    fn br_if(&mut self, label: &str) {
+        let depth = self.depth(label);
+        self.index(BR_IF, depth);
+    }
+
+    fn br_table(&mut self, labels: &[String], default: &str) {
+        self.op(BR_TABLE);
+        unsigned(&mut self.bytes, labels.len() as u64);
+        for label in labels {
+            let depth = self.depth(label);
+            unsigned(&mut self.bytes, depth as u64);
+        }
+        let depth = self.depth(default);
+        unsigned(&mut self.bytes, depth as u64);
+    }
+}
+
+// Parameters and results of a function
+type Signature = (Vec<u8>, Vec<u8>);
+
+struct Function {
+    params: Vec<u8>,
+    results: Vec<u8>,
+    locals: Vec<u8>,
+    code: Code,
+}
+
+// Function indices, imports first
+struct Indices {
+    functions: HashMap<String, u32>,
+}
+
+impl Indices {
+    fn get(&self, id: &str) -> u32 {
+        self.functions[id]
+    }
+}
+
+const HEAP: u32 = 0;
+const STACK: u32 = 1;
+const RANDOM_STATE: u32 = 2;
+
+const RUNTIME: &[&str] = &[
+    "goopea_alloc_cell",
+    "goopea_free_cell",
+    "goopea_rc",
+    "goopea_ctor",
+    "goopea_inc",
+    "goopea_release",
+    "goopea_dec_onto",
+    "goopea_free_cells",
+    "goopea_dec",
+    "goopea_drop_reuse_keep",
+    "goopea_drop_reuse",
+];
+
+fn runtime_function(id: &str, at: &Indices) -> Function {
+    let mut c = Code::new();
+    let (params, results, locals): (&[u8], &[u8], &[u8]) = match id {
+        // A cell from the free list of its size, or else from the end of the
+        // heap, which grows the memory when it runs past it
+        "goopea_alloc_cell" => {
+            c.local_get(0);
+            c.op(I32_WRAP_I64);
+            c.i32_const(3);
+            c.op(I32_SHL);
+            c.i32_const(FREE_LISTS as i32);
+            c.op(I32_ADD);
+            c.local_set(1);
+            c.local_get(1);
+            c.bytes.extend([I64_LOAD, 3, 0]);
+            c.op(I32_WRAP_I64);
+            c.index(LOCAL_TEE, 2);
+            c.if_then("reused");
+            c.local_get(1);
+            c.local_get(2);
+            c.bytes.extend([I64_LOAD, 3, 0]);
+            c.store(0);
+            c.local_get(2);
+            c.op(I64_EXTEND_I32_U);
+            c.op(RETURN);
+            c.end();
+            c.index(GLOBAL_GET, HEAP);
+            c.local_set(2);
+            c.index(GLOBAL_GET, HEAP);
+            c.local_get(0);
+            c.op(I32_WRAP_I64);
+            c.i32_const(3);
+            c.op(I32_SHL);
+            c.op(I32_ADD);
+            c.index(GLOBAL_SET, HEAP);
+            c.index(GLOBAL_GET, HEAP);
+            c.bytes.extend([MEMORY_SIZE, 0]);
+            c.i32_const(16);
+            c.op(I32_SHL);
+            c.op(I32_GT_U);
+            c.if_then("grow");
+            c.index(GLOBAL_GET, HEAP);
+            c.bytes.extend([MEMORY_SIZE, 0]);
+            c.i32_const(16);
+            c.op(I32_SHL);
+            c.op(I32_SUB);
+            c.i32_const(16);
+            c.op(I32_SHR_U);
+            c.i32_const(1);
+            c.op(I32_ADD);
+            c.bytes.extend([MEMORY_GROW, 0]);
+            c.i32_const(-1);
+            c.op(I32_EQ);
+            c.if_then("full");
+            c.op(UNREACHABLE);
+            c.end();
+            c.end();
+            c.local_get(2);
+            c.op(I64_EXTEND_I32_U);
+            (&[I64], &[I64], &[I32, I32])
+        }
+        // Freed cells are chained through their first word
+        "goopea_free_cell" => {
+            c.address(0);
+            c.local_get(1);
+            c.op(I32_WRAP_I64);
+            c.i32_const(3);
+            c.op(I32_SHL);
+            c.i32_const(FREE_LISTS as i32);
+            c.op(I32_ADD);
+            c.bytes.extend([I64_LOAD, 3, 0]);
+            c.store(0);
+            c.local_get(1);
+            c.op(I32_WRAP_I64);
+            c.i32_const(3);
+            c.op(I32_SHL);
+            c.i32_const(FREE_LISTS as i32);
+            c.op(I32_ADD);
+            c.local_get(0);
+            c.store(0);
+            (&[I64, I64], &[], &[])
+        }
+        "goopea_rc" => {
+            c.local_get(0);
+            c.load(0);
+            c.i64_const(16);
+            c.op(I64_SHR_S);
+            (&[I64], &[I64], &[])
+        }
+        "goopea_ctor" => {
+            c.local_get(0);
+            c.i64_const(1);
+            c.op(I64_AND);
+            c.op(I32_WRAP_I64);
+            c.open(IF, I64, "immediate");
+            c.local_get(0);
+            c.untag();
+            c.else_then();
+            c.local_get(0);
+            c.load(0);
+            c.i64_const(0xff);
+            c.op(I64_AND);
+            c.end();
+            (&[I64], &[I64], &[])
+        }
+        "goopea_inc" => {
+            c.local_get(0);
+            c.i64_const(1);
+            c.op(I64_AND);
+            c.op(I64_EQZ);
+            c.if_then("cell");
+            c.local_get(0);
+            c.load(0);
+            c.index(LOCAL_TEE, 1);
+            c.i64_const(16);
+            c.op(I64_SHR_S);
+            c.i64_const(0);
+            c.op(I64_NE);
+            c.if_then("counted");
+            c.address(0);
+            c.local_get(1);
+            c.i64_const(1 << 16);
+            c.op(I64_ADD);
+            c.store(0);
+            c.end();
+            c.end();
+            (&[I64], &[], &[I64])
+        }
+        // Drops a reference that is not the last one, unless the cell is static
+        "goopea_release" => {
+            c.local_get(0);
+            c.load(0);
+            c.index(LOCAL_TEE, 1);
+            c.i64_const(16);
+            c.op(I64_SHR_S);
+            c.i64_const(0);
+            c.op(I64_NE);
+            c.if_then("counted");
+            c.address(0);
+            c.local_get(1);
+            c.i64_const(1 << 16);
+            c.op(I64_SUB);
+            c.store(0);
+            c.end();
+            (&[I64], &[], &[I64])
+        }
+        // Drops a reference, and if it was the last one pushes the cell on
+        // the cells still to visit, chained through last fields like in
+        // goopea_runtime.h. Locals: 2 cell, 3 its last field, 4 address of it
+        "goopea_dec_onto" => {
+            c.local_get(0);
+            c.i64_const(1);
+            c.op(I64_AND);
+            c.op(I32_WRAP_I64);
+            c.if_then("int");
+            c.local_get(1);
+            c.op(RETURN);
+            c.end();
+            c.local_get(0);
+            c.call(at.get("goopea_rc"));
+            c.i64_const(1);
+            c.op(I64_NE);
+            c.if_then("shared");
+            c.local_get(0);
+            c.call(at.get("goopea_release"));
+            c.local_get(1);
+            c.op(RETURN);
+            c.end();
+            c.local_get(0);
+            c.local_set(2);
+            c.block("chain");
+            c.looping("walk");
+            c.local_get(2);
+            c.load(0);
+            c.i64_const(8);
+            c.op(I64_SHR_S);
+            c.i64_const(0xff);
+            c.op(I64_AND);
+            c.i64_const(3);
+            c.op(I64_SHL);
+            c.local_get(2);
+            c.op(I64_ADD);
+            c.index(LOCAL_TEE, 4);
+            c.load(0);
+            c.index(LOCAL_TEE, 3);
+            c.i64_const(1);
+            c.op(I64_AND);
+            c.op(I32_WRAP_I64);
+            c.br_if("chain");
+            c.local_get(3);
+            c.call(at.get("goopea_rc"));
+            c.i64_const(1);
+            c.op(I64_EQ);
+            c.if_then("last");
+            c.local_get(3);
+            c.local_set(2);
+            c.br("walk");
+            c.end();
+            c.local_get(3);
+            c.call(at.get("goopea_release"));
+            c.end();
+            c.end();
+            c.address(4);
+            c.local_get(1);
+            c.store(0);
+            c.local_get(0);
+            (&[I64, I64], &[I64], &[I64, I64, I64])
+        }
+        // Each cell is freed before its fields, which are visited from first
+        // to last. Locals: 1 todo, 2 cell, 3 arity, 4 field index
+        "goopea_free_cells" => {
+            c.local_get(0);
+            c.i64_const(0);
+            c.call(at.get("goopea_dec_onto"));
+            c.local_set(1);
+            c.block("exit");
+            c.looping("next");
+            c.local_get(1);
+            c.op(I64_EQZ);
+            c.br_if("exit");
+            c.local_get(1);
+            c.local_set(2);
+            c.local_get(2);
+            c.load(0);
+            c.i64_const(8);
+            c.op(I64_SHR_S);
+            c.i64_const(0xff);
+            c.op(I64_AND);
+            c.local_set(3);
+            c.local_get(3);
+            c.i64_const(3);
+            c.op(I64_SHL);
+            c.local_get(2);
+            c.op(I64_ADD);
+            c.load(0);
+            c.local_set(1);
+            c.local_get(3);
+            c.i64_const(1);
+            c.op(I64_SUB);
+            c.local_set(4);
+            c.block("fields");
+            c.looping("field");
+            c.local_get(4);
+            c.i64_const(1);
+            c.op(I64_LT_S);
+            c.br_if("fields");
+            c.local_get(4);
+            c.i64_const(3);
+            c.op(I64_SHL);
+            c.local_get(2);
+            c.op(I64_ADD);
+            c.load(0);
+            c.local_get(1);
+            c.call(at.get("goopea_dec_onto"));
+            c.local_set(1);
+            c.local_get(4);
+            c.i64_const(1);
+            c.op(I64_SUB);
+            c.local_set(4);
+            c.br("field");
+            c.end();
+            c.end();
+            c.local_get(2);
+            c.local_get(3);
+            c.i64_const(HEADER_WORDS);
+            c.op(I64_ADD);
+            c.call(at.get("goopea_free_cell"));
+            c.br("next");
+            c.end();
+            c.end();
+            (&[I64], &[], &[I64, I64, I64, I64])
+        }
+        "goopea_dec" => {
+            c.local_get(0);
+            c.i64_const(1);
+            c.op(I64_AND);
+            c.op(I64_EQZ);
+            c.if_then("cell");
+            c.local_get(0);
+            c.call(at.get("goopea_rc"));
+            c.i64_const(1);
+            c.op(I64_EQ);
+            c.if_then("last");
+            c.local_get(0);
+            c.call(at.get("goopea_free_cells"));
+            c.else_then();
+            c.local_get(0);
+            c.call(at.get("goopea_release"));
+            c.end();
+            c.end();
+            (&[I64], &[], &[])
+        }
+        // The cell to reuse if the reference was the last one, otherwise 0.
+        // The fields in the keep mask stay in the cell. Locals: 2 arity,
+        // 3 field index
+        "goopea_drop_reuse_keep" => {
+            c.local_get(0);
+            c.load(0);
+            c.i64_const(8);
+            c.op(I64_SHR_S);
+            c.i64_const(0xff);
+            c.op(I64_AND);
+            c.local_set(2);
+            c.local_get(0);
+            c.call(at.get("goopea_rc"));
+            c.i64_const(1);
+            c.op(I64_EQ);
+            c.if_then("last");
+            kept_fields(&mut c, at, false);
+            c.local_get(0);
+            c.op(RETURN);
+            c.end();
+            kept_fields(&mut c, at, true);
+            c.local_get(0);
+            c.call(at.get("goopea_release"));
+            c.i64_const(0);
+            (&[I64, I64], &[I64], &[I64, I64])
+        }
+        "goopea_drop_reuse" => {
+            c.local_get(0);
+            c.i64_const(0);
+            c.call(at.get("goopea_drop_reuse_keep"));
+            (&[I64], &[I64], &[])
+        }
+        _ => unreachable!(),
+    };
+    Function {
+        params: params.to_vec(),
+        results: results.to_vec(),
+        locals: locals.to_vec(),
+        code: c,
+    }
+}
+
+// Decs the fields that are not kept of a cell about to be reused, or incs
+// the kept ones of a cell that is not
+fn kept_fields(c: &mut Code, at: &Indices, kept: bool) {
+    c.block("fields");
+    c.looping("field");
+    c.local_get(3);
+    c.local_get(2);
+    c.op(I64_GE_S);
+    c.br_if("fields");
+    c.local_get(3);
+    c.i64_const(1);
+    c.op(I64_ADD);
+    c.local_set(3);
+    c.local_get(1);
+    c.local_get(3);
+    c.i64_const(1);
+    c.op(I64_SUB);
+    c.op(I64_SHR_S);
+    c.i64_const(1);
+    c.op(I64_AND);
+    if !kept {
+        c.op(I64_EQZ);
+    } else {
+        c.op(I32_WRAP_I64);
+    }
+    c.if_then("visit");
+    c.local_get(0);
+    c.local_get(3);
+    c.i64_const(3);
+    c.op(I64_SHL);
+    c.op(I64_ADD);
+    c.load(0);
+    c.call(at.get(if kept { "goopea_inc" } else { "goopea_dec" }));
+    c.end();
+    c.br("field");
+    c.end();
+    c.end();
+}
+
+// The intrinsics in wasm, on tagged values like their C versions
+fn intrinsic_function(id: &str, at: &Indices) -> Function {
+    let mut c = Code::new();
+    let (params, locals): (&[u8], &[u8]) = match id {
+        "abs" => {
+            c.local_get(0);
+            c.untag();
+            c.local_set(1);
+            c.i64_const(0);
+            c.local_get(1);
+            c.op(I64_SUB);
+            c.local_get(1);
+            c.local_get(1);
+            c.i64_const(0);
+            c.op(I64_LT_S);
+            c.op(SELECT);
+            c.tag();
+            (&[I64], &[I64])
+        }
+        "min" | "max" => {
+            c.local_get(0);
+            c.local_get(1);
+            c.local_get(0);
+            c.local_get(1);
+            c.op(if id == "min" { I64_LT_S } else { I64_GT_S });
+            c.op(SELECT);
+            (&[I64, I64], &[])
+        }
+        "random" => {
+            c.index(GLOBAL_GET, RANDOM_STATE);
+            c.i64_const(RANDOM_MULTIPLIER as i64);
+            c.op(I64_MUL);
+            c.i64_const(RANDOM_INCREMENT as i64);
+            c.op(I64_ADD);
+            c.index(GLOBAL_SET, RANDOM_STATE);
+            c.local_get(0);
+            c.untag();
+            c.index(LOCAL_TEE, 1);
+            c.i64_const(0);
+            c.op(I64_GT_S);
+            c.open(IF, I64, "bounded");
+            c.index(GLOBAL_GET, RANDOM_STATE);
+            c.i64_const(33);
+            c.op(I64_SHR_U);
+            c.local_get(1);
+            c.op(I64_REM_U);
+            c.tag();
+            c.else_then();
+            c.i64_const(1);
+            c.end();
+            (&[I64], &[I64])
+        }
+        // The host prints the untagged value
+        "trace" => {
+            c.local_get(0);
+            c.untag();
+            c.call(at.get("trace"));
+            c.local_get(0);
+            (&[I64], &[])
+        }
+        _ => unreachable!(),
+    };
+    Function {
+        params: params.to_vec(),
+        results: vec![I64],
+        locals: locals.to_vec(),
+        code: c,
+    }
+}
+
+// Where the static cells go, followed by the text of the printers
+fn static_addresses(prog: &Prog) -> (Vec<u32>, u32) {
+    let mut addresses = vec![];
+    let mut next = DATA_START;
+    for cell in &prog.2 {
+        addresses.push(next);
+        next += 8 * (cell.fields.len() as u32 + HEADER_WORDS as u32);
+    }
+    (addresses, next)
+}
+
+fn var_size(typ: &Type) -> u32 {
+    match typ {
+        Type::Value(size) => *size as u32,
+        Type::Standard | Type::None => 1,
+    }
+}
+
+// The first local of every variable, tuples take one per element
+struct Frame<'a> {
+    def: &'a Def,
+    vars: BTreeMap<String, Type>,
+    locals: HashMap<String, u32>,
+    cells: BTreeMap<String, u32>,
+    frame_bytes: u32,
+    frame: u32,
+    count: u32,
+}
+
+struct Emitter<'a> {
+    at: &'a Indices,
+    statics: &'a [u32],
+    code: Code,
+}
+
+impl<'a> Emitter<'a> {
+    fn get(&mut self, frame: &Frame, var: &str) {
+        let first = frame.locals[var];
+        for i in 0..var_size(&frame.vars[var]) {
+            self.code.local_get(first + i);
+        }
+    }
+
+    fn set(&mut self, frame: &Frame, var: &str) {
+        let first = frame.locals[var];
+        for i in (0..var_size(&frame.vars[var])).rev() {
+            self.code.local_set(first + i);
+        }
+    }
+
+    fn operand(&mut self, frame: &Frame, op: &Operand) {
+        match op {
+            Operand::Ident(var) => self.get(frame, var),
+            Operand::Int(i) => self.code.i64_const(i << 1 | 1),
+            Operand::NonShifted(i) => self.code.i64_const(*i),
+            Operand::Negate(var) => {
+                self.get(frame, var);
+                self.code.op(I64_EQZ);
+                self.code.op(I64_EXTEND_I32_U);
+            }
+            Operand::Static(i) => self.code.i64_const(self.statics[*i] as i64),
+        }
+    }
+
+    fn condition(&mut self, frame: &Frame, op: &Operand) {
+        match op {
+            Operand::Negate(var) => {
+                self.get(frame, var);
+                self.code.op(I64_EQZ);
+            }
+            op => {
+                self.operand(frame, op);
+                self.code.i64_const(0);
+                self.code.op(I64_NE);
+            }
+        }
+    }
+
+    fn field(&mut self, frame: &Frame, var: &str, index: i64) {
+        self.get(frame, var);
+        self.code.load(index * 8);
+    }
+
+    // Stack cells of the function are given back before it returns
+    fn leave(&mut self, frame: &Frame) {
+        if frame.frame_bytes > 0 {
+            self.code.local_get(frame.frame);
+            self.code.i32_const(frame.frame_bytes as i32);
+            self.code.op(I32_ADD);
+            self.code.index(GLOBAL_SET, STACK);
+        }
+    }
+
+    // Statements up to a join point are wrapped in a block, which the jumps
+    // to it leave
+    fn statements(&mut self, frame: &Frame, stmts: &[Statement]) {
+        match stmts
+            .iter()
+            .position(|stmt| matches!(stmt, Statement::Label(_)))
+        {
+            Some(i) => {
+                let Statement::Label(label) = &stmts[i] else {
+                    unreachable!()
+                };
+                self.code.block(&format!("j.{}", label));
+                self.statements(frame, &stmts[..i]);
+                self.code.end();
+                self.statements(frame, &stmts[i + 1..]);
+            }
+            None => stmts.iter().for_each(|stmt| self.statement(frame, stmt)),
+        }
+    }
+
+    fn statement(&mut self, frame: &Frame, stmt: &Statement) {
+        let def = frame.def;
+        match stmt {
+            Statement::Assign(_, var, op) => {
+                self.operand(frame, op);
+                self.set(frame, var);
+            }
+            Statement::AssignToField(var, index, op) | Statement::FillHole(var, index, op) => {
+                self.code.address(frame.locals[var]);
+                self.operand(frame, op);
+                self.code.store(index * 8);
+            }
+            Statement::AssignHeader(var, tag, arity) => {
+                self.code.address(frame.locals[var]);
+                self.code.i64_const(header(*tag, *arity));
+                self.code.store(0);
+            }
+            Statement::Declare(_, _) | Statement::Label(_) => (),
+            Statement::Jump(label) => self.code.br(&format!("j.{}", label)),
+            Statement::AssignFromField(var, index, op) => {
+                self.operand(frame, op);
+                self.code.load(index * 8);
+                self.set(frame, var);
+            }
+            Statement::AssignMalloc(_, var, size) => {
+                self.code.i64_const(*size as i64 + HEADER_WORDS);
+                self.code.call(self.at.get("goopea_alloc_cell"));
+                self.set(frame, var);
+            }
+            // The cell is in the frame of the function, it is never freed nor
+            // counted
+            Statement::AssignStackAlloc(var, _) => {
+                self.code.local_get(frame.frame);
+                self.code.i32_const(frame.cells[var] as i32);
+                self.code.op(I32_ADD);
+                self.code.op(I64_EXTEND_I32_U);
+                self.set(frame, var);
+            }
+            // Like the else chain of the C backend, the last of several
+            // branches is taken without looking at its condition
+            Statement::IfElse(branches) => {
+                let mut open = 0;
+                for (i, (cond, stmts)) in branches.iter().enumerate() {
+                    if i > 0 {
+                        self.code.else_then();
+                    }
+                    if i > 0 && i == branches.len() - 1 {
+                        self.statements(frame, stmts);
+                        break;
+                    }
+                    self.condition(frame, cond);
+                    let label = self.code.label("then");
+                    self.code.if_then(&label);
+                    open += 1;
+                    self.statements(frame, stmts);
+                }
+                for _ in 0..open {
+                    self.code.end();
+                }
+            }
+            Statement::Switch(_, cases) if cases.len() == 1 => self.statements(frame, &cases[0].1),
+            // A block per case, the innermost one holds the table jumping out
+            // of the block whose end the case's statements follow
+            Statement::Switch(op, cases) => {
+                let end = self.code.label("end");
+                self.code.block(&end);
+                let labels = cases
+                    .iter()
+                    .map(|_| self.code.label("case"))
+                    .collect::<Vec<_>>();
+                for label in labels.iter().rev() {
+                    self.code.block(label);
+                }
+                self.operand(frame, op);
+                if cases.iter().all(|(boxed, _)| *boxed) {
+                    self.code.load(0);
+                    self.code.i64_const(0xff);
+                    self.code.op(I64_AND);
+                } else if cases.iter().all(|(boxed, _)| !*boxed) {
+                    self.code.untag();
+                } else {
+                    self.code.call(self.at.get("goopea_ctor"));
+                }
+                self.code.op(I32_WRAP_I64);
+                let (default, rest) = labels.split_last().unwrap();
+                self.code.br_table(rest, default);
+                for (_, stmts) in cases {
+                    self.code.end();
+                    self.statements(frame, stmts);
+                    self.code.br(&end);
+                }
+                self.code.end();
+            }
+            Statement::Return(_) if def.id == "main" => {
+                self.leave(frame);
+                self.code.op(RETURN);
+            }
+            Statement::Return(op) => {
+                self.operand(frame, op);
+                self.leave(frame);
+                self.code.op(RETURN);
+            }
+            Statement::Print(op) => {
+                self.operand(frame, op);
+                self.code.call(self.at.get("print_result"));
+            }
+            Statement::AssignBinaryOperation(var, op, left, right) => {
+                match op {
+                    Operator::Add => {
+                        self.operand(frame, left);
+                        self.operand(frame, right);
+                        self.code.op(I64_ADD);
+                        self.code.i64_const(1);
+                        self.code.op(I64_SUB);
+                    }
+                    Operator::Sub => {
+                        self.operand(frame, left);
+                        self.operand(frame, right);
+                        self.code.op(I64_SUB);
+                        self.code.i64_const(1);
+                        self.code.op(I64_OR);
+                    }
+                    Operator::Mul => {
+                        self.operand(frame, left);
+                        self.code.i64_const(1);
+                        self.code.op(I64_SUB);
+                        self.operand(frame, right);
+                        self.code.untag();
+                        self.code.op(I64_MUL);
+                        self.code.i64_const(1);
+                        self.code.op(I64_OR);
+                    }
+                    // Signed division truncates like the interpreter does
+                    Operator::Div | Operator::Mod => {
+                        self.operand(frame, left);
+                        self.code.untag();
+                        self.operand(frame, right);
+                        self.code.untag();
+                        self.code.op(match op {
+                            Operator::Div => I64_DIV_S,
+                            _ => I64_REM_S,
+                        });
+                        self.code.tag();
+                    }
+                    op => {
+                        self.operand(frame, left);
+                        self.operand(frame, right);
+                        self.code.op(match op {
+                            Operator::Equal => I64_EQ,
+                            Operator::NotEqual => I64_NE,
+                            Operator::Less => I64_LT_S,
+                            Operator::LessOrEq => I64_LE_S,
+                            Operator::Greater => I64_GT_S,
+                            _ => I64_GE_S,
+                        });
+                        self.code.op(I64_EXTEND_I32_U);
+                        self.code.tag();
+                    }
+                }
+                self.set(frame, var);
+            }
+            Statement::AssignFunctionCall(var, fun, ops, _) => {
+                ops.iter().for_each(|op| self.operand(frame, op));
+                self.code.call(self.at.get(fun));
+                self.set(frame, var);
+            }
+            // Ints are untagged on the way to the host and tagged again on the
+            // way back
+            Statement::AssignExternCall(var, fun, ops) => {
+                for op in ops {
+                    self.operand(frame, op);
+                    self.code.untag();
+                }
+                self.code.call(self.at.get(fun));
+                self.code.tag();
+                self.set(frame, var);
+            }
+            Statement::AssignIntrinsic(var, id, ops) => {
+                ops.iter().for_each(|op| self.operand(frame, op));
+                self.code.call(self.at.get(&format!("goopea_{}", id)));
+                self.set(frame, var);
+            }
+            // Arguments are all on the stack before any parameter is written
+            Statement::TailCall(fun, ops) if *fun == def.id => {
+                ops.iter().for_each(|op| self.operand(frame, op));
+                for i in (0..def.args.len()).rev() {
+                    self.code.local_set(i as u32);
+                }
+                self.code.br("tailcall");
+            }
+            Statement::TailCall(fun, ops) => {
+                ops.iter().for_each(|op| self.operand(frame, op));
+                self.code.call(self.at.get(fun));
+                self.leave(frame);
+                self.code.op(RETURN);
+            }
+            Statement::AssignDropReuse(var, reset_var, kept) => {
+                self.get(frame, reset_var);
+                if kept.is_empty() {
+                    self.code.call(self.at.get("goopea_drop_reuse"));
+                } else {
+                    let mask = kept.iter().fold(0i64, |mask, i| mask | (1 << i));
+                    self.code.i64_const(mask);
+                    self.code.call(self.at.get("goopea_drop_reuse_keep"));
+                }
+                self.set(frame, var);
+            }
+            Statement::AssignUTuple(_, var, args) => {
+                args.iter().for_each(|arg| self.get(frame, arg));
+                self.set(frame, var);
+            }
+            Statement::AssignUTupleField(var, i, op) => {
+                let Operand::Ident(tuple) = op else {
+                    panic!("projection of {:?}", op)
+                };
+                self.code.local_get(frame.locals[tuple] + *i as u32);
+                self.set(frame, var);
+            }
+            Statement::Inc(var) => {
+                self.get(frame, var);
+                self.code.call(self.at.get("goopea_inc"));
+            }
+            Statement::Dec(var) => {
+                self.get(frame, var);
+                self.code.call(self.at.get("goopea_dec"));
+            }
+            Statement::DecUTuple(var, size) => {
+                for i in 0..*size as u32 {
+                    self.code.local_get(frame.locals[var] + i);
+                    self.code.call(self.at.get("goopea_dec"));
+                }
+            }
+            Statement::Drop(var, decs, incs) => {
+                self.get(frame, var);
+                self.code.call(self.at.get("goopea_rc"));
+                self.code.i64_const(1);
+                self.code.op(I64_EQ);
+                let label = self.code.label("unique");
+                self.code.if_then(&label);
+                for i in decs {
+                    self.field(frame, var, *i as i64 + HEADER_WORDS);
+                    self.code.call(self.at.get("goopea_dec"));
+                }
+                self.get(frame, var);
+                self.field(frame, var, 0);
+                self.code.i64_const(8);
+                self.code.op(I64_SHR_S);
+                self.code.i64_const(0xff);
+                self.code.op(I64_AND);
+                self.code.i64_const(HEADER_WORDS);
+                self.code.op(I64_ADD);
+                self.code.call(self.at.get("goopea_free_cell"));
+                self.code.else_then();
+                for i in incs {
+                    self.field(frame, var, *i as i64 + HEADER_WORDS);
+                    self.code.call(self.at.get("goopea_inc"));
+                }
+                self.get(frame, var);
+                self.code.call(self.at.get("goopea_release"));
+                self.code.end();
+            }
+        }
+    }
+}
+
+fn function_definition(def: &Def, at: &Indices, statics: &[u32]) -> Function {
+    let mut vars = BTreeMap::new();
+    let mut sizes = BTreeMap::new();
+    for arg in &def.args {
+        vars.insert(arg.clone(), Type::Standard);
+    }
+    collect_vars(&def.body, &mut vars, &mut sizes);
+
+    let mut locals = HashMap::new();
+    let mut count = 0;
+    for arg in &def.args {
+        locals.insert(arg.clone(), count);
+        count += 1;
+    }
+    for (var, typ) in &vars {
+        if !locals.contains_key(var) {
+            locals.insert(var.clone(), count);
+            count += var_size(typ);
+        }
+    }
+    let mut cells = BTreeMap::new();
+    let mut frame_bytes = 0;
+    for (var, size) in sizes {
+        cells.insert(var, frame_bytes);
+        frame_bytes += 8 * (size as u32 + HEADER_WORDS as u32);
+    }
+    let frame = Frame {
+        def,
+        vars,
+        locals,
+        cells,
+        frame_bytes,
+        frame: count,
+        count: count + 1,
+    };
+
+    let mut emitter = Emitter {
+        at,
+        statics,
+        code: Code::new(),
+    };
+    if frame_bytes > 0 {
+        emitter.code.index(GLOBAL_GET, STACK);
+        emitter.code.i32_const(frame_bytes as i32);
+        emitter.code.op(I32_SUB);
+        emitter.code.index(LOCAL_TEE, frame.frame);
+        emitter.code.index(GLOBAL_SET, STACK);
+    }
+    let tail = calls_self(&def.body, &def.id);
+    if tail {
+        emitter.code.looping("tailcall");
+    }
+    emitter.statements(&frame, &def.body);
+    if tail {
+        emitter.code.end();
+    }
+    let results = match def.id.as_str() {
+        "main" => vec![],
+        _ => vec![I64; var_size(&def.typ) as usize],
+    };
+    if !results.is_empty() {
+        emitter.code.op(UNREACHABLE);
+    }
+    let mut locals = vec![I64; (frame.count - def.args.len() as u32 - 1) as usize];
+    locals.push(I32);
+    Function {
+        params: vec![I64; def.args.len()],
+        results,
+        locals,
+        code: emitter.code,
+    }
+}
+
+// Printers for the result of main and every ADT reachable from it, writing
+// values like the printers of the C backend. Text is written from memory
+struct Printer<'a> {
+    at: &'a Indices,
+    text: &'a mut Vec<u8>,
+    text_start: u32,
+    code: Code,
+}
+
+impl<'a> Printer<'a> {
+    fn print_text(&mut self, text: &str) {
+        let offset = match self
+            .text
+            .windows(text.len())
+            .position(|window| window == text.as_bytes())
+        {
+            Some(offset) => offset,
+            None => {
+                self.text.extend(text.as_bytes());
+                self.text.len() - text.len()
+            }
+        };
+        self.code
+            .i32_const((self.text_start + offset as u32) as i32);
+        self.code.i32_const(text.len() as i32);
+        self.code.call(self.at.get("print_text"));
+    }
+
+    // The value is on the stack
+    fn print_value(&mut self, typ: &ast::Type) {
+        match typ {
+            ast::Type::Int => {
+                self.code.untag();
+                self.code.call(self.at.get("print_int"));
+            }
+            ast::Type::ADT(adt) => self.code.call(self.at.get(&format!("print_{}", adt))),
+        }
+    }
+
+    // Prints the constructor up to its last field, which is left to the caller
+    fn print_ctor_head(&mut self, name: &str, fields: Vec<(u32, Option<i64>, &ast::Type)>) {
+        self.print_text(&format!("{}(", name));
+        for (local, offset, typ) in fields {
+            self.code.local_get(local);
+            if let Some(offset) = offset {
+                self.code.load(offset);
+            }
+            self.print_value(typ);
+            self.print_text(", ");
+        }
+    }
+
+    // Fields are in a local, or at an offset in the cell a local points at
+    fn print_ctor(&mut self, name: &str, fields: Vec<(u32, Option<i64>, &ast::Type)>) {
+        if fields.is_empty() {
+            self.print_text(name);
+            return;
+        }
+        self.print_text(&format!("{}(", name));
+        for (i, (local, offset, typ)) in fields.into_iter().enumerate() {
+            if i > 0 {
+                self.print_text(", ");
+            }
+            self.code.local_get(local);
+            if let Some(offset) = offset {
+                self.code.load(offset);
+            }
+            self.print_value(typ);
+        }
+        self.print_text(")");
+    }
+}
+
+fn printer(adt: &str, layouts: &Layouts, printer: &mut Printer) {
+    let (repr, ctors) = &layouts.adts[adt];
+    let names = &layouts.names[adt];
+    match repr {
+        // Like in C, a last field of the same type is printed by going around
+        // again instead of recursing. Local 2 counts the parentheses to close
+        Repr::Boxed => {
+            let own = ast::Type::ADT(adt.to_string());
+            let walks = ctors.iter().any(|fields| fields.last() == Some(&own));
+            if walks {
+                printer.code.looping("next");
+            }
+            printer.code.block("end");
+            let labels = ctors
+                .iter()
+                .map(|_| printer.code.label("case"))
+                .collect::<Vec<_>>();
+            for label in labels.iter().rev() {
+                printer.code.block(label);
+            }
+            printer.code.local_get(0);
+            printer.code.call(printer.at.get("goopea_ctor"));
+            printer.code.op(I32_WRAP_I64);
+            printer.code.br_table(&labels, "end");
+            for (name, fields) in names.iter().zip(ctors) {
+                printer.code.end();
+                let mut fields = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, typ)| (0, Some((i as i64 + HEADER_WORDS) * 8), typ))
+                    .collect::<Vec<_>>();
+                if fields.last().is_some_and(|(_, _, typ)| **typ == own) {
+                    let (_, offset, _) = fields.pop().unwrap();
+                    printer.print_ctor_head(name, fields);
+                    printer.code.local_get(0);
+                    printer.code.load(offset.unwrap());
+                    printer.code.local_set(0);
+                    printer.code.local_get(2);
+                    printer.code.i64_const(1);
+                    printer.code.op(I64_ADD);
+                    printer.code.local_set(2);
+                    printer.code.br("next");
+                } else {
+                    printer.print_ctor(name, fields);
+                    printer.code.br("end");
+                }
+            }
+            printer.code.end();
+            if walks {
+                printer.code.end();
+                printer.code.block("closed");
+                printer.code.looping("close");
+                printer.code.local_get(2);
+                printer.code.op(I64_EQZ);
+                printer.code.br_if("closed");
+                printer.print_text(")");
+                printer.code.local_get(2);
+                printer.code.i64_const(1);
+                printer.code.op(I64_SUB);
+                printer.code.local_set(2);
+                printer.code.br("close");
+                printer.code.end();
+                printer.code.end();
+            }
+        }
+        Repr::Newtype => {
+            printer.print_ctor(&names[0], vec![(0, None, &ctors[0][0])]);
+        }
+        // Odd values hold the field, even ones are a nullary constructor.
+        // The field is 2 * x + 1, which is how a shifted int looks anyway
+        Repr::Immediate => {
+            printer.code.local_get(0);
+            printer.code.untag();
+            printer.code.local_set(1);
+            printer.code.local_get(1);
+            printer.code.i64_const(1);
+            printer.code.op(I64_AND);
+            printer.code.op(I32_WRAP_I64);
+            printer.code.if_then("field");
+            let (name, fields) = names
+                .iter()
+                .zip(ctors)
+                .find(|(_, fields)| !fields.is_empty())
+                .unwrap();
+            printer.print_ctor(name, vec![(1, None, &fields[0])]);
+            printer.code.op(RETURN);
+            printer.code.end();
+            let nullary = names
+                .iter()
+                .zip(ctors)
+                .filter(|(_, fields)| fields.is_empty())
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+            printer.code.block("end");
+            let labels = nullary
+                .iter()
+                .map(|_| printer.code.label("case"))
+                .collect::<Vec<_>>();
+            for label in labels.iter().rev() {
+                printer.code.block(label);
+            }
+            printer.code.local_get(1);
+            printer.code.untag();
+            printer.code.op(I32_WRAP_I64);
+            printer.code.br_table(&labels, "end");
+            for name in nullary {
+                printer.code.end();
+                printer.print_ctor(name, vec![]);
+                printer.code.br("end");
+            }
+            printer.code.end();
+        }
+    }
+}
+
+pub fn output(prog: &Prog, layouts: &Layouts) -> Vec<u8> {
+    let main = prog.0.iter().find(|def| def.id == "main");
+    let intrinsics = used_intrinsics(prog);
+    let adts = printed_adts(layouts);
+
+    // Imports come first in the function index space
+    let mut imports: Vec<(String, Signature)> = vec![
+        ("print_int".to_string(), (vec![I64], vec![])),
+        ("print_text".to_string(), (vec![I32, I32], vec![])),
+    ];
+    if intrinsics.iter().any(|intrinsic| intrinsic.id == "trace") {
+        imports.push(("trace".to_string(), (vec![I64], vec![])));
+    }
+    for ext in &prog.3 {
+        imports.push((ext.id.clone(), (vec![I64; ext.arity], vec![I64])));
+    }
+    let mut ids = imports.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+    ids.extend(RUNTIME.iter().map(|id| id.to_string()));
+    ids.extend(
+        intrinsics
+            .iter()
+            .map(|intrinsic| format!("goopea_{}", intrinsic.id)),
+    );
+    if main.is_some() {
+        ids.extend(adts.iter().map(|adt| format!("print_{}", adt)));
+        ids.push("print_result".to_string());
+    }
+    ids.extend(prog.0.iter().map(|def| def.id.clone()));
+    let at = Indices {
+        functions: ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.clone(), i as u32))
+            .collect(),
+    };
+
+    let (statics, text_start) = static_addresses(prog);
+    let mut functions = RUNTIME
+        .iter()
+        .map(|id| runtime_function(id, &at))
+        .collect::<Vec<_>>();
+    functions.extend(
+        intrinsics
+            .iter()
+            .map(|intrinsic| intrinsic_function(intrinsic.id, &at)),
+    );
+    let mut text = vec![];
+    if let Some(main) = main {
+        for adt in &adts {
+            let mut p = Printer {
+                at: &at,
+                text: &mut text,
+                text_start,
+                code: Code::new(),
+            };
+            printer(adt, layouts, &mut p);
+            functions.push(Function {
+                params: vec![I64],
+                results: vec![],
+                locals: vec![I64, I64],
+                code: p.code,
+            });
+        }
+        let size = var_size(&main.typ);
+        let mut p = Printer {
+            at: &at,
+            text: &mut text,
+            text_start,
+            code: Code::new(),
+        };
+        match &layouts.result[..] {
+            [typ] => {
+                p.code.local_get(0);
+                p.print_value(typ);
+            }
+            types => {
+                p.print_text("(");
+                for (i, typ) in types.iter().enumerate() {
+                    if i > 0 {
+                        p.print_text(", ");
+                    }
+                    p.code.local_get(i as u32);
+                    p.print_value(typ);
+                }
+                p.print_text(")");
+            }
+        }
+        p.print_text("\n");
+        functions.push(Function {
+            params: vec![I64; size as usize],
+            results: vec![],
+            locals: vec![],
+            code: p.code,
+        });
+    }
+    for def in &prog.0 {
+        functions.push(function_definition(def, &at, &statics));
+    }
+
+    // Static cells and text, then the frames of stack cells, then the heap
+    let mut data = vec![];
+    for (cell, address) in prog.2.iter().zip(&statics) {
+        let mut words = vec![static_header(cell.tag, cell.fields.len() as u8)];
+        words.extend(cell.fields.iter().map(|field| match field {
+            Operand::Int(i) => i << 1 | 1,
+            Operand::NonShifted(i) => *i,
+            Operand::Static(i) => statics[*i] as i64,
+            Operand::Ident(_) | Operand::Negate(_) => panic!("static cell holds a variable"),
+        }));
+        debug_assert_eq!(*address as usize, DATA_START as usize + data.len());
+        words
+            .iter()
+            .for_each(|word| data.extend(word.to_le_bytes()));
+    }
+    data.extend(&text);
+    let stack_top = (DATA_START + data.len() as u32).next_multiple_of(8) + STACK_BYTES;
+    let pages = stack_top / PAGE + 1;
+
+    let mut types: Vec<Signature> = vec![];
+    let mut type_of = |params: &Vec<u8>, results: &Vec<u8>| {
+        let typ = (params.clone(), results.clone());
+        match types.iter().position(|t| *t == typ) {
+            Some(i) => i as u64,
+            None => {
+                types.push(typ);
+                types.len() as u64 - 1
+            }
+        }
+    };
+    let import_types = imports
+        .iter()
+        .map(|(_, (params, results))| type_of(params, results))
+        .collect::<Vec<_>>();
+    let function_types = functions
+        .iter()
+        .map(|function| type_of(&function.params, &function.results))
+        .collect::<Vec<_>>();
+
+    let mut module = b"\0asm".to_vec();
+    module.extend([1, 0, 0, 0]);
+
+    let mut content = vec![];
+    for (params, results) in &types {
+        content.push(0x60);
+        unsigned(&mut content, params.len() as u64);
+        content.extend(params);
+        unsigned(&mut content, results.len() as u64);
+        content.extend(results);
+    }
+    section(&mut module, 1, types.len(), content);
+
+    let mut content = vec![];
+    for ((id, _), typ) in imports.iter().zip(&import_types) {
+        name(&mut content, "env");
+        name(&mut content, id);
+        content.push(0);
+        unsigned(&mut content, *typ);
+    }
+    section(&mut module, 2, imports.len(), content);
+
+    let mut content = vec![];
+    for typ in &function_types {
+        unsigned(&mut content, *typ);
+    }
+    section(&mut module, 3, functions.len(), content);
+
+    let mut content = vec![0];
+    unsigned(&mut content, pages as u64);
+    section(&mut module, 5, 1, content);
+
+    let mut content = vec![];
+    for (typ, init) in [
+        (I32, stack_top as i64),
+        (I32, stack_top as i64),
+        (I64, RANDOM_SEED as i64),
+    ] {
+        content.extend([typ, 1]);
+        content.push(if typ == I32 { I32_CONST } else { I64_CONST });
+        signed(&mut content, init);
+        content.push(END);
+    }
+    section(&mut module, 6, 3, content);
+
+    let mut content = vec![];
+    name(&mut content, "memory");
+    content.extend([2, 0]);
+    let mut exports = 1;
+    if main.is_some() {
+        name(&mut content, "main");
+        content.push(0);
+        unsigned(&mut content, at.get("main") as u64);
+        exports += 1;
+    }
+    section(&mut module, 7, exports, content);
+
+    let mut content = vec![];
+    for function in functions {
+        let mut body = vec![];
+        unsigned(&mut body, function.locals.len() as u64);
+        for local in &function.locals {
+            body.extend([1, *local]);
+        }
+        body.extend(function.code.bytes);
+        body.push(END);
+        unsigned(&mut content, body.len() as u64);
+        content.extend(body);
+    }
+    section(&mut module, 10, function_types.len(), content);
+
+    let mut content = vec![0, I32_CONST];
+    signed(&mut content, DATA_START as i64);
+    content.push(END);
+    unsigned(&mut content, data.len() as u64);
+    content.extend(data);
+    section(&mut module, 11, 1, content);
+    module
+}
@@ -44,6 +44,8 @@ pub enum ErrorReason {
     WrongVariableCountInMatchCase { fid: String, expected: usize, actual: usize },
     #[error("Wrong variable count for function call of '{fid}'. Expected {expected}, but got {actual}")]
     WrongVariableCountInFunctionCall { fid: FID, expected: usize, actual: usize },
+    #[error("Extern function '{0}' must take Ints and return a single Int")]
+    ExternWithNonIntType(FID),
     #[error("Use of undeclared ADT '{0}'")]
     UnknownADTInType(AID),
     #[error("The program is missing a main function")]
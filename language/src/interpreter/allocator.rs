@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Places cells in a heap of words. Addresses start at 1, since 0 means no
+// reuse, and the top is one past the last word handed out
+pub trait Allocator {
+    fn alloc(&mut self, words: usize) -> usize;
+    fn free(&mut self, address: usize, words: usize);
+    fn top(&self) -> usize;
+    fn boxed(&self) -> Box<dyn Allocator>;
+}
+
+impl Clone for Box<dyn Allocator> {
+    fn clone(&self) -> Self {
+        self.boxed()
+    }
+}
+
+pub const ALLOCATORS: [&str; 4] = ["first-fit", "free-list", "bump", "size-class"];
+
+pub fn allocator(name: &str) -> Option<Box<dyn Allocator>> {
+    Some(match name {
+        "first-fit" => Box::new(FirstFit::default()),
+        "free-list" => Box::new(FreeList::default()),
+        "bump" => Box::new(Bump::default()),
+        "size-class" => Box::new(SizeClass::default()),
+        _ => return None,
+    })
+}
+
+// Takes the lowest free range that fits, merging neighbouring free ranges and
+// giving the ones at the top back
+#[derive(Clone)]
+pub struct FirstFit {
+    // The length of every free range by its start
+    free: BTreeMap<usize, usize>,
+    // The starts of the free ranges of every length, so that only the lowest
+    // range of each length that fits is looked at
+    lengths: BTreeMap<usize, BTreeSet<usize>>,
+    top: usize,
+}
+
+impl Default for FirstFit {
+    fn default() -> Self {
+        FirstFit {
+            free: BTreeMap::new(),
+            lengths: BTreeMap::new(),
+            top: 1,
+        }
+    }
+}
+
+impl FirstFit {
+    fn insert(&mut self, start: usize, len: usize) {
+        self.free.insert(start, len);
+        self.lengths.entry(len).or_default().insert(start);
+    }
+
+    fn remove(&mut self, start: usize) -> Option<usize> {
+        let len = self.free.remove(&start)?;
+        let starts = self.lengths.get_mut(&len).unwrap();
+        starts.remove(&start);
+        if starts.is_empty() {
+            self.lengths.remove(&len);
+        }
+        Some(len)
+    }
+}
+
+impl Allocator for FirstFit {
+    fn alloc(&mut self, words: usize) -> usize {
+        let fit = self
+            .lengths
+            .range(words..)
+            .filter_map(|(_, starts)| starts.first())
+            .min()
+            .copied();
+        match fit {
+            Some(start) => {
+                let len = self.remove(start).unwrap();
+                if len > words {
+                    self.insert(start + words, len - words);
+                }
+                start
+            }
+            None => {
+                self.top += words;
+                self.top - words
+            }
+        }
+    }
+
+    fn free(&mut self, address: usize, words: usize) {
+        let (mut start, mut len) = (address, words);
+        if let Some(next) = self.remove(start + len) {
+            len += next;
+        }
+        let prev = self.free.range(..start).next_back();
+        if let Some((&prev, &prev_len)) = prev.filter(|&(prev, len)| prev + len == start) {
+            self.remove(prev);
+            start = prev;
+            len += prev_len;
+        }
+        if start + len == self.top {
+            self.top = start;
+        } else {
+            self.insert(start, len);
+        }
+    }
+
+    fn top(&self) -> usize {
+        self.top
+    }
+
+    fn boxed(&self) -> Box<dyn Allocator> {
+        Box::new(self.clone())
+    }
+}
+
+// Recycles freed cells of exactly the same size, newest first, like the C
+// runtime does with GOOPEA_FREE_LIST
+#[derive(Clone)]
+pub struct FreeList {
+    lists: HashMap<usize, Vec<usize>>,
+    top: usize,
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        FreeList {
+            lists: HashMap::new(),
+            top: 1,
+        }
+    }
+}
+
+impl Allocator for FreeList {
+    fn alloc(&mut self, words: usize) -> usize {
+        if let Some(address) = self.lists.get_mut(&words).and_then(Vec::pop) {
+            return address;
+        }
+        self.top += words;
+        self.top - words
+    }
+
+    fn free(&mut self, address: usize, words: usize) {
+        self.lists.entry(words).or_default().push(address);
+    }
+
+    fn top(&self) -> usize {
+        self.top
+    }
+
+    fn boxed(&self) -> Box<dyn Allocator> {
+        Box::new(self.clone())
+    }
+}
+
+// Never reuses anything, so the heap only grows
+#[derive(Clone)]
+pub struct Bump {
+    top: usize,
+}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Bump { top: 1 }
+    }
+}
+
+impl Allocator for Bump {
+    fn alloc(&mut self, words: usize) -> usize {
+        self.top += words;
+        self.top - words
+    }
+
+    fn free(&mut self, _address: usize, _words: usize) {}
+
+    fn top(&self) -> usize {
+        self.top
+    }
+
+    fn boxed(&self) -> Box<dyn Allocator> {
+        Box::new(self.clone())
+    }
+}
+
+// Rounds every cell up to a power of two and recycles freed cells within
+// their class, trading the rounding for reuse across sizes
+#[derive(Clone)]
+pub struct SizeClass {
+    classes: Vec<Vec<usize>>,
+    top: usize,
+}
+
+impl Default for SizeClass {
+    fn default() -> Self {
+        SizeClass {
+            classes: Vec::new(),
+            top: 1,
+        }
+    }
+}
+
+fn size_class(words: usize) -> usize {
+    words.next_power_of_two().trailing_zeros() as usize
+}
+
+impl Allocator for SizeClass {
+    fn alloc(&mut self, words: usize) -> usize {
+        let class = size_class(words);
+        if let Some(address) = self.classes.get_mut(class).and_then(Vec::pop) {
+            return address;
+        }
+        self.top += 1 << class;
+        self.top - (1 << class)
+    }
+
+    fn free(&mut self, address: usize, words: usize) {
+        let class = size_class(words);
+        if self.classes.len() <= class {
+            self.classes.resize(class + 1, Vec::new());
+        }
+        self.classes[class].push(address);
+    }
+
+    fn top(&self) -> usize {
+        self.top
+    }
+
+    fn boxed(&self) -> Box<dyn Allocator> {
+        Box::new(self.clone())
+    }
+}
+
+// Live words are the ones in cells that are not yet freed, the footprint is
+// every word below the top
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AllocStats {
+    pub live: usize,
+    pub peak: usize,
+    pub footprint: usize,
+    pub peak_footprint: usize,
+    // Live words the last time an allocation left the footprint at its peak
+    pub live_at_peak_footprint: usize,
+}
+
+impl AllocStats {
+    pub fn allocated(&mut self, words: usize, top: usize) {
+        self.live += words;
+        self.peak = self.peak.max(self.live);
+        self.footprint = top - 1;
+        if self.footprint >= self.peak_footprint {
+            self.peak_footprint = self.footprint;
+            self.live_at_peak_footprint = self.live;
+        }
+    }
+
+    pub fn freed(&mut self, words: usize, top: usize) {
+        self.live -= words;
+        self.footprint = top - 1;
+    }
+
+    // The share of the footprint not holding live cells
+    pub fn fragmentation(&self) -> f64 {
+        if self.footprint == 0 {
+            0.0
+        } else {
+            1.0 - self.live as f64 / self.footprint as f64
+        }
+    }
+
+    // The share of the biggest footprint not holding live cells while it was
+    // that big, rather than against the peak of live words, which may have
+    // come at another moment
+    pub fn peak_fragmentation(&self) -> f64 {
+        if self.peak_footprint == 0 {
+            0.0
+        } else {
+            1.0 - self.live_at_peak_footprint as f64 / self.peak_footprint as f64
+        }
+    }
+}
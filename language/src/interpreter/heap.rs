@@ -0,0 +1,209 @@
+use super::allocator::{AllocStats, Allocator};
+use super::iast::IOperand;
+use super::interpreter::Data;
+use crate::compiler::core::{HEADER_WORDS, RC_ONE, StaticCell, header_rc, static_header};
+use std::ops::{Index, IndexMut};
+use std::rc::Rc;
+
+// The cells of the interpreter and the VM and their reference counts. A cell
+// lives at the address of its first word, the rest of its words stay empty.
+// Clones share the cells until one of them writes to a cell, so the editor
+// can keep a copy from every step
+#[derive(Clone)]
+pub struct Heap {
+    cells: Vec<Rc<Vec<Data>>>,
+    allocator: Box<dyn Allocator>,
+    stats: AllocStats,
+    // Where each static cell was put
+    statics: Vec<usize>,
+}
+
+impl Index<usize> for Heap {
+    type Output = Vec<Data>;
+
+    fn index(&self, ptr: usize) -> &Vec<Data> {
+        &self.cells[ptr]
+    }
+}
+
+impl IndexMut<usize> for Heap {
+    fn index_mut(&mut self, ptr: usize) -> &mut Vec<Data> {
+        Rc::make_mut(&mut self.cells[ptr])
+    }
+}
+
+impl Heap {
+    pub fn new(allocator: Box<dyn Allocator>) -> Self {
+        Heap {
+            // Address 0 stays unused, as 0 means no cell to reuse
+            cells: vec![Rc::default()],
+            allocator,
+            stats: AllocStats::default(),
+            statics: Vec::new(),
+        }
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &Vec<Data>> {
+        self.cells.iter().map(|cell| &**cell)
+    }
+
+    pub fn alloc(&mut self, width: usize) -> usize {
+        let ptr = self.allocator.alloc(width);
+        if self.cells.len() <= ptr {
+            self.cells.resize(ptr + 1, Rc::default());
+        }
+        self.cells[ptr] = Rc::new(vec![Data::Value(0); width]);
+        self.stats.allocated(width, self.allocator.top());
+        ptr
+    }
+
+    pub fn free(&mut self, ptr: usize) -> Rc<Vec<Data>> {
+        let cell = std::mem::take(&mut self.cells[ptr]);
+        self.allocator.free(ptr, cell.len());
+        self.stats.freed(cell.len(), self.allocator.top());
+        self.cells.truncate(self.allocator.top());
+        cell
+    }
+
+    pub fn add_static(&mut self, cell: &StaticCell) {
+        let ptr = self.alloc(cell.fields.len() + HEADER_WORDS as usize);
+        self[ptr][0] = Data::Value(static_header(cell.tag, cell.fields.len() as u8));
+        for (i, field) in cell.fields.iter().enumerate() {
+            let data = match IOperand::from_constant(field) {
+                IOperand::Int(i) => Data::Value(i),
+                IOperand::Static(i) => Data::Pointer(self.statics[i]),
+                IOperand::Ident(_) | IOperand::Negate(_) => unreachable!(),
+            };
+            self[ptr][i + HEADER_WORDS as usize] = data;
+        }
+        self.statics.push(ptr);
+    }
+
+    pub fn static_cell(&self, i: usize) -> usize {
+        self.statics[i]
+    }
+
+    pub fn header(&self, ptr: usize) -> i64 {
+        self.cells[ptr][0].unwrap_val()
+    }
+
+    pub fn rc(&self, ptr: usize) -> i64 {
+        header_rc(self.header(ptr))
+    }
+
+    // Static cells keep their count of zero
+    pub fn add_rc(&mut self, ptr: usize, delta: i64) {
+        if self.rc(ptr) != 0 {
+            let header = self.header(ptr);
+            self[ptr][0] = Data::Value(header + delta * RC_ONE);
+        }
+    }
+
+    pub fn inc(&mut self, ptr: usize) {
+        self.add_rc(ptr, 1);
+    }
+
+    pub fn dec(&mut self, ptr: usize) {
+        if self.rc(ptr) == 1 {
+            self.free_cells(ptr);
+        } else {
+            self.add_rc(ptr, -1);
+        }
+    }
+
+    // Drops a reference like goopea_dec_onto in the C runtime. When it was the
+    // last one, the cell goes on the stack along with the cells only it held
+    // on to through last fields, so that they come off in that order
+    fn dec_onto(&mut self, data: Data, todo: &mut Vec<usize>) {
+        let Data::Pointer(ptr) = data else {
+            return;
+        };
+        if self.rc(ptr) != 1 {
+            self.add_rc(ptr, -1);
+            return;
+        }
+        let mut spine = vec![ptr];
+        loop {
+            match *self.cells[*spine.last().unwrap()].last().unwrap() {
+                Data::Pointer(cell) if self.rc(cell) == 1 => spine.push(cell),
+                Data::Pointer(cell) => {
+                    self.add_rc(cell, -1);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        todo.extend(spine.into_iter().rev());
+    }
+
+    // Frees the cell and whatever only it held on to, without recursing. Each
+    // cell is freed before its fields, which are visited from first to last,
+    // the same order as in the other runtimes
+    fn free_cells(&mut self, ptr: usize) {
+        let mut todo = Vec::new();
+        self.dec_onto(Data::Pointer(ptr), &mut todo);
+        while let Some(ptr) = todo.pop() {
+            let cell = self.free(ptr);
+            // The last field is on the stack already
+            for &data in cell[HEADER_WORDS as usize..cell.len() - 1].iter().rev() {
+                self.dec_onto(data, &mut todo);
+            }
+        }
+    }
+
+    // The cell to reuse, or 0 when it is shared. The kept fields move to the
+    // new cell, so they lose no reference when the old one goes
+    pub fn drop_reuse(&mut self, data: Data, kept: &[u8]) -> Data {
+        let Data::Pointer(ptr) = data else {
+            return Data::Value(0);
+        };
+        let unique = self.rc(ptr) == 1;
+        for i in HEADER_WORDS as usize..self.cells[ptr].len() {
+            let Data::Pointer(field) = self.cells[ptr][i] else {
+                continue;
+            };
+            match (unique, kept.contains(&((i - HEADER_WORDS as usize) as u8))) {
+                (true, false) => self.dec(field),
+                (false, true) => self.inc(field),
+                _ => (),
+            }
+        }
+        if unique {
+            Data::Pointer(ptr)
+        } else {
+            self.add_rc(ptr, -1);
+            Data::Value(0)
+        }
+    }
+
+    pub fn drop(&mut self, ptr: usize, decs: &[u8], incs: &[u8]) {
+        if self.rc(ptr) == 1 {
+            for i in decs {
+                if let Data::Pointer(field) = self.cells[ptr][*i as usize + HEADER_WORDS as usize] {
+                    self.dec(field);
+                }
+            }
+            self.free(ptr);
+        } else {
+            for i in incs {
+                if let Data::Pointer(field) = self.cells[ptr][*i as usize + HEADER_WORDS as usize] {
+                    self.inc(field);
+                }
+            }
+            self.add_rc(ptr, -1);
+        }
+    }
+
+    pub fn dec_tuple(&mut self, ptr: usize) {
+        for i in 1..self.cells[ptr].len() {
+            if let Data::Pointer(field) = self.cells[ptr][i] {
+                self.dec(field);
+            }
+        }
+        self.free(ptr);
+    }
+}
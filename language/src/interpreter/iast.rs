@@ -1,30 +1,46 @@
-use crate::compiler::core::{Def, Operand, Statement};
+use crate::compiler::core::{Def, HEADER_WORDS, Operand, Statement, header};
 use crate::compiler::crux::Operator;
 use itertools::Itertools;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter, Result};
 
+// A variable and the slot of the frame it is kept in
+#[derive(Debug, Clone)]
+pub struct Var {
+    pub slot: usize,
+    pub id: String,
+}
+
+impl Display for Var {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.id)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum IOperand {
-    Ident(String),
-    Negate(String),
+    Ident(Var),
+    Negate(Var),
     Int(i64),
+    Static(usize),
 }
 
 impl IOperand {
-    pub fn from_op(operand: &Operand) -> Self {
+    // Static cells only hold constants, so there are no variables to resolve
+    pub fn from_constant(operand: &Operand) -> Self {
         match operand {
-            Operand::Ident(id) => Self::Ident(id.clone()),
-            Operand::Int(i) => Self::Int(*i),
-            Operand::NonShifted(i) => Self::Int(*i),
-            Operand::Negate(id) => IOperand::Negate(id.clone()),
+            Operand::Int(i) | Operand::NonShifted(i) => IOperand::Int(*i),
+            Operand::Static(i) => IOperand::Static(*i),
+            Operand::Ident(_) | Operand::Negate(_) => panic!("Not a constant"),
         }
     }
 
-    pub fn unwrap_id(&self) -> String {
+    pub fn unwrap_var(&self) -> &Var {
         match self {
-            IOperand::Ident(s) => s.clone(),
+            IOperand::Ident(var) => var,
             IOperand::Int(_) => panic!("Not an identifier"),
             IOperand::Negate(_) => panic!("Not an identifier"),
+            IOperand::Static(_) => panic!("Not an identifier"),
         }
     }
 }
@@ -32,9 +48,10 @@ impl IOperand {
 impl Display for IOperand {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
-            IOperand::Ident(id) => write!(f, "{id}"),
+            IOperand::Ident(var) => write!(f, "{var}"),
             IOperand::Int(i) => write!(f, "{i}"),
-            IOperand::Negate(id) => write!(f, "!{id}"),
+            IOperand::Negate(var) => write!(f, "!{var}"),
+            IOperand::Static(i) => write!(f, "static{i}"),
         }
     }
 }
@@ -42,24 +59,70 @@ impl Display for IOperand {
 #[derive(Debug, Clone)]
 pub enum IStatement {
     IfExpr(Vec<(IOperand, Vec<IStatement>)>),
+    Switch(IOperand, Vec<Vec<IStatement>>),
     Return(IOperand),
-    AssignMalloc(String, u32),
-    Assign(String, IOperand),
-    AssignToField(String, i64, IOperand),
-    AssignFromField(String, i64, IOperand),
-    AssignBinaryOperation(String, Operator, IOperand, IOperand),
-    AssignTagCheck(String, bool, IOperand, i64),
+    AssignMalloc(Var, u32),
+    AssignStackAlloc(Var, u32),
+    Assign(Var, IOperand),
+    AssignToField(Var, i64, IOperand),
+    AssignFromField(Var, i64, IOperand),
+    AssignBinaryOperation(Var, Operator, IOperand, IOperand),
     FunctionCall(String, Vec<IOperand>),
-    AssignReturnvalue(String),
-    AssignDropReuse(String, String),
+    AssignExternCall(Var, String, Vec<IOperand>),
+    AssignIntrinsic(Var, String, Vec<IOperand>),
+    AssignReturnvalue(Var),
+    TailCall(String, Vec<IOperand>),
+    AssignDropReuse(Var, Var, Vec<u8>),
     Inc(IOperand),
     Dec(IOperand),
-    AssignUTuple(usize, String, Vec<String>),
-    DecUTuple(String),
-    AssignUTupleField(String, usize, IOperand),
+    AssignUTuple(usize, Var, Vec<Var>),
+    DecUTuple(Var),
+    AssignUTupleField(Var, usize, IOperand),
+    Drop(Var, Vec<u8>, Vec<u8>),
+    Label(String),
+    Jump(String),
 }
 
-fn from_statements(statements: Vec<Statement>) -> Vec<IStatement> {
+// Gives each variable of a function the next slot the first time it is seen
+#[derive(Default)]
+struct Slots {
+    ids: Vec<String>,
+    slots: HashMap<String, usize>,
+}
+
+impl Slots {
+    fn var(&mut self, id: &str) -> Var {
+        let slot = match self.slots.get(id) {
+            Some(slot) => *slot,
+            None => {
+                self.ids.push(id.to_string());
+                self.slots.insert(id.to_string(), self.ids.len() - 1);
+                self.ids.len() - 1
+            }
+        };
+        Var {
+            slot,
+            id: id.to_string(),
+        }
+    }
+
+    fn operand(&mut self, operand: &Operand) -> IOperand {
+        match operand {
+            Operand::Ident(id) => IOperand::Ident(self.var(id)),
+            Operand::Negate(id) => IOperand::Negate(self.var(id)),
+            operand => IOperand::from_constant(operand),
+        }
+    }
+
+    fn operands(&mut self, operands: &[Operand]) -> Vec<IOperand> {
+        operands
+            .iter()
+            .map(|operand| self.operand(operand))
+            .collect()
+    }
+}
+
+fn from_statements(statements: Vec<Statement>, slots: &mut Slots) -> Vec<IStatement> {
     let mut istatements = Vec::new();
     for statement in statements {
         let s = match statement {
@@ -67,52 +130,82 @@ fn from_statements(statements: Vec<Statement>) -> Vec<IStatement> {
                 items
                     .into_iter()
                     .map(|(operand, _statements)| {
-                        (IOperand::from_op(&operand), from_statements(_statements))
+                        (slots.operand(&operand), from_statements(_statements, slots))
                     })
                     .collect(),
             ),
-            Statement::Return(operand) => IStatement::Return(IOperand::from_op(&operand)),
-            Statement::Print(operand) => IStatement::Return(IOperand::from_op(&operand)),
-            Statement::AssignMalloc(_, id, n) => IStatement::AssignMalloc(id, n as u32 + 3),
+            Statement::Switch(operand, cases) => IStatement::Switch(
+                slots.operand(&operand),
+                cases
+                    .into_iter()
+                    .map(|(_, statements)| from_statements(statements, slots))
+                    .collect(),
+            ),
+            Statement::Return(operand) => IStatement::Return(slots.operand(&operand)),
+            Statement::Print(operand) => IStatement::Return(slots.operand(&operand)),
+            Statement::AssignMalloc(_, id, n) => {
+                IStatement::AssignMalloc(slots.var(&id), n as u32 + HEADER_WORDS as u32)
+            }
+            Statement::AssignStackAlloc(id, n) => {
+                IStatement::AssignStackAlloc(slots.var(&id), n as u32 + HEADER_WORDS as u32)
+            }
             Statement::Assign(_, id, operand) => {
-                IStatement::Assign(id, IOperand::from_op(&operand))
+                IStatement::Assign(slots.var(&id), slots.operand(&operand))
+            }
+            Statement::AssignToField(id, i, operand) | Statement::FillHole(id, i, operand) => {
+                IStatement::AssignToField(slots.var(&id), i, slots.operand(&operand))
             }
-            Statement::AssignToField(id, i, operand) => {
-                IStatement::AssignToField(id, i, IOperand::from_op(&operand))
+            Statement::AssignHeader(id, tag, arity) => {
+                IStatement::AssignToField(slots.var(&id), 0, IOperand::Int(header(tag, arity)))
             }
             Statement::AssignFromField(id, i, operand) => {
-                IStatement::AssignFromField(id, i, IOperand::from_op(&operand))
+                IStatement::AssignFromField(slots.var(&id), i, slots.operand(&operand))
             }
             Statement::AssignBinaryOperation(id, operator, operand, operand1) => {
                 IStatement::AssignBinaryOperation(
-                    id.clone(),
+                    slots.var(&id),
                     operator.clone(),
-                    IOperand::from_op(&operand),
-                    IOperand::from_op(&operand1),
+                    slots.operand(&operand),
+                    slots.operand(&operand1),
                 )
             }
-            Statement::AssignTagCheck(id, b, operand, i) => {
-                IStatement::AssignTagCheck(id, b, IOperand::from_op(&operand), i >> 1)
-            }
             Statement::AssignFunctionCall(id, fid, operands, _) => {
                 // first add a function call that puts the returned value in a register
                 istatements.push(IStatement::FunctionCall(
                     fid.clone(),
-                    operands.iter().map(IOperand::from_op).collect(),
+                    slots.operands(&operands),
                 ));
                 // then assign the value to the identifier
-                IStatement::AssignReturnvalue(id.clone())
+                IStatement::AssignReturnvalue(slots.var(&id))
+            }
+            Statement::AssignExternCall(id, fid, operands) => {
+                IStatement::AssignExternCall(slots.var(&id), fid, slots.operands(&operands))
+            }
+            Statement::AssignIntrinsic(id, fid, operands) => {
+                IStatement::AssignIntrinsic(slots.var(&id), fid, slots.operands(&operands))
             }
-            Statement::AssignDropReuse(a, b) => IStatement::AssignDropReuse(a, b),
-            Statement::Inc(operand) => IStatement::Inc(IOperand::Ident(operand)),
-            Statement::Dec(operand) => IStatement::Dec(IOperand::Ident(operand)),
-            Statement::AssignUTuple(n, id, fields) => {
-                IStatement::AssignUTuple(n as usize, id, fields)
+            Statement::TailCall(fid, operands) => {
+                IStatement::TailCall(fid, slots.operands(&operands))
             }
-            Statement::DecUTuple(id, _) => IStatement::DecUTuple(id),
+            Statement::AssignDropReuse(a, b, c) => {
+                IStatement::AssignDropReuse(slots.var(&a), slots.var(&b), c)
+            }
+            Statement::Inc(operand) => IStatement::Inc(IOperand::Ident(slots.var(&operand))),
+            Statement::Dec(operand) => IStatement::Dec(IOperand::Ident(slots.var(&operand))),
+            Statement::AssignUTuple(n, id, fields) => IStatement::AssignUTuple(
+                n as usize,
+                slots.var(&id),
+                fields.iter().map(|field| slots.var(field)).collect(),
+            ),
+            Statement::DecUTuple(id, _) => IStatement::DecUTuple(slots.var(&id)),
+            Statement::Drop(id, decs, incs) => IStatement::Drop(slots.var(&id), decs, incs),
             Statement::AssignUTupleField(id, i, op) => {
-                IStatement::AssignUTupleField(id, i as usize, IOperand::from_op(&op))
+                IStatement::AssignUTupleField(slots.var(&id), i as usize, slots.operand(&op))
             }
+            // Variables need no declaring here
+            Statement::Declare(_, _) => continue,
+            Statement::Label(label) => IStatement::Label(label),
+            Statement::Jump(label) => IStatement::Jump(label),
         };
         istatements.push(s);
     }
@@ -130,7 +223,11 @@ impl Display for IStatement {
                     .map(|(operand, _)| format!("{operand}"))
                     .collect_vec()
             ),
+            IStatement::Switch(ioperand, cases) => {
+                write!(f, "Switch {} [{} cases]", ioperand, cases.len())
+            }
             IStatement::AssignMalloc(id, s) => write!(f, "{id} = malloc({s})"),
+            IStatement::AssignStackAlloc(id, s) => write!(f, "{id} = alloca({s})"),
             IStatement::Return(ioperand) => write!(f, "Return({ioperand})"),
             IStatement::Inc(ioperand) => write!(f, "Inc({})", ioperand),
             IStatement::Dec(ioperand) => write!(f, "Dec({})", ioperand),
@@ -140,29 +237,45 @@ impl Display for IStatement {
             IStatement::AssignBinaryOperation(id, operator, ioperand, ioperand1) => {
                 write!(f, "{id} = {} {operator} {}", ioperand, ioperand1)
             }
-            IStatement::AssignTagCheck(id, b, ioperand, i) => {
-                write!(
-                    f,
-                    "{id} = {}",
-                    if *b {
-                        format!("tag({}) == {}", ioperand, i)
-                    } else {
-                        format!("{} == {}", ioperand, i)
-                    }
-                )
-            }
             IStatement::FunctionCall(id, ioperands) => write!(
                 f,
                 "call {id}{:?}",
                 ioperands.iter().map(|iop| format!("{iop}")).collect_vec()
             ),
+            IStatement::AssignExternCall(id, fid, ioperands) => write!(
+                f,
+                "{id} = extern {fid}{:?}",
+                ioperands.iter().map(|iop| format!("{iop}")).collect_vec()
+            ),
+            IStatement::AssignIntrinsic(id, fid, ioperands) => write!(
+                f,
+                "{id} = {fid}{:?}",
+                ioperands.iter().map(|iop| format!("{iop}")).collect_vec()
+            ),
             IStatement::AssignReturnvalue(id) => write!(f, "{id} = _ret_"),
-            IStatement::AssignDropReuse(id1, id2) => write!(f, "DropReuse {} {}", id1, id2),
-            IStatement::AssignUTuple(_, id, items) => write!(f, "{} = {:?}", id, items),
+            IStatement::TailCall(id, ioperands) => write!(
+                f,
+                "tailcall {id}{:?}",
+                ioperands.iter().map(|iop| format!("{iop}")).collect_vec()
+            ),
+            IStatement::AssignDropReuse(id1, id2, kept) => {
+                write!(f, "DropReuse {} {} {:?}", id1, id2, kept)
+            }
+            IStatement::AssignUTuple(_, id, items) => write!(
+                f,
+                "{} = {:?}",
+                id,
+                items.iter().map(|item| item.id.as_str()).collect_vec()
+            ),
             IStatement::DecUTuple(id) => write!(f, "DecUTuple({})", id),
+            IStatement::Drop(id, decs, incs) => {
+                write!(f, "Drop {} {:?} {:?}", id, decs, incs)
+            }
             IStatement::AssignUTupleField(id, i, ioperand) => {
                 write!(f, "{} = {}.{}", id, ioperand, i)
             }
+            IStatement::Label(label) => write!(f, "{}:", label),
+            IStatement::Jump(label) => write!(f, "jump {}", label),
         }
     }
 }
@@ -171,15 +284,23 @@ impl Display for IStatement {
 pub struct IDef {
     pub id: String,
     pub args: Vec<String>,
+    // The variables by slot, starting with the arguments
+    pub slots: Vec<String>,
     pub body: Vec<IStatement>,
 }
 
 impl IDef {
     pub fn from_def(def: &Def) -> Self {
+        let mut slots = Slots::default();
+        for arg in &def.args {
+            slots.var(arg);
+        }
+        let body = from_statements(def.body.clone(), &mut slots);
         IDef {
             id: def.id.clone(),
             args: def.args.clone(),
-            body: from_statements(def.body.clone()),
+            slots: slots.ids,
+            body,
         }
     }
 }
@@ -202,6 +323,22 @@ impl Display for IDef {
                         }
                     }
                 }
+                IStatement::Switch(operand, cases) => {
+                    write!(f, "{}", "    ".repeat(indent))?;
+                    writeln!(f, "switch {}:", operand)?;
+                    let n_cases = cases.len();
+                    for (i, statements) in cases.iter().enumerate() {
+                        write!(f, "{}", "    ".repeat(indent))?;
+                        writeln!(f, "case {}:", i)?;
+                        let n_statements = statements.len();
+                        for (j, statement) in statements.iter().enumerate() {
+                            write_indent(f, statement.clone(), indent + 1)?;
+                            if i < n_cases - 1 || j < n_statements - 1 {
+                                writeln!(f)?;
+                            }
+                        }
+                    }
+                }
                 _ => {
                     write!(f, "{}", "    ".repeat(indent))?;
                     write!(f, "{}", s)?;
@@ -1,13 +1,23 @@
+use super::allocator::{AllocStats, Allocator, FirstFit};
+use super::heap::Heap;
 use super::iast::*;
 use super::mempeek::MemObj;
+use crate::ast::ast;
 use crate::ast::{base::BaseSliceProgram, scoped::ScopedProgram, typed::TypedProgram};
-use crate::compiler::{self, compile::CompiledProgram, crux::Operator};
+use crate::compiler::{
+    self,
+    compile::{CompileOptions, CompiledProgram},
+    core::{HEADER_WORDS, StaticCell, header_tag},
+    crux::{Layouts, Operator, Repr},
+    intrinsics,
+};
 use crate::preprocessor::preprocess;
 use input::*;
 use itertools::Itertools;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
+use std::rc::Rc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
@@ -78,38 +88,135 @@ impl Data {
     }
 }
 
-fn make63bit(i: i64) -> i64 {
+pub fn make63bit(i: i64) -> i64 {
     let msb_c = (i >> 62) & 1;
     (i & !(1 << 63)) | (msb_c << 63)
 }
 
+// Runs an extern of the program in place of the C function it calls,
+// given the arguments as Ints
+pub type ExternFn = Rc<dyn Fn(&[i64]) -> i64>;
+
+// The statements of the branch a program counter is in
+fn branch_at<'a>(body: &'a [IStatement], pc: &[(usize, usize)]) -> &'a [IStatement] {
+    let mut statements = body;
+    for levels in pc.windows(2) {
+        statements = match &statements[levels[0].1] {
+            IStatement::IfExpr(items) => &items[levels[1].0].1,
+            IStatement::Switch(_, cases) => &cases[levels[1].0],
+            _ => panic!("Only ifs and switches have branches"),
+        };
+    }
+    statements
+}
+
+// A call of a function, with its variables in the slots they were given
+#[derive(Clone)]
+struct Frame {
+    function: Rc<IDef>,
+    // For every branch the frame is in, the one taken and the index of the
+    // statement in it. The levels further out point at the if or switch
+    // they went into
+    pc: Vec<(usize, usize)>,
+    slots: Vec<Option<Data>>,
+    // Cells of stack constructors, freed when the frame is left
+    cells: Vec<usize>,
+}
+
+impl Frame {
+    fn new(function: Rc<IDef>, passed_args: Vec<Data>) -> Self {
+        let mut slots = vec![None; function.slots.len()];
+        for (slot, arg) in slots.iter_mut().zip(passed_args) {
+            *slot = Some(arg);
+        }
+        Frame {
+            function,
+            pc: vec![(0, 0)],
+            slots,
+            cells: Vec::new(),
+        }
+    }
+
+    fn statement(&self) -> Option<&IStatement> {
+        branch_at(&self.function.body, &self.pc).get(self.pc.last().unwrap().1)
+    }
+
+    // The statement to run next, followed by the rest of every branch it is in
+    fn upcoming(&self) -> impl Iterator<Item = &IStatement> {
+        (1..=self.pc.len()).rev().flat_map(|depth| {
+            let (_, index) = self.pc[depth - 1];
+            let skip = if depth == self.pc.len() { 0 } else { 1 };
+            branch_at(&self.function.body, &self.pc[..depth])[index + skip..].iter()
+        })
+    }
+
+    fn leave_finished_branches(&mut self) {
+        while self.pc.len() > 1
+            && self.pc.last().unwrap().1 >= branch_at(&self.function.body, &self.pc).len()
+        {
+            self.pc.pop();
+            self.pc.last_mut().unwrap().1 += 1;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.pc.last_mut().unwrap().1 += 1;
+        self.leave_finished_branches();
+    }
+
+    fn enter(&mut self, branch: usize) {
+        self.pc.push((branch, 0));
+        self.leave_finished_branches();
+    }
+
+    // The label follows the match the jump is in, so whatever is left of the
+    // branch is skipped on the way there
+    fn jump(&mut self, label: &str) {
+        loop {
+            let (_, index) = *self.pc.last().unwrap();
+            let found = branch_at(&self.function.body, &self.pc)[index + 1..]
+                .iter()
+                .position(|s| matches!(s, IStatement::Label(l) if l == label));
+            if let Some(at) = found {
+                self.pc.last_mut().unwrap().1 = index + at + 2;
+                break;
+            }
+            if self.pc.len() == 1 {
+                panic!("Label '{}' should follow its jump", label);
+            }
+            self.pc.pop();
+        }
+        self.leave_finished_branches();
+    }
+}
+
 #[derive(Clone)]
 pub struct Interpreter {
-    functions: HashMap<String, IDef>,
-    heap: Vec<Vec<Data>>,
-    function_names_stack: Vec<String>,
-    statements: VecDeque<IStatement>,
-    statement_stack: Vec<VecDeque<IStatement>>,
-    local_variables: HashMap<String, Data>,
-    variable_stack: Vec<HashMap<String, Data>>,
+    functions: Rc<HashMap<String, Rc<IDef>>>,
+    heap: Heap,
+    // Shared with the copies the editor keeps, like the heap, and only
+    // copied when one of them changes
+    frames: Vec<Rc<Frame>>,
     return_value: Option<Data>,
     steps: u64,
     malloc_time: Duration,
+    layouts: Rc<Layouts>,
+    externs: Rc<HashMap<String, ExternFn>>,
+    random_state: u64,
 }
 // init
 impl Interpreter {
     pub fn new() -> Self {
         Interpreter {
-            functions: HashMap::new(),
-            heap: vec![Vec::new()],
-            function_names_stack: Vec::new(),
-            statements: VecDeque::new(),
-            statement_stack: Vec::new(),
-            local_variables: HashMap::new(),
-            variable_stack: Vec::new(),
+            functions: Rc::new(HashMap::new()),
+            heap: Heap::new(Box::new(FirstFit::default())),
+            frames: Vec::new(),
             return_value: None,
             steps: 0,
             malloc_time: Duration::ZERO,
+            layouts: Rc::default(),
+            externs: Rc::default(),
+            random_state: intrinsics::RANDOM_SEED,
         }
     }
 
@@ -118,17 +225,42 @@ impl Interpreter {
         self
     }
 
+    // Only before anything is on the heap, so the statics too
+    pub fn with_allocator(mut self, allocator: Box<dyn Allocator>) -> Self {
+        assert!(self.heap.stats().peak == 0, "The heap is already in use");
+        self.heap = Heap::new(allocator);
+        self
+    }
+
     pub fn from_program(program: &CompiledProgram) -> Self {
-        let mut interpreter = Interpreter::new();
-        for def in program.core.clone().0 {
-            interpreter = interpreter.with_fn(IDef::from_def(&def));
+        Interpreter::from_program_with(program, Box::new(FirstFit::default()))
+    }
+
+    pub fn from_program_with(program: &CompiledProgram, allocator: Box<dyn Allocator>) -> Self {
+        let mut interpreter = Interpreter::new().with_allocator(allocator);
+        for def in &program.core.0 {
+            interpreter = interpreter.with_fn(IDef::from_def(def));
+        }
+        for cell in &program.core.2 {
+            interpreter = interpreter.with_static(cell);
         }
         interpreter = interpreter.with_entry_point("main");
+        interpreter.layouts = Rc::new(program.layouts.clone());
         interpreter
     }
 
+    pub fn with_extern(mut self, id: &str, function: impl Fn(&[i64]) -> i64 + 'static) -> Self {
+        Rc::make_mut(&mut self.externs).insert(id.to_string(), Rc::new(function));
+        self
+    }
+
     pub fn with_fn(mut self, function: IDef) -> Self {
-        self.functions.insert(function.id.clone(), function);
+        Rc::make_mut(&mut self.functions).insert(function.id.clone(), Rc::new(function));
+        self
+    }
+
+    pub fn with_static(mut self, cell: &StaticCell) -> Self {
+        self.heap.add_static(cell);
         self
     }
 
@@ -141,10 +273,11 @@ impl Interpreter {
 impl Interpreter {
     fn eval_op(&self, op: &IOperand) -> i64 {
         make63bit(match op {
-            IOperand::Ident(id) => self.get_local_var(id).unwrap_val(),
+            IOperand::Ident(var) => self.get_local_var(var).unwrap_val(),
             IOperand::Int(i) => *i,
-            IOperand::Negate(id) => {
-                if self.get_local_var(id)._unwrap_raw() == 0 {
+            IOperand::Static(_) => panic!("Not a value"),
+            IOperand::Negate(var) => {
+                if self.get_local_var(var)._unwrap_raw() == 0 {
                     1
                 } else {
                     0
@@ -155,228 +288,244 @@ impl Interpreter {
 
     fn op_to_data(&self, op: &IOperand) -> Data {
         match op {
-            IOperand::Ident(id) => self.get_local_var(id),
+            IOperand::Ident(var) => self.get_local_var(var),
             IOperand::Int(i) => Data::Value(*i),
+            IOperand::Static(i) => Data::Pointer(self.heap.static_cell(*i)),
             IOperand::Negate(_) => panic!("Hoppsan"),
         }
     }
 
-    fn get_local_var(&self, id: &str) -> Data {
-        self.local_variables
-            .get(id)
-            .expect(&format!("Variable {id} not in scope"))
-            .clone()
+    fn frame(&mut self) -> &mut Frame {
+        Rc::make_mut(self.frames.last_mut().expect("No function is running"))
+    }
+
+    fn get_local_var(&self, var: &Var) -> Data {
+        self.frames.last().unwrap().slots[var.slot]
+            .unwrap_or_else(|| panic!("Variable {} not in scope", var.id))
+    }
+
+    fn set_local_var(&mut self, var: &Var, data: Data) {
+        self.frame().slots[var.slot] = Some(data);
     }
 
     fn malloc(&mut self, width: usize) -> Data {
         #[cfg(not(target_arch = "wasm32"))]
         sleep(self.malloc_time);
-        for n in 1..self.heap.len() {
-            if self.heap[n].is_empty() {
-                self.heap[n] = vec![Data::Value(0); width];
-                return Data::Pointer(n);
-            }
+        Data::Pointer(self.heap.alloc(width))
+    }
+
+    fn get_allocated_mem_size(&self) -> usize {
+        self.heap.stats().live
+    }
+
+    fn free_frame_cells(&mut self) {
+        for ptr in std::mem::take(&mut self.frame().cells) {
+            self.heap.free(ptr);
         }
-        self.heap.push(vec![Data::Value(0); width]);
-        Data::Pointer(self.heap.len() - 1)
     }
 
-    fn inc(&mut self, ptr: usize) {
-        self.heap[ptr][2].inc();
+    fn function(&self, name: &str) -> Rc<IDef> {
+        let f = self
+            .functions
+            .get(name)
+            .unwrap_or_else(|| panic!("Function '{}' should be in functions but is not", name));
+        Rc::clone(f)
     }
 
-    fn clean_memory(&mut self) {
-        // could make much more clean
-        let i = self.heap.iter().rposition(|x| !x.is_empty()).unwrap_or(0);
-        self.heap.truncate(i + 1);
+    fn enter_fn(&mut self, name: &str, passed_args: Vec<Data>) {
+        let f = self.function(name);
+        self.frames.push(Rc::new(Frame::new(f, passed_args)));
     }
 
-    fn get_allocated_mem_size(&self) -> usize {
-        self.heap.iter().map(|entry| entry.len()).sum()
+    // Like enter_fn but takes over the current frame, for calls in tail position
+    fn replace_fn(&mut self, name: &str, passed_args: Vec<Data>) {
+        self.free_frame_cells();
+        let f = self.function(name);
+        *self.frames.last_mut().unwrap() = Rc::new(Frame::new(f, passed_args));
     }
 
-    fn dec(&mut self, ptr: usize) {
-        self.heap[ptr][2].dec();
-        if self.heap[ptr][2].unwrap_val() == 0 {
-            for i in 3..self.heap[ptr].len() {
-                if let Data::Pointer(ptr) = self.heap[ptr][i] {
-                    self.dec(ptr);
+    // Runs the next statement, if there is one left
+    pub fn step(&mut self) -> bool {
+        let Some(frame) = self.frames.last() else {
+            return false;
+        };
+        // The statement is borrowed from the function, not the frame, as the
+        // frame changes while it runs
+        let function = Rc::clone(&frame.function);
+        let Some(statement) = branch_at(&function.body, &frame.pc).get(frame.pc.last().unwrap().1)
+        else {
+            return false;
+        };
+        self.steps += 1;
+        match statement {
+            IStatement::IfExpr(items) => {
+                match items
+                    .iter()
+                    .position(|(operand, _)| self.eval_op(operand) == 1)
+                {
+                    Some(branch) => self.frame().enter(branch),
+                    None => self.frame().advance(),
                 }
             }
-            self.heap[ptr] = Vec::new();
+            // The last case also takes any tag past it, like the default
+            // of the C switch
+            IStatement::Switch(iop, cases) => {
+                let data = self.op_to_data(iop);
+                let tag = if data.is_ptr() {
+                    header_tag(self.heap.header(data.unwrap_ptr()))
+                } else {
+                    data._unwrap_raw()
+                };
+                let last = cases.len() - 1;
+                self.frame().enter((tag as usize).min(last));
+            }
+            IStatement::Jump(label) => self.frame().jump(label),
+            statement => {
+                self.frame().advance();
+                self.execute(statement);
+            }
         }
+        true
     }
 
-    fn enter_fn(&mut self, name: &str, passed_args: Vec<Data>) {
-        let f = self.functions.get(name).expect(&format!(
-            "Function '{}' should be in functions but is not",
-            name
-        ));
-        self.function_names_stack.push(f.id.clone());
-        // std::mem::take could make it faster
-        self.statement_stack.push(self.statements.clone());
-        self.variable_stack.push(self.local_variables.clone());
-
-        self.statements = f.body.clone().into();
-        // beautiful code🦀
-        self.local_variables.clear();
-        self.local_variables
-            .extend(f.args.clone().into_iter().zip(passed_args));
-    }
-
-    pub fn step(&mut self) -> Option<IStatement> {
-        let s = self.statements.pop_front();
-        if let Some(statement) = s.clone() {
-            self.steps += 1;
-            match statement {
-                IStatement::IfExpr(items) => {
-                    for (operand, statements) in items {
-                        if self.eval_op(&operand) == 1 {
-                            // beautiful code🦀
-                            // inside_if ++ old_code
-                            self.statements.reserve(statements.len());
-                            for elem in statements.into_iter().rev() {
-                                self.statements.push_front(elem);
-                            }
-                            break;
-                        }
-                    }
-                }
-                IStatement::AssignMalloc(id, w) => {
-                    let ptr = self.malloc(w as usize);
-                    self.local_variables.insert(id.clone(), ptr);
-                }
-                IStatement::Return(ioperand) => {
-                    self.return_value = Some(self.op_to_data(&ioperand));
-                    self.statements = self.statement_stack.pop().expect("this should not happen");
-                    self.local_variables =
-                        self.variable_stack.pop().expect("this should not happen");
-                    self.function_names_stack.pop();
-                }
-                IStatement::Inc(ioperand) => {
-                    let id = ioperand.unwrap_id();
-                    let data = self.get_local_var(&id);
-                    if let Data::Pointer(ptr) = data {
-                        self.inc(ptr);
-                    }
-                }
-                IStatement::Dec(ioperand) => {
-                    let id = ioperand.unwrap_id();
-                    let data = self.get_local_var(&id);
-                    if let Data::Pointer(ptr) = data {
-                        self.dec(ptr);
-                    }
-                    self.clean_memory();
-                }
-                IStatement::Assign(id, ioperand) => {
-                    let val = self.op_to_data(&ioperand);
-                    self.local_variables.insert(id, val);
-                }
-                IStatement::AssignToField(id, i, ioperand) => {
-                    let ptr = self.get_local_var(&id).unwrap_ptr();
-                    let val = self.op_to_data(&ioperand);
-                    self.heap[ptr][i as usize] = val;
-                }
-                IStatement::AssignFromField(id, i, ioperand) => {
-                    let name = ioperand.unwrap_id();
-                    let ptr = self.get_local_var(&name).unwrap_ptr();
-                    let val = self.heap[ptr][i as usize];
-                    self.local_variables.insert(id, val);
-                }
-                IStatement::AssignBinaryOperation(id, operator, ioperand, ioperand1) => {
-                    let lhs = make63bit(self.eval_op(&ioperand));
-                    let rhs = make63bit(self.eval_op(&ioperand1));
-                    let val = match operator {
-                        Operator::Equal => (lhs == rhs) as i64,
-                        Operator::NotEqual => (lhs != rhs) as i64,
-                        Operator::Less => (lhs < rhs) as i64,
-                        Operator::LessOrEq => (lhs <= rhs) as i64,
-                        Operator::Greater => (lhs > rhs) as i64,
-                        Operator::GreaterOrEqual => (lhs >= rhs) as i64,
-                        Operator::Add => lhs + rhs,
-                        Operator::Sub => lhs - rhs,
-                        Operator::Mul => lhs * rhs,
-                        Operator::Div => lhs / rhs,
-                        Operator::Mod => lhs % rhs,
-                    };
-                    self.local_variables.insert(id, Data::Value(make63bit(val)));
-                }
-                IStatement::AssignTagCheck(id, b, iop, i) => {
-                    let val = if b {
-                        let name = iop.unwrap_id();
-                        let data = self.get_local_var(&name);
-                        data.is_ptr() && i == self.heap[data.unwrap_ptr()][0].unwrap_val()
-                    } else {
-                        i == self.op_to_data(&iop)._unwrap_raw()
-                    } as i64;
-                    self.local_variables.insert(id, Data::Value(val));
-                }
-                IStatement::FunctionCall(fid, ioperands) => {
-                    self.enter_fn(&fid, ioperands.iter().map(|x| self.op_to_data(x)).collect());
-                }
-                IStatement::AssignReturnvalue(id) => {
-                    self.local_variables.insert(id, self.return_value.unwrap());
-                    self.return_value = None;
-                }
-                IStatement::AssignDropReuse(id, id1) => {
-                    let reff = self.get_local_var(&id1);
-
-                    if let Data::Pointer(ptr) = reff {
-                        if self.heap[ptr][2].unwrap_val() == 1 {
-                            for i in 3..self.heap[ptr].len() {
-                                if self.heap[ptr][i].is_ptr() {
-                                    self.dec(self.heap[ptr][i].unwrap_ptr());
-                                }
-                            }
-                            self.local_variables.insert(id, Data::Pointer(ptr));
-                        } else {
-                            self.heap[ptr][2].dec();
-                            self.local_variables.insert(id, Data::Value(0));
-                        }
-                    } else {
-                        self.local_variables.insert(id, Data::Value(0));
-                    }
-                }
-                IStatement::AssignUTuple(len, id, items) => {
-                    let ptr = self.malloc(1 + len);
-                    self.local_variables.insert(id, ptr);
-                    let data = (-1..)
-                        .take(1)
-                        .map(|x| Data::Value(x))
-                        .chain(items.iter().map(|_id| self.get_local_var(_id)))
-                        .collect();
-                    self.heap[ptr.unwrap_ptr()] = data;
-                }
-                IStatement::DecUTuple(id) => {
-                    let ptr = self.get_local_var(&id).unwrap_ptr();
-                    for data in self.heap[ptr].clone().iter().skip(1) {
-                        if let Data::Pointer(_ptr) = data {
-                            self.dec(*_ptr);
-                        }
-                    }
-                    self.heap[ptr] = Vec::new();
-                    self.clean_memory();
+    fn execute(&mut self, statement: &IStatement) {
+        match statement {
+            IStatement::IfExpr(_) | IStatement::Switch(..) | IStatement::Jump(_) => {
+                unreachable!("Branches and jumps move the frame along themselves")
+            }
+            IStatement::AssignMalloc(id, w) => {
+                let ptr = self.malloc(*w as usize);
+                self.set_local_var(id, ptr);
+            }
+            IStatement::AssignStackAlloc(id, w) => {
+                let ptr = self.heap.alloc(*w as usize);
+                self.frame().cells.push(ptr);
+                self.set_local_var(id, Data::Pointer(ptr));
+            }
+            IStatement::Return(ioperand) => {
+                self.return_value = Some(self.op_to_data(ioperand));
+                self.free_frame_cells();
+                self.frames.pop();
+            }
+            IStatement::Inc(ioperand) => {
+                let data = self.get_local_var(ioperand.unwrap_var());
+                if let Data::Pointer(ptr) = data {
+                    self.heap.inc(ptr);
                 }
-                IStatement::AssignUTupleField(id, i, ioperand) => {
-                    let tuple_id = ioperand.unwrap_id();
-                    let ptr = self.get_local_var(&tuple_id);
-                    let data = self.heap[ptr.unwrap_ptr()][1 + i];
-                    self.local_variables.insert(id, data);
+            }
+            IStatement::Dec(ioperand) => {
+                let data = self.get_local_var(ioperand.unwrap_var());
+                if let Data::Pointer(ptr) = data {
+                    self.heap.dec(ptr);
                 }
             }
+            IStatement::Assign(id, ioperand) => {
+                let val = self.op_to_data(ioperand);
+                self.set_local_var(id, val);
+            }
+            IStatement::AssignToField(id, i, ioperand) => {
+                let ptr = self.get_local_var(id).unwrap_ptr();
+                let val = self.op_to_data(ioperand);
+                self.heap[ptr][*i as usize] = val;
+            }
+            IStatement::AssignFromField(id, i, ioperand) => {
+                let ptr = self.get_local_var(ioperand.unwrap_var()).unwrap_ptr();
+                let val = self.heap[ptr][*i as usize];
+                self.set_local_var(id, val);
+            }
+            IStatement::AssignBinaryOperation(id, operator, ioperand, ioperand1) => {
+                let lhs = make63bit(self.eval_op(ioperand));
+                let rhs = make63bit(self.eval_op(ioperand1));
+                let val = match operator {
+                    Operator::Equal => (lhs == rhs) as i64,
+                    Operator::NotEqual => (lhs != rhs) as i64,
+                    Operator::Less => (lhs < rhs) as i64,
+                    Operator::LessOrEq => (lhs <= rhs) as i64,
+                    Operator::Greater => (lhs > rhs) as i64,
+                    Operator::GreaterOrEqual => (lhs >= rhs) as i64,
+                    Operator::Add => lhs + rhs,
+                    Operator::Sub => lhs - rhs,
+                    Operator::Mul => lhs * rhs,
+                    Operator::Div => lhs / rhs,
+                    Operator::Mod => lhs % rhs,
+                };
+                self.set_local_var(id, Data::Value(make63bit(val)));
+            }
+            IStatement::FunctionCall(fid, ioperands) => {
+                self.enter_fn(fid, ioperands.iter().map(|x| self.op_to_data(x)).collect());
+            }
+            IStatement::AssignExternCall(id, fid, ioperands) => {
+                let args = ioperands
+                    .iter()
+                    .map(|x| make63bit(self.eval_op(x)))
+                    .collect::<Vec<_>>();
+                let function = self
+                    .externs
+                    .get(fid)
+                    .unwrap_or_else(|| panic!("Extern '{}' should be registered but is not", fid));
+                let val = make63bit(function(&args));
+                self.set_local_var(id, Data::Value(val));
+            }
+            IStatement::AssignIntrinsic(id, fid, ioperands) => {
+                let args = ioperands
+                    .iter()
+                    .map(|x| make63bit(self.eval_op(x)))
+                    .collect::<Vec<_>>();
+                let intrinsic =
+                    intrinsics::get(fid).unwrap_or_else(|| panic!("Unknown intrinsic '{}'", fid));
+                let val = (intrinsic.eval)(&mut self.random_state, &args);
+                self.set_local_var(id, Data::Value(make63bit(val)));
+            }
+            IStatement::TailCall(fid, ioperands) => {
+                let args = ioperands.iter().map(|x| self.op_to_data(x)).collect();
+                self.replace_fn(fid, args);
+            }
+            IStatement::AssignReturnvalue(id) => {
+                self.set_local_var(id, self.return_value.unwrap());
+                self.return_value = None;
+            }
+            IStatement::AssignDropReuse(id, id1, kept) => {
+                let reused = self.heap.drop_reuse(self.get_local_var(id1), kept);
+                self.set_local_var(id, reused);
+            }
+            IStatement::AssignUTuple(len, id, items) => {
+                let ptr = self.malloc(1 + len);
+                self.set_local_var(id, ptr);
+                let data = (-1..)
+                    .take(1)
+                    .map(|x| Data::Value(x))
+                    .chain(items.iter().map(|var| self.get_local_var(var)))
+                    .collect();
+                self.heap[ptr.unwrap_ptr()] = data;
+            }
+            IStatement::DecUTuple(id) => {
+                let ptr = self.get_local_var(id).unwrap_ptr();
+                self.heap.dec_tuple(ptr);
+            }
+            IStatement::Drop(id, decs, incs) => {
+                let ptr = self.get_local_var(id).unwrap_ptr();
+                self.heap.drop(ptr, decs, incs);
+            }
+            IStatement::Label(_) => (),
+            IStatement::AssignUTupleField(id, i, ioperand) => {
+                let ptr = self.get_local_var(ioperand.unwrap_var());
+                let data = self.heap[ptr.unwrap_ptr()][1 + i];
+                self.set_local_var(id, data);
+            }
         }
-        s
     }
 }
 // running until
 impl Interpreter {
     pub fn run_until_next_mem(&mut self) {
         self.step();
-        while let Some(s) = self.statements.get(0) {
+        while let Some(s) = self.get_statement() {
             match s {
                 IStatement::AssignMalloc(..)
+                | IStatement::AssignStackAlloc(..)
                 | IStatement::Inc(_)
                 | IStatement::Dec(_)
+                | IStatement::Drop(..)
                 | IStatement::AssignToField(..) => {
                     break;
                 }
@@ -389,8 +538,8 @@ impl Interpreter {
 
     pub fn run_until_next_ptr(&mut self) {
         self.step();
-        while let Some(s) = self.statements.get(0) {
-            if let IStatement::AssignMalloc(_, _) = s {
+        while let Some(s) = self.get_statement() {
+            if let IStatement::AssignMalloc(_, _) | IStatement::AssignStackAlloc(_, _) = s {
                 break;
             } else if let IStatement::Dec(op) = s {
                 match *op {
@@ -410,8 +559,8 @@ impl Interpreter {
 
     pub fn run_until_delta_data(&mut self) {
         self.step();
-        while let Some(s) = self.statements.get(0) {
-            if let IStatement::AssignMalloc(_, _) = s {
+        while let Some(s) = self.get_statement() {
+            if let IStatement::AssignMalloc(_, _) | IStatement::AssignStackAlloc(_, _) = s {
                 break;
             } else if let IStatement::Dec(op) = s {
                 match *op {
@@ -421,7 +570,7 @@ impl Interpreter {
                     _ => (),
                 }
             } else if let IStatement::AssignToField(id, i, op) = s {
-                if *i > 2 {
+                if *i >= HEADER_WORDS {
                     let ptr = self.get_local_var(id).unwrap_ptr();
                     if self.heap[ptr][*i as usize] != self.op_to_data(op) {
                         break;
@@ -433,21 +582,21 @@ impl Interpreter {
     }
 
     pub fn run_until_done(&mut self) {
-        while let Some(_) = self.step() {}
+        while self.step() {}
     }
 
     pub fn run_until_return(&mut self) {
-        let s = self.function_names_stack.len();
+        let s = self.frames.len();
 
-        while self.function_names_stack.len() >= s && !self.statements.is_empty() {
+        while self.frames.len() >= s && self.get_statement().is_some() {
             self.step();
         }
     }
 
     pub fn run_step_over(&mut self) {
-        let s = self.function_names_stack.len();
+        let s = self.frames.len();
         self.step();
-        while self.function_names_stack.len() > s && !self.statements.is_empty() {
+        while self.frames.len() > s && self.get_statement().is_some() {
             self.step();
         }
     }
@@ -456,11 +605,14 @@ impl Interpreter {
 #[allow(unused)]
 impl Interpreter {
     pub fn get_memory_raw(&self) -> Vec<Vec<Data>> {
-        self.heap.clone()
+        self.heap.iter().cloned().collect()
     }
 
     pub fn get_function_names_stack(&self) -> Vec<String> {
-        self.function_names_stack.clone()
+        self.frames
+            .iter()
+            .map(|frame| frame.function.id.clone())
+            .collect()
     }
 
     pub fn get_return_value(&self) -> Option<Data> {
@@ -474,12 +626,12 @@ impl Interpreter {
         }
 
         let data = self.heap[ptr].clone();
-        let tag = data[0].unwrap_val();
+        let tag = header_tag(data[0].unwrap_val());
         let tag = ('A' as u8) + tag as u8;
         let tag = tag as char;
         let rest = data
             .iter()
-            .skip(3)
+            .skip(HEADER_WORDS as usize)
             .map(|x| match x {
                 Data::Value(val) => format!("{}", val),
                 Data::Pointer(ptr) => self.get_heap_format(*ptr),
@@ -513,27 +665,137 @@ impl Interpreter {
 
     pub fn get_return_format(&self) -> String {
         if let Some(data) = self.get_return_value() {
-            self.get_data_format(data)
+            match &self.layouts.result[..] {
+                [] => self.get_data_format(data),
+                [typ] => rebox(&self.heap, &self.layouts, &data, typ).format(),
+                types => format!(
+                    "({})",
+                    self.heap[data.unwrap_ptr()]
+                        .iter()
+                        .skip(1)
+                        .zip(types)
+                        .map(|(field, typ)| rebox(&self.heap, &self.layouts, field, typ).format())
+                        .join(", ")
+                ),
+            }
         } else {
             panic!("Dont use this when the interpreter has not finished");
         }
     }
 
+    // The result the way a compiled C program prints it
+    pub fn get_return_named_format(&self) -> String {
+        let Some(data) = self.get_return_value() else {
+            panic!("Dont use this when the interpreter has not finished");
+        };
+        named_format(&self.heap, &self.layouts, data)
+    }
+
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn get_alloc_stats(&self) -> AllocStats {
+        self.heap.stats()
+    }
+
+    // The statement the next step runs
+    pub fn get_statement(&self) -> Option<&IStatement> {
+        self.frames.last()?.statement()
+    }
+
     pub fn get_statements(&self) -> Vec<IStatement> {
-        self.statements.clone().into_iter().collect::<Vec<_>>()
+        match self.frames.last() {
+            Some(frame) => frame.upcoming().cloned().collect(),
+            None => Vec::new(),
+        }
     }
 
     pub fn get_variables_raw(&self) -> Vec<(String, Data)> {
-        let mut list = self.local_variables.clone().into_iter().collect_vec();
+        let Some(frame) = self.frames.last() else {
+            return Vec::new();
+        };
+        let mut list = frame
+            .function
+            .slots
+            .iter()
+            .zip(&frame.slots)
+            .filter_map(|(id, data)| Some((id.clone(), (*data)?)))
+            .collect_vec();
         list.sort_by(|(a, _), (b, _)| a.cmp(b));
         list
     }
 
+    fn get_variable(&self, id: &str) -> Option<Data> {
+        let frame = self.frames.last()?;
+        let slot = frame.function.slots.iter().position(|other| other == id)?;
+        frame.slots[slot]
+    }
+
     pub fn get_variable_json(&self, id: &str) -> String {
-        if !self.local_variables.contains_key(id) {
-            return "{}".to_string();
+        match self.get_variable(id) {
+            Some(data) => MemObj::from_data(&data, &self.heap).as_json(),
+            None => "{}".to_string(),
+        }
+    }
+}
+
+// Rebuilds a value the way it would look boxed, so that unboxed
+// representations print like everything else
+pub fn rebox(heap: &Heap, layouts: &Layouts, data: &Data, typ: &ast::Type) -> MemObj {
+    let ast::Type::ADT(adt) = typ else {
+        return MemObj::Value(data.unwrap_val());
+    };
+    let (repr, ctors) = &layouts.adts[adt];
+    match repr {
+        Repr::Newtype => MemObj::cell(0, vec![rebox(heap, layouts, data, &ctors[0][0])]),
+        Repr::Immediate if data.unwrap_val() % 2 == 0 => {
+            let tag = ctors
+                .iter()
+                .positions(|fields| fields.is_empty())
+                .nth((data.unwrap_val() / 2) as usize)
+                .unwrap();
+            MemObj::Value(tag as i64)
         }
-        MemObj::from_data(&self.get_local_var(id), &self.heap).as_json()
+        Repr::Immediate => {
+            let tag = ctors.iter().position(|fields| !fields.is_empty()).unwrap();
+            let field = Data::Value((data.unwrap_val() - 1) / 2);
+            MemObj::cell(
+                tag as i64,
+                vec![rebox(heap, layouts, &field, &ctors[tag][0])],
+            )
+        }
+        Repr::Boxed => match data {
+            Data::Value(tag) => MemObj::Value(*tag),
+            Data::Pointer(ptr) => {
+                let cell = &heap[*ptr];
+                let tag = header_tag(cell[0].unwrap_val());
+                MemObj::cell(
+                    tag,
+                    cell.iter()
+                        .skip(HEADER_WORDS as usize)
+                        .zip(&ctors[tag as usize])
+                        .map(|(field, typ)| rebox(heap, layouts, field, typ))
+                        .collect(),
+                )
+            }
+        },
+    }
+}
+
+// A result the way a compiled C program prints it
+pub fn named_format(heap: &Heap, layouts: &Layouts, data: Data) -> String {
+    match &layouts.result[..] {
+        [typ] => rebox(heap, layouts, &data, typ).format_named(typ, layouts),
+        types => format!(
+            "({})",
+            heap[data.unwrap_ptr()]
+                .iter()
+                .skip(1)
+                .zip(types)
+                .map(|(field, typ)| rebox(heap, layouts, field, typ).format_named(typ, layouts))
+                .join(", ")
+        ),
     }
 }
 
@@ -558,17 +820,20 @@ impl Debug for Interpreter {
             "{:=^50}",
             format!(
                 " Interpreter Debug Print | Inside '{}' ",
-                self.function_names_stack.last().unwrap_or(&"".to_string())
+                self.frames
+                    .last()
+                    .map_or("", |frame| frame.function.id.as_str())
             )
         )?;
 
-        let bruh = format!("{}", self.heap.len()).len();
+        let bruh = format!("{}", self.heap.iter().len()).len();
         let heap_lines = vec!["Heap data:".to_string()]
             .into_iter()
             .chain(
                 self.heap
                     .iter()
                     .enumerate()
+                    .filter(|(_, m)| !m.is_empty())
                     .map(|(i, m)| format!("{:>bruh$}  {:?}", i, m)),
             )
             .collect_vec();
@@ -588,8 +853,10 @@ impl Debug for Interpreter {
         let combined = concat_columns(&heap_lines, &vars_lines, " | ");
 
         let statements_lines = self
-            .statements
-            .iter()
+            .frames
+            .last()
+            .into_iter()
+            .flat_map(|frame| frame.upcoming())
             .map(|s| format!("{}", s))
             .chain(vec!["...".to_string()].into_iter().cycle())
             .take(15)
@@ -601,9 +868,13 @@ impl Debug for Interpreter {
             writeln!(f, "{}", line).unwrap();
         }
 
-        writeln!(f, "Statement stack:")?;
-        let sizes: Vec<_> = self.statement_stack.iter().map(|d| d.len()).collect();
-        writeln!(f, "{:?}", sizes)?;
+        writeln!(f, "Call stack:")?;
+        let pcs: Vec<_> = self
+            .frames
+            .iter()
+            .map(|frame| format!("{} {:?}", frame.function.id, frame.pc))
+            .collect();
+        writeln!(f, "{:?}", pcs)?;
 
         Ok(())
     }
@@ -620,10 +891,15 @@ impl Debug for Data {
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn _compile_string(code: String) -> CompiledProgram {
+    _compile_string_with(code, &CompileOptions::default())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn _compile_string_with(code: String, options: &CompileOptions) -> CompiledProgram {
     let base_program = BaseSliceProgram::new(&code).unwrap();
     let scoped_program = ScopedProgram::new(base_program).unwrap();
     let typed_program = TypedProgram::new(scoped_program).unwrap();
-    let compiled = compiler::compile::compile_typed(&typed_program);
+    let compiled = compiler::compile::compile_typed_with(&typed_program, options);
     compiled
 }
 
@@ -646,12 +922,12 @@ where
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn interpreter_test<P>(path: P)
+pub fn interpreter_test<P>(path: P, allocator: &dyn Allocator)
 where
     P: AsRef<Path>,
 {
     let core_ir = _compile(path);
-    let mut interpreter = Interpreter::from_program(&core_ir);
+    let mut interpreter = Interpreter::from_program_with(&core_ir, allocator.boxed());
     let mut history = Vec::new();
     loop {
         println!("\n\n\n");
@@ -688,8 +964,8 @@ where
                     println!("{s}");
                 }
             }
-            x if interpreter.local_variables.contains_key(x) => {
-                let data = interpreter.get_local_var(x);
+            x if interpreter.get_variable(x).is_some() => {
+                let data = interpreter.get_variable(x).unwrap();
                 let obj = MemObj::from_data(&data, &interpreter.heap);
                 if obj.is_list() {
                     println!("{}", obj.list_string())
@@ -707,12 +983,12 @@ where
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn interpreter_bench<P>(path: P)
+pub fn interpreter_bench<P>(path: P, allocator: &dyn Allocator)
 where
     P: AsRef<Path>,
 {
     let core_ir = _compile(path);
-    let mut interpreter = Interpreter::from_program(&core_ir);
+    let mut interpreter = Interpreter::from_program_with(&core_ir, allocator.boxed());
     let now = Instant::now();
     interpreter.run_until_done();
     let elapsed = now.elapsed();
@@ -725,38 +1001,31 @@ where
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn interpreter_bench_fip<P>(path: P, malloc_time: Duration) -> String
+pub fn interpreter_bench_fip<P>(path: P, malloc_time: Duration, allocator: &dyn Allocator) -> String
 where
     P: AsRef<Path>,
 {
     assert!(path.as_ref().is_dir());
 
-    fn test(compiled: CompiledProgram, malloc_time: Duration) -> String {
-        let mut interpreter = Interpreter::from_program(&compiled).with_malloc_time(malloc_time);
+    let test = |compiled: CompiledProgram, malloc_time: Duration| -> String {
+        let mut interpreter = Interpreter::from_program_with(&compiled, allocator.boxed())
+            .with_malloc_time(malloc_time);
         let now = Instant::now();
         interpreter.run_until_done();
         let elapsed = now.elapsed();
         let steps = interpreter.steps;
-
-        interpreter = Interpreter::from_program(&compiled);
-        let mut max_mem = 0;
-        while let Some(x) = interpreter.step() {
-            match x {
-                IStatement::AssignMalloc(_, _) | IStatement::AssignUTuple(_, _, _) => {
-                    max_mem = max_mem.max(interpreter.get_allocated_mem_size());
-                }
-                _ => (),
-            }
-        }
+        let stats = interpreter.heap.stats();
 
         format!(
-            "{}, {}, {}, {}",
+            "{}, {}, {}, {}, {}, {:.3}",
             elapsed.as_micros() as f64 / 1000.,
             steps,
             (steps as u128 * 1_000_000) / elapsed.as_micros(),
-            max_mem
+            stats.peak,
+            stats.peak_footprint,
+            stats.peak_fragmentation()
         )
-    }
+    };
     let mut lines = Vec::new();
     for entry in path.as_ref().read_dir().unwrap() {
         if let Ok(file) = entry {
@@ -770,7 +1039,7 @@ where
                 "{:?}, fip, {}, {}",
                 file.file_name(),
                 malloc_time.as_micros(),
-                test(_compile_string(code), malloc_time)
+                test(_compile_string(code.clone()), malloc_time)
             );
             lines.push(fip);
 
@@ -778,10 +1047,44 @@ where
                 "{:?}, nofip, {}, {}",
                 file.file_name(),
                 malloc_time.as_micros(),
-                test(_compile_string(code_nofip), malloc_time)
+                test(_compile_string(code_nofip.clone()), malloc_time)
             );
             lines.push(nofip);
 
+            let noreuse = format!(
+                "{:?}, noreuse, {}, {}",
+                file.file_name(),
+                malloc_time.as_micros(),
+                test(
+                    _compile_string_with(
+                        code_nofip,
+                        &CompileOptions {
+                            reuse_fip_only: true,
+                            ..Default::default()
+                        }
+                    ),
+                    malloc_time
+                )
+            );
+            lines.push(noreuse);
+
+            let nofuse = format!(
+                "{:?}, nofuse, {}, {}",
+                file.file_name(),
+                malloc_time.as_micros(),
+                test(
+                    _compile_string_with(
+                        code.clone(),
+                        &CompileOptions {
+                            fuse_rc: false,
+                            ..Default::default()
+                        }
+                    ),
+                    malloc_time
+                )
+            );
+            lines.push(nofuse);
+
             let scoped_rc = format!(
                 "{:?}, sc_rc, {}, {}",
                 file.file_name(),
@@ -795,22 +1098,32 @@ where
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn interpreter_bench_peak_mem<P>(path: P)
+pub fn interpreter_bench_peak_mem<P>(path: P, allocator: &dyn Allocator)
 where
     P: AsRef<Path>,
 {
     let core_ir = _compile(path);
-    let mut interpreter = Interpreter::from_program(&core_ir);
-    let mut max_mem = 0;
-    while let Some(x) = interpreter.step() {
-        match x {
-            IStatement::AssignMalloc(_, _) | IStatement::AssignUTuple(_, _, _) => {
-                max_mem = max_mem.max(interpreter.get_allocated_mem_size());
-            }
-            _ => (),
+    let mut interpreter = Interpreter::from_program_with(&core_ir, allocator.boxed());
+    let mut max_depth = 0;
+    while let Some(statement) = interpreter.get_statement() {
+        let calls = matches!(statement, IStatement::FunctionCall(_, _));
+        interpreter.step();
+        if calls {
+            max_depth = max_depth.max(interpreter.frames.len());
         }
     }
 
-    println!("Peak memory was {} words", max_mem);
-    println!("Heap left: {} words", interpreter.get_allocated_mem_size())
+    let stats = interpreter.heap.stats();
+    println!("Peak memory was {} words", stats.peak);
+    println!(
+        "Peak heap size was {} words, {:.1}% fragmented at the peak",
+        stats.peak_footprint,
+        stats.peak_fragmentation() * 100.
+    );
+    println!("Peak stack depth was {} frames", max_depth);
+    println!(
+        "Heap left: {} words, {:.1}% fragmented",
+        interpreter.get_allocated_mem_size(),
+        stats.fragmentation() * 100.
+    )
 }
@@ -1,4 +1,8 @@
+use super::heap::Heap;
 use super::interpreter::Data;
+use crate::ast::ast::Type;
+use crate::compiler::core::{header, header_tag, HEADER_WORDS};
+use crate::compiler::crux::Layouts;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
@@ -11,7 +15,7 @@ pub struct MemPeek {
 impl MemPeek {
     fn is_list(&self) -> bool {
         match &self.data[..] {
-            [_, _, _, x, p] => x.is_val() && match p {
+            [_, x, p] => x.is_val() && match p {
                 MemObj::Value(0) => true,
                 MemObj::Value(_) => false,
                 MemObj::Pointer(p) => p.is_list()
@@ -45,7 +49,7 @@ impl MemObj {
 
 impl MemObj {
     // Infinite loop on cycles
-    pub fn from_data(x: &Data, mem: &Vec<Vec<Data>>) -> Self {
+    pub fn from_data(x: &Data, mem: &Heap) -> Self {
         match x {
             Data::Value(i) => MemObj::Value(*i),
             Data::Pointer(n) => MemObj::Pointer(Box::new({
@@ -78,8 +82,8 @@ impl MemObj {
             MemObj::Pointer(mem_peek) => mem_peek,
         };
         loop {
-            vec.push(data.data[3].unwrap_val()); 
-            match &data.data[4] {
+            vec.push(data.data[1].unwrap_val()); 
+            match &data.data[2] {
                 MemObj::Value(_) => {break;},
                 MemObj::Pointer(mem_peek) => {data = mem_peek},
             } 
@@ -90,4 +94,45 @@ impl MemObj {
     pub fn list_string(&self) -> String {
         format!("[{}]", self.list().iter().map(|x| x.to_string()).join(", "))
     }
+
+    // A cell that is not on the heap, with the header it would have there
+    pub fn cell(tag: i64, fields: Vec<MemObj>) -> Self {
+        let mut data = vec![MemObj::Value(header(tag as u8, fields.len() as u8))];
+        data.extend(fields);
+        MemObj::Pointer(Box::new(MemPeek { data }))
+    }
+
+    // Formatted like the interpreter formats its results
+    pub fn format(&self) -> String {
+        match self {
+            MemObj::Value(x) => x.to_string(),
+            MemObj::Pointer(_) if self.is_list() => self.list_string(),
+            MemObj::Pointer(mem_peek) => {
+                let tag = (b'A' + header_tag(mem_peek.data[0].unwrap_val()) as u8) as char;
+                let rest = mem_peek.data.iter().skip(1).map(|x| x.format()).join(", ");
+                format!("[{}: {}]", tag, rest)
+            }
+        }
+    }
+
+    // Formatted with constructor names, like compiled programs print their
+    // results. Expects a reboxed value of the type
+    pub fn format_named(&self, typ: &Type, layouts: &Layouts) -> String {
+        let Type::ADT(adt) = typ else {
+            return self.format();
+        };
+        let names = &layouts.names[adt];
+        match self {
+            MemObj::Value(tag) => names[*tag as usize].clone(),
+            MemObj::Pointer(mem_peek) => {
+                let tag = header_tag(mem_peek.data[0].unwrap_val()) as usize;
+                let rest = mem_peek.data[HEADER_WORDS as usize..]
+                    .iter()
+                    .zip(&layouts.adts[adt].1[tag])
+                    .map(|(x, typ)| x.format_named(typ, layouts))
+                    .join(", ");
+                format!("{}({})", names[tag], rest)
+            }
+        }
+    }
 }
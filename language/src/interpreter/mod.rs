@@ -1,4 +1,7 @@
 mod iast;
+pub mod allocator;
+pub mod heap;
 pub mod interpreter;
 pub use interpreter::*;
 pub mod mempeek;
+pub mod vm;
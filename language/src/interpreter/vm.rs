@@ -0,0 +1,603 @@
+use super::allocator::{AllocStats, Allocator, FirstFit};
+use super::heap::Heap;
+use super::iast::{IDef, IOperand, IStatement, Var};
+use super::interpreter::{Data, ExternFn, make63bit, named_format};
+use crate::compiler::{
+    compile::CompiledProgram,
+    core::header_tag,
+    crux::{Layouts, Operator},
+    intrinsics,
+};
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[cfg(not(target_arch = "wasm32"))]
+use {super::interpreter::_compile, std::path::Path, std::time::Instant};
+
+// An operand with its variable resolved to a register of the frame
+#[derive(Debug, Clone, Copy)]
+pub enum Src {
+    Reg(u32),
+    Not(u32),
+    Int(i64),
+    Static(u32),
+}
+
+// One op for every statement the interpreter steps through, so both count
+// the same steps. Only Goto is extra: it ends a branch of an If or a Switch
+// by skipping the branches after it, and is not counted
+#[derive(Debug, Clone)]
+pub enum Op {
+    If(Box<[(Src, u32)]>, u32),
+    Switch(Src, Box<[u32]>),
+    Goto(u32),
+    Return(Src),
+    Malloc(u32, u32),
+    StackAlloc(u32, u32),
+    Move(u32, Src),
+    Store(u32, u32, Src),
+    Load(u32, u32, u32),
+    Binary(u32, Operator, Src, Src),
+    Call(u32, Box<[Src]>),
+    Result(u32),
+    TailCall(u32, Box<[Src]>),
+    Extern(u32, u32, Box<[Src]>),
+    Intrinsic(u32, intrinsics::Eval, Box<[Src]>),
+    DropReuse(u32, u32, Box<[u8]>),
+    Inc(u32),
+    Dec(u32),
+    Tuple(u32, Box<[u32]>),
+    DecTuple(u32),
+    TupleField(u32, u32, u32),
+    Drop(u32, Box<[u8]>, Box<[u8]>),
+    Label,
+    Jump(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    // The arguments come first
+    pub registers: usize,
+    pub code: Vec<Op>,
+}
+
+// The slots of the variables are their registers
+fn register(var: &Var) -> u32 {
+    var.slot as u32
+}
+
+struct Compiler<'a> {
+    functions: &'a HashMap<String, u32>,
+    externs: &'a mut Vec<String>,
+    code: Vec<Op>,
+    // Jumps waiting for the label they go to
+    jumps: HashMap<String, Vec<usize>>,
+}
+
+impl Compiler<'_> {
+    fn src(&self, operand: &IOperand) -> Src {
+        match operand {
+            IOperand::Ident(var) => Src::Reg(register(var)),
+            IOperand::Negate(var) => Src::Not(register(var)),
+            IOperand::Int(i) => Src::Int(*i),
+            IOperand::Static(i) => Src::Static(*i as u32),
+        }
+    }
+
+    fn srcs(&self, operands: &[IOperand]) -> Box<[Src]> {
+        operands.iter().map(|operand| self.src(operand)).collect()
+    }
+
+    fn function(&self, id: &str) -> u32 {
+        *self
+            .functions
+            .get(id)
+            .unwrap_or_else(|| panic!("Function '{}' should be in functions but is not", id))
+    }
+
+    fn extern_index(&mut self, id: &str) -> u32 {
+        match self.externs.iter().position(|other| other == id) {
+            Some(i) => i as u32,
+            None => {
+                self.externs.push(id.to_string());
+                self.externs.len() as u32 - 1
+            }
+        }
+    }
+
+    // The branches follow the op that picks one of them, and all but the
+    // last end by going past the others
+    fn branches(&mut self, branches: &[&[IStatement]]) -> (Vec<u32>, u32) {
+        let mut starts = Vec::new();
+        let mut gotos = Vec::new();
+        for (i, statements) in branches.iter().enumerate() {
+            starts.push(self.code.len() as u32);
+            self.statements(statements);
+            if i < branches.len() - 1 {
+                gotos.push(self.code.len());
+                self.code.push(Op::Goto(0));
+            }
+        }
+        let end = self.code.len() as u32;
+        for at in gotos {
+            self.code[at] = Op::Goto(end);
+        }
+        (starts, end)
+    }
+
+    fn statements(&mut self, statements: &[IStatement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &IStatement) {
+        let op = match statement {
+            IStatement::IfExpr(items) => {
+                let at = self.code.len();
+                self.code.push(Op::Label);
+                let conditions = items
+                    .iter()
+                    .map(|(operand, _)| self.src(operand))
+                    .collect_vec();
+                let branches = items.iter().map(|(_, body)| &body[..]).collect_vec();
+                let (starts, end) = self.branches(&branches);
+                self.code[at] = Op::If(conditions.into_iter().zip(starts).collect(), end);
+                return;
+            }
+            IStatement::Switch(operand, cases) => {
+                let at = self.code.len();
+                self.code.push(Op::Label);
+                let src = self.src(operand);
+                let branches = cases.iter().map(|body| &body[..]).collect_vec();
+                let (starts, _) = self.branches(&branches);
+                self.code[at] = Op::Switch(src, starts.into());
+                return;
+            }
+            IStatement::Return(operand) => Op::Return(self.src(operand)),
+            IStatement::AssignMalloc(id, w) => Op::Malloc(register(id), *w),
+            IStatement::AssignStackAlloc(id, w) => Op::StackAlloc(register(id), *w),
+            IStatement::Assign(id, operand) => Op::Move(register(id), self.src(operand)),
+            IStatement::AssignToField(id, i, operand) => {
+                Op::Store(register(id), *i as u32, self.src(operand))
+            }
+            IStatement::AssignFromField(id, i, operand) => {
+                Op::Load(register(id), register(operand.unwrap_var()), *i as u32)
+            }
+            IStatement::AssignBinaryOperation(id, operator, lhs, rhs) => {
+                Op::Binary(register(id), *operator, self.src(lhs), self.src(rhs))
+            }
+            IStatement::FunctionCall(fid, operands) => {
+                Op::Call(self.function(fid), self.srcs(operands))
+            }
+            IStatement::AssignReturnvalue(id) => Op::Result(register(id)),
+            IStatement::TailCall(fid, operands) => {
+                Op::TailCall(self.function(fid), self.srcs(operands))
+            }
+            IStatement::AssignExternCall(id, fid, operands) => {
+                Op::Extern(register(id), self.extern_index(fid), self.srcs(operands))
+            }
+            IStatement::AssignIntrinsic(id, fid, operands) => {
+                let intrinsic =
+                    intrinsics::get(fid).unwrap_or_else(|| panic!("Unknown intrinsic '{}'", fid));
+                Op::Intrinsic(register(id), intrinsic.eval, self.srcs(operands))
+            }
+            IStatement::AssignDropReuse(id, id1, kept) => {
+                Op::DropReuse(register(id), register(id1), kept.clone().into())
+            }
+            IStatement::Inc(operand) => Op::Inc(register(operand.unwrap_var())),
+            IStatement::Dec(operand) => Op::Dec(register(operand.unwrap_var())),
+            IStatement::AssignUTuple(_, id, items) => {
+                Op::Tuple(register(id), items.iter().map(register).collect())
+            }
+            IStatement::DecUTuple(id) => Op::DecTuple(register(id)),
+            IStatement::AssignUTupleField(id, i, operand) => {
+                Op::TupleField(register(id), register(operand.unwrap_var()), *i as u32)
+            }
+            IStatement::Drop(id, decs, incs) => {
+                Op::Drop(register(id), decs.clone().into(), incs.clone().into())
+            }
+            // Jumping to a label skips it, like the interpreter does
+            IStatement::Label(label) => {
+                let target = self.code.len() as u32 + 1;
+                for at in self.jumps.remove(label).unwrap_or_default() {
+                    self.code[at] = Op::Jump(target);
+                }
+                Op::Label
+            }
+            IStatement::Jump(label) => {
+                self.jumps
+                    .entry(label.clone())
+                    .or_default()
+                    .push(self.code.len());
+                Op::Jump(0)
+            }
+        };
+        self.code.push(op);
+    }
+}
+
+fn compile(def: &IDef, functions: &HashMap<String, u32>, externs: &mut Vec<String>) -> Function {
+    let mut compiler = Compiler {
+        functions,
+        externs,
+        code: Vec::new(),
+        jumps: HashMap::new(),
+    };
+    compiler.statements(&def.body);
+    if let Some(label) = compiler.jumps.keys().next() {
+        panic!("Label '{}' should follow its jumps in '{}'", label, def.id);
+    }
+    Function {
+        registers: def.slots.len(),
+        code: compiler.code,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    function: usize,
+    // Where its registers start
+    base: usize,
+    // Where its stack cells start
+    cells: usize,
+    return_pc: usize,
+}
+
+// Runs the program like the interpreter, but on bytecode with the variables
+// in registers, for when only the result and the steps are of interest
+#[derive(Clone)]
+pub struct Vm {
+    functions: Rc<[Function]>,
+    heap: Heap,
+    registers: Vec<Data>,
+    frames: Vec<Frame>,
+    frame_cells: Vec<usize>,
+    pc: usize,
+    base: usize,
+    return_value: Option<Data>,
+    steps: u64,
+    layouts: Layouts,
+    extern_ids: Vec<String>,
+    externs: Vec<Option<ExternFn>>,
+    random_state: u64,
+}
+// init
+impl Vm {
+    pub fn from_program(program: &CompiledProgram) -> Self {
+        Vm::from_program_with(program, Box::new(FirstFit::default()))
+    }
+
+    pub fn from_program_with(program: &CompiledProgram, allocator: Box<dyn Allocator>) -> Self {
+        let defs = program.core.0.iter().map(IDef::from_def).collect_vec();
+        let indices = defs
+            .iter()
+            .enumerate()
+            .map(|(i, def)| (def.id.clone(), i as u32))
+            .collect::<HashMap<_, _>>();
+        let mut extern_ids = Vec::new();
+        let functions = defs
+            .iter()
+            .map(|def| compile(def, &indices, &mut extern_ids))
+            .collect::<Rc<[_]>>();
+        let main = *indices
+            .get("main")
+            .expect("Function 'main' should be in functions but is not")
+            as usize;
+
+        let mut vm = Vm {
+            externs: vec![None; extern_ids.len()],
+            extern_ids,
+            heap: Heap::new(allocator),
+            registers: vec![Data::Value(0); functions[main].registers],
+            frames: vec![Frame {
+                function: main,
+                base: 0,
+                cells: 0,
+                return_pc: 0,
+            }],
+            functions,
+            frame_cells: Vec::new(),
+            pc: 0,
+            base: 0,
+            return_value: None,
+            steps: 0,
+            layouts: program.layouts.clone(),
+            random_state: intrinsics::RANDOM_SEED,
+        };
+        for cell in &program.core.2 {
+            vm.heap.add_static(cell);
+        }
+        vm
+    }
+
+    pub fn with_extern(mut self, id: &str, function: impl Fn(&[i64]) -> i64 + 'static) -> Self {
+        // Externs the program never calls are not needed
+        if let Some(i) = self.extern_ids.iter().position(|other| other == id) {
+            self.externs[i] = Some(Rc::new(function));
+        }
+        self
+    }
+}
+// memory
+impl Vm {
+    fn free_frame_cells(&mut self, from: usize) {
+        while self.frame_cells.len() > from {
+            let ptr = self.frame_cells.pop().unwrap();
+            self.heap.free(ptr);
+        }
+    }
+}
+// running
+impl Vm {
+    fn get(&self, reg: u32) -> Data {
+        self.registers[self.base + reg as usize]
+    }
+
+    fn set(&mut self, reg: u32, data: Data) {
+        self.registers[self.base + reg as usize] = data;
+    }
+
+    fn ptr(&self, reg: u32) -> usize {
+        match self.get(reg) {
+            Data::Pointer(ptr) => ptr,
+            Data::Value(_) => panic!("Not a pointer"),
+        }
+    }
+
+    fn data(&self, src: Src) -> Data {
+        match src {
+            Src::Reg(reg) => self.get(reg),
+            Src::Int(i) => Data::Value(i),
+            Src::Static(i) => Data::Pointer(self.heap.static_cell(i as usize)),
+            Src::Not(_) => panic!("Negated operands are only conditions"),
+        }
+    }
+
+    fn eval(&self, src: Src) -> i64 {
+        make63bit(match src {
+            Src::Reg(reg) => self.get(reg).unwrap_val(),
+            Src::Int(i) => i,
+            Src::Static(_) => panic!("Not a value"),
+            Src::Not(reg) => match self.get(reg) {
+                Data::Value(0) | Data::Pointer(0) => 1,
+                _ => 0,
+            },
+        })
+    }
+
+    pub fn step(&mut self) -> bool {
+        let functions = Rc::clone(&self.functions);
+        self.execute(&functions)
+    }
+
+    pub fn run_until_done(&mut self) {
+        let functions = Rc::clone(&self.functions);
+        while self.execute(&functions) {}
+    }
+
+    fn execute(&mut self, functions: &[Function]) -> bool {
+        let Some(frame) = self.frames.last() else {
+            return false;
+        };
+        let op = &functions[frame.function].code[self.pc];
+        self.pc += 1;
+        self.steps += 1;
+        match op {
+            Op::If(arms, end) => {
+                self.pc = *end as usize;
+                for (condition, target) in arms.iter() {
+                    if self.eval(*condition) == 1 {
+                        self.pc = *target as usize;
+                        break;
+                    }
+                }
+            }
+            // The last case also takes any tag past it, like the default of
+            // the C switch
+            Op::Switch(src, cases) => {
+                let tag = match self.data(*src) {
+                    Data::Pointer(ptr) => header_tag(self.heap.header(ptr)),
+                    Data::Value(i) => i,
+                };
+                self.pc = cases[(tag as usize).min(cases.len() - 1)] as usize;
+            }
+            Op::Goto(target) => {
+                self.steps -= 1;
+                self.pc = *target as usize;
+            }
+            Op::Return(src) => {
+                self.return_value = Some(self.data(*src));
+                let frame = self.frames.pop().unwrap();
+                self.free_frame_cells(frame.cells);
+                self.registers.truncate(frame.base);
+                self.pc = frame.return_pc;
+                if let Some(caller) = self.frames.last() {
+                    self.base = caller.base;
+                }
+            }
+            Op::Malloc(dst, w) => {
+                let ptr = self.heap.alloc(*w as usize);
+                self.set(*dst, Data::Pointer(ptr));
+            }
+            Op::StackAlloc(dst, w) => {
+                let ptr = self.heap.alloc(*w as usize);
+                self.frame_cells.push(ptr);
+                self.set(*dst, Data::Pointer(ptr));
+            }
+            Op::Move(dst, src) => {
+                let data = self.data(*src);
+                self.set(*dst, data);
+            }
+            Op::Store(reg, i, src) => {
+                let ptr = self.ptr(*reg);
+                self.heap[ptr][*i as usize] = self.data(*src);
+            }
+            Op::Load(dst, reg, i) => {
+                let data = self.heap[self.ptr(*reg)][*i as usize];
+                self.set(*dst, data);
+            }
+            Op::Binary(dst, operator, lhs, rhs) => {
+                let lhs = self.eval(*lhs);
+                let rhs = self.eval(*rhs);
+                let val = match operator {
+                    Operator::Equal => (lhs == rhs) as i64,
+                    Operator::NotEqual => (lhs != rhs) as i64,
+                    Operator::Less => (lhs < rhs) as i64,
+                    Operator::LessOrEq => (lhs <= rhs) as i64,
+                    Operator::Greater => (lhs > rhs) as i64,
+                    Operator::GreaterOrEqual => (lhs >= rhs) as i64,
+                    Operator::Add => lhs + rhs,
+                    Operator::Sub => lhs - rhs,
+                    Operator::Mul => lhs * rhs,
+                    Operator::Div => lhs / rhs,
+                    Operator::Mod => lhs % rhs,
+                };
+                self.set(*dst, Data::Value(make63bit(val)));
+            }
+            Op::Call(function, args) => {
+                let base = self.registers.len();
+                for arg in args.iter() {
+                    let data = self.data(*arg);
+                    self.registers.push(data);
+                }
+                let function = *function as usize;
+                self.registers
+                    .resize(base + functions[function].registers, Data::Value(0));
+                self.frames.push(Frame {
+                    function,
+                    base,
+                    cells: self.frame_cells.len(),
+                    return_pc: self.pc,
+                });
+                self.base = base;
+                self.pc = 0;
+            }
+            Op::Result(dst) => {
+                let data = self.return_value.take().unwrap();
+                self.set(*dst, data);
+            }
+            // The arguments are put after the registers of the frame and
+            // then moved down to replace them
+            Op::TailCall(function, args) => {
+                let end = self.registers.len();
+                for arg in args.iter() {
+                    let data = self.data(*arg);
+                    self.registers.push(data);
+                }
+                self.registers.drain(self.base..end);
+                let function = *function as usize;
+                self.registers
+                    .resize(self.base + functions[function].registers, Data::Value(0));
+                let frame = self.frames.last_mut().unwrap();
+                frame.function = function;
+                let cells = frame.cells;
+                self.free_frame_cells(cells);
+                self.pc = 0;
+            }
+            Op::Extern(dst, i, args) => {
+                let args = args.iter().map(|arg| self.eval(*arg)).collect_vec();
+                let function = self.externs[*i as usize].as_ref().unwrap_or_else(|| {
+                    panic!(
+                        "Extern '{}' should be registered but is not",
+                        self.extern_ids[*i as usize]
+                    )
+                });
+                let val = make63bit(function(&args));
+                self.set(*dst, Data::Value(val));
+            }
+            Op::Intrinsic(dst, eval, args) => {
+                let args = args.iter().map(|arg| self.eval(*arg)).collect_vec();
+                let val = eval(&mut self.random_state, &args);
+                self.set(*dst, Data::Value(make63bit(val)));
+            }
+            Op::DropReuse(dst, reg, kept) => {
+                let reused = self.heap.drop_reuse(self.get(*reg), kept);
+                self.set(*dst, reused);
+            }
+            Op::Inc(reg) => {
+                if let Data::Pointer(ptr) = self.get(*reg) {
+                    self.heap.inc(ptr);
+                }
+            }
+            Op::Dec(reg) => {
+                if let Data::Pointer(ptr) = self.get(*reg) {
+                    self.heap.dec(ptr);
+                }
+            }
+            Op::Tuple(dst, items) => {
+                let ptr = self.heap.alloc(1 + items.len());
+                self.heap[ptr][0] = Data::Value(-1);
+                for (i, item) in items.iter().enumerate() {
+                    self.heap[ptr][1 + i] = self.get(*item);
+                }
+                self.set(*dst, Data::Pointer(ptr));
+            }
+            Op::DecTuple(reg) => {
+                let ptr = self.ptr(*reg);
+                self.heap.dec_tuple(ptr);
+            }
+            Op::TupleField(dst, reg, i) => {
+                let data = self.heap[self.ptr(*reg)][1 + *i as usize];
+                self.set(*dst, data);
+            }
+            Op::Drop(reg, decs, incs) => {
+                let ptr = self.ptr(*reg);
+                self.heap.drop(ptr, decs, incs);
+            }
+            Op::Label => (),
+            Op::Jump(target) => self.pc = *target as usize,
+        }
+        true
+    }
+}
+// results
+impl Vm {
+    pub fn get_return_value(&self) -> Option<Data> {
+        self.return_value
+    }
+
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn get_alloc_stats(&self) -> AllocStats {
+        self.heap.stats()
+    }
+
+    pub fn get_functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    // The result the way a compiled C program prints it
+    pub fn get_return_named_format(&self) -> String {
+        let Some(data) = self.get_return_value() else {
+            panic!("Dont use this when the vm has not finished");
+        };
+        named_format(&self.heap, &self.layouts, data)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn vm_bench<P>(path: P, allocator: &dyn Allocator)
+where
+    P: AsRef<Path>,
+{
+    let core_ir = _compile(path);
+    let mut vm = Vm::from_program_with(&core_ir, allocator.boxed());
+    let now = Instant::now();
+    vm.run_until_done();
+    let elapsed = now.elapsed();
+    let steps = vm.steps;
+    println!("{steps} steps in {} ms", elapsed.as_micros() as f64 / 1000.);
+    println!(
+        "{} steps/s",
+        (steps as u128 * 1_000_000) / elapsed.as_micros().max(1)
+    );
+    let stats = vm.get_alloc_stats();
+    println!(
+        "Peak memory was {} words, peak heap size {} words",
+        stats.peak, stats.peak_footprint
+    );
+}
@@ -65,10 +65,16 @@ pub enum Token {
 
     #[token("fip")]
     Fip,
+    #[token("inline")]
+    Inline,
+    #[token("noinline")]
+    NoInline,
     #[token("match")]
     Match,
     #[token("enum")]
     Enum,
+    #[token("extern")]
+    Extern,
     #[token("let")]
     Let,
     #[token("in")]
@@ -7,7 +7,7 @@ pub mod compiler;
 pub mod error;
 pub mod interpreter;
 mod lexer;
-mod preprocessor;
+pub mod preprocessor;
 
 lalrpop_mod!(pub grammar);
 
@@ -35,7 +35,15 @@ pub fn compile(code: &str) -> Result<CompiledProgram> {
 }
 
 pub fn c_code(program: &CompiledProgram) -> String {
-    compiler::core::output(&program.core).join("\n")
+    compiler::core::output(&program.core, &program.layouts).join("\n")
+}
+
+pub fn llvm_code(program: &CompiledProgram) -> String {
+    compiler::llvm::output(&program.core, &program.layouts).join("\n")
+}
+
+pub fn wasm_module(program: &CompiledProgram) -> Vec<u8> {
+    compiler::wasm::output(&program.core, &program.layouts)
 }
 
 pub fn stir_str(program: &CompiledProgram) -> String {
@@ -2,6 +2,7 @@
 #![feature(btree_cursors)]
 #![feature(mixed_integer_ops_unsigned_sub)]
 
+use std::io::Write;
 use std::time::Duration;
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -10,21 +11,12 @@ use std::path::PathBuf;
 #[cfg(not(target_arch = "wasm32"))]
 use clap::Parser;
 
-use ast::base::BaseSliceProgram;
-use ast::{scoped::ScopedProgram, typed::TypedProgram};
-use compiler::compile::compile_typed;
-use error::Result;
-use lalrpop_util::lalrpop_mod;
-use preprocessor::preprocess;
-
-pub mod ast;
-pub mod compiler;
-mod error;
-mod interpreter;
-mod lexer;
-pub mod preprocessor;
-
-lalrpop_mod!(pub grammar);
+use language::ast::base::BaseSliceProgram;
+use language::ast::{scoped::ScopedProgram, typed::TypedProgram};
+use language::compiler::compile::{self, CompileOptions, compile_typed_with};
+use language::error::Result;
+use language::preprocessor::preprocess;
+use language::{compiler, interpreter};
 
 #[cfg(target_arch = "wasm32")]
 fn main() {}
@@ -35,6 +27,14 @@ fn parse_and_validate(code: &str) -> Result<TypedProgram<'_>> {
     TypedProgram::new(scoped_program)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+fn check_entry_points(typed: &TypedProgram, entry_points: &[String]) {
+    if let Some(id) = compile::unknown_entry_point(typed, entry_points) {
+        eprintln!("error: entry point {} is not a function of the program", id);
+        std::process::exit(1);
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long)]
@@ -45,6 +45,60 @@ struct Args {
     preprocess: bool,
     #[arg(short, long)]
     benchmark: bool,
+    /// Run the program to the end on the bytecode VM instead of stepping through
+    /// it, with -i
+    #[arg(long)]
+    vm: bool,
+    /// How the interpreter and the VM place cells on their heap: first-fit, free-list,
+    /// bump or size-class, with -i
+    #[arg(long, default_value = "first-fit")]
+    allocator: String,
+    /// How much to simplify the program before compiling it, 0 to 2
+    #[arg(short = 'O', long, default_value_t = 2)]
+    opt_level: u8,
+    /// Inline non-recursive functions up to this size, 0 to only inline those marked inline
+    #[arg(long, default_value_t = 20)]
+    inline_size: usize,
+    /// Only look for reuse in functions marked fip
+    #[arg(long)]
+    fip_only_reuse: bool,
+    /// Write every field of a reused cell, even unchanged ones
+    #[arg(long)]
+    no_reuse_specialization: bool,
+    /// Keep reference counting exactly as inserted, without fusing incs and decs
+    #[arg(long)]
+    no_rc_fusion: bool,
+    /// Leave self calls in constructor context as ordinary recursion
+    #[arg(long)]
+    no_trmc: bool,
+    /// Build constant constructor terms every time instead of once, at compile time
+    #[arg(long)]
+    no_static_data: bool,
+    /// Allocate every constructor on the heap, even ones that never leave their function
+    #[arg(long)]
+    no_stack_alloc: bool,
+    /// Recycle freed cells through per-size free lists in the C runtime
+    #[arg(long)]
+    free_list: bool,
+    /// Print allocation counts of the C runtime to stderr when the program ends
+    #[arg(long)]
+    alloc_stats: bool,
+    /// Paste the C runtime into the output instead of including goopea_runtime.h
+    #[arg(long)]
+    inline_runtime: bool,
+    /// Emit LLVM IR instead of C, with the runtime written in IR, for llc or clang
+    #[arg(long)]
+    llvm: bool,
+    /// Emit a binary WebAssembly module instead of C, run with runtime/run_wasm.js
+    #[arg(long)]
+    wasm: bool,
+    /// Emit a library exporting the entry points instead of a program, with its
+    /// header written to this path
+    #[arg(long = "lib", value_name = "HEADER")]
+    lib: Option<PathBuf>,
+    /// Functions to compile along with everything they call
+    #[arg(long = "entry", default_values_t = ["main".to_string()])]
+    entry_points: Vec<String>,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -57,37 +111,123 @@ fn main() {
 
     let args = Args::parse();
     let file = args.file;
+    let options = CompileOptions {
+        opt_level: args.opt_level,
+        inline_size: args.inline_size,
+        reuse_fip_only: args.fip_only_reuse,
+        specialize_reuse: !args.no_reuse_specialization,
+        fuse_rc: !args.no_rc_fusion,
+        trmc: !args.no_trmc,
+        static_data: !args.no_static_data,
+        stack_alloc: !args.no_stack_alloc,
+        entry_points: args.entry_points,
+    };
     match (args.interpret, args.preprocess) {
         (false, false) => {
             let code = preprocess(file);
             let typed_program = parse_and_validate(&code)
                 .map_err(|e| e.to_string())
                 .unwrap();
-            let compiled_program = compile_typed(&typed_program);
-            let result = compiler::core::output(&compiled_program.core);
+            check_entry_points(&typed_program, &options.entry_points);
+            let compiled_program = compile_typed_with(&typed_program, &options);
+            // A library exports every constructor through its header
+            if args.lib.is_none() {
+                for cons in &compiled_program.unused_constructors {
+                    eprintln!("warning: constructor {} is never used", cons);
+                }
+            }
+            let runtime = compiler::core::Runtime {
+                free_list: args.free_list,
+                alloc_stats: args.alloc_stats,
+                inline: args.inline_runtime,
+            };
+            if args.llvm {
+                if args.lib.is_some() || args.free_list || args.alloc_stats {
+                    eprintln!("error: the LLVM backend only emits programs, with a malloc runtime");
+                    std::process::exit(1);
+                }
+                let result =
+                    compiler::llvm::output(&compiled_program.core, &compiled_program.layouts);
+                println!("{}", result.join("\n"));
+                return;
+            }
+            if args.wasm {
+                if args.lib.is_some() || args.llvm || args.free_list || args.alloc_stats {
+                    eprintln!("error: the wasm backend only emits programs, with its own runtime");
+                    std::process::exit(1);
+                }
+                let module =
+                    compiler::wasm::output(&compiled_program.core, &compiled_program.layouts);
+                std::io::stdout().write_all(&module).unwrap();
+                return;
+            }
+            if let Some(header) = &args.lib {
+                if options.entry_points.iter().any(|id| id == "main") {
+                    eprintln!("error: a library exports the functions given with --entry, not main");
+                    std::process::exit(1);
+                }
+                let exports = compile::exports(&compiled_program, &options.entry_points);
+                let name = header.file_name().unwrap().to_string_lossy();
+                let (source, lines) = compiler::library::output_lib(
+                    &compiled_program.core,
+                    &compiled_program.layouts,
+                    runtime,
+                    &name,
+                    &exports,
+                );
+                std::fs::write(header, lines.join("\n") + "\n").unwrap();
+                println!("{}", source.join("\n"));
+                return;
+            }
+            let result = compiler::core::output_with(
+                &compiled_program.core,
+                &compiled_program.layouts,
+                runtime,
+            );
             println!("{}", result.join("\n"));
         }
         (false, true) => {
             println!("{}", preprocess(file));
         }
         (true, false) => {
+            let Some(allocator) = interpreter::allocator::allocator(&args.allocator) else {
+                eprintln!(
+                    "error: unknown allocator '{}', expected one of {}",
+                    args.allocator,
+                    interpreter::allocator::ALLOCATORS.join(", ")
+                );
+                std::process::exit(1);
+            };
+            let allocator = allocator.as_ref();
             if args.benchmark {
                 if file.is_dir() {
                     // warmup, needed for cache reasons
-                    interpreter::interpreter_bench_fip(&file, Duration::from_micros(0));
+                    interpreter::interpreter_bench_fip(&file, Duration::from_micros(0), allocator);
                     // write lines to std out
-                    println!("file, fip, malloc_time_micros, exec_time_ms, steps, steps/s, max_mem_words");
-                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(0)));
-                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(1)));
-                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(2)));
-                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(5)));
-                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(10)));
+                    println!("file, fip, malloc_time_micros, exec_time_ms, steps, steps/s, max_mem_words, max_heap_words, fragmentation");
+                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(0), allocator));
+                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(1), allocator));
+                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(2), allocator));
+                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(5), allocator));
+                    println!("{}", interpreter::interpreter_bench_fip(&file, Duration::from_micros(10), allocator));
+                } else if args.vm {
+                    interpreter::vm::vm_bench(&file, allocator);
                 } else {
-                    interpreter::interpreter_bench(&file);
-                    interpreter::interpreter_bench_peak_mem(file);
+                    interpreter::interpreter_bench(&file, allocator);
+                    interpreter::interpreter_bench_peak_mem(file, allocator);
                 }
+            } else if args.vm {
+                let code = preprocess(file);
+                let typed_program = parse_and_validate(&code).map_err(|e| e.to_string()).unwrap();
+                check_entry_points(&typed_program, &options.entry_points);
+                let compiled_program = compile_typed_with(&typed_program, &options);
+                let mut vm =
+                    interpreter::vm::Vm::from_program_with(&compiled_program, allocator.boxed());
+                vm.run_until_done();
+                println!("{}", vm.get_return_named_format());
+                eprintln!("{} steps", vm.get_steps());
             } else {
-                interpreter::interpreter_test(file);
+                interpreter::interpreter_test(file, allocator);
             }
         }
         (true, true) => panic!("cant interpret and preprocess"),
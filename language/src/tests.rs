@@ -1,10 +1,73 @@
 use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::compiler::compile::{CompileOptions, CompiledProgram};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::interpreter::{Interpreter, _compile_string_with};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::preprocessor::preprocess;
+
 fn test_file(filename: &str) -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR"))
         .join("tests")
         .join(filename)
 }
 
+fn example_file(filename: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("examples")
+        .join(filename)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn compile_with(path: PathBuf, options: CompileOptions) -> CompiledProgram {
+    _compile_string_with(preprocess(path), &options)
+}
+
+// Runs the program, returning its formatted result and the number of steps taken
+#[cfg(not(target_arch = "wasm32"))]
+fn run(core_ir: &CompiledProgram) -> (String, usize) {
+    let mut interpreter = Interpreter::from_program(core_ir);
+    let mut steps = 0;
+    while interpreter.step() {
+        steps += 1;
+    }
+    (interpreter.get_return_format(), steps)
+}
+
+// Builds C output with cc against the runtime and runs it
+#[cfg(not(target_arch = "wasm32"))]
+fn run_c(name: &str, code: &str, flags: &[&str]) -> std::process::Output {
+    let dir = std::env::temp_dir().join(format!("goopea_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(format!("{}.c", name)), code).unwrap();
+    let runtime = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("runtime");
+    let status = std::process::Command::new("cc")
+        .args(flags)
+        .arg("-I")
+        .arg(&runtime)
+        .arg(dir.join(format!("{}.c", name)))
+        .arg("-o")
+        .arg(dir.join(name))
+        .status()
+        .unwrap();
+    assert!(status.success(), "cc failed on {}", name);
+    let output = std::process::Command::new(dir.join(name)).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{} crashed", name);
+    output
+}
+
+// Runs a program compiled with a pass on and off, checking both give the same
+// result and that the pass saved steps. Returns the shared result
+#[cfg(not(target_arch = "wasm32"))]
+fn compare_steps(on: &CompiledProgram, off: &CompiledProgram) -> String {
+    let (on_result, on_steps) = run(on);
+    let (off_result, off_steps) = run(off);
+    assert_eq!(on_result, off_result);
+    assert!(on_steps < off_steps);
+    on_result
+}
+
 #[cfg(test)]
 #[cfg(not(target_arch = "wasm32"))]
 mod tests_parse_lex {
@@ -61,12 +124,36 @@ mod tests_preprocessor {
     }
 }
 
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_division {
+    use super::run_c;
+    use crate::c_code;
+    use crate::interpreter::{Interpreter, _compile_string};
+
+    #[test]
+    fn negative_quotients_truncate_in_c() {
+        let core_ir = _compile_string(
+            "noinline (Int, Int): Int\nquot(a, b) = a / b;\n\n(): Int\nmain = quot(-6, 3) * 100 + quot(-7, 2);"
+                .to_string(),
+        );
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), -203);
+
+        let output = run_c("division", &c_code(&core_ir), &[]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "-203");
+    }
+}
+
 #[cfg(test)]
 #[cfg(not(target_arch = "wasm32"))]
 mod tests_interpreter {
     use super::test_file;
+    use crate::compiler::borrow::{Status, get_ownership};
+    use crate::compiler::compile::CompileOptions;
     use crate::interpreter;
-    use interpreter::{_compile, Interpreter};
+    use interpreter::{_compile, _compile_string_with, Interpreter};
 
     #[test]
     fn interpreter_0() {
@@ -76,6 +163,129 @@ mod tests_interpreter {
         assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 8);
     }
 
+    // Once the Int in Some is projected out nothing else reads the cell it was
+    // in, so only the four cells of the returned list are left
+    #[test]
+    fn unboxed_projection_frees_its_cell() {
+        let program = crate::compile(
+            "enum List = Nil, Cons(Int, List);
+            enum Option = None, Some(Int);
+            enum Iter = Range(Int, Int);
+            Iter: (Option, Iter)
+            next(iter) = match iter {
+                Range(curr, stop): match curr < stop {
+                    True: (Some(curr), Range(curr + 1, stop)),
+                    False: (None, Range(curr, stop))
+                }
+            };
+            Iter: List
+            fromIter(iter) = let (o, iter2) = next iter in match o {
+                Some(x): Cons(x, fromIter iter2),
+                None: Nil
+            };
+            Int: Int
+            count n = match n == 0 {
+                True: 0,
+                False: 1 + count(n - 1)
+            };
+            (): List
+            main = fromIter(Range(-2, count(2)));",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::from_program(&program);
+        interpreter.run_until_done();
+        let cells = interpreter
+            .get_memory_raw()
+            .into_iter()
+            .filter(|cell| !cell.is_empty())
+            .count();
+        assert_eq!(cells, 4);
+    }
+
+    // sumAcc only reads its list, but owning it saves a dec after the tail call
+    // in sumTo, which would take the call out of tail position
+    #[test]
+    fn tail_calls_own_what_they_are_passed() {
+        let program = crate::compile(
+            "enum List = Nil, Cons(Int, List);
+            (List, Int): Int
+            sumAcc(list, acc) = match list {
+                Nil: acc,
+                Cons(x, xs): sumAcc(xs, acc + x)
+            };
+            Int: List
+            build n = match n == 0 {
+                True: Nil,
+                False: Cons(n, build(n - 1))
+            };
+            (Int, Int): Int
+            sumTo(n, times) = match times == 0 {
+                True: sumAcc(build(n), 0),
+                False: sumTo(n, times - 1)
+            };
+            (): Int
+            main = sumTo(10, 2);",
+        )
+        .unwrap();
+        let ownership = get_ownership(&program.reuse);
+        assert_eq!(ownership["sumAcc"][0], Status::Owned);
+    }
+
+    // The editor steps to right before the heap changes, which here is when a
+    // cell is allocated or one of its fields written. Writing Nil to the zeroed
+    // tail of the first cell changes nothing
+    #[test]
+    fn delta_data_stops_before_the_heap_changes() {
+        let program = _compile_string_with(
+            "enum List = Nil, Cons(Int, List);
+            noinline Int: List
+            build n = match n == 0 {
+                True: Nil,
+                False: Cons(n, build(n - 1))
+            };
+            (): List
+            main = build(3);"
+                .to_string(),
+            &CompileOptions {
+                static_data: false,
+                trmc: false,
+                ..Default::default()
+            },
+        );
+        let mut interpreter = Interpreter::from_program(&program);
+        let mut stops = 0;
+        loop {
+            interpreter.run_until_delta_data();
+            let mut next = interpreter.clone();
+            if !next.step() {
+                break;
+            }
+            assert_ne!(next.get_memory_raw(), interpreter.get_memory_raw());
+            stops += 1;
+        }
+        assert_eq!(stops, 8);
+        assert_eq!(interpreter.get_return_format(), "[3, 2, 1]");
+    }
+
+    // The editor keeps a copy from every step, which share cells with the
+    // interpreter until either writes to them
+    #[test]
+    fn stored_steps_are_left_alone() {
+        let mut interpreter = Interpreter::from_program(&_compile(test_file("test_1.goo")));
+        let mut history = vec![];
+        loop {
+            history.push((interpreter.clone(), interpreter.get_memory_raw()));
+            if !interpreter.step() {
+                break;
+            }
+        }
+        for (mut stored, memory) in history {
+            assert_eq!(stored.get_memory_raw(), memory);
+            stored.run_until_done();
+            assert_eq!(stored.get_return_format(), interpreter.get_return_format());
+        }
+    }
+
     #[test]
     fn interpreter_1() {
         let core_ir = _compile(test_file("test_1.goo"));
@@ -138,4 +348,1234 @@ mod tests_interpreter {
         interpreter.run_until_done();
         assert_eq!(interpreter.get_return_format(), "[2, 3]");
     }
+
+    #[test]
+    fn one_header_word_per_cell() {
+        let core_ir = _compile(test_file("test_2.goo"));
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        let cells = interpreter
+            .get_memory_raw()
+            .into_iter()
+            .filter(|cell| !cell.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(cells.len(), 5);
+        assert!(cells.iter().all(|cell| cell.len() == 3));
+    }
+
+    // Snapshots share the functions, so a copy taken halfway runs on by itself
+    #[test]
+    fn snapshot_resumes_where_it_was_taken() {
+        let core_ir = _compile(test_file("test_9.goo"));
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        while interpreter.get_function_names_stack().last().unwrap() != "sumAcc" {
+            interpreter.step();
+        }
+        let snapshot = interpreter.clone();
+        let variables = interpreter.get_variables_raw();
+        assert_eq!(
+            variables.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            ["acc", "list"]
+        );
+        interpreter.run_until_done();
+
+        let mut resumed = snapshot;
+        assert_eq!(resumed.get_variables_raw(), variables);
+        resumed.run_until_done();
+        assert_eq!(resumed.get_return_value().unwrap().unwrap_val(), 2001000);
+        assert_eq!(resumed.get_steps(), interpreter.get_steps());
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_reuse {
+    use super::test_file;
+    use crate::compiler::compile::CompileOptions;
+    use crate::interpreter::{Interpreter, _compile, _compile_string_with};
+    use crate::preprocessor::preprocess;
+    use crate::reuse_str;
+
+    #[test]
+    fn reuse_non_fip_and_across_types() {
+        let core_ir = _compile(test_file("test_7.goo"));
+        let reused = reuse_str(&core_ir);
+        assert_eq!(reused.matches("reset").count(), 2);
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 13);
+    }
+
+    // Resetting as soon as one path can use the cell, as fip functions used to,
+    // put the reset before the inner match and left the False branch with a
+    // token it never used, which crashed both backends. The reset now goes
+    // into the branch that builds the Cons
+    #[test]
+    fn fip_reuse_only_on_paths_that_use_the_cell() {
+        let core_ir = _compile(test_file("test_23.goo"));
+        let reused = reuse_str(&core_ir);
+        assert_eq!(reused.matches("reset xs").count(), 1);
+        assert!(reused.find("x > ").unwrap() < reused.find("reset xs").unwrap());
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_format(), "[5, 4, 3, 2, 1]");
+        let cells = interpreter
+            .get_memory_raw()
+            .into_iter()
+            .filter(|cell| !cell.is_empty())
+            .count();
+        assert_eq!(cells, 5);
+    }
+
+    #[test]
+    fn reuse_fip_only() {
+        let core_ir = _compile_string_with(
+            preprocess(test_file("test_7.goo")),
+            &CompileOptions {
+                reuse_fip_only: true,
+                ..Default::default()
+            },
+        );
+        assert!(!reuse_str(&core_ir).contains("reset"));
+    }
+
+    #[test]
+    fn reuse_specialization() {
+        let core_ir = _compile(test_file("test_8.goo"));
+        assert!(reuse_str(&core_ir).contains("keep [tag, 0]"));
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 24);
+
+        let core_ir = _compile_string_with(
+            preprocess(test_file("test_8.goo")),
+            &CompileOptions {
+                specialize_reuse: false,
+                ..Default::default()
+            },
+        );
+        assert!(!reuse_str(&core_ir).contains("keep"));
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_fusion {
+    use super::{compare_steps, compile_with, example_file, test_file};
+    use crate::compiler::compile::CompileOptions;
+    use crate::rc_str;
+
+    #[test]
+    fn fusion_cancels_incs() {
+        let fused = rc_str(&compile_with(test_file("test_8.goo"), CompileOptions::default()));
+        let unfused = rc_str(&compile_with(
+            test_file("test_8.goo"),
+            CompileOptions {
+                fuse_rc: false,
+                ..Default::default()
+            },
+        ));
+        assert!(fused.matches("inc ").count() < unfused.matches("inc ").count());
+    }
+
+    #[test]
+    fn fusion_specializes_drops() {
+        let fused = compile_with(example_file("tree_flip.goo"), CompileOptions::default());
+        let unfused = compile_with(
+            example_file("tree_flip.goo"),
+            CompileOptions {
+                fuse_rc: false,
+                ..Default::default()
+            },
+        );
+        assert!(rc_str(&fused).contains("drop "));
+        compare_steps(&fused, &unfused);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_tail_calls {
+    use super::{compile_with, test_file};
+    use crate::compiler::compile::{CompileOptions, CompiledProgram};
+    use crate::interpreter::Interpreter;
+
+    // Runs the program, returning its result and the deepest the call stack got
+    fn run_depth(core_ir: &CompiledProgram) -> (i64, usize) {
+        let mut interpreter = Interpreter::from_program(core_ir);
+        let mut max_depth = 0;
+        while interpreter.step() {
+            max_depth = max_depth.max(interpreter.get_function_names_stack().len());
+        }
+        (
+            interpreter.get_return_value().unwrap().unwrap_val(),
+            max_depth,
+        )
+    }
+
+    #[test]
+    fn tail_calls_reuse_frame() {
+        let (result, max_depth) =
+            run_depth(&compile_with(test_file("test_9.goo"), CompileOptions::default()));
+        assert_eq!(result, 2001000);
+        assert!(max_depth <= 2);
+    }
+
+    #[test]
+    fn trmc_keeps_stack_flat() {
+        let (result, max_depth) =
+            run_depth(&compile_with(test_file("test_10.goo"), CompileOptions::default()));
+        assert_eq!(result, 1001007);
+        assert!(max_depth <= 2);
+
+        let (result, max_depth) = run_depth(&compile_with(
+            test_file("test_10.goo"),
+            CompileOptions {
+                trmc: false,
+                ..Default::default()
+            },
+        ));
+        assert_eq!(result, 1001007);
+        assert!(max_depth > 1000);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_join_points {
+    use super::test_file;
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::stir_str;
+
+    #[test]
+    fn join_points_share_continuation() {
+        let core_ir = _compile(test_file("test_11.goo"));
+        let stir = stir_str(&core_ir);
+        let signs = stir
+            .split("\n\n")
+            .find(|func| func.starts_with("signs"))
+            .unwrap();
+        // Four matches in a row, without join points the sum would be copied 16 times
+        assert_eq!(signs.matches("ret ").count(), 1);
+        assert_eq!(signs.matches("join join").count(), 4);
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 228);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_simplify {
+    use super::{compare_steps, compile_with, test_file};
+    use crate::compiler::compile::{CompileOptions, CompiledProgram};
+    use crate::stir_str;
+
+    #[test]
+    fn simplify_known_matches() {
+        let simplified = compile_with(test_file("test_12.goo"), CompileOptions::default());
+        let plain = compile_with(
+            test_file("test_12.goo"),
+            CompileOptions {
+                opt_level: 0,
+                ..Default::default()
+            },
+        );
+        let main = |core_ir: &CompiledProgram| {
+            stir_str(core_ir)
+                .split("\n\n")
+                .find(|func| func.starts_with("main"))
+                .unwrap()
+                .to_string()
+        };
+        // The condition is folded away and the match on the fresh Rect is gone
+        assert!(!main(&simplified).contains("match"));
+        assert!(main(&plain).contains("match"));
+
+        assert_eq!(compare_steps(&simplified, &plain), "69");
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_inline {
+    use super::{compare_steps, compile_with, test_file};
+    use crate::compiler::compile::{CompileOptions, CompiledProgram};
+    use crate::stir_str;
+
+    fn function(core_ir: &CompiledProgram, id: &str) -> String {
+        stir_str(core_ir)
+            .split("\n\n")
+            .find(|func| func.starts_with(&format!("{id} ")))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn inline_small_functions() {
+        let inlined = compile_with(test_file("test_13.goo"), CompileOptions::default());
+        assert!(!function(&inlined, "rotate").contains("leftHeavy("));
+        assert!(!function(&inlined, "main").contains("reverseList("));
+        // Recursive and noinline functions stay calls
+        assert!(function(&inlined, "insert").contains("insert("));
+        assert!(function(&inlined, "depth").contains("max("));
+
+        let plain = compile_with(
+            test_file("test_13.goo"),
+            CompileOptions {
+                inline_size: 0,
+                ..Default::default()
+            },
+        );
+        assert!(function(&plain, "rotate").contains("leftHeavy("));
+        assert!(function(&plain, "main").contains("reverseList("));
+        // Marked inline, so inlined regardless of its size
+        assert!(!function(&plain, "main").contains("summary("));
+
+        assert_eq!(compare_steps(&inlined, &plain), "200023");
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_reach {
+    use super::test_file;
+    use crate::compiler::compile::CompileOptions;
+    use crate::interpreter::{Interpreter, _compile_string_with};
+    use crate::preprocessor::preprocess;
+
+    #[test]
+    fn unreachable_functions_dropped() {
+        let core_ir = _compile_string_with(
+            preprocess(test_file("test_14.goo")),
+            &CompileOptions::default(),
+        );
+        let ids = core_ir.stir.iter().map(|func| func.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["area", "main"]);
+        assert_eq!(core_ir.core.0.len(), 2);
+        assert_eq!(
+            core_ir.unused_constructors,
+            ["Circle", "Cons", "Green", "Nil", "Red"]
+        );
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 9);
+
+        let core_ir = _compile_string_with(
+            preprocess(test_file("test_14.goo")),
+            &CompileOptions {
+                entry_points: vec!["main".to_string(), "concatList".to_string()],
+                ..Default::default()
+            },
+        );
+        let ids = core_ir.stir.iter().map(|func| func.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["area", "concatList", "main"]);
+        assert_eq!(core_ir.unused_constructors, ["Circle", "Green", "Nil", "Red"]);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_unboxed {
+    use super::test_file;
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::stir_str;
+
+    #[test]
+    fn newtypes_and_immediates_unboxed() {
+        let core_ir = _compile(test_file("test_15.goo"));
+        let stir = stir_str(&core_ir);
+        for id in ["addMeters", "classify"] {
+            let func = stir
+                .split("\n\n")
+                .find(|func| func.starts_with(&format!("{id} ")))
+                .unwrap();
+            assert!(!func.contains("Ctor("));
+        }
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 41950913);
+    }
+
+    // Only constructors holding an enum are immediates, an Int needs every bit
+    #[test]
+    fn large_ints_stay_boxed() {
+        let core_ir = _compile(test_file("test_24.goo"));
+        let stir = stir_str(&core_ir);
+        let function = |id: &str| {
+            stir.split("\n\n")
+                .find(|func| func.starts_with(&format!("{id} ")))
+                .unwrap()
+                .to_string()
+        };
+        assert!(function("wrap").contains("Ctor("));
+        assert!(!function("heading").contains("Ctor("));
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(
+            interpreter.get_return_named_format(),
+            "(Some(4611686018427387000), -4611686018427387000, Towards(Down), Still)"
+        );
+    }
+
+    #[test]
+    fn unboxed_results_print_boxed() {
+        let core_ir = _compile(test_file("test_16.goo"));
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(
+            interpreter.get_return_format(),
+            "([A: [B: -4], [A: 7]], 0, [B: 5])"
+        );
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_stack_alloc {
+    use super::{compile_with, test_file};
+    use crate::compiler::compile::CompileOptions;
+    use crate::interpreter::Interpreter;
+    use crate::rc_str;
+
+    #[test]
+    fn non_escaping_ctors_on_stack() {
+        let core_ir = compile_with(
+            test_file("test_17.goo"),
+            CompileOptions {
+                static_data: false,
+                ..Default::default()
+            },
+        );
+        let rc = rc_str(&core_ir);
+        let main = rc.split("\n\n").find(|func| func.starts_with("main ")).unwrap();
+        assert_eq!(main.matches("StackCtor(").count(), 3);
+        let heap = compile_with(
+            test_file("test_17.goo"),
+            CompileOptions {
+                stack_alloc: false,
+                static_data: false,
+                ..Default::default()
+            },
+        );
+        assert!(!rc_str(&heap).contains("StackCtor("));
+
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 32077);
+        assert!(interpreter.get_memory_raw().iter().all(|cell| cell.is_empty()));
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_static {
+    use super::{compile_with, run_c, test_file};
+    use crate::compiler::compile::CompileOptions;
+    use crate::compiler::core::{Runtime, output_with};
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::rc_str;
+
+    #[test]
+    fn constant_terms_built_once() {
+        let core_ir = _compile(test_file("test_18.goo"));
+        assert!(rc_str(&core_ir).contains("static Ctor(1, 1, Ctor(1, 2, Ctor(1, 3, 0)))"));
+        assert_eq!(core_ir.core.2.len(), 3);
+        let rebuilt = compile_with(
+            test_file("test_18.goo"),
+            CompileOptions {
+                static_data: false,
+                ..Default::default()
+            },
+        );
+        assert!(!rc_str(&rebuilt).contains("static "));
+
+        // Reusing a static cell copies it, so the second build sees it unchanged
+        let mut interpreter = Interpreter::from_program(&core_ir);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 24);
+        let cells = interpreter
+            .get_memory_raw()
+            .into_iter()
+            .filter(|cell| !cell.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(cells.len(), 3);
+
+        // The C runtime never counts, frees or reuses a static cell, each build
+        // of the list gets fresh cells
+        let c = output_with(
+            &core_ir.core,
+            &core_ir.layouts,
+            Runtime {
+                alloc_stats: true,
+                ..Default::default()
+            },
+        )
+        .join("\n");
+        assert!(c.contains("static Value static0[3] = {STATIC_HEADER(1, 2), 7, 1};"));
+        assert!(c.contains("goopea_drop_reuse_keep(xs, 2)"));
+        let run = run_c("static", &c, &[]);
+        assert_eq!(String::from_utf8_lossy(&run.stdout).trim(), "24");
+        assert_eq!(
+            String::from_utf8_lossy(&run.stderr).trim(),
+            "mallocs: 6, reused: 0, frees: 6, peak: 3"
+        );
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_free {
+    use super::{compile_with, run_c, test_file};
+    use crate::compiler::compile::CompileOptions;
+    use crate::compiler::core::output;
+    use crate::interpreter::allocator::{Allocator, FirstFit};
+    use crate::interpreter::{Interpreter, _compile};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Freeing a list used to recurse once per cell
+    #[test]
+    fn long_list_freed_without_recursion() {
+        let program = _compile(test_file("test_19.goo"));
+        let mut interpreter = Interpreter::from_program(&program);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 3000000);
+        assert!(interpreter.get_memory_raw().iter().all(|cell| cell.is_empty()));
+
+        let run = run_c("free", &output(&program.core, &program.layouts).join("\n"), &[]);
+        assert_eq!(String::from_utf8_lossy(&run.stdout).trim(), "3000000");
+    }
+
+    // Keeps the addresses of the cells freed, in order
+    #[derive(Clone)]
+    struct Recording {
+        allocator: FirstFit,
+        freed: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Allocator for Recording {
+        fn alloc(&mut self, words: usize) -> usize {
+            self.allocator.alloc(words)
+        }
+
+        fn free(&mut self, address: usize, words: usize) {
+            self.freed.borrow_mut().push(address);
+            self.allocator.free(address, words);
+        }
+
+        fn top(&self) -> usize {
+            self.allocator.top()
+        }
+
+        fn boxed(&self) -> Box<dyn Allocator> {
+            Box::new(self.clone())
+        }
+    }
+
+    // The labels of the freed nodes, in the order the interpreter and the C
+    // runtime free them
+    #[test]
+    fn freed_in_the_same_order_as_in_c() {
+        let program = compile_with(
+            test_file("test_25.goo"),
+            CompileOptions {
+                stack_alloc: false,
+                ..Default::default()
+            },
+        );
+        let freed = Rc::new(RefCell::new(Vec::new()));
+        let recording = Recording {
+            allocator: FirstFit::default(),
+            freed: Rc::clone(&freed),
+        };
+        let mut interpreter = Interpreter::from_program_with(&program, Box::new(recording));
+        let mut heap = interpreter.get_memory_raw();
+        let mut labels = Vec::new();
+        while interpreter.step() {
+            for ptr in freed.borrow_mut().drain(..) {
+                labels.push(heap[ptr][1].unwrap_val().to_string());
+            }
+            heap = interpreter.get_memory_raw();
+        }
+        // Each node before its subtrees, the shared tree once the second
+        // reference to it is dropped
+        assert_eq!(
+            labels,
+            ["0", "16", "32", "64", "65", "33", "66", "67", "100", "1", "2", "4", "5", "3", "6", "7"]
+        );
+
+        // Prints the first field of every cell as it is freed
+        let hook = [
+            "#include <stdint.h>",
+            "#include <stdio.h>",
+            "#include <stdlib.h>",
+            "static void record_free(void* cell) {",
+            "\tfprintf(stderr, \"%lld\\n\", (long long) (((int64_t*) cell)[1] >> 1));",
+            "\tfree(cell);",
+            "}",
+            "#define free(cell) record_free(cell)",
+        ];
+        let c = output(&program.core, &program.layouts).join("\n");
+        let c = c.replacen(
+            "#include \"goopea_runtime.h\"",
+            &format!("{}\n#include \"goopea_runtime.h\"", hook.join("\n")),
+            1,
+        );
+        let run = run_c("free_order", &c, &[]);
+        assert_eq!(String::from_utf8_lossy(&run.stdout).trim(), "7");
+        assert_eq!(String::from_utf8_lossy(&run.stderr).lines().collect::<Vec<_>>(), labels);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_alloc {
+    use super::test_file;
+    use crate::compiler::core::{Runtime, output, output_with};
+    use crate::interpreter::_compile;
+
+    fn c_lines(runtime: Option<Runtime>) -> Vec<String> {
+        let program = _compile(test_file("test_19.goo"));
+        match runtime {
+            Some(runtime) => output_with(&program.core, &program.layouts, runtime),
+            None => output(&program.core, &program.layouts),
+        }
+    }
+
+    #[test]
+    fn cells_go_through_the_runtime_allocator() {
+        let lines = c_lines(None);
+        assert!(!lines.iter().any(|line| line.starts_with("#define GOOPEA_")));
+        let body = lines.join("\n");
+        assert!(body.contains("goopea_alloc_cell(3)"));
+        assert!(!body.contains("malloc(") && !body.contains("free("));
+    }
+
+    #[test]
+    fn runtime_switches_become_defines() {
+        let lines = c_lines(Some(Runtime {
+            free_list: true,
+            alloc_stats: true,
+            inline: false,
+        }));
+        assert_eq!(lines[0], "#define GOOPEA_FREE_LIST");
+        assert_eq!(lines[1], "#define GOOPEA_ALLOC_STATS");
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_c_output {
+    use super::{run_c, test_file};
+    use crate::compile;
+    use crate::compiler::core::{RUNTIME_HEADER, RUNTIME_VERSION, Runtime, output, output_with};
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::preprocessor::preprocess;
+
+    #[test]
+    fn runtime_version_matches_header() {
+        let define = format!("#define GOOPEA_RUNTIME_VERSION {}", RUNTIME_VERSION);
+        assert!(RUNTIME_HEADER.lines().any(|line| line == define));
+    }
+
+    #[test]
+    fn runtime_included_unless_inlined() {
+        let program = _compile(test_file("test_17.goo"));
+        let included = output(&program.core, &program.layouts);
+        assert!(included.contains(&"#include \"goopea_runtime.h\"".to_string()));
+        assert!(!included.iter().any(|line| line.contains("static inline")));
+        let inlined = output_with(
+            &program.core,
+            &program.layouts,
+            Runtime {
+                inline: true,
+                ..Default::default()
+            },
+        );
+        assert!(!inlined.iter().any(|line| line.starts_with("#include \"")));
+        assert!(inlined.iter().any(|line| line.contains("static inline")));
+    }
+
+    #[test]
+    fn matches_switch_on_the_tag() {
+        let program = _compile(test_file("test_17.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        assert!(body.contains("switch (CTOR("));
+        assert!(body.contains("default: {"));
+        assert!(!body.contains("else if"));
+    }
+
+    #[test]
+    fn results_print_with_constructor_names() {
+        let program = _compile(test_file("test_16.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        for adt in ["Pair", "Maybe", "Meters"] {
+            assert!(body.contains(&format!("void print_{}(Value v) {{", adt)));
+        }
+        assert!(body.contains("void print_result(Value3 v) {"));
+        let mut interpreter = Interpreter::from_program(&program);
+        interpreter.run_until_done();
+        assert_eq!(
+            interpreter.get_return_named_format(),
+            "(Pair(Some(-4), Meters(7)), None, Some(5))"
+        );
+    }
+
+    #[test]
+    fn lists_print_nested() {
+        let mut interpreter = Interpreter::from_program(&_compile(test_file("test_2.goo")));
+        interpreter.run_until_done();
+        assert_eq!(
+            interpreter.get_return_named_format(),
+            "Cons(2, Cons(3, Cons(4, Cons(6, Cons(5, Nil)))))"
+        );
+    }
+
+    // Printing a list used to recurse once per cell
+    #[test]
+    fn long_lists_print_without_recursion() {
+        let program = _compile(test_file("test_26.goo"));
+        let run = run_c("print", &output(&program.core, &program.layouts).join("\n"), &[]);
+        let printed = String::from_utf8_lossy(&run.stdout);
+        assert!(printed.starts_with("Cons(1, Cons(2, Cons(3, "));
+        assert!(printed.trim_end().ends_with(&format!("Cons(1000000, Nil{}", ")".repeat(1000000))));
+    }
+
+    // Builds the examples without a single warning, with every runtime switch
+    #[test]
+    fn examples_build_without_warnings() {
+        let strict = ["-Wall", "-Wextra", "-Werror", "-std=c11"];
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            // Some examples are only there to show errors
+            let Ok(program) = compile(&preprocess(&path)) else {
+                continue;
+            };
+            let mut interpreter = Interpreter::from_program(&program);
+            interpreter.run_until_done();
+
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let c = output(&program.core, &program.layouts).join("\n");
+            for defines in [&[][..], &["-DGOOPEA_FREE_LIST", "-DGOOPEA_ALLOC_STATS"]] {
+                let flags = [&strict[..], defines].concat();
+                let run = run_c(&format!("strict_{}", name), &c, &flags);
+                assert_eq!(
+                    String::from_utf8_lossy(&run.stdout).trim(),
+                    interpreter.get_return_named_format(),
+                    "{} {:?}",
+                    name,
+                    defines
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_library {
+    use super::test_file;
+    use crate::compiler::compile::{CompileOptions, exports, unknown_entry_point};
+    use crate::compiler::core::Runtime;
+    use crate::compiler::library::output_lib;
+    use crate::ast::{base::BaseSliceProgram, scoped::ScopedProgram, typed::TypedProgram};
+    use crate::interpreter::_compile_string_with;
+    use crate::preprocessor::preprocess;
+
+    fn library() -> (String, String) {
+        let entry_points = ["sumList", "reverseList", "safeHead", "twice"].map(String::from);
+        let program = _compile_string_with(
+            preprocess(test_file("test_20.goo")),
+            &CompileOptions {
+                entry_points: entry_points.to_vec(),
+                ..Default::default()
+            },
+        );
+        let (source, header) = output_lib(
+            &program.core,
+            &program.layouts,
+            Runtime::default(),
+            "lists.h",
+            &exports(&program, &entry_points),
+        );
+        (source.join("\n"), header.join("\n"))
+    }
+
+    #[test]
+    fn header_declares_exports_with_ownership() {
+        let (_, header) = library();
+        assert!(header.contains("#ifndef GOOPEA_LIB_LISTS_H"));
+        assert!(header.contains("Value reverseList(Value list);"));
+        assert!(header.contains("Value sumList(Value /* borrowed */ list);"));
+        assert!(!header.contains("main("));
+    }
+
+    #[test]
+    fn header_builds_and_reads_constructors() {
+        let (_, header) = library();
+        assert!(header.contains("enum List { List_Nil, List_Cons };"));
+        assert!(header.contains("static inline Value new_Cons(Value field0, Value field1) {"));
+        assert!(header.contains("static inline enum Maybe Maybe_tag(Value v) {"));
+        assert!(header.contains("static inline Value Some_field0(Value v) {"));
+        assert!(header.contains("static inline Value new_Meters(Value field0) {"));
+    }
+
+    #[test]
+    fn internal_functions_stay_static() {
+        let (source, _) = library();
+        assert!(source.starts_with("#include \"lists.h\""));
+        assert!(source.contains("\nValue sumList(Value list) {"));
+        assert!(source.contains("\nstatic Value reverseHelper(Value list, Value acc) {"));
+    }
+
+    #[test]
+    fn unknown_entry_points_are_named() {
+        let code = preprocess(test_file("test_20.goo"));
+        let scoped = ScopedProgram::new(BaseSliceProgram::new(&code).unwrap()).unwrap();
+        let typed = TypedProgram::new(scoped).unwrap();
+        let entry_points = ["sumList", "build"].map(String::from);
+        assert_eq!(unknown_entry_point(&typed, &entry_points).unwrap(), "build");
+        assert_eq!(unknown_entry_point(&typed, &entry_points[..1]), None);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_externs {
+    use super::test_file;
+    use crate::ast::base::BaseSliceProgram;
+    use crate::compiler::core::output;
+    use crate::error::ErrorReason;
+    use crate::interpreter::{Interpreter, _compile};
+
+    #[test]
+    fn externs_run_through_registered_closures() {
+        let program = _compile(test_file("test_21.goo"));
+        let mut interpreter = Interpreter::from_program(&program)
+            .with_extern("seed", |_| 7)
+            .with_extern("mix", |args| (args[0] * 31 + args[1]) % 1000 - 500);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), -3875);
+    }
+
+    #[test]
+    fn externs_are_called_with_untagged_ints() {
+        let body = output(&_compile(test_file("test_21.goo")).core, &Default::default()).join("\n");
+        assert!(body.contains("int64_t mix(int64_t, int64_t);"));
+        assert!(body.contains("int64_t seed(void);"));
+        assert!(body.contains("goopea_int(seed())"));
+        assert!(body.contains("goopea_int(mix(goopea_from_int(x), goopea_from_int(n)))"));
+    }
+
+    #[test]
+    fn externs_only_take_ints() {
+        let code = "enum Maybe = None, Some(Int);\nextern Maybe: Int weigh;\n(): Int\nmain = 0;";
+        let error = BaseSliceProgram::new(code).unwrap_err();
+        assert!(matches!(error.reason, ErrorReason::ExternWithNonIntType(fid) if fid == "weigh"));
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_intrinsics {
+    use super::{run_c, test_file};
+    use crate::compiler::core::output;
+    use crate::interpreter::{Interpreter, _compile};
+
+    #[test]
+    fn intrinsics_run_in_the_interpreter() {
+        let mut interpreter = Interpreter::from_program(&_compile(test_file("test_22.goo")));
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_named_format(), "(23, 3, -4)");
+    }
+
+    #[test]
+    fn only_used_intrinsics_are_emitted() {
+        let program = _compile(test_file("test_22.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        for id in ["abs", "min", "random", "trace"] {
+            assert!(body.contains(&format!("static inline Value goopea_{}(", id)));
+        }
+        let program = _compile(test_file("test_0.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        assert!(!body.contains("goopea_random"));
+    }
+
+    #[test]
+    fn functions_of_the_program_come_first() {
+        let program = _compile(test_file("test_13.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        assert!(!body.contains("goopea_max"));
+        let mut interpreter = Interpreter::from_program(&program);
+        interpreter.run_until_done();
+        assert_eq!(interpreter.get_return_value().unwrap().unwrap_val(), 200023);
+    }
+
+    #[test]
+    fn random_draws_the_same_numbers_in_c() {
+        let program = _compile(test_file("test_22.goo"));
+        let code = output(&program.core, &program.layouts).join("\n");
+        let run = run_c("random", &code, &[]);
+        assert_eq!(String::from_utf8_lossy(&run.stdout).trim(), "(23, 3, -4)");
+        assert_eq!(String::from_utf8_lossy(&run.stderr), "trace: 23\n");
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_llvm {
+    use super::test_file;
+    use crate::compile;
+    use crate::compiler::llvm::output;
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::preprocessor::preprocess;
+    use std::process::Command;
+
+    #[test]
+    fn runtime_is_part_of_the_module() {
+        let program = _compile(test_file("test_17.goo"));
+        let body = output(&program.core, &program.layouts).join("\n");
+        assert!(body.contains("define internal i64 @goopea_dec(i64 %ref) {"));
+        assert!(body.contains("define i32 @main() {"));
+        assert!(body.contains("switch i64"));
+    }
+
+    #[test]
+    #[ignore = "needs llc, run with --ignored"]
+    fn random_draws_the_same_numbers_in_llvm() {
+        assert_eq!(run(&_compile(test_file("test_22.goo")), "random"), "(23, 3, -4)");
+    }
+
+    // Builds the program with llc and the C compiler and runs it
+    fn run(program: &crate::compiler::compile::CompiledProgram, name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("goopea_llvm_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ir = dir.join(format!("{}.ll", name));
+        let object = dir.join(format!("{}.o", name));
+        let binary = dir.join(name);
+        std::fs::write(&ir, output(&program.core, &program.layouts).join("\n")).unwrap();
+        let llc = Command::new("llc")
+            .args(["-relocation-model=pic", "-filetype=obj", "-o"])
+            .arg(&object)
+            .arg(&ir)
+            .status()
+            .expect("llc should be installed");
+        assert!(llc.success(), "llc failed on {}", name);
+        let cc = Command::new("cc")
+            .arg(&object)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .unwrap();
+        assert!(cc.success(), "linking failed on {}", name);
+        let run = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(run.status.success(), "{} crashed", name);
+        String::from_utf8_lossy(&run.stdout).trim().to_string()
+    }
+
+    #[test]
+    #[ignore = "needs llc, run with --ignored"]
+    fn examples_match_the_interpreter() {
+        for entry in std::fs::read_dir("examples").unwrap() {
+            let path = entry.unwrap().path();
+            // Some examples are only there to show errors
+            let Ok(program) = compile(&preprocess(&path)) else {
+                continue;
+            };
+            let mut interpreter = Interpreter::from_program(&program);
+            interpreter.run_until_done();
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            assert_eq!(
+                run(&program, &name),
+                interpreter.get_return_named_format(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    // Printing a list used to recurse once per cell
+    #[test]
+    #[ignore = "needs llc, run with --ignored"]
+    fn long_lists_print_without_recursion() {
+        let printed = run(&_compile(test_file("test_26.goo")), "print");
+        assert!(printed.starts_with("Cons(1, Cons(2, Cons(3, "));
+        assert!(printed.ends_with(&format!("Cons(1000000, Nil{}", ")".repeat(1000000))));
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_wasm {
+    use super::test_file;
+    use crate::compile;
+    use crate::compiler::wasm::output;
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::preprocessor::preprocess;
+    use std::process::Command;
+
+    #[test]
+    fn module_exports_main_and_memory() {
+        let program = _compile(test_file("test_17.goo"));
+        let module = output(&program.core, &program.layouts);
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let contains = |name: &[u8]| module.windows(name.len()).any(|window| window == name);
+        assert!(contains(b"\x04main\x00"));
+        assert!(contains(b"\x06memory\x02\x00"));
+        assert!(contains(b"\x03env\x09print_int"));
+    }
+
+    fn run(program: &crate::compiler::compile::CompiledProgram, name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("goopea_{}_{}.wasm", name, std::process::id()));
+        std::fs::write(&path, output(&program.core, &program.layouts)).unwrap();
+        let run = Command::new("node")
+            .arg("runtime/run_wasm.js")
+            .arg(&path)
+            .output()
+            .expect("node should be installed");
+        std::fs::remove_file(&path).unwrap();
+        assert!(run.status.success(), "{} failed", name);
+        String::from_utf8_lossy(&run.stdout).trim().to_string()
+    }
+
+    // Runs the examples and the intrinsics with node
+    #[test]
+    #[ignore = "needs node, run with --ignored"]
+    fn examples_match_the_interpreter() {
+        let mut paths = std::fs::read_dir("examples")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.extend([test_file("test_22.goo"), test_file("test_24.goo")]);
+        for path in paths {
+            // Some examples are only there to show errors
+            let Ok(program) = compile(&preprocess(&path)) else {
+                continue;
+            };
+            let mut interpreter = Interpreter::from_program(&program);
+            interpreter.run_until_done();
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            assert_eq!(
+                run(&program, &name),
+                interpreter.get_return_named_format(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    // Printing a list used to recurse once per cell
+    #[test]
+    #[ignore = "needs node, run with --ignored"]
+    fn long_lists_print_without_recursion() {
+        let printed = run(&_compile(test_file("test_26.goo")), "print");
+        assert!(printed.starts_with("Cons(1, Cons(2, Cons(3, "));
+        assert!(printed.ends_with(&format!("Cons(1000000, Nil{}", ")".repeat(1000000))));
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_vm {
+    use super::{run, test_file};
+    use crate::compile;
+    use crate::interpreter::allocator::{ALLOCATORS, allocator};
+    use crate::interpreter::vm::{Op, Vm};
+    use crate::interpreter::{Interpreter, _compile};
+    use crate::preprocessor::preprocess;
+
+    #[test]
+    fn same_results_and_steps_as_the_interpreter() {
+        // Leaving out test_19 and the benchmarks, which take too many steps
+        // to interpret in debug builds
+        let paths = std::fs::read_dir("examples")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .chain(
+                (0..=22)
+                    .filter(|i| *i != 19)
+                    .map(|i| test_file(&format!("test_{}.goo", i))),
+            );
+        for path in paths {
+            // Some examples are only there to show errors
+            let Ok(program) = compile(&preprocess(&path)) else {
+                continue;
+            };
+            let mut interpreter = Interpreter::from_program(&program)
+                .with_extern("seed", |_| 7)
+                .with_extern("mix", |args| (args[0] * 31 + args[1]) % 1000 - 500);
+            interpreter.run_until_done();
+            let mut vm = Vm::from_program(&program)
+                .with_extern("seed", |_| 7)
+                .with_extern("mix", |args| (args[0] * 31 + args[1]) % 1000 - 500);
+            vm.run_until_done();
+            let name = path.display();
+            assert_eq!(
+                vm.get_return_named_format(),
+                interpreter.get_return_named_format(),
+                "{}",
+                name
+            );
+            assert_eq!(vm.get_steps(), interpreter.get_steps(), "{}", name);
+        }
+    }
+
+    // Both allocate and free the same cells in the same order
+    #[test]
+    fn cells_placed_like_in_the_interpreter() {
+        for name in ALLOCATORS {
+            let program = _compile(test_file("test_25.goo"));
+            let mut interpreter =
+                Interpreter::from_program_with(&program, allocator(name).unwrap());
+            interpreter.run_until_done();
+            let mut vm = Vm::from_program_with(&program, allocator(name).unwrap());
+            vm.run_until_done();
+            assert_eq!(vm.get_alloc_stats(), interpreter.get_alloc_stats(), "{}", name);
+        }
+    }
+
+    // Gotos are not steps, so stepping the VM op by op counts only what the
+    // interpreter would have taken
+    #[test]
+    fn stepping_counts_like_the_interpreter() {
+        for i in [2, 9, 15, 17, 23] {
+            let program = _compile(test_file(&format!("test_{}.goo", i)));
+            let (_, steps) = run(&program);
+            let mut stepped = Vm::from_program(&program);
+            while stepped.step() {}
+            assert_eq!(stepped.get_steps() as usize, steps, "test_{}", i);
+
+            let mut vm = Vm::from_program(&program);
+            vm.run_until_done();
+            assert_eq!(stepped.get_return_named_format(), vm.get_return_named_format());
+        }
+    }
+
+    #[test]
+    fn jumps_are_resolved_to_offsets() {
+        let vm = Vm::from_program(&_compile(test_file("test_17.goo")));
+        let ops = vm.get_functions().iter().flat_map(|f| &f.code).collect::<Vec<_>>();
+        assert!(ops.iter().any(|op| matches!(op, Op::Switch(..))));
+        for op in ops {
+            if let Op::Jump(target) | Op::Goto(target) = op {
+                assert_ne!(*target, 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests_allocator {
+    use super::test_file;
+    use crate::interpreter::allocator::{
+        ALLOCATORS, AllocStats, Allocator, Bump, FirstFit, SizeClass, allocator,
+    };
+    use crate::interpreter::{Interpreter, _compile};
+
+    // Where cells end up changes nothing but the heap size
+    #[test]
+    fn every_allocator_runs_the_same() {
+        for i in (0..=22).filter(|i| *i != 19) {
+            let program = _compile(test_file(&format!("test_{}.goo", i)));
+            let runs = ALLOCATORS
+                .iter()
+                .map(|name| {
+                    let mut interpreter =
+                        Interpreter::from_program_with(&program, allocator(name).unwrap())
+                            .with_extern("seed", |_| 7)
+                            .with_extern("mix", |args| (args[0] * 31 + args[1]) % 1000 - 500);
+                    interpreter.run_until_done();
+                    interpreter
+                })
+                .collect::<Vec<_>>();
+            for (name, run) in ALLOCATORS.iter().zip(&runs) {
+                let stats = run.get_alloc_stats();
+                assert!(stats.peak_footprint >= stats.peak, "test_{} {}", i, name);
+                assert_eq!(stats.peak, runs[0].get_alloc_stats().peak, "test_{} {}", i, name);
+                assert_eq!(stats.live, runs[0].get_alloc_stats().live, "test_{} {}", i, name);
+                assert_eq!(run.get_steps(), runs[0].get_steps(), "test_{} {}", i, name);
+                assert_eq!(
+                    run.get_return_named_format(),
+                    runs[0].get_return_named_format(),
+                    "test_{} {}",
+                    i,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn first_fit_merges_freed_neighbours() {
+        let mut first_fit = FirstFit::default();
+        let cells = [3, 2, 4, 3].map(|words| first_fit.alloc(words));
+        assert_eq!(cells, [1, 4, 6, 10]);
+        first_fit.free(cells[0], 3);
+        first_fit.free(cells[2], 4);
+        assert_eq!(first_fit.alloc(5), 13);
+        first_fit.free(cells[1], 2);
+        assert_eq!(first_fit.alloc(9), 1);
+        first_fit.free(13, 5);
+        first_fit.free(cells[3], 3);
+        assert_eq!(first_fit.top(), 10);
+    }
+
+    // Looking free ranges up by length still takes the lowest one, not the
+    // one that fits best
+    #[test]
+    fn first_fit_takes_the_lowest_range_that_fits() {
+        let mut first_fit = FirstFit::default();
+        let cells = [5, 1, 3, 1].map(|words| first_fit.alloc(words));
+        assert_eq!(cells, [1, 6, 7, 10]);
+        first_fit.free(cells[0], 5);
+        first_fit.free(cells[2], 3);
+        assert_eq!(first_fit.alloc(3), 1);
+        assert_eq!(first_fit.alloc(3), 7);
+        assert_eq!(first_fit.alloc(2), 4);
+        assert_eq!(first_fit.alloc(1), 11);
+    }
+
+    #[test]
+    fn bump_and_size_class_leave_gaps() {
+        let mut bump = Bump::default();
+        let cell = bump.alloc(3);
+        bump.free(cell, 3);
+        assert_eq!(bump.alloc(3), 4);
+
+        let mut size_class = SizeClass::default();
+        let cells = [3, 4, 5].map(|words| size_class.alloc(words));
+        assert_eq!(cells, [1, 5, 9]);
+        size_class.free(cells[0], 3);
+        assert_eq!(size_class.alloc(4), 1);
+        assert_eq!(size_class.top(), 17);
+
+        let program = _compile(test_file("test_1.goo"));
+        let mut interpreter = Interpreter::from_program_with(&program, Box::new(Bump::default()));
+        interpreter.run_until_done();
+        let stats = interpreter.get_alloc_stats();
+        assert!(stats.peak_footprint > stats.peak);
+        assert!(stats.peak_fragmentation() > 0.0);
+    }
+
+    // The heap is biggest after the peak of live words has been freed, when
+    // only 2 of its 6 words are in use
+    #[test]
+    fn peak_fragmentation_is_taken_at_the_biggest_footprint() {
+        let mut stats = AllocStats::default();
+        stats.allocated(4, 5);
+        stats.freed(4, 5);
+        stats.allocated(2, 7);
+        assert_eq!((stats.peak, stats.peak_footprint), (4, 6));
+        assert!((stats.peak_fragmentation() - 2.0 / 3.0).abs() < 1e-9);
+    }
 }